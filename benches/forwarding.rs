@@ -0,0 +1,123 @@
+// cargo bench, to justify changes to the hot path with numbers
+//
+// only the blocking backends exist, single threaded and threads sharing one socket, others go here
+// as they are added so they can be compared on the same loopback setup
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use wireguard_udp_proxy::{config::Config, ExpiringSocket, Proxy, Session, Sessions, WgPacket};
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+const SESSIONS: u32 = 10_000;
+
+fn packet(kind: u8, len: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; len];
+    packet[0] = kind;
+    packet[4..8].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    packet
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, packet) in [
+        ("initiation", packet(1, 148)),
+        ("response", packet(2, 92)),
+        ("cookie", packet(3, 64)),
+        ("data", packet(4, 1420)),
+        ("invalid", packet(9, 32)),
+    ] {
+        group.bench_function(name, |b| b.iter(|| WgPacket::parse(black_box(&packet))));
+    }
+    group.finish();
+}
+
+fn client(i: u32) -> SocketAddr {
+    SocketAddr::from(([10, (i >> 16) as u8, (i >> 8) as u8, i as u8], 51820))
+}
+
+fn session(i: u32) -> Session {
+    let target = SocketAddr::from(([192, 0, 2, 1], 51820));
+    Session::new(ExpiringSocket::new(client(i), None, 0), target)
+}
+
+fn full_table() -> Sessions {
+    let mut sessions = Sessions::default();
+    for i in 0..SESSIONS {
+        sessions.insert(i, session(i), 0);
+    }
+    sessions
+}
+
+fn sessions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sessions");
+    let sessions = full_table();
+    group.bench_function("lookup", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % SESSIONS;
+            sessions.get(black_box(&i)).is_some()
+        })
+    });
+    group.bench_function("lookup_miss", |b| {
+        b.iter(|| sessions.get(black_box(&SESSIONS)).is_some())
+    });
+    group.bench_function("insert", |b| {
+        b.iter_batched_ref(
+            full_table,
+            |sessions| sessions.insert(SESSIONS, session(SESSIONS), 0),
+            BatchSize::LargeInput,
+        )
+    });
+    // the housekeeping thread's pass every second, off the forwarding path since expiries are
+    // queued by when they're due
+    group.bench_function("expire", |b| {
+        let mut sessions = full_table();
+        // at tick 0, before anything in the table expires, so only the look at the queue
+        b.iter(|| sessions.expire(black_box(0)))
+    });
+    group.finish();
+}
+
+/// a proxy on loopback forwarding to target with threads threads, returns its address
+fn spawn_proxy(target: &UdpSocket, threads: usize) -> SocketAddr {
+    let args = [target.local_addr().unwrap().to_string()];
+    let config = Config::from_args(args).unwrap().unwrap();
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = udp_socket.local_addr().unwrap();
+    let proxy = Proxy::start(udp_socket, config).unwrap();
+    for _ in 0..threads {
+        thread::spawn(move || proxy.run());
+    }
+    proxy_addr
+}
+
+fn forwarding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forwarding");
+    let data = packet(4, 1420);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for (name, threads) in [("blocking", 1), ("blocking_threaded", 4)] {
+        let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+        target
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let proxy_addr = spawn_proxy(&target, threads);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(proxy_addr).unwrap();
+        let mut buf = [0u8; 2048];
+        // client to target through the proxy, the direction that needs no session
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                client.send(&data).unwrap();
+                target.recv(&mut buf).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, sessions, forwarding);
+criterion_main!(benches);
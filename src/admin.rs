@@ -0,0 +1,316 @@
+// --admin, a unix socket taking one command per line, for poking at a running proxy with something
+// like `socat - UNIX-CONNECT:path`
+//
+// a command's output is followed by a line that is either "ok" or "error: reason", so scripts know
+// when it's done
+//
+// with --admin-tokens a connection has to `auth token` before anything else, and a tenant's token
+// only reaches that tenant's listener
+
+use crate::args;
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result, Write},
+    time::Duration,
+};
+
+/// whose commands a connection sends
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    /// everything, what every connection is without --admin-tokens
+    Operator,
+    /// the listener of the tenant with this name only
+    Tenant(String),
+}
+
+/// --admin-tokens, a line per token with the tenant it belongs to after it, or none for the
+/// operator's
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tokens(Vec<(String, Scope)>);
+
+impl Tokens {
+    pub fn from_file(path: &str) -> Result<Tokens> {
+        let mut tokens = Vec::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let mut words = line.split_whitespace();
+            let (token, scope) = match (words.next(), words.next(), words.next()) {
+                (None, ..) => continue,
+                (Some(token), _, _) if token.starts_with('#') => continue,
+                (Some(token), None, _) => (token, Scope::Operator),
+                (Some(token), Some(tenant), None) => (token, Scope::Tenant(tenant.to_string())),
+                _ => {
+                    return Err(args::invalid(format!(
+                        "expected token [tenant] in {}: {}",
+                        path, line
+                    ))
+                    .into())
+                }
+            };
+            tokens.push((token.to_string(), scope));
+        }
+        if tokens.is_empty() {
+            return Err(args::invalid(format!("no tokens in {}", path)).into());
+        }
+        Ok(Tokens(tokens))
+    }
+
+    /// the tenants tokens were given for
+    pub fn tenants(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|(_, scope)| match scope {
+            Scope::Tenant(name) => Some(name.as_str()),
+            Scope::Operator => None,
+        })
+    }
+
+    /// the scope of token, every one of ours is looked at so how long it takes tells nothing
+    pub fn scope(&self, token: &str) -> Option<Scope> {
+        let mut found = None;
+        for (ours, scope) in &self.0 {
+            if same(ours.as_bytes(), token.as_bytes()) {
+                found = Some(scope.clone());
+            }
+        }
+        found
+    }
+}
+
+/// compares a and b in a time that depends on their lengths only
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// the error for a command that makes no sense, reported back to whoever sent it
+pub fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// where a command's output goes
+pub trait Output: Write {
+    /// whether whoever asked for a command that goes on until they stop it did, by sending another
+    /// line or hanging up
+    fn cancelled(&mut self) -> bool {
+        false
+    }
+}
+
+impl Output for Vec<u8> {}
+
+/// a connection to the admin socket, what we write to it is buffered until the command's done or
+/// flushes
+#[cfg(unix)]
+struct Connection {
+    out: std::io::BufWriter<std::os::unix::net::UnixStream>,
+}
+
+#[cfg(unix)]
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(unix)]
+impl Output for Connection {
+    fn cancelled(&mut self) -> bool {
+        use std::os::unix::io::AsRawFd;
+
+        let mut buf = [0u8; 1];
+        let fd = self.out.get_ref().as_raw_fd();
+        let peeked = unsafe {
+            libc::recv(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        // a line, or 0 for hung up, anything but nothing to read yet
+        peeked >= 0 || Error::last_os_error().kind() != ErrorKind::WouldBlock
+    }
+}
+
+/// a session's receiver index as we log them, 8 hex digits
+pub fn parse_index(index: &str) -> Result<u32> {
+    u32::from_str_radix(index, 16).map_err(|_| invalid(format!("invalid index: {}", index)))
+}
+
+/// a duration like 600, 90s, 10m or 1h
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let (n, unit) = match duration.char_indices().last() {
+        Some((i, 's')) => (&duration[..i], 1),
+        Some((i, 'm')) => (&duration[..i], 60),
+        Some((i, 'h')) => (&duration[..i], 60 * 60),
+        _ => (duration, 1),
+    };
+    n.parse::<u64>()
+        .map(|n| Duration::from_secs(n * unit))
+        .map_err(|_| invalid(format!("invalid duration: {}", duration)))
+}
+
+#[cfg(unix)]
+pub fn serve<F>(path: &str, tokens: Option<Tokens>, handle: F) -> Result<()>
+where
+    F: Fn(&Scope, &[&str], &mut dyn Output) -> Result<()> + Send + Sync + 'static,
+{
+    use std::{fs, os::unix::net::UnixListener, sync::Arc, thread};
+
+    // left over from a previous run, nobody can be listening on it anymore
+    if fs::metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let handle = Arc::new(handle);
+    let tokens = Arc::new(tokens);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handle = Arc::clone(&handle);
+            let tokens = Arc::clone(&tokens);
+            thread::spawn(move || {
+                let _ = respond(stream, tokens.as_ref().as_ref(), &*handle);
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve<F>(_path: &str, _tokens: Option<Tokens>, _handle: F) -> Result<()>
+where
+    F: Fn(&Scope, &[&str], &mut dyn Output) -> Result<()> + Send + Sync + 'static,
+{
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "--admin needs unix sockets",
+    ))
+}
+
+#[cfg(unix)]
+fn respond<F>(
+    stream: std::os::unix::net::UnixStream,
+    tokens: Option<&Tokens>,
+    handle: &F,
+) -> Result<()>
+where
+    F: Fn(&Scope, &[&str], &mut dyn Output) -> Result<()>,
+{
+    use std::io::{BufRead, BufReader, BufWriter};
+
+    let mut out = Connection {
+        out: BufWriter::new(stream.try_clone()?),
+    };
+    let mut scope = match tokens {
+        Some(_) => None,
+        None => Some(Scope::Operator),
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+        let result = match (&args[..], tokens, &scope) {
+            (["auth", token], Some(tokens), _) => match tokens.scope(token) {
+                Some(found) => {
+                    scope = Some(found);
+                    Ok(())
+                }
+                None => {
+                    // no second guess on this connection
+                    writeln!(out, "error: unknown token")?;
+                    return out.flush();
+                }
+            },
+            (_, _, Some(scope)) => handle(scope, &args, &mut out),
+            (_, _, None) => Err(invalid("auth token first".to_string())),
+        };
+        match result {
+            Ok(()) => writeln!(out, "ok")?,
+            Err(e) if e.kind() == ErrorKind::InvalidInput => writeln!(out, "error: {}", e)?,
+            Err(e) => return Err(e),
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_index() {
+        assert_eq!(parse_index("000004d2").unwrap(), 1234);
+        assert_eq!(parse_index("ffffffff").unwrap(), u32::MAX);
+        assert!(parse_index("1ffffffff").is_err());
+        assert!(parse_index("xyz").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("600").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10d").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tokens() {
+        use std::{
+            io::{BufRead, BufReader},
+            os::unix::net::UnixStream,
+            thread,
+        };
+
+        let path = std::env::temp_dir().join(format!("tokens-test-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# token tenant
+s3cret
+
+acme-token acme
+",
+        )
+        .unwrap();
+        let tokens = Tokens::from_file(path.to_str().unwrap()).unwrap();
+        fs::write(
+            &path, "a b c
+",
+        )
+        .unwrap();
+        assert!(Tokens::from_file(path.to_str().unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(tokens.tenants().collect::<Vec<_>>(), ["acme"]);
+        assert_eq!(tokens.scope("s3cret"), Some(Scope::Operator));
+        assert_eq!(tokens.scope("s3cre"), None);
+
+        let session = |commands: &'static str| {
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            let tokens = tokens.clone();
+            let server = thread::spawn(move || {
+                respond(
+                    theirs,
+                    Some(&tokens),
+                    &|scope: &Scope, _: &[&str], out: &mut dyn Output| writeln!(out, "{:?}", scope),
+                )
+            });
+            (&ours).write_all(commands.as_bytes()).unwrap();
+            ours.shutdown(std::net::Shutdown::Write).unwrap();
+            let lines: Vec<String> = BufReader::new(ours).lines().map(|l| l.unwrap()).collect();
+            server.join().unwrap().unwrap();
+            lines
+        };
+        assert_eq!(
+            session("sessions\nauth acme-token\nsessions\n"),
+            ["error: auth token first", "ok", "Tenant(\"acme\")", "ok"]
+        );
+        assert_eq!(session("auth wrong\nsessions\n"), ["error: unknown token"]);
+    }
+}
@@ -0,0 +1,152 @@
+// --amplification, what we send an address that has no session with the target yet is capped at
+// ratio times what it sent us, so spoofing a victim's address can't make us its reflector: the
+// target's handshake responses and cookie replies are smaller than what asks for them, forwarded
+// unknown types and --other-backend's answers needn't be
+//
+// transport data doesn't count either way, the target only sends any once the client answered its
+// handshake response, which a spoofer never sees; budgets are by ip, spoofers pick any port, and
+// start over every WINDOW
+//
+// an address that sent us nothing but transport data has no budget and isn't capped, unless the
+// table was too full to give every address one, then nothing without one gets anything; a full
+// table is purged of expired budgets once a PURGE_INTERVAL at most and refuses new addresses in
+// between
+
+use crate::{
+    clock::{self, Tick},
+    poison::Recover,
+};
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration};
+
+const WINDOW: Duration = Duration::from_secs(10);
+
+// addresses we keep a budget for at most
+const MAX_SOURCES: usize = 65536;
+
+// how often a full table is purged of expired budgets at most
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    received: u64,
+    sent: u64,
+    since: Tick,
+}
+
+#[derive(Default)]
+struct Table {
+    budgets: HashMap<IpAddr, Budget>,
+    // an address went without a budget since the last purge that made room
+    overflowed: bool,
+    next_purge: Tick,
+}
+
+pub struct Amplification {
+    ratio: u64,
+    table: Mutex<Table>,
+}
+
+impl Amplification {
+    pub fn new(ratio: u64) -> Amplification {
+        Amplification {
+            ratio,
+            table: Mutex::default(),
+        }
+    }
+
+    /// ip sent us bytes that weren't transport data at now
+    pub fn received(&self, ip: IpAddr, bytes: usize, now: Tick) {
+        let mut table = self.table.lock().recover();
+        if table.budgets.len() >= MAX_SOURCES && !table.budgets.contains_key(&ip) {
+            if now < table.next_purge {
+                table.overflowed = true;
+                return;
+            }
+            table.budgets.retain(|_, budget| !expired(budget, now));
+            table.next_purge = now + clock::ticks(PURGE_INTERVAL);
+            table.overflowed = table.budgets.len() >= MAX_SOURCES;
+            if table.overflowed {
+                return;
+            }
+        }
+        let budget = table.budgets.entry(ip).or_insert(Budget {
+            received: 0,
+            sent: 0,
+            since: now,
+        });
+        if expired(budget, now) {
+            *budget = Budget {
+                received: 0,
+                sent: 0,
+                since: now,
+            };
+        }
+        budget.received += bytes as u64;
+    }
+
+    /// whether we may send ip bytes that aren't transport data at now, they count against its
+    /// budget if so
+    pub fn allows(&self, ip: IpAddr, bytes: usize, now: Tick) -> bool {
+        let mut table = self.table.lock().recover();
+        let overflowed = table.overflowed;
+        match table.budgets.get_mut(&ip) {
+            Some(budget) if !expired(budget, now) => {
+                let allowed = budget.sent + bytes as u64 <= budget.received * self.ratio;
+                if allowed {
+                    budget.sent += bytes as u64;
+                }
+                allowed
+            }
+            _ => !overflowed,
+        }
+    }
+}
+
+fn expired(budget: &Budget, now: Tick) -> bool {
+    now.saturating_sub(budget.since) >= clock::ticks(WINDOW)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amplification() {
+        let victim = "192.0.2.66".parse().unwrap();
+        let client = "192.0.2.10".parse().unwrap();
+        let amplification = Amplification::new(3);
+        let window = clock::ticks(WINDOW);
+
+        // only transport data so far
+        assert!(amplification.allows(client, 1000, 0));
+
+        amplification.received(victim, 100, 0);
+        assert!(amplification.allows(victim, 92, 1));
+        assert!(amplification.allows(victim, 200, 1));
+        assert!(!amplification.allows(victim, 9, 1));
+        amplification.received(victim, 3, 2);
+        assert!(amplification.allows(victim, 9, 2));
+
+        // a new window starts over
+        amplification.received(victim, 10, window);
+        assert!(!amplification.allows(victim, 31, window));
+        assert!(amplification.allows(victim, 30, window));
+        // and it's gone once that's over too
+        assert!(amplification.allows(victim, 1000, 2 * window));
+
+        // a full table refuses new addresses until the next purge makes room
+        let amplification = Amplification::new(3);
+        for n in 0..MAX_SOURCES as u32 {
+            amplification.received(IpAddr::from(n.to_be_bytes()), 100, 0);
+        }
+        amplification.received(victim, 100, window - 1);
+        assert!(!amplification.allows(client, 1000, window - 1));
+        amplification.received(victim, 100, window);
+        assert!(!amplification.allows(victim, 1, window));
+        let purge = window - 1 + clock::ticks(PURGE_INTERVAL);
+        amplification.received(victim, 100, purge);
+        assert!(amplification.allows(victim, 300, purge));
+        assert!(amplification.allows(client, 1000, purge));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::error::{Error, Result};
+
+use std::str::FromStr;
+
+/// minimal command line parser, options are `--name value` or `--name=value` and may appear
+/// anywhere, everything left over once all known options are taken is positional
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    pub fn new<I: IntoIterator<Item = String>>(args: I) -> Args {
+        Args {
+            args: args.into_iter().collect(),
+        }
+    }
+
+    /// removes and returns whether the flag was present
+    pub fn flag(&mut self, name: &str) -> bool {
+        let before = self.args.len();
+        self.args.retain(|arg| arg != name);
+        self.args.len() != before
+    }
+
+    /// removes and returns the value of the last occurrence of option
+    pub fn get_option(&mut self, name: &str) -> Result<Option<String>> {
+        Ok(self.get_all(name)?.pop())
+    }
+
+    /// removes and returns the values of every occurrence of option, in order
+    pub fn get_all(&mut self, name: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}=", name);
+        let mut values = Vec::new();
+        let mut i = 0;
+        while i < self.args.len() {
+            if self.args[i] == name {
+                if i + 1 == self.args.len() {
+                    return Err(invalid(format!("{} requires a value", name)));
+                }
+                self.args.remove(i);
+                values.push(self.args.remove(i));
+            } else if let Some(value) = self.args[i].strip_prefix(&prefix) {
+                values.push(value.to_string());
+                self.args.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        Ok(values)
+    }
+
+    /// removes and parses the value of option, None if not present
+    pub fn get<T: FromStr>(&mut self, name: &str) -> Result<Option<T>> {
+        self.get_option(name)?
+            .map(|value| parse(name, &value))
+            .transpose()
+    }
+
+    /// whatever wasn't taken yet, as it was
+    pub fn rest(self) -> Vec<String> {
+        self.args
+    }
+
+    /// the remaining positional arguments, errors if any unknown options are left over
+    pub fn positional(self) -> Result<Vec<String>> {
+        match self.args.iter().find(|arg| arg.starts_with("--")) {
+            Some(arg) => Err(invalid(format!("unknown option: {}", arg))),
+            None => Ok(self.args),
+        }
+    }
+}
+
+pub fn parse<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid value for {}: {}", name, value)))
+}
+
+/// a 32 byte key in the base64 wg uses
+#[cfg_attr(not(any(feature = "selftest", feature = "identity")), allow(dead_code))]
+pub fn key_from_base64(key: &str) -> Result<[u8; 32]> {
+    let invalid = || invalid(format!("invalid key: {}", key));
+    let digit = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    // 43 digits and a = pad, the last digit only carries 4 bits
+    let digits = key
+        .strip_suffix('=')
+        .filter(|d| d.len() == 43)
+        .ok_or_else(invalid)?;
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    let mut out = Vec::with_capacity(33);
+    for c in digits.bytes() {
+        bits = bits << 6 | digit(c).ok_or_else(invalid)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    out.try_into().map_err(|_| invalid())
+}
+
+pub fn invalid(msg: String) -> Error {
+    Error::Invalid(msg)
+}
+
+/// for options that need a cargo feature we were built without
+#[allow(dead_code)]
+pub fn unavailable(options: &str, feature: &str) -> Error {
+    invalid(format!(
+        "{}: wireguard-udp-proxy was built without the {} feature",
+        options, feature
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::new(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_args() {
+        let mut a = args(&[
+            "--flag",
+            "one",
+            "--opt",
+            "val",
+            "two",
+            "--eq=x",
+            "--opt=last",
+        ]);
+        assert!(a.flag("--flag"));
+        assert!(!a.flag("--flag"));
+        assert_eq!(a.get_option("--opt").unwrap().as_deref(), Some("last"));
+        assert_eq!(a.get_all("--eq").unwrap(), vec!["x".to_string()]);
+        assert_eq!(a.get::<u32>("--missing").unwrap(), None);
+        assert_eq!(a.positional().unwrap(), vec!["one", "two"]);
+
+        assert!(args(&["--opt"]).get_option("--opt").is_err());
+        assert!(args(&["--num", "x"]).get::<u32>("--num").is_err());
+        assert!(args(&["one", "--unknown"]).positional().is_err());
+    }
+}
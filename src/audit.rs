@@ -0,0 +1,157 @@
+// --index-audit, watching the sender indexes of each client's handshake initiations, wireguard picks
+// every one of them at random, so a client whose indexes repeat, count up or keep bits the same runs
+// an implementation that's broken, or is something only posing as wireguard
+//
+// a random 32 bit index does any of that by chance less than once in millions of clients
+
+use crate::{
+    clock::{self, Tick},
+    poison::Recover,
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// the most recent indexes we keep per client
+const SAMPLES: usize = 16;
+
+// judging fewer would flag random ones too often
+const MIN_SAMPLES: usize = 8;
+
+// indexes this close to the one before count as counting up
+const NEAR: u32 = 1 << 16;
+
+// bits that stayed the same over all samples before that's a verdict, by chance 8 of 32 bits do
+// across 8 random indexes about once in ten billion
+const CONSTANT_BITS: u32 = 8;
+
+// clients we keep track of, beyond that new ones aren't audited until old ones went quiet
+const MAX_CLIENTS: usize = 4096;
+
+// how long a client that stopped sending handshakes is remembered
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+struct History {
+    indexes: VecDeque<u32>,
+    verdict: Option<&'static str>,
+    seen: Tick,
+}
+
+#[derive(Default)]
+pub struct Audit {
+    clients: Mutex<HashMap<SocketAddr, History>>,
+    /// clients that got a verdict since we started
+    pub flagged: AtomicU64,
+}
+
+impl Audit {
+    /// records that client initiated a handshake with sender at now, returns the verdict on its
+    /// indexes if it's the first one it got
+    pub fn record(&self, client: SocketAddr, sender: u32, now: Tick) -> Option<&'static str> {
+        let mut clients = self.clients.lock().recover();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            clients
+                .retain(|_, history| now.saturating_sub(history.seen) < clock::ticks(FORGET_AFTER));
+            if clients.len() >= MAX_CLIENTS {
+                return None;
+            }
+        }
+        let history = clients.entry(client).or_insert(History {
+            indexes: VecDeque::with_capacity(SAMPLES),
+            verdict: None,
+            seen: now,
+        });
+        history.seen = now;
+        if history.indexes.len() == SAMPLES {
+            history.indexes.pop_front();
+        }
+        history.indexes.push_back(sender);
+        if history.verdict.is_some() {
+            return None;
+        }
+        history.verdict = judge(&history.indexes);
+        if history.verdict.is_some() {
+            self.flagged.fetch_add(1, Relaxed);
+        }
+        history.verdict
+    }
+
+    /// every client we audit, with how many of its indexes we have and the verdict on them, those
+    /// with one first
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn report(&self) -> Vec<(SocketAddr, usize, Option<&'static str>)> {
+        let clients = self.clients.lock().recover();
+        let mut report: Vec<_> = clients
+            .iter()
+            .map(|(client, history)| (*client, history.indexes.len(), history.verdict))
+            .collect();
+        report.sort_by_key(|(client, _, verdict)| (verdict.is_none(), *client));
+        report
+    }
+}
+
+/// what's wrong with indexes, None if they look random or there are too few to tell
+fn judge(indexes: &VecDeque<u32>) -> Option<&'static str> {
+    if indexes.len() < MIN_SAMPLES {
+        return None;
+    }
+    let mut sorted: Vec<u32> = indexes.iter().copied().collect();
+    sorted.sort_unstable();
+    if sorted.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Some("repeats indexes");
+    }
+    let near = indexes
+        .iter()
+        .zip(indexes.iter().skip(1))
+        .filter(|(a, b)| b.wrapping_sub(**a) < NEAR || a.wrapping_sub(**b) < NEAR)
+        .count();
+    if near * 2 >= indexes.len() - 1 {
+        return Some("counts up");
+    }
+    let all = indexes.iter().fold(u32::MAX, |all, index| all & index);
+    let any = indexes.iter().fold(0, |any, index| any | index);
+    if (all | !any).count_ones() >= CONSTANT_BITS {
+        return Some("keeps bits the same");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+
+    #[test]
+    fn test_audit() {
+        let random = || RandomState::new().build_hasher().finish() as u32;
+        let audit = Audit::default();
+        let client = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        for i in 0..SAMPLES as u32 * 2 {
+            assert_eq!(audit.record(client(1), random(), i as Tick), None);
+            assert_eq!(audit.record(client(2), 1000 + i * 3, 0).is_some(), i == 7);
+            audit.record(client(3), random() & 0x00ff_ffff, 0);
+            audit.record(client(4), [7, 8, 9][i as usize % 3] << 20, 0);
+        }
+        assert_eq!(audit.flagged.load(Relaxed), 3);
+        assert_eq!(
+            audit.report(),
+            [
+                (client(2), SAMPLES, Some("counts up")),
+                (client(3), SAMPLES, Some("keeps bits the same")),
+                (client(4), SAMPLES, Some("repeats indexes")),
+                (client(1), SAMPLES, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// the startup banner, what we ended up with once defaults are filled in and names resolved, logged
+// to stderr as we start and printed to stdout by --print-config: the targets and the addresses
+// they resolved to, where we listen, the workers, buffer sizes and the features we were built with
+//
+// a line per setting, name first, options we were given by name only like crash reports have them,
+// their values can be urls with credentials in them
+
+use crate::{
+    config::{Config, Target},
+    proxy::{MAX_PACKET, SMALL_BUFFERS},
+};
+
+const FEATURES: [(&str, bool); 18] = [
+    ("admin", cfg!(feature = "admin")),
+    ("canary", cfg!(feature = "canary")),
+    ("control-plane", cfg!(feature = "control-plane")),
+    ("dns-tunnel", cfg!(feature = "dns-tunnel")),
+    ("events", cfg!(feature = "events")),
+    ("faketcp", cfg!(feature = "faketcp")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("icmp-tunnel", cfg!(feature = "icmp-tunnel")),
+    ("identity", cfg!(feature = "identity")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("peer-relay", cfg!(feature = "peer-relay")),
+    ("replay", cfg!(feature = "replay")),
+    ("seal", cfg!(feature = "seal")),
+    ("selftest", cfg!(feature = "selftest")),
+    ("signed-config", cfg!(feature = "signed-config")),
+    ("soak", cfg!(feature = "soak")),
+    ("tcp", cfg!(feature = "tcp")),
+    ("tls", cfg!(feature = "tls")),
+];
+
+/// the banner for config, a line per setting
+pub fn lines(config: &Config) -> Vec<String> {
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, built)| *built)
+        .map(|(name, _)| *name)
+        .collect();
+    let mut lines = vec![
+        format!("version {}", env!("CARGO_PKG_VERSION")),
+        format!("features {}", features.join(",")),
+    ];
+    match &config.target {
+        Some(target) => lines.push(format!("target {}", resolved(target))),
+        None => lines.push("target none, --peer-relay".to_string()),
+    }
+    for target in &config.failover {
+        lines.push(format!("failover {}", resolved(target)));
+    }
+    for (_, target) in &config.schedule {
+        lines.push(format!("scheduled {}", resolved(target)));
+    }
+    lines.push(format!("transport {}", transport(config)));
+    lines.push(match config.dual_stack {
+        true => format!("bind {} dual-stack", config.bind_addr),
+        false => format!("bind {}", config.bind_addr),
+    });
+    lines.push(match (&config.threads, config.small) {
+        (_, Some(sessions)) => format!("threads 1, --small with {} sessions", sessions),
+        (Some(bounds), None) => format!(
+            "threads auto {}-{}, {} to start",
+            bounds.min, bounds.max, config.thread_count
+        ),
+        (None, None) => format!("threads {}", config.thread_count),
+    });
+    if let Some(busy_poll) = config.busy_poll {
+        lines.push(format!("busy-poll {}us", busy_poll.as_micros()));
+    }
+    lines.push(match config.small {
+        Some(_) => format!(
+            "buffers packet {}, socket {}k",
+            MAX_PACKET,
+            SMALL_BUFFERS / 1024
+        ),
+        None => format!("buffers packet {}, socket the kernel's default", MAX_PACKET),
+    });
+    lines.push(match config.max_memory {
+        Some(bytes) => format!("max-memory {}k", bytes.div_ceil(1024)),
+        None => "max-memory unlimited".to_string(),
+    });
+    for (name, tenant) in &config.tenants {
+        let target = tenant.target.as_ref().map(resolved);
+        lines.push(format!(
+            "tenant {} target {} bind {}",
+            name,
+            target.as_deref().unwrap_or("none"),
+            tenant.bind_addr
+        ));
+    }
+    let mut options: Vec<&str> = config
+        .options
+        .iter()
+        .filter(|option| option.starts_with("--"))
+        .map(|option| option.split('=').next().unwrap_or_default())
+        .collect();
+    options.sort();
+    options.dedup();
+    lines.push(
+        format!("options {}", options.join(" "))
+            .trim_end()
+            .to_string(),
+    );
+    lines
+}
+
+/// target as given and what it resolved to, once if they're the same
+fn resolved(target: &Target) -> String {
+    match target.addr.to_string() == target.host {
+        true => target.host.clone(),
+        false => format!("{} {}", target.host, target.addr),
+    }
+}
+
+/// how packets get to the target
+fn transport(config: &Config) -> String {
+    let tls = |kind: &str| {
+        #[cfg(feature = "tls")]
+        if config.tls.is_some() {
+            return format!("{} with tls", kind);
+        }
+        kind.to_string()
+    };
+    if config.peer_relay {
+        return "peer-relay".to_string();
+    }
+    if let Some(domain) = &config.dns_target {
+        return format!("dns-target {}", domain);
+    }
+    if let Some(domain) = &config.dns_clients {
+        return format!("dns-clients {}", domain);
+    }
+    let kind = [
+        (config.icmp_target, "icmp-target"),
+        (config.icmp_clients, "icmp-clients"),
+        (config.faketcp_target, "faketcp-target"),
+        (config.faketcp_clients, "faketcp-clients"),
+        (
+            config.tcp_target && config.tcp_mux,
+            "tcp-target with --tcp-mux",
+        ),
+        (config.tcp_target, "tcp-target"),
+        (config.tcp_clients, "tcp-clients"),
+    ]
+    .into_iter()
+    .find(|(on, _)| *on);
+    match kind {
+        Some((_, kind)) if kind.starts_with("tcp") => tls(kind),
+        Some((_, kind)) => kind.to_string(),
+        None if !config.fallback.is_empty() => {
+            format!("udp, then {} fallbacks", config.fallback.len())
+        }
+        None => "udp".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lines() {
+        let banner = lines(&config(&[
+            "--verbose",
+            "--max-memory",
+            "16M",
+            "127.0.0.1:51820",
+            "0.0.0.0:5678",
+            "4",
+        ]));
+        assert_eq!(banner[0], format!("version {}", env!("CARGO_PKG_VERSION")));
+        assert!(banner[1].starts_with("features "));
+        assert_eq!(
+            banner[2..],
+            [
+                "target 127.0.0.1:51820",
+                "transport udp",
+                "bind 0.0.0.0:5678",
+                "threads 4",
+                &format!("buffers packet {}, socket the kernel's default", MAX_PACKET),
+                "max-memory 16384k",
+                "options --max-memory --verbose",
+            ]
+        );
+
+        let banner = lines(&config(&["--small", "128", "127.0.0.1:51820"]));
+        assert!(banner.contains(&"threads 1, --small with 128 sessions".to_string()));
+        assert!(banner.contains(&format!("buffers packet {}, socket 64k", MAX_PACKET)));
+        assert_eq!(banner.last().unwrap(), "options --small");
+    }
+}
@@ -0,0 +1,138 @@
+// --tcp-camouflage, our port number on tcp too, answering like something mundane, so a scan that
+// finds the tcp port open and unremarkable doesn't go looking at the udp one: an nginx 404 for
+// whatever is asked, or the handshake failure a TLS server sends for a hello it doesn't like
+//
+// nothing that arrives on it goes anywhere, every connection gets its answer and is closed, at
+// most MAX_CONNECTIONS at once and each for at most about TIMEOUT, so it can't be used to tie us up
+
+use std::{
+    io::{Read, Result, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAX_CONNECTIONS: usize = 64;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// what we read of a request at most before answering
+const MAX_REQUEST: usize = 4096;
+
+const NOT_FOUND: &str = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n<center><h1>404 Not Found</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n";
+
+// a fatal handshake_failure alert, in a TLS 1.2 record like TLS 1.3 servers send it too
+const HANDSHAKE_FAILURE: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Camouflage {
+    Http,
+    Tls,
+}
+
+impl FromStr for Camouflage {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Camouflage::Http),
+            "tls" => Ok(Camouflage::Tls),
+            _ => Err(format!("unknown camouflage: {}", s)),
+        }
+    }
+}
+
+impl Camouflage {
+    /// what we answer to request, None while it may not be all there yet
+    fn answer(&self, request: &[u8], done: bool) -> Option<Vec<u8>> {
+        match self {
+            Camouflage::Http => {
+                let complete = request.windows(4).any(|w| w == b"\r\n\r\n");
+                (complete || done).then(|| {
+                    format!(
+                        "HTTP/1.1 404 Not Found\r\nServer: nginx\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        NOT_FOUND.len(),
+                        NOT_FOUND
+                    )
+                    .into_bytes()
+                })
+            }
+            // a record header says how long the hello is, a server answers once it has read it
+            Camouflage::Tls => {
+                let complete = request.len() >= 5
+                    && request.len() >= 5 + u16::from_be_bytes([request[3], request[4]]) as usize;
+                (complete || done).then(|| HANDSHAKE_FAILURE.to_vec())
+            }
+        }
+    }
+}
+
+/// answers connections to addr like camouflage says, from a thread of its own
+pub fn serve(addr: SocketAddr, camouflage: Camouflage) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if connections.fetch_add(1, Relaxed) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Relaxed);
+                continue;
+            }
+            let connections = Arc::clone(&connections);
+            thread::spawn(move || {
+                let _ = respond(stream, camouflage);
+                connections.fetch_sub(1, Relaxed);
+            });
+        }
+    });
+    Ok(local)
+}
+
+fn respond(mut stream: TcpStream, camouflage: Camouflage) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let started = Instant::now();
+    let answer = loop {
+        // a client that only connects gets an answer once it's been quiet for TIMEOUT, or hung up
+        let read = stream.read(&mut buf).unwrap_or(0);
+        request.extend_from_slice(&buf[..read]);
+        let done = read == 0 || request.len() >= MAX_REQUEST || started.elapsed() >= TIMEOUT;
+        if let Some(answer) = camouflage.answer(&request, done) {
+            break answer;
+        }
+    };
+    stream.write_all(&answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_camouflage() {
+        let addr = serve("127.0.0.1:0".parse().unwrap(), Camouflage::Http).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(answer.ends_with("</html>\r\n"));
+
+        let tls = Camouflage::Tls;
+        let hello = [0x16, 0x03, 0x01, 0x00, 0x03, 0x01, 0x00, 0x00];
+        assert_eq!(tls.answer(&hello[..6], false), None);
+        assert_eq!(tls.answer(&hello, false), Some(HANDSHAKE_FAILURE.to_vec()));
+        assert_eq!(tls.answer(b"", true), Some(HANDSHAKE_FAILURE.to_vec()));
+        assert_eq!(Camouflage::Http.answer(b"GET / HTTP/1.1\r\n", false), None);
+        assert!("ssh".parse::<Camouflage>().is_err());
+    }
+}
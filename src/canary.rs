@@ -0,0 +1,143 @@
+// --canary, checking the whole loop every so often while real traffic flows: a real handshake
+// initiation from a peer the target knows goes into our own socket like a client's would, and
+// the target's response has to come back out of it, through a session under INDEX
+//
+// the canary's keys are given like selftest's, the target needs it as a peer of its own that
+// nothing else uses, a loop that answered before and then failed DOWN_AFTER times in a row is an
+// event and a log line, and so is it working again
+
+use crate::{
+    poison::Recover,
+    selftest::{self, Keys},
+};
+
+use std::{
+    io::Result,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// the sender index of the canary's handshakes, a client picks it once in four billion sessions
+pub const INDEX: u32 = 0xffff_fffe;
+
+// how long we wait for the response
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// rounds failed in a row before we say the loop is broken
+const DOWN_AFTER: u32 = 3;
+
+pub struct Settings {
+    /// how often a round goes through
+    pub every: Duration,
+    pub keys: Keys,
+}
+
+/// what the canary found so far
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct State {
+    /// the round trip time of the last round answered
+    pub rtt: Option<Duration>,
+    pub sent: u64,
+    pub failed: u64,
+    failed_in_a_row: u32,
+    answered: bool,
+}
+
+impl State {
+    /// whether the loop works, as far as we know
+    pub fn up(&self) -> bool {
+        self.answered && self.failed_in_a_row < DOWN_AFTER
+    }
+}
+
+pub struct Canary {
+    pub every: Duration,
+    keys: Keys,
+    // the same one every round, so its session always has the same client
+    socket: UdpSocket,
+    state: Mutex<State>,
+}
+
+impl Canary {
+    /// a canary sending to us at bind_addr, at loopback if we listen on every address
+    pub fn open(settings: Settings, bind_addr: SocketAddr) -> Result<Canary> {
+        let ip = match bind_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        Ok(Canary {
+            every: settings.every,
+            keys: settings.keys,
+            socket: selftest::connect(SocketAddr::new(ip, bind_addr.port()))?,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// one round, the round trip time or what went wrong
+    pub fn check(&self) -> std::result::Result<Duration, String> {
+        let sent = Instant::now();
+        selftest::exchange_on(&self.socket, Some(&self.keys), INDEX, TIMEOUT)?;
+        Ok(sent.elapsed())
+    }
+
+    /// records a round, returns whether the loop just broke, or Some(false) if it works again
+    pub fn record(&self, result: &std::result::Result<Duration, String>) -> Option<bool> {
+        let mut state = self.state.lock().recover();
+        record(&mut state, result)
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn state(&self) -> State {
+        self.state.lock().recover().clone()
+    }
+}
+
+fn record(state: &mut State, result: &std::result::Result<Duration, String>) -> Option<bool> {
+    let was_up = state.up();
+    state.sent += 1;
+    match result {
+        Ok(rtt) => {
+            state.rtt = Some(*rtt);
+            state.failed_in_a_row = 0;
+            // the first answer isn't news
+            let recovered = state.answered && !was_up;
+            state.answered = true;
+            recovered.then_some(false)
+        }
+        Err(_) => {
+            state.failed += 1;
+            state.failed_in_a_row += 1;
+            (was_up && !state.up()).then_some(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut state = State::default();
+        let answered = Ok(Duration::from_millis(5));
+        let failed = Err("no response within 2000ms".to_string());
+        // a loop that never worked isn't broken, it may not be set up yet
+        for _ in 0..DOWN_AFTER {
+            assert_eq!(record(&mut state, &failed), None);
+        }
+        assert!(!state.up());
+        assert_eq!(record(&mut state, &answered), None);
+        assert!(state.up());
+        for _ in 1..DOWN_AFTER {
+            assert_eq!(record(&mut state, &failed), None);
+        }
+        assert_eq!(record(&mut state, &failed), Some(true));
+        assert_eq!(record(&mut state, &failed), None);
+        assert_eq!(record(&mut state, &answered), Some(false));
+        assert_eq!(state.sent, 2 * DOWN_AFTER as u64 + 3);
+        assert_eq!(state.failed, 2 * DOWN_AFTER as u64 + 1);
+        assert_eq!(state.rtt, Some(Duration::from_millis(5)));
+    }
+}
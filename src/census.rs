@@ -0,0 +1,169 @@
+// what Sessions keeps next to its tables so nothing it's asked with the sessions lock held takes a
+// scan of them: which sessions each client has and how many of those the target answered, which
+// clients each target has, and which of the target's indexes lead to each session
+//
+// only sessions in memory are counted, --cold-store's are not
+
+use crate::memory;
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem,
+    net::SocketAddr,
+};
+
+#[derive(Default)]
+pub struct Census {
+    // sessions by client
+    clients: HashMap<SocketAddr, usize>,
+    // and their receiver indexes
+    receivers: BTreeSet<(SocketAddr, u32)>,
+    // and those of them the target answered the handshake of
+    answered: HashMap<SocketAddr, usize>,
+    // sessions by target, and by client there
+    targets: HashMap<SocketAddr, HashMap<SocketAddr, usize>>,
+    // the target's indexes by the receiver index they lead to
+    indexes: BTreeSet<(u32, u32)>,
+}
+
+impl Census {
+    /// the session with receiver, client's with target, was added
+    pub fn added(&mut self, receiver: u32, client: SocketAddr, target: SocketAddr) {
+        *self.clients.entry(client).or_default() += 1;
+        self.receivers.insert((client, receiver));
+        *self
+            .targets
+            .entry(target)
+            .or_default()
+            .entry(client)
+            .or_default() += 1;
+    }
+
+    /// it was removed
+    pub fn removed(&mut self, receiver: u32, client: SocketAddr, target: SocketAddr) {
+        decrement(&mut self.clients, client);
+        self.receivers.remove(&(client, receiver));
+        if let Some(clients) = self.targets.get_mut(&target) {
+            decrement(clients, client);
+            if clients.is_empty() {
+                self.targets.remove(&target);
+            }
+        }
+    }
+
+    /// the target answered the handshake of one of client's sessions
+    pub fn answered(&mut self, client: SocketAddr) {
+        *self.answered.entry(client).or_default() += 1;
+    }
+
+    /// one of those was removed
+    pub fn unanswered(&mut self, client: SocketAddr) {
+        decrement(&mut self.answered, client);
+    }
+
+    /// whether the target answered the handshake of any session client has
+    pub fn was_answered(&self, client: &SocketAddr) -> bool {
+        self.answered.contains_key(client)
+    }
+
+    /// how many sessions client has
+    pub fn sessions(&self, client: &SocketAddr) -> usize {
+        self.clients.get(client).copied().unwrap_or_default()
+    }
+
+    /// the receiver indexes of client's sessions
+    pub fn receivers(&self, client: SocketAddr) -> Vec<u32> {
+        self.receivers
+            .range((client, 0)..=(client, u32::MAX))
+            .map(|(_, receiver)| *receiver)
+            .collect()
+    }
+
+    /// how many clients have a session with target, and whether client is one of them
+    pub fn clients_of(&self, target: &SocketAddr, client: &SocketAddr) -> (usize, bool) {
+        self.targets.get(target).map_or((0, false), |clients| {
+            (clients.len(), clients.contains_key(client))
+        })
+    }
+
+    /// the target's index leads to the session with receiver
+    pub fn indexed(&mut self, receiver: u32, index: u32) {
+        self.indexes.insert((receiver, index));
+    }
+
+    /// not any more
+    pub fn unindexed(&mut self, receiver: u32, index: u32) {
+        self.indexes.remove(&(receiver, index));
+    }
+
+    /// the target's indexes that lead to the session with receiver
+    pub fn indexes(&self, receiver: u32) -> Vec<u32> {
+        self.indexes
+            .range((receiver, 0)..=(receiver, u32::MAX))
+            .map(|(_, index)| *index)
+            .collect()
+    }
+
+    /// approximately what it takes, a BTreeSet's entry like a HashMap's
+    pub fn memory(&self) -> usize {
+        memory::table::<SocketAddr, usize>(self.clients.len())
+            + memory::table::<SocketAddr, usize>(self.answered.len())
+            + self
+                .targets
+                .values()
+                .map(|clients| memory::table::<SocketAddr, usize>(clients.len() + 1))
+                .sum::<usize>()
+            + self.receivers.len() * mem::size_of::<(SocketAddr, u32)>() * 8 / 7
+            + self.indexes.len() * mem::size_of::<(u32, u32)>() * 8 / 7
+    }
+}
+
+fn decrement(counts: &mut HashMap<SocketAddr, usize>, key: SocketAddr) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_census() {
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.11:1000".parse().unwrap();
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let mut census = Census::default();
+        census.added(1, client, target);
+        census.added(2, client, target);
+        census.added(3, other, target);
+        assert_eq!(census.sessions(&client), 2);
+        assert_eq!(census.receivers(client), [1, 2]);
+        assert_eq!(census.clients_of(&target, &client), (2, true));
+        census.removed(1, client, target);
+        assert_eq!(census.clients_of(&target, &client), (2, true));
+        census.removed(2, client, target);
+        assert_eq!(census.sessions(&client), 0);
+        assert_eq!(census.receivers(client), []);
+        assert_eq!(census.receivers(other), [3]);
+        assert_eq!(census.clients_of(&target, &client), (1, false));
+        census.removed(3, other, target);
+        assert!(census.targets.is_empty());
+
+        census.answered(client);
+        assert!(census.was_answered(&client));
+        census.unanswered(client);
+        assert!(!census.was_answered(&client));
+
+        census.indexed(1, 9);
+        census.indexed(1, 7);
+        census.indexed(2, 8);
+        assert_eq!(census.indexes(1), [7, 9]);
+        census.unindexed(1, 7);
+        assert_eq!(census.indexes(1), [9]);
+        assert_eq!(census.indexes(3), []);
+    }
+}
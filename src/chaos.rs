@@ -0,0 +1,154 @@
+// chaos on the admin socket, packets we'd forward dropped at random while it's on, to see how
+// clients and the target cope with a lossy path without finding one or restarting us with a flag
+//
+// off costs the workers an atomic load per packet, on a cheap random number too
+
+use crate::admin;
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::Result,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering::Relaxed},
+};
+
+// loss is in parts per million
+const MILLION: u32 = 1_000_000;
+
+/// which way packets are dropped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Both,
+    ToTarget,
+    ToClients,
+}
+
+const DIRECTIONS: [(&str, Direction); 3] = [
+    ("both", Direction::Both),
+    ("to-target", Direction::ToTarget),
+    ("to-clients", Direction::ToClients),
+];
+
+pub struct Chaos {
+    loss: AtomicU32,
+    direction: AtomicU8,
+    /// packets dropped since it was turned on
+    pub dropped: AtomicU64,
+    random: AtomicU64,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos {
+            loss: AtomicU32::new(0),
+            direction: AtomicU8::new(0),
+            dropped: AtomicU64::new(0),
+            random: AtomicU64::new(RandomState::new().build_hasher().finish()),
+        }
+    }
+}
+
+impl Chaos {
+    /// the chaos command's arguments: off, or loss n% [direction]
+    pub fn set(&self, args: &[&str]) -> Result<()> {
+        let invalid =
+            || admin::invalid("chaos takes off or loss n% [both|to-target|to-clients]".to_string());
+        let (percent, direction) = match args {
+            ["off"] => (0.0, Direction::Both),
+            ["loss", percent, direction @ ..] => {
+                let percent: f64 = percent
+                    .trim_end_matches('%')
+                    .parse()
+                    .ok()
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .ok_or_else(invalid)?;
+                let direction = match direction {
+                    [] => Direction::Both,
+                    [direction] => DIRECTIONS
+                        .iter()
+                        .find(|(name, _)| name == direction)
+                        .map(|(_, direction)| *direction)
+                        .ok_or_else(invalid)?,
+                    _ => return Err(invalid()),
+                };
+                (percent, direction)
+            }
+            _ => return Err(invalid()),
+        };
+        let index = DIRECTIONS.iter().position(|(_, d)| *d == direction);
+        self.direction.store(index.unwrap_or(0) as u8, Relaxed);
+        self.dropped.store(0, Relaxed);
+        self.loss
+            .store((percent / 100.0 * MILLION as f64) as u32, Relaxed);
+        Ok(())
+    }
+
+    /// what it's set to, for the chaos command without arguments
+    pub fn describe(&self) -> String {
+        let (name, _) = DIRECTIONS[self.direction.load(Relaxed) as usize];
+        match self.loss.load(Relaxed) {
+            0 => "off".to_string(),
+            loss => format!(
+                "loss {}% {}, {} dropped",
+                loss as f64 / (MILLION / 100) as f64,
+                name,
+                self.dropped.load(Relaxed)
+            ),
+        }
+    }
+
+    /// whether to drop a packet going to_target, or to a client
+    pub fn drops(&self, to_target: bool) -> bool {
+        let loss = self.loss.load(Relaxed);
+        if loss == 0 {
+            return false;
+        }
+        let (_, direction) = DIRECTIONS[self.direction.load(Relaxed) as usize];
+        match (direction, to_target) {
+            (Direction::ToTarget, false) | (Direction::ToClients, true) => return false,
+            _ => {}
+        }
+        // splitmix64, workers racing on the state only makes it more random
+        let mut z = self
+            .random
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        let dropped = (z % MILLION as u64) < loss as u64;
+        if dropped {
+            self.dropped.fetch_add(1, Relaxed);
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drops() {
+        let chaos = Chaos::default();
+        assert!(!(0..1000).any(|_| chaos.drops(true)));
+        assert_eq!(chaos.describe(), "off");
+
+        chaos.set(&["loss", "25%"]).unwrap();
+        let dropped = (0..10_000).filter(|_| chaos.drops(true)).count();
+        assert!((2000..3000).contains(&dropped), "{}", dropped);
+        assert_eq!(
+            chaos.describe(),
+            format!("loss 25% both, {} dropped", dropped)
+        );
+
+        chaos.set(&["loss", "100", "to-clients"]).unwrap();
+        assert!(chaos.drops(false));
+        assert!(!chaos.drops(true));
+
+        assert!(chaos.set(&["loss", "101%"]).is_err());
+        assert!(chaos.set(&["loss", "5%", "sideways"]).is_err());
+        chaos.set(&["off"]).unwrap();
+        assert!(!chaos.drops(false));
+    }
+}
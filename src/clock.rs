@@ -0,0 +1,95 @@
+// a coarse monotonic clock, a ticker thread bumps a shared tick count so the forwarding loops read
+// an atomic per packet instead of asking the kernel what time it is, --small has no ticker and
+// its worker updates the count itself whenever a packet wakes it
+//
+// expiry is stored and compared in ticks, the few places that do math on them take the current
+// tick as an argument so tests can make time pass without sleeping
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Once, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// how often the ticker updates the clock, nothing we expire needs to be more precise
+pub const TICK: Duration = Duration::from_millis(100);
+
+pub const TICKS_PER_SEC: u64 = 1000 / TICK.as_millis() as u64;
+
+/// ticks since the clock was started, 0 until it is
+pub type Tick = u64;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static START: Once = Once::new();
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// starts the ticker thread, only the first call does anything
+pub fn start() {
+    START.call_once(|| {
+        update();
+        thread::spawn(move || loop {
+            thread::sleep(TICK);
+            update();
+        });
+    });
+}
+
+/// brings the clock up to date, the first call starts it
+pub fn update() {
+    let started = STARTED.get_or_init(Instant::now);
+    // from elapsed rather than counting, so oversleeping doesn't make the clock slow
+    TICKS.store(ticks(started.elapsed()), Relaxed);
+}
+
+/// the current tick
+pub fn now() -> Tick {
+    TICKS.load(Relaxed)
+}
+
+/// where the forwarding loops get the time from, tests make their own
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Tick;
+
+    /// whole seconds, what the per second counters count in
+    fn seconds(&self) -> u64 {
+        self.now() / TICKS_PER_SEC
+    }
+}
+
+/// the clock the ticker thread keeps
+#[derive(Debug, Default)]
+pub struct Coarse;
+
+impl Clock for Coarse {
+    fn now(&self) -> Tick {
+        now()
+    }
+}
+
+/// how many whole ticks duration is
+pub fn ticks(duration: Duration) -> Tick {
+    (duration.as_millis() / TICK.as_millis()) as Tick
+}
+
+/// how long ticks are
+pub fn duration(ticks: Tick) -> Duration {
+    Duration::from_millis(ticks * TICK.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticks() {
+        assert_eq!(ticks(Duration::from_secs(180)), 180 * TICKS_PER_SEC);
+        assert_eq!(ticks(TICK - Duration::from_millis(1)), 0);
+        assert_eq!(
+            duration(ticks(Duration::from_secs(3))),
+            Duration::from_secs(3)
+        );
+    }
+}
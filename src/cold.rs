@@ -0,0 +1,222 @@
+// --cold-store, sessions the target sent nothing for in --cold-after spill out of the session
+// table into a file we map, so hundreds of thousands of peers that are mostly idle don't need all
+// their sessions in memory, the kernel writes out what isn't touched and reads it back when it is
+//
+// each session is a RECORD_LEN record in a slot of the file, only its receiver index, slot and
+// when it expires stay in memory; a packet for a cold session brings it back into the table
+// before anything looks it up, which costs a read of its record, the file grows by doubling and
+// freed slots are reused
+//
+// what goes through every session, like counting a target's clients for --max-sessions or the
+// admin's listing, only sees those in memory
+
+use crate::{clock::Tick, memory, pktinfo::LocalAddr, platform::Mapped};
+
+use std::{
+    collections::HashMap,
+    io::Result,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+// a version 6 address with its port, or a version 4 one v4-mapped, or none
+const ADDR_LEN: usize = 1 + 16 + 2;
+
+// client, local address and its interface, target, expires, last seen, flags
+const RECORD_LEN: usize = ADDR_LEN + ADDR_LEN + 4 + ADDR_LEN + 8 + 8 + 1;
+
+// slots the file starts out with
+const FIRST_SLOTS: usize = 1024;
+
+const PINNED: u8 = 1;
+const FROZEN: u8 = 2;
+
+/// what we keep of a session while it's cold, its circuit breaker and failures start over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    pub client: SocketAddr,
+    pub local_addr: Option<LocalAddr>,
+    pub expires: Tick,
+    pub target: SocketAddr,
+    pub last_seen: Tick,
+    pub pinned: bool,
+    pub frozen: bool,
+}
+
+impl Record {
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0; RECORD_LEN];
+        let local = self.local_addr.map(|local| SocketAddr::new(local.ip, 0));
+        put_addr(&mut buf[..ADDR_LEN], Some(self.client));
+        put_addr(&mut buf[ADDR_LEN..2 * ADDR_LEN], local);
+        let ifindex = self.local_addr.map_or(0, |local| local.ifindex);
+        buf[2 * ADDR_LEN..2 * ADDR_LEN + 4].copy_from_slice(&ifindex.to_le_bytes());
+        let rest = &mut buf[2 * ADDR_LEN + 4..];
+        put_addr(&mut rest[..ADDR_LEN], Some(self.target));
+        rest[ADDR_LEN..ADDR_LEN + 8].copy_from_slice(&self.expires.to_le_bytes());
+        rest[ADDR_LEN + 8..ADDR_LEN + 16].copy_from_slice(&self.last_seen.to_le_bytes());
+        rest[ADDR_LEN + 16] = (self.pinned as u8 * PINNED) | (self.frozen as u8 * FROZEN);
+        buf
+    }
+
+    fn decode(buf: &[u8; RECORD_LEN]) -> Option<Record> {
+        let client = get_addr(&buf[..ADDR_LEN])?;
+        let ifindex = buf[2 * ADDR_LEN..2 * ADDR_LEN + 4].try_into().ok()?;
+        let local_addr = get_addr(&buf[ADDR_LEN..2 * ADDR_LEN]).map(|local| LocalAddr {
+            ip: local.ip(),
+            ifindex: u32::from_le_bytes(ifindex),
+        });
+        let rest = &buf[2 * ADDR_LEN + 4..];
+        Some(Record {
+            client,
+            local_addr,
+            target: get_addr(&rest[..ADDR_LEN])?,
+            expires: u64::from_le_bytes(rest[ADDR_LEN..ADDR_LEN + 8].try_into().ok()?),
+            last_seen: u64::from_le_bytes(rest[ADDR_LEN + 8..ADDR_LEN + 16].try_into().ok()?),
+            pinned: rest[ADDR_LEN + 16] & PINNED != 0,
+            frozen: rest[ADDR_LEN + 16] & FROZEN != 0,
+        })
+    }
+}
+
+fn put_addr(buf: &mut [u8], addr: Option<SocketAddr>) {
+    let Some(addr) = addr else {
+        return;
+    };
+    let (family, ip) = match addr.ip() {
+        IpAddr::V4(ip) => (4, ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => (6, ip),
+    };
+    buf[0] = family;
+    buf[1..17].copy_from_slice(&ip.octets());
+    buf[17..19].copy_from_slice(&addr.port().to_le_bytes());
+}
+
+fn get_addr(buf: &[u8]) -> Option<SocketAddr> {
+    let octets: [u8; 16] = buf[1..17].try_into().ok()?;
+    let ip = Ipv6Addr::from(octets);
+    let ip = match buf[0] {
+        4 => IpAddr::V4(ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED)),
+        6 => IpAddr::V6(ip),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_le_bytes([buf[17], buf[18]])))
+}
+
+pub struct Cold {
+    store: Mapped,
+    /// receiver index -> slot and when the session expires
+    index: HashMap<u32, (usize, Tick)>,
+    free: Vec<usize>,
+    // slots ever used, those past it are free too
+    used: usize,
+}
+
+impl Cold {
+    /// a store at path, which is emptied
+    pub fn create(path: &str) -> Result<Cold> {
+        Ok(Cold {
+            store: Mapped::create(path)?,
+            index: HashMap::new(),
+            free: Vec::new(),
+            used: 0,
+        })
+    }
+
+    #[cfg_attr(not(any(feature = "admin", feature = "metrics")), allow(dead_code))]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, receiver: &u32) -> bool {
+        self.index.contains_key(receiver)
+    }
+
+    /// approximately what we keep in memory, the records aren't
+    pub fn memory(&self) -> usize {
+        memory::table::<u32, (usize, Tick)>(self.index.len())
+            + self.free.capacity() * std::mem::size_of::<usize>()
+    }
+
+    /// keeps the session with receiver as record until it's taken back
+    pub fn spill(&mut self, receiver: u32, record: &Record) -> Result<()> {
+        let slot = match self.index.get(&receiver) {
+            Some((slot, _)) => *slot,
+            None => match self.free.pop() {
+                Some(slot) => slot,
+                None => {
+                    if (self.used + 1) * RECORD_LEN > self.store.len() {
+                        let slots = (self.used * 2).max(FIRST_SLOTS);
+                        self.store.grow(slots * RECORD_LEN)?;
+                    }
+                    self.used += 1;
+                    self.used - 1
+                }
+            },
+        };
+        if let Err(e) = self.store.write(slot * RECORD_LEN, &record.encode()) {
+            if !self.index.contains_key(&receiver) {
+                self.free.push(slot);
+            }
+            return Err(e);
+        }
+        self.index.insert(receiver, (slot, record.expires));
+        Ok(())
+    }
+
+    /// the session with receiver, which is no longer cold
+    pub fn take(&mut self, receiver: u32) -> Result<Option<Record>> {
+        let Some((slot, _)) = self.index.remove(&receiver) else {
+            return Ok(None);
+        };
+        self.free.push(slot);
+        let mut buf = [0; RECORD_LEN];
+        self.store.read(slot * RECORD_LEN, &mut buf)?;
+        Ok(Record::decode(&buf))
+    }
+
+    /// when the session with receiver expires, if it's here
+    pub fn expires(&self, receiver: &u32) -> Option<Tick> {
+        self.index.get(receiver).map(|(_, expires)| *expires)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cold() {
+        let path = std::env::temp_dir().join(format!("cold-test-{}", std::process::id()));
+        let mut cold = Cold::create(path.to_str().unwrap()).unwrap();
+        let record = |n: u16, expires| Record {
+            client: SocketAddr::from(([192, 0, 2, 10], n)),
+            local_addr: Some(LocalAddr {
+                ip: "2001:db8::1".parse().unwrap(),
+                ifindex: 3,
+            }),
+            expires,
+            target: "[2001:db8::2]:51820".parse().unwrap(),
+            last_seen: 7,
+            pinned: true,
+            frozen: false,
+        };
+        for n in 0..2000 {
+            cold.spill(n as u32, &record(n, 10 + n as u64)).unwrap();
+        }
+        assert_eq!(cold.len(), 2000);
+        assert_eq!(cold.take(5).unwrap(), Some(record(5, 15)));
+        assert_eq!(cold.take(5).unwrap(), None);
+        assert!(cold.contains(&1999));
+        // its slot is reused
+        cold.spill(5, &record(5, 15)).unwrap();
+        assert_eq!(cold.used, 2000);
+
+        assert_eq!(cold.expires(&2), Some(12));
+        assert_eq!(cold.expires(&2000), None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
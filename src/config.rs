@@ -0,0 +1,1507 @@
+use crate::{
+    args::{self, Args},
+    camouflage::Camouflage,
+    cidr::Cidr,
+    decoy::{self, Decoy},
+    error::{Error, Result},
+    fragment, heartbeat,
+    ladder::Rung,
+    log::{self, Rotate},
+    other::Protocol,
+    pacer::Pace,
+    pcap::Filter,
+    pmtu::Policy,
+    ports,
+    probe::{self, Probe},
+    scale::Bounds,
+    schedule::Cron,
+    seal::Seal,
+    uci,
+};
+
+#[cfg(feature = "admin")]
+use crate::admin::Tokens;
+#[cfg(feature = "dns-tunnel")]
+use crate::dns_tunnel;
+#[cfg(feature = "control-plane")]
+use crate::fleet;
+#[cfg(feature = "icmp-tunnel")]
+use crate::icmp_tunnel;
+#[cfg(feature = "identity")]
+use crate::identity::{Proxies, Servers};
+#[cfg(feature = "events")]
+use crate::mqtt::Credentials;
+#[cfg(feature = "peer-relay")]
+use crate::peer_relay::Psk;
+#[cfg(feature = "signed-config")]
+use crate::signed;
+#[cfg(feature = "tls")]
+use crate::tls::{self, Tls};
+#[cfg(feature = "canary")]
+use crate::{canary, selftest::Keys};
+
+use std::{
+    env, fs,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    time::Duration,
+};
+
+/// where we listen without a bind_addr
+pub const DEFAULT_BIND: &str = "0.0.0.0:5678";
+
+pub const USAGE: &str = "usage: wireguard-udp-proxy [options] (target_addr | --relay relay_addr) [bind_addr default: 0.0.0.0:5678] [num_threads default: 1]
+       wireguard-udp-proxy [options] --peer-relay --psk-file path [bind_addr default: 0.0.0.0:5678] [num_threads default: 1]
+       wireguard-udp-proxy selftest --via proxy_addr --target target_addr [options], see selftest --help
+       wireguard-udp-proxy soak --via proxy_addr --target target_addr [options], see soak --help
+       wireguard-udp-proxy replay capture.pcap [--speed n[x] | --speed max] [options] target_addr [bind_addr]
+
+options:
+    --relay relay_addr    forward to another wireguard-udp-proxy instance instead of the final target
+    --relay-envelope      prepend the original client address to packets sent to the relay
+    --accept-envelope     expect packets from clients to be enveloped by a previous --relay-envelope hop
+    --compact-envelope    with --relay-envelope, give each client an id and send only that with most
+                          of its packets, 4 bytes instead of the address, for an --accept-envelope
+                          hop of this version or later
+    --seal-target path    authenticate and encrypt packets to and from the target with the pre-shared
+                          key in path, for another wireguard-udp-proxy there with --seal-clients
+    --seal-clients path   expect packets from clients to be sealed by a previous --seal-target hop
+                          with the key in path, and seal what goes back to them
+    --allow-proxies path  the same for several previous hops, each with its own key, one per line in
+                          path, what goes back to one is sealed with the key it used
+    --fragment-target mtu split packets to the target that don't fit a path with mtu (at least 576)
+                          and put fragments from it back together, for another wireguard-udp-proxy
+                          there with --fragment-clients, when wrapping makes them too big
+    --fragment-clients mtu
+                          the same towards clients, for a previous --fragment-target hop
+    --reassembly-timeout ms
+                          how long to wait for the rest of a fragmented packet (default: 1000)
+    --pmtu policy         set DF and learn the path mtu to each destination from ICMP, packets that
+                          don't fit it are counted and with drop dropped, with forward fragmented by
+                          the kernel, --fragment-target and --fragment-clients fragment to it
+    --dns-target domain   experimental, for networks that only let DNS out: carry packets to
+                          target_addr, a resolver or the far end itself, in TXT queries for names
+                          under domain, and take packets back from the answers, for another
+                          wireguard-udp-proxy with --dns-clients as the authoritative server of domain
+    --dns-clients domain  be that server: answer queries for names under domain at bind_addr, port
+                          53 for resolvers to find it, passing the packets in them on to target_addr
+    --icmp-target         experimental, for networks that only let pings out: carry packets to
+                          target_addr's host, its port is ignored, in ICMP echo requests and take
+                          packets back from the replies, for another wireguard-udp-proxy there with
+                          --icmp-clients, needs an unprivileged ICMP socket or CAP_NET_RAW
+    --icmp-clients        be that end: answer echo requests carrying packets with a raw socket, which
+                          needs CAP_NET_RAW, passing them on to target_addr, bind_addr is unused
+    --icmp-mtu mtu        the path mtu echoes must fit, packets too big are dropped (default: 1500)
+    --faketcp-target      for networks that throttle or drop UDP: carry packets to target_addr as the
+                          segments of a made up TCP connection to it, for another wireguard-udp-proxy
+                          there with --faketcp-clients, with raw sockets, linux and IPv4 only, needs
+                          CAP_NET_RAW and a firewall rule against the kernel's resets, which it prints
+    --faketcp-clients     be that end: take those connections on bind_addr's port, passing the packets
+                          in them on to target_addr
+    --tcp-target          for networks that let nothing but TCP through: carry packets to target_addr
+                          over a TCP connection per client, for another wireguard-udp-proxy there with
+                          --tcp-clients
+    --tcp-clients         be that end: take those connections on bind_addr, passing the packets in them
+                          on to target_addr
+    --tcp-mux             with --tcp-target, every client over one connection, each a stream of its own
+                          that can't hold up the others
+    --tls                 wrap those connections in TLS, to look like HTTPS, --tcp-clients needs
+    --tls-cert path       the certificate chain and its key, PEM files
+    --tls-key path
+    --tls-sni name        the server name --tcp-target asks for (default: target_addr's host)
+    --tls-alpn protocols  what both ends offer, comma separated (default: h2,http/1.1)
+    --tls-ca path         with --tcp-target, trust the CA certificates in path instead of the web's
+    --tls-pin sha256      or accept the certificate with this SHA-256 in hex, a self-signed one say
+    --tls-insecure        or any certificate at all, still encrypted but open to whoever is in between
+    --tls-ticket-key path with --tcp-clients, seal session tickets with the key in path, 32 bytes in
+                          hex, a random one is written there if it doesn't exist, so client ends
+                          resume their sessions across our restarts, or with any far end sharing it
+    --fallback kind=addr  for a network that might block UDP: once target_addr stopped answering
+                          handshakes, carry packets to addr with kind instead, a transport as above,
+                          tcp, tls (--tcp-target with --tls, the --tls-* options apply), faketcp,
+                          icmp or dns=domain@resolver, may be given more than once, they are tried
+                          in order after target_addr and then from the top again, with --probe
+                          the path of each is probed too, a connection for tcp and tls, and clients
+                          move to one that does clearly better in round trip time and loss
+    --threads-min n       with num_threads auto, which starts a worker per core and every 10s adds
+    --threads-max n       one while the kernel drops packets for us or they are busy most of the
+                          time, or retires one while the others could easily do its share, keep at
+                          least n (default: 1) and at most n (default: twice the cores), --tenants
+                          and --peer-relay keep the number of workers they start with
+    --busy-poll usecs     spin up to usecs waiting for a packet before blocking in recv, and have the
+                          driver polled for packets meanwhile, a core busy per worker for lower
+                          latency, polling the driver for longer than net.core.busy_read needs
+                          CAP_NET_ADMIN, without it we only spin
+    --dual-stack          listen on ipv6 and ipv4 at once, clients of either family reach a target of
+                          either, bind_addr must be 0.0.0.0 or [::] and a port, ipv4 clients and
+                          targets are themselves in sessions and logs, not v4-mapped
+    --flow-labels         give the ipv6 packets of each client one of 32 flow labels we lease at
+                          start, so ECMP routers keep its sessions on one path and spread clients
+                          across paths, instead of one label for everything to the target
+    --source-ports first-last
+                          send each client's packets to the target from a port of its own, drawn at
+                          random from first-last, so ECMP and RSS at the target's end spread clients
+                          across paths and queues, and firewalls in between can police each one
+    --small n             for routers with little memory to spare: at most n sessions, in an array
+                          allocated at start, smaller socket buffers and no thread but the worker,
+                          so none of the options that need one, like --metrics, --admin or --probe
+    --max-memory size     cap what the sessions, pending handshakes and other tables that grow with
+                          clients take, like 16M: past 90% of it the sessions idle longest are
+                          evicted, at it handshakes for new sessions are refused
+    --cold-store path     move sessions the target sent nothing for in --cold-after out of memory
+                          into path, mapped, for hundreds of thousands of mostly idle peers, their
+                          next packet takes a little longer while it brings them back
+    --cold-after secs     with --cold-store, how long a session is idle before it moves (default: 30)
+    --verbose             log new sessions, and a sample of packets from the target for unknown ones
+    --log-file path       append what we print to path instead of stdout and stderr
+    --log-rotate settings with --log-file, move it to path.1 and start over once it's too big or old,
+                          comma separated: size=n[k|M|G], every=n(m|h|d), keep=n rotated files
+                          (default: 7), and gzip to compress them, like size=100M,keep=7,gzip
+    --syslog              send what we print to syslog instead, what goes to stderr as warnings
+    --print-config        print what we'd start with, the targets and the addresses they resolve to,
+                          bind_addr, the workers, buffer sizes and the features we were built with,
+                          and exit, the lines we log as we start
+    --uci path            read options from OpenWrt configuration in path, or stdin for -, either a
+                          /etc/config file with one section of option and list lines, or key=value
+                          lines like uci show prints, each named like the option with _ for -, and
+                          target, bind and threads for the positional arguments, see openwrt/
+    --config-key key      require a signature by key at path.minisig for every --uci and --tenants
+                          path before reading it, key as minisign's .pub file has it or that file,
+                          or an ed25519 key as 64 hex digits, whose signatures are 128 hex digits
+    --peer-relay          relay between peers registered with --psk-file instead of to a target
+    --psk-file path       pre-shared key for --peer-relay, without --peer-relay register with the
+                          peer relay at target_addr and relay packets from its other peers too
+    --no-hairpin          with --psk-file, don't pass packets between two of our own clients directly,
+                          they can't reach each other through the peer relay then
+    --stun                answer STUN binding requests so clients can learn their public address
+    --max-session-pps n   pause a session for 30s once it is over n packets/s for 5s in a row
+    --shed-load           while the kernel keeps dropping packets because we can't keep up, only
+                          accept handshakes from addresses that already have a session
+    --metrics addr        serve prometheus metrics at http://addr/metrics, and at /health the load as
+                          JSON for an autoscaler: sessions, session_capacity from --max-sessions or
+                          --small, packets_per_second, workers, max_workers, busy and utilization,
+                          the larger of the sessions' and the workers' share of their capacity
+    --forward-unknown-types
+                          relay message types wireguard doesn't have for existing sessions instead of
+                          dropping them, they must carry a receiver index like data packets do
+    --extra-types list    comma separated, only forward these unknown message types, implies
+                          --forward-unknown-types
+    --startup-grace secs  for secs after starting, send packets from the target for sessions we don't
+                          know to the client we heard from last, so a session from before a restart
+                          keeps working until it rekeys
+    --expiry-grace secs   keep sending packets from the target for a session for secs after it
+                          expired, but no more from its client, so what was on the way when the
+                          client rekeyed right at the 180s edge still arrives
+    --dscp cidr=value     set DSCP value (0-63) on packets we forward from clients in cidr, may be
+                          given more than once, the first matching rule wins
+    --schedule 'cron addr'
+                          from the local times matching the 5 field cron expression on, send new
+                          sessions to addr, may be given more than once, for example
+                          --schedule '0 22 * * * night_addr' --schedule '0 6 * * * target_addr'
+    --schedule-transition secs
+                          after switching, keep existing sessions on their old target this long before
+                          cutting them over, which makes their clients handshake again (default: 0)
+    --blackhole-after n   once n handshake initiations in a row got no response from a target, look
+                          its name up again in case its address changed, or else fail over, only
+                          initiations from clients whose handshakes it answered before count
+    --failover addr       target to fail over to when the active one is blackholed, may be given
+                          more than once, they are tried in order after target_addr
+    --probe secs[,udp=port]
+                          every secs, send each target's host an ICMP echo request, or with udp= a
+                          datagram to that port, which should have nothing listening, to measure the
+                          round trip time and loss, once a path that answered loses 5 in a row its
+                          target is looked up again or else failed over like with --blackhole-after
+    --canary secs         every secs, send a real handshake initiation through our own socket to the
+                          target and wait for its response to come back out, the target needs the
+                          canary as a peer, metrics tell whether the loop works and 3 failures after
+                          it did are a canary_failed event
+    --canary-private-key path
+                          the canary's private key, as wg genkey writes it
+    --canary-public-key key
+                          the target's public key, for the canary's handshakes
+    --max-sessions target=n
+                          at most n clients may have a session with target, as given above, the
+                          handshakes of others go to the next failover target with room, or are
+                          dropped if there is none
+    --allow-servers path  only relay handshakes for the servers whose public keys are in path, one per
+                          line as wg pubkey writes them, by their mac1, for an open relay in front
+                          of a target several servers are behind
+    --control-plane url   every --control-plane-every, fetch a signed configuration from the https://
+                          url and apply it all at once: the target, failover targets, --max-sessions,
+                          --max-session-pps and which clients may have sessions, /health says which
+                          version is applied
+    --control-plane-key hex
+                          the ed25519 public key it has to be signed with
+    --control-plane-every secs
+                          how often to fetch it (default: 60)
+    --admin path          listen for admin commands on a unix socket at path, send help for a list
+    --admin-tokens path   make admin connections send auth token first, path has a line per token
+                          written token [tenant], a tenant's token only sees and manages that
+                          tenant's sessions and targets, one without is the operator's
+    --grpc addr           serve the admin commands and streams of events and counters over gRPC on
+                          tcp addr, plaintext HTTP/2, the service is in proto/admin.proto, calls
+                          give --admin-tokens' tokens as their authorization metadata
+    --sender-collision policy
+                          what to do with a handshake reusing the sender index of another client's
+                          session, which wireguard never does by chance: overwrite takes the session
+                          over (default), reject drops the handshake, idle takes it over only if the
+                          target sent the old session nothing for 30s
+    --strict-source       only accept a session's packets from the address its handshake came from,
+                          no roaming, for clients known to be static, with --sender-collision reject
+    --freeze-hijacked     when the address a session was taken over from still sends for it within
+                          30s, which is two endpoints claiming its return traffic, stop forwarding
+                          for the session until the admin pins or unfreezes it
+    --strict-responses    only accept a handshake response from a target if we forwarded the
+                          initiation it answers there within the last 5s
+    --index-audit         watch the sender indexes of each client's handshakes and flag those that
+                          don't look random, a sign of a broken implementation or an imitation,
+                          send index-audit to the admin socket for the verdicts
+    --fingerprints        count clients by traits that tell implementations apart: initiation size,
+                          mac2 use, keepalive interval and whether a target answered them, in
+                          metrics and with fingerprints on the admin socket
+    --pcap path           write the wireguard packets we forward to path in pcap format, each as a
+                          udp datagram from its sender to us
+    --pcap-filter expr    only those expr matches, like 'type==handshake or peer==203.0.113.0/24',
+                          with type, peer, port, index and direction, see pcap.rs
+    --pace target=kbit[,burst]
+                          hold packets to target back so they leave at no more than kbit kbit/s on
+                          average, allowing bursts of burst bytes (default: 10ms worth, at least
+                          3000), for links with a policer that drops bursts, beyond 1024 packets
+                          held back they're dropped
+    --dead-after n        expire a session early once n packets in a row from the target couldn't
+                          reach its client, because sending failed or ICMP said it's unreachable
+    --state-file path     keep the cumulative counters in path, so they carry on where they were
+                          after a restart, it's rewritten every 10s
+    --crash-dir path      where a panic writes a crash report to send along with a bug report, with
+                          a backtrace, the names of our options and each listener's table sizes and
+                          counters, no addresses or values (default: the temp directory, like /tmp)
+    --heartbeat secs      print a line every secs with how we are doing, for tailing our log without
+                          --metrics: sessions, packets per second to the target and to clients,
+                          packets dropped by reason and the client the target sent most to
+    --heartbeat-fields list
+                          what's on it, in order, comma separated: sessions, handshakes, pps, bps,
+                          drops, top and memory (default: sessions,pps,drops,top)
+    --webhook-url url     POST session, target health and security events as JSON to the http:// url
+    --mqtt host[:port]    publish the same events to the MQTT broker at host (default port: 1883)
+    --mqtt-topic prefix   publish them to prefix/event_name (default: wireguard-udp-proxy)
+    --mqtt-credentials path
+                          log in to the broker with the user:password in path
+    --etw                 write the same events to Event Tracing for Windows, as a warning for
+                          security ones and an error for a target going down
+    --other-backend addr  forward datagrams that aren't wireguard to addr instead of dropping them,
+                          and its answers back, so our port can serve another udp service too
+    --other-protocol kind only start forwarding a client to --other-backend with a packet that looks
+                          like kind: dns, quic or any (default), the rest of its packets follow
+    --tcp-camouflage kind also listen on our port number on tcp, answering like something mundane so
+                          scans see an ordinary service there: http for an nginx 404, or tls for the
+                          handshake failure a TLS server sends, nothing on it goes anywhere
+    --quic-camouflage     answer QUIC probes for versions other than 1 with a version negotiation
+                          like a QUIC server would, instead of the silence of a filtered port
+    --decoy kind          what addresses without a session get back for packets that aren't
+                          wireguard: silence (default), unreachable for an ICMP port unreachable like
+                          a closed port, which needs CAP_NET_RAW, or reply=path for the contents of
+                          path, to packets at least as long as it only, at most 100/s of them
+    --amplification ratio send an address without a session at most ratio times the bytes it sent
+                          us in the last 10s, besides transport data, so we can't be used to reflect
+                          at whoever it spoofs (default: 3), off for no limit
+    --refuse-unreachable  answer wireguard packets from clients we refuse, for a seal or envelope
+                          that doesn't open, a sender index collision we reject, or no room at
+                          --max-sessions or --small, with an ICMP port unreachable so they fail
+                          fast, needs CAP_NET_RAW, at most 100/s of these and decoys together
+    --tenants path        also run a listener for each line of path, written
+                          name [options] target_addr [bind_addr] [num_threads]
+                          with our options but --admin, --admin-tokens, --grpc, --metrics and
+                          --state-file and then its own, so each has its own sessions, targets,
+                          limits and stats, and its own --metrics if it gives one, one that gives
+                          any of --relay, --*-envelope, --seal-* or --fragment-* takes none of
+                          ours, so one process can be both ends of a sealed, fragmented or
+                          enveloped hop";
+
+// --amplification, QUIC's limit for addresses it hasn't validated
+const AMPLIFICATION: u64 = 3;
+
+// --cold-after, longer than wireguard's usual 25s persistent keepalive
+const COLD_AFTER: Duration = Duration::from_secs(30);
+
+// what only one listener per process can have, or each needs its own of, the others don't inherit
+// them
+const PER_PROCESS: [&str; 13] = [
+    "--admin",
+    "--admin-tokens",
+    "--cold-after",
+    "--cold-store",
+    "--control-plane",
+    "--control-plane-every",
+    "--control-plane-key",
+    "--grpc",
+    "--log-file",
+    "--log-rotate",
+    "--metrics",
+    "--state-file",
+    "--tenants",
+];
+
+// which end of a hop between two of us a listener is, one that picks its own doesn't inherit ours,
+// which would be the other end
+const ROLE: [&str; 6] = [
+    "--relay",
+    "--seal-target",
+    "--seal-clients",
+    "--allow-proxies",
+    "--fragment-target",
+    "--fragment-clients",
+];
+const ROLE_FLAGS: [&str; 3] = [
+    "--relay-envelope",
+    "--accept-envelope",
+    "--compact-envelope",
+];
+
+/// a target as given on the command line, and the address it resolved to
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub host: String,
+    pub addr: SocketAddr,
+}
+
+impl Target {
+    pub fn resolve(host: &str) -> Result<Target> {
+        Ok(Target {
+            host: host.to_string(),
+            addr: resolve(host)?,
+        })
+    }
+}
+
+/// what to do with a handshake initiation reusing the sender index of another client's session
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Collision {
+    #[default]
+    Overwrite,
+    Reject,
+    Idle,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Collision::Overwrite),
+            "reject" => Ok(Collision::Reject),
+            "idle" => Ok(Collision::Idle),
+            _ => Err(format!("unknown sender collision policy: {}", s)),
+        }
+    }
+}
+
+pub struct Config {
+    /// where packets from clients are forwarded, either the final target or the next relay, None
+    /// in peer relay mode
+    pub target: Option<Target>,
+    pub bind_addr: String,
+    /// what we were started with but the positional arguments, for listeners added later
+    pub options: Vec<String>,
+    /// listeners to run next to ours, by tenant name
+    pub tenants: Vec<(String, Config)>,
+    pub thread_count: usize,
+    /// num_threads auto, adding and retiring workers between these as the load says
+    pub threads: Option<Bounds>,
+    /// how long workers spin waiting for a packet
+    pub busy_poll: Option<Duration>,
+    /// one ipv6 socket for both families at bind_addr's port
+    pub dual_stack: bool,
+    /// a flow label per session on ipv6
+    pub flow_labels: bool,
+    /// the range each client's port towards the target is drawn from
+    pub source_ports: Option<(u16, u16)>,
+    /// --small's session capacity
+    pub small: Option<usize>,
+    /// what the tables that grow with clients may take, in bytes
+    pub max_memory: Option<usize>,
+    /// where idle sessions go, and how long they are idle before
+    pub cold_store: Option<(String, Duration)>,
+    /// wrap packets towards the target in an envelope carrying the original client address
+    pub relay_envelope: bool,
+    /// packets from clients are wrapped in an envelope by a previous proxy hop
+    pub accept_envelope: bool,
+    /// envelope most packets with an id for the client instead of its address
+    pub compact_envelope: bool,
+    /// packets to and from the target are sealed with this key
+    pub seal_target: Option<Seal>,
+    /// packets to and from clients are sealed with this key by a previous proxy hop
+    pub seal_clients: Option<Seal>,
+    /// packets from clients are sealed by one of several previous proxy hops, each with its own key
+    #[cfg(feature = "identity")]
+    pub allow_proxies: Option<Proxies>,
+    /// split packets to the target that don't fit this mtu, and reassemble fragments from it
+    pub fragment_target: Option<usize>,
+    /// split packets to clients that don't fit this mtu, and reassemble fragments from them
+    pub fragment_clients: Option<usize>,
+    pub reassembly_timeout: Duration,
+    /// what to do with packets that don't fit the path mtu, None leaves it to the kernel
+    pub pmtu: Option<Policy>,
+    pub verbose: bool,
+    /// where our output goes instead of stdout and stderr
+    pub log_file: Option<String>,
+    /// when to start it over
+    pub log_rotate: Option<Rotate>,
+    /// print to syslog instead
+    pub syslog: bool,
+    /// print the startup banner and exit instead of starting
+    pub print_config: bool,
+    /// tunnel to the target in DNS queries for names under this domain
+    pub dns_target: Option<String>,
+    /// answer DNS queries for names under this domain, passing what they carry to the target
+    pub dns_clients: Option<String>,
+    /// tunnel to the target's host in ICMP echo requests
+    pub icmp_target: bool,
+    /// answer ICMP echo requests, passing what they carry to the target
+    pub icmp_clients: bool,
+    /// what echoes either way must fit
+    pub icmp_mtu: usize,
+    /// carry packets to the target as segments of a made up TCP connection
+    pub faketcp_target: bool,
+    /// take those connections on bind_addr's port, passing what they carry to the target
+    pub faketcp_clients: bool,
+    /// carry packets to the target over a TCP connection per client
+    pub tcp_target: bool,
+    /// take those connections on bind_addr, passing what they carry to the target
+    pub tcp_clients: bool,
+    /// every client over one connection
+    pub tcp_mux: bool,
+    /// transports to fall back to in order once target_addr stops answering over UDP
+    pub fallback: Vec<Rung>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<Tls>,
+    pub peer_relay: bool,
+    #[cfg(feature = "peer-relay")]
+    pub psk: Option<Psk>,
+    #[cfg(feature = "canary")]
+    pub canary: Option<canary::Settings>,
+    /// where to fetch our configuration from, and what it's signed with
+    #[cfg(feature = "control-plane")]
+    pub control_plane: Option<fleet::Settings>,
+    /// pass packets between our own clients directly when registered with a peer relay
+    pub hairpin: bool,
+    pub stun: bool,
+    pub max_session_pps: Option<u32>,
+    pub shed_load: bool,
+    pub metrics_addr: Option<String>,
+    /// message types wireguard doesn't have to forward for existing sessions, None drops them and
+    /// an empty list forwards every one
+    pub unknown_types: Option<Vec<u8>>,
+    /// how long after starting packets for unknown sessions go to the last client
+    pub startup_grace: Option<Duration>,
+    /// how long expired sessions still get packets from the target
+    pub expiry_grace: Option<Duration>,
+    /// DSCP values for packets from clients in each range, first match wins
+    pub dscp: Vec<(Cidr, u8)>,
+    /// when to switch the active target to which one
+    pub schedule: Vec<(Cron, Target)>,
+    /// how long sessions may keep using the target that was active when they started
+    pub schedule_transition: Duration,
+    /// how many unanswered handshake initiations in a row mean a target is gone
+    pub blackhole_after: Option<u32>,
+    /// where to go once the active target is gone, in order
+    pub failover: Vec<Target>,
+    /// how to probe the paths to our targets
+    pub probe: Option<Probe>,
+    /// how many clients each target named here may have sessions with
+    pub max_sessions: Vec<(String, usize)>,
+    /// the servers handshakes may be for
+    #[cfg(feature = "identity")]
+    pub allow_servers: Option<Servers>,
+    /// unix socket for admin commands
+    pub admin_path: Option<String>,
+    /// who may send them, anyone who can connect if None
+    #[cfg(feature = "admin")]
+    pub admin_tokens: Option<Tokens>,
+    /// where --grpc listens
+    pub grpc_addr: Option<String>,
+    pub sender_collision: Collision,
+    /// a session's packets only come from where its handshake did
+    pub strict_source: bool,
+    /// stop forwarding for a session two addresses claim until the admin says which is right
+    pub freeze_hijacked: bool,
+    /// drop handshake responses that don't answer an initiation we just forwarded
+    pub strict_responses: bool,
+    /// look for clients whose sender indexes aren't random
+    pub index_audit: bool,
+    /// classify clients by what their packets show about their implementation
+    pub fingerprints: bool,
+    /// where to capture packets to, and which
+    pub pcap: Option<String>,
+    pub pcap_filter: Option<Filter>,
+    /// how fast packets to each target named here may go
+    pub pace: Vec<(String, Pace)>,
+    /// how many packets in a row that couldn't reach a client end its session
+    pub dead_after: Option<u32>,
+    /// where counters are kept across restarts
+    pub state_path: Option<String>,
+    /// where a panic leaves its crash report
+    pub crash_dir: String,
+    /// how often we log a heartbeat line, and what's on it
+    pub heartbeat: Option<(Duration, Vec<heartbeat::Field>)>,
+    /// where events are POSTed
+    pub webhook_url: Option<String>,
+    /// where events are published, and under which topic
+    pub mqtt_broker: Option<String>,
+    pub mqtt_topic: String,
+    #[cfg(feature = "events")]
+    pub mqtt_credentials: Option<Credentials>,
+    /// whether events are written to ETW too
+    pub etw: bool,
+    /// where datagrams that aren't wireguard go, if they look like protocol
+    pub other_backend: Option<SocketAddr>,
+    pub other_protocol: Protocol,
+    /// answer QUIC version probes like a QUIC server
+    pub quic_camouflage: bool,
+    /// what our port number answers on tcp
+    pub tcp_camouflage: Option<Camouflage>,
+    /// the answer to packets that aren't wireguard from addresses without a session
+    pub decoy: Decoy,
+    /// what we send an address without a session at most, times what it sent us
+    pub amplification: Option<u64>,
+    /// answer packets we refuse with an ICMP port unreachable
+    pub refuse_unreachable: bool,
+}
+
+impl Config {
+    /// None means print usage and exit
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Config>> {
+        let mut options = uci::expand(args.into_iter().collect())?;
+        let mut args = Args::new(options.clone());
+        let relay = args.get_option("--relay")?;
+        let relay_envelope = args.flag("--relay-envelope");
+        let accept_envelope = args.flag("--accept-envelope");
+        let compact_envelope = args.flag("--compact-envelope");
+        if compact_envelope && !relay_envelope {
+            return Err(args::invalid(
+                "--compact-envelope requires --relay-envelope".to_string(),
+            ));
+        }
+        let seal_target = args
+            .get_option("--seal-target")?
+            .map(|path| Seal::from_file(&path))
+            .transpose()?;
+        let seal_clients = args
+            .get_option("--seal-clients")?
+            .map(|path| Seal::from_file(&path))
+            .transpose()?;
+        #[cfg(feature = "identity")]
+        let (allow_servers, allow_proxies) = (
+            args.get_option("--allow-servers")?
+                .map(|path| Servers::from_file(&path))
+                .transpose()?,
+            args.get_option("--allow-proxies")?
+                .map(|path| Proxies::from_file(&path))
+                .transpose()?,
+        );
+        #[cfg(not(feature = "identity"))]
+        let allow_proxies = match (
+            args.get_option("--allow-servers")?,
+            args.get_option("--allow-proxies")?,
+        ) {
+            (None, None) => None::<()>,
+            _ => {
+                return Err(args::unavailable(
+                    "--allow-servers and --allow-proxies",
+                    "identity",
+                ))
+            }
+        };
+        if seal_clients.is_some() && allow_proxies.is_some() {
+            return Err(args::invalid(
+                "--seal-clients and --allow-proxies are either or".to_string(),
+            ));
+        }
+        let fragment_target = args.get("--fragment-target")?;
+        let fragment_clients = args.get("--fragment-clients")?;
+        if fragment_target
+            .into_iter()
+            .chain(fragment_clients)
+            .any(|mtu: usize| mtu < fragment::MIN_MTU)
+        {
+            return Err(args::invalid(format!(
+                "--fragment-target and --fragment-clients must be at least {}",
+                fragment::MIN_MTU
+            )));
+        }
+        let pmtu = args.get("--pmtu")?;
+        let busy_poll = args.get("--busy-poll")?.map(Duration::from_micros);
+        let dual_stack = args.flag("--dual-stack");
+        let flow_labels = args.flag("--flow-labels");
+        let source_ports = args
+            .get_option("--source-ports")?
+            .map(|range| ports::parse(&range))
+            .transpose()?;
+        let small = args.get("--small")?;
+        if small == Some(0) {
+            return Err(args::invalid("--small must be at least 1".to_string()));
+        }
+        let max_memory = args
+            .get_option("--max-memory")?
+            .map(|size| match log::bytes(&size) {
+                Ok(bytes) if bytes > 0 => Ok(bytes as usize),
+                _ => Err(args::invalid(format!(
+                    "invalid value for --max-memory: {}",
+                    size
+                ))),
+            })
+            .transpose()?;
+        let cold_store = args.get_option("--cold-store")?;
+        let cold_after = args.get("--cold-after")?.map(Duration::from_secs);
+        if cold_after.is_some() && cold_store.is_none() {
+            return Err(args::invalid(
+                "--cold-after requires --cold-store".to_string(),
+            ));
+        }
+        if cold_store.is_some() && small.is_some() {
+            return Err(args::invalid(
+                "--cold-store is for more sessions than fit in memory, --small for few".to_string(),
+            ));
+        }
+        let cold_store = cold_store.map(|path| (path, cold_after.unwrap_or(COLD_AFTER)));
+        let reassembly_timeout = args
+            .get("--reassembly-timeout")?
+            .map(Duration::from_millis)
+            .unwrap_or(fragment::DEFAULT_TIMEOUT);
+        let verbose = args.flag("--verbose");
+        let log_file = args.get_option("--log-file")?;
+        let log_rotate = args
+            .get_option("--log-rotate")?
+            .map(|rotate| log::parse(&rotate))
+            .transpose()?;
+        if log_rotate.is_some() && log_file.is_none() {
+            return Err(args::invalid(
+                "--log-rotate requires --log-file".to_string(),
+            ));
+        }
+        let syslog = args.flag("--syslog");
+        if syslog && log_file.is_some() {
+            return Err(args::invalid(
+                "--syslog and --log-file are either or".to_string(),
+            ));
+        }
+        let print_config = args.flag("--print-config");
+        let dns_target = args.get_option("--dns-target")?;
+        let dns_clients = args.get_option("--dns-clients")?;
+        #[cfg(feature = "dns-tunnel")]
+        for domain in dns_target.iter().chain(&dns_clients) {
+            dns_tunnel::check(domain).map_err(args::invalid)?;
+        }
+        #[cfg(not(feature = "dns-tunnel"))]
+        if dns_target.is_some() || dns_clients.is_some() {
+            return Err(args::unavailable(
+                "--dns-target and --dns-clients",
+                "dns-tunnel",
+            ));
+        }
+        if dns_target.is_some() && dns_clients.is_some() {
+            return Err(args::invalid(
+                "--dns-target and --dns-clients are either or".to_string(),
+            ));
+        }
+        let icmp_target = args.flag("--icmp-target");
+        let icmp_clients = args.flag("--icmp-clients");
+        let icmp_mtu = args.get("--icmp-mtu")?;
+        #[cfg(not(feature = "icmp-tunnel"))]
+        if icmp_target || icmp_clients || icmp_mtu.is_some() {
+            return Err(args::unavailable(
+                "--icmp-target, --icmp-clients and --icmp-mtu",
+                "icmp-tunnel",
+            ));
+        }
+        let faketcp_target = args.flag("--faketcp-target");
+        let faketcp_clients = args.flag("--faketcp-clients");
+        #[cfg(not(feature = "faketcp"))]
+        if faketcp_target || faketcp_clients {
+            return Err(args::unavailable(
+                "--faketcp-target and --faketcp-clients",
+                "faketcp",
+            ));
+        }
+        let tcp_target = args.flag("--tcp-target");
+        let tcp_clients = args.flag("--tcp-clients");
+        let tcp_mux = args.flag("--tcp-mux");
+        #[cfg(not(feature = "tcp"))]
+        if tcp_target || tcp_clients || tcp_mux {
+            return Err(args::unavailable(
+                "--tcp-target, --tcp-clients and --tcp-mux",
+                "tcp",
+            ));
+        }
+        if tcp_mux && !tcp_target {
+            return Err(args::invalid("--tcp-mux requires --tcp-target".to_string()));
+        }
+        let fallback = args
+            .get_all("--fallback")?
+            .iter()
+            .map(|rung| Rung::parse(rung))
+            .collect::<Result<Vec<_>>>()?;
+        #[cfg(feature = "tls")]
+        let tls_rung = fallback
+            .iter()
+            .any(|rung| rung.kind == crate::ladder::Kind::Tls);
+        #[cfg(feature = "tls")]
+        let tls = tls::parse(&mut args, tcp_target || tls_rung, tcp_clients, tls_rung)?;
+        #[cfg(not(feature = "tls"))]
+        if args.flag("--tls") {
+            return Err(args::unavailable("--tls", "tls"));
+        }
+        let transports = [
+            dns_target.is_some(),
+            dns_clients.is_some(),
+            icmp_target,
+            icmp_clients,
+            faketcp_target,
+            faketcp_clients,
+            tcp_target,
+            tcp_clients,
+            !fallback.is_empty(),
+        ];
+        if transports.iter().filter(|given| **given).count() > 1 {
+            return Err(args::invalid(
+                "--dns-target, --dns-clients, --icmp-target, --icmp-clients, --faketcp-target, \
+                 --faketcp-clients, --tcp-target, --tcp-clients and --fallback are either or"
+                    .to_string(),
+            ));
+        }
+        #[cfg(feature = "icmp-tunnel")]
+        let icmp_rung = fallback
+            .iter()
+            .any(|rung| rung.kind == crate::ladder::Kind::Icmp);
+        #[cfg(not(feature = "icmp-tunnel"))]
+        let icmp_rung = false;
+        if icmp_mtu.is_some() && !icmp_target && !icmp_clients && !icmp_rung {
+            return Err(args::invalid(
+                "--icmp-mtu requires --icmp-target, --icmp-clients or a --fallback icmp rung"
+                    .to_string(),
+            ));
+        }
+        if icmp_mtu.is_some_and(|mtu: usize| mtu < fragment::MIN_MTU) {
+            return Err(args::invalid(format!(
+                "--icmp-mtu must be at least {}",
+                fragment::MIN_MTU
+            )));
+        }
+        #[cfg(feature = "icmp-tunnel")]
+        let icmp_mtu = icmp_mtu.unwrap_or(icmp_tunnel::DEFAULT_MTU);
+        #[cfg(not(feature = "icmp-tunnel"))]
+        let icmp_mtu = icmp_mtu.unwrap_or_default();
+        let peer_relay = args.flag("--peer-relay");
+        let hairpin = !args.flag("--no-hairpin");
+        let stun = args.flag("--stun");
+        let max_session_pps = args.get("--max-session-pps")?;
+        let shed_load = args.flag("--shed-load");
+        let metrics_addr = args.get_option("--metrics")?;
+        #[cfg(not(feature = "metrics"))]
+        if metrics_addr.is_some() {
+            return Err(args::unavailable("--metrics", "metrics"));
+        }
+        let forward_unknown_types = args.flag("--forward-unknown-types");
+        let extra_types = args
+            .get_option("--extra-types")?
+            .map(|types| {
+                types
+                    .split(',')
+                    .map(|t| args::parse("--extra-types", t.trim()))
+                    .collect::<Result<Vec<u8>>>()
+            })
+            .transpose()?;
+        let startup_grace = args.get("--startup-grace")?.map(Duration::from_secs);
+        let expiry_grace = args.get("--expiry-grace")?.map(Duration::from_secs);
+        let dscp = args
+            .get_all("--dscp")?
+            .iter()
+            .map(|rule| parse_dscp(rule))
+            .collect::<Result<_>>()?;
+        let schedule = args
+            .get_all("--schedule")?
+            .iter()
+            .map(|entry| parse_schedule(entry))
+            .collect::<Result<Vec<_>>>()?;
+        let schedule_transition =
+            Duration::from_secs(args.get("--schedule-transition")?.unwrap_or(0));
+        let blackhole_after = args.get("--blackhole-after")?;
+        let failover = args
+            .get_all("--failover")?
+            .iter()
+            .map(|host| Target::resolve(host))
+            .collect::<Result<_>>()?;
+        let probe = args
+            .get_option("--probe")?
+            .map(|probe| probe::parse(&probe))
+            .transpose()?;
+        let max_sessions = args
+            .get_all("--max-sessions")?
+            .iter()
+            .map(|quota| match quota.rsplit_once('=') {
+                Some((host, n)) => Ok((host.to_string(), args::parse("--max-sessions", n)?)),
+                None => Err(args::invalid(format!(
+                    "invalid value for --max-sessions: {}",
+                    quota
+                ))),
+            })
+            .collect::<Result<_>>()?;
+        let admin_path = args.get_option("--admin")?;
+        #[cfg(feature = "admin")]
+        let admin_tokens = args
+            .get_option("--admin-tokens")?
+            .map(|path| Tokens::from_file(&path))
+            .transpose()?;
+        #[cfg(not(feature = "admin"))]
+        if admin_path.is_some() || args.get_option("--admin-tokens")?.is_some() {
+            return Err(args::unavailable("--admin and --admin-tokens", "admin"));
+        }
+        let grpc_addr = args.get_option("--grpc")?;
+        #[cfg(not(feature = "grpc"))]
+        if grpc_addr.is_some() {
+            return Err(args::unavailable("--grpc", "grpc"));
+        }
+        let tenants_path = args.get_option("--tenants")?;
+        let config_key = args.get_option("--config-key")?;
+        #[cfg(feature = "signed-config")]
+        if let Some(key) = &config_key {
+            signed::Key::parse(key)?;
+        }
+        #[cfg(not(feature = "signed-config"))]
+        if config_key.is_some() {
+            return Err(args::unavailable("--config-key", "signed-config"));
+        }
+        let strict_source = args.flag("--strict-source");
+        let sender_collision = match (args.get("--sender-collision")?, strict_source) {
+            (Some(Collision::Reject) | None, true) => Collision::Reject,
+            (Some(_), true) => {
+                return Err(args::invalid(
+                    "--strict-source takes sessions away from nobody, --sender-collision can only be reject with it"
+                        .to_string(),
+                ))
+            }
+            (collision, false) => collision.unwrap_or_default(),
+        };
+        let freeze_hijacked = args.flag("--freeze-hijacked");
+        let strict_responses = args.flag("--strict-responses");
+        let index_audit = args.flag("--index-audit");
+        let fingerprints = args.flag("--fingerprints");
+        let pcap = args.get_option("--pcap")?;
+        let pcap_filter = args
+            .get_option("--pcap-filter")?
+            .map(|filter| Filter::parse(&filter))
+            .transpose()?;
+        if pcap_filter.is_some() && pcap.is_none() {
+            return Err(args::invalid("--pcap-filter requires --pcap".to_string()));
+        }
+        let pace = args
+            .get_all("--pace")?
+            .iter()
+            .map(|pace| parse_pace(pace))
+            .collect::<Result<Vec<_>>>()?;
+        let dead_after = args.get("--dead-after")?;
+        let state_path = args.get_option("--state-file")?;
+        let crash_dir = args
+            .get_option("--crash-dir")?
+            .unwrap_or_else(|| env::temp_dir().to_string_lossy().into_owned());
+        let heartbeat_fields = args
+            .get_option("--heartbeat-fields")?
+            .map(|fields| heartbeat::parse(&fields))
+            .transpose()?;
+        let heartbeat = match args.get::<u64>("--heartbeat")? {
+            Some(0) => return Err(args::invalid("--heartbeat must be at least 1".to_string())),
+            Some(secs) => Some((
+                Duration::from_secs(secs),
+                heartbeat_fields.unwrap_or_else(|| heartbeat::DEFAULT_FIELDS.to_vec()),
+            )),
+            None if heartbeat_fields.is_some() => {
+                return Err(args::invalid(
+                    "--heartbeat-fields requires --heartbeat".to_string(),
+                ))
+            }
+            None => None,
+        };
+        let webhook_url = args.get_option("--webhook-url")?;
+        let mqtt_broker = args.get_option("--mqtt")?;
+        let mqtt_topic = args
+            .get_option("--mqtt-topic")?
+            .unwrap_or_else(|| "wireguard-udp-proxy".to_string());
+        let etw = args.flag("--etw");
+        #[cfg(feature = "events")]
+        let mqtt_credentials = args
+            .get_option("--mqtt-credentials")?
+            .map(|path| Credentials::from_file(&path))
+            .transpose()?;
+        #[cfg(not(feature = "events"))]
+        if webhook_url.is_some()
+            || mqtt_broker.is_some()
+            || etw
+            || args.get_option("--mqtt-credentials")?.is_some()
+        {
+            return Err(args::unavailable(
+                "--webhook-url, --mqtt and --etw",
+                "events",
+            ));
+        }
+        let other_backend = args
+            .get_option("--other-backend")?
+            .map(|addr| resolve(&addr))
+            .transpose()?;
+        let other_protocol = args.get("--other-protocol")?.unwrap_or_default();
+        let quic_camouflage = args.flag("--quic-camouflage");
+        let tcp_camouflage = args.get("--tcp-camouflage")?;
+        if tcp_camouflage.is_some() && (tcp_clients || faketcp_clients) {
+            return Err(args::invalid(
+                "--tcp-camouflage needs the tcp port --tcp-clients and --faketcp-clients take"
+                    .to_string(),
+            ));
+        }
+        let decoy = args
+            .get_option("--decoy")?
+            .map(|kind| decoy::parse(&kind))
+            .transpose()?
+            .unwrap_or_default();
+        let amplification = match args.get_option("--amplification")?.as_deref() {
+            Some("off") => None,
+            Some(ratio) => match args::parse("--amplification", ratio)? {
+                0 => {
+                    return Err(args::invalid(
+                        "--amplification must be at least 1, or off".to_string(),
+                    ))
+                }
+                ratio => Some(ratio),
+            },
+            None => Some(AMPLIFICATION),
+        };
+        let refuse_unreachable = args.flag("--refuse-unreachable");
+        let unknown_types = match extra_types {
+            Some(types) => Some(types),
+            None => forward_unknown_types.then(Vec::new),
+        };
+        let threads_min = args.get("--threads-min")?;
+        let threads_max = args.get("--threads-max")?;
+        if threads_min == Some(0) {
+            return Err(args::invalid(
+                "--threads-min must be at least 1".to_string(),
+            ));
+        }
+        #[cfg(feature = "peer-relay")]
+        let psk = args
+            .get_option("--psk-file")?
+            .map(|path| Psk::from_file(&path))
+            .transpose()?;
+        #[cfg(not(feature = "peer-relay"))]
+        let psk = match args.get_option("--psk-file")? {
+            Some(_) => return Err(args::unavailable("--psk-file", "peer-relay")),
+            None => None::<()>,
+        };
+        #[cfg(feature = "canary")]
+        let canary = match (
+            args.get("--canary")?,
+            args.get_option("--canary-private-key")?,
+            args.get_option("--canary-public-key")?,
+        ) {
+            (Some(0), _, _) => {
+                return Err(args::invalid("--canary must be at least 1".to_string()));
+            }
+            (Some(secs), Some(path), Some(key)) => Some(canary::Settings {
+                every: Duration::from_secs(secs),
+                keys: Keys::read(&path, &key)?,
+            }),
+            (Some(_), _, _) => {
+                return Err(args::invalid(
+                    "--canary requires --canary-private-key and --canary-public-key".to_string(),
+                ));
+            }
+            (None, None, None) => None,
+            (None, _, _) => {
+                return Err(args::invalid(
+                    "--canary-private-key and --canary-public-key require --canary".to_string(),
+                ));
+            }
+        };
+        #[cfg(not(feature = "canary"))]
+        let canary = match args.get_option("--canary")? {
+            Some(_) => return Err(args::unavailable("--canary", "canary")),
+            None => None::<()>,
+        };
+        #[cfg(feature = "control-plane")]
+        let control_plane = match (
+            args.get_option("--control-plane")?,
+            args.get_option("--control-plane-key")?,
+            args.get("--control-plane-every")?,
+        ) {
+            (_, _, Some(0)) => {
+                return Err(args::invalid(
+                    "--control-plane-every must be at least 1".to_string(),
+                ));
+            }
+            (Some(url), Some(key), every) => Some(fleet::Settings::parse(
+                &url,
+                &key,
+                every.map_or(fleet::EVERY, Duration::from_secs),
+            )?),
+            (Some(_), None, _) => {
+                return Err(args::invalid(
+                    "--control-plane requires --control-plane-key".to_string(),
+                ));
+            }
+            (None, None, None) => None,
+            (None, _, _) => {
+                return Err(args::invalid(
+                    "--control-plane-key and --control-plane-every require --control-plane"
+                        .to_string(),
+                ));
+            }
+        };
+        #[cfg(not(feature = "control-plane"))]
+        let control_plane = match args.get_option("--control-plane")? {
+            Some(_) => return Err(args::unavailable("--control-plane", "control-plane")),
+            None => None::<()>,
+        };
+        let positional = args.positional()?;
+        for arg in &positional {
+            if let Some(i) = options.iter().rposition(|option| option == arg) {
+                options.remove(i);
+            }
+        }
+        let mut positional = positional.into_iter();
+
+        let target = if peer_relay {
+            if psk.is_none() {
+                return Err(args::invalid(
+                    "--peer-relay requires --psk-file".to_string(),
+                ));
+            }
+            None
+        } else {
+            match relay.or_else(|| positional.next()) {
+                None => return Ok(None),
+                Some(target_addr) => Some(Target::resolve(&target_addr)?),
+            }
+        };
+        let bind_addr = positional
+            .next()
+            .unwrap_or_else(|| DEFAULT_BIND.to_string());
+        if dual_stack
+            && !bind_addr
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| addr.ip().is_unspecified())
+        {
+            return Err(args::invalid(format!(
+                "--dual-stack listens on every address, bind_addr must be 0.0.0.0 or [::] and a port, not {}",
+                bind_addr
+            )));
+        }
+        let (thread_count, threads) = match positional.next().as_deref() {
+            Some("auto") => {
+                let bounds = Bounds::new(threads_min, threads_max);
+                (bounds.initial(), Some(bounds))
+            }
+            _ if threads_min.is_some() || threads_max.is_some() => {
+                return Err(args::invalid(
+                    "--threads-min and --threads-max require num_threads auto".to_string(),
+                ))
+            }
+            Some(threads) => (args::parse("num_threads", threads)?, None),
+            None => (1, None),
+        };
+        if let Some(extra) = positional.next() {
+            return Err(args::invalid(format!("unexpected argument: {}", extra)));
+        }
+        let tenants = match tenants_path {
+            Some(path) => load_tenants(&path, &options, config_key.as_deref())?,
+            None => Vec::new(),
+        };
+        if small.is_some() {
+            let threaded = [
+                (metrics_addr.is_some(), "--metrics"),
+                (admin_path.is_some(), "--admin"),
+                (grpc_addr.is_some(), "--grpc"),
+                (state_path.is_some(), "--state-file"),
+                (!schedule.is_empty(), "--schedule"),
+                (probe.is_some(), "--probe"),
+                (canary.is_some(), "--canary"),
+                (control_plane.is_some(), "--control-plane"),
+                (blackhole_after.is_some(), "--blackhole-after"),
+                (heartbeat.is_some(), "--heartbeat"),
+                (source_ports.is_some(), "--source-ports"),
+                (tcp_camouflage.is_some(), "--tcp-camouflage"),
+                (webhook_url.is_some(), "--webhook-url"),
+                (mqtt_broker.is_some(), "--mqtt"),
+                (etw, "--etw"),
+                (log_rotate.is_some(), "--log-rotate"),
+                (syslog, "--syslog"),
+                (!tenants.is_empty(), "--tenants"),
+                (psk.is_some(), "--psk-file"),
+                (other_backend.is_some(), "--other-backend"),
+                (!pace.is_empty(), "--pace"),
+                (pcap.is_some(), "--pcap"),
+                (
+                    thread_count > 1 || threads.is_some(),
+                    "more than one worker",
+                ),
+            ];
+            if let Some((_, option)) = threaded.iter().find(|(set, _)| *set) {
+                return Err(args::invalid(format!(
+                    "--small runs no threads but the worker, so no {}",
+                    option
+                )));
+            }
+        }
+        #[cfg(feature = "admin")]
+        if let Some(tokens) = &admin_tokens {
+            if admin_path.is_none() && grpc_addr.is_none() {
+                return Err(args::invalid(
+                    "--admin-tokens requires --admin or --grpc".to_string(),
+                ));
+            }
+            if let Some(name) = tokens
+                .tenants()
+                .find(|name| !tenants.iter().any(|(tenant, _)| tenant == name))
+            {
+                return Err(args::invalid(format!(
+                    "--admin-tokens for unknown tenant: {}",
+                    name
+                )));
+            }
+        }
+
+        Ok(Some(Config {
+            target,
+            bind_addr,
+            options,
+            tenants,
+            thread_count,
+            threads,
+            relay_envelope,
+            accept_envelope,
+            compact_envelope,
+            seal_target,
+            seal_clients,
+            #[cfg(feature = "identity")]
+            allow_proxies,
+            fragment_target,
+            fragment_clients,
+            reassembly_timeout,
+            pmtu,
+            busy_poll,
+            dual_stack,
+            flow_labels,
+            source_ports,
+            small,
+            max_memory,
+            cold_store,
+            verbose,
+            log_file,
+            log_rotate,
+            syslog,
+            print_config,
+            dns_target,
+            dns_clients,
+            icmp_target,
+            icmp_clients,
+            icmp_mtu,
+            faketcp_target,
+            faketcp_clients,
+            tcp_target,
+            tcp_clients,
+            tcp_mux,
+            fallback,
+            #[cfg(feature = "tls")]
+            tls,
+            peer_relay,
+            #[cfg(feature = "peer-relay")]
+            psk,
+            #[cfg(feature = "canary")]
+            canary,
+            #[cfg(feature = "control-plane")]
+            control_plane,
+            hairpin,
+            stun,
+            max_session_pps,
+            shed_load,
+            metrics_addr,
+            unknown_types,
+            startup_grace,
+            expiry_grace,
+            dscp,
+            schedule,
+            schedule_transition,
+            blackhole_after,
+            failover,
+            probe,
+            max_sessions,
+            #[cfg(feature = "identity")]
+            allow_servers,
+            admin_path,
+            grpc_addr,
+            #[cfg(feature = "admin")]
+            admin_tokens,
+            sender_collision,
+            strict_source,
+            freeze_hijacked,
+            strict_responses,
+            index_audit,
+            fingerprints,
+            pcap,
+            pcap_filter,
+            pace,
+            dead_after,
+            state_path,
+            crash_dir,
+            heartbeat,
+            webhook_url,
+            mqtt_broker,
+            mqtt_topic,
+            #[cfg(feature = "events")]
+            mqtt_credentials,
+            etw,
+            other_backend,
+            other_protocol,
+            quic_camouflage,
+            tcp_camouflage,
+            decoy,
+            amplification,
+            refuse_unreachable,
+        }))
+    }
+}
+
+/// a config for another listener, from options without what only the process has, or its role if
+/// args give one, and then args
+pub fn derive(options: &[String], args: &[String]) -> Result<Config> {
+    let mut inherited = Args::new(options.iter().cloned());
+    for name in PER_PROCESS {
+        inherited.get_all(name)?;
+    }
+    let mut own = Args::new(args.iter().cloned());
+    let own_role = ROLE_FLAGS.iter().any(|name| own.flag(name))
+        || ROLE
+            .iter()
+            .any(|name| own.get_all(name).is_ok_and(|v| !v.is_empty()));
+    if own_role {
+        for name in ROLE {
+            inherited.get_all(name)?;
+        }
+        for name in ROLE_FLAGS {
+            inherited.flag(name);
+        }
+    }
+    let mut inherited = inherited.rest();
+    inherited.extend_from_slice(args);
+    Config::from_args(inherited)?
+        .ok_or_else(|| args::invalid("a listener needs a target".to_string()))
+}
+
+/// what's in path, a configuration file, once its signature verifies if there's a --config-key
+pub fn read(path: &str, key: Option<&str>) -> Result<String> {
+    match key {
+        None => Ok(fs::read_to_string(path)?),
+        #[cfg(feature = "signed-config")]
+        Some(key) => signed::Key::parse(key)?.read(path),
+        #[cfg(not(feature = "signed-config"))]
+        Some(_) => Err(args::unavailable("--config-key", "signed-config")),
+    }
+}
+
+/// the tenants in path, one per line, empty ones and those starting with # are skipped
+fn load_tenants(
+    path: &str,
+    options: &[String],
+    key: Option<&str>,
+) -> Result<Vec<(String, Config)>> {
+    let mut tenants: Vec<(String, Config)> = Vec::new();
+    for line in read(path, key)?.lines() {
+        let mut words = line.split_whitespace().map(String::from);
+        let name = match words.next() {
+            Some(name) if !name.starts_with('#') => name,
+            _ => continue,
+        };
+        if tenants.iter().any(|(other, _)| *other == name) {
+            return Err(args::invalid(format!(
+                "tenant {} is in {} twice",
+                name, path
+            )));
+        }
+        let config = derive(options, &words.collect::<Vec<_>>())
+            .map_err(|e| args::invalid(format!("tenant {} in {}: {}", name, path, e)))?;
+        tenants.push((name, config));
+    }
+    Ok(tenants)
+}
+
+pub fn resolve(addr: &str) -> Result<SocketAddr> {
+    let resolve = |source| Error::Resolve {
+        addr: addr.to_string(),
+        source,
+    };
+    addr.to_socket_addrs()
+        .map_err(resolve)?
+        .next()
+        .ok_or_else(|| resolve(ErrorKind::NotFound.into()))
+}
+
+fn parse_dscp(rule: &str) -> Result<(Cidr, u8)> {
+    let (cidr, value) = rule
+        .split_once('=')
+        .ok_or_else(|| args::invalid(format!("invalid value for --dscp: {}", rule)))?;
+    let value = args::parse("--dscp", value)?;
+    if value > 63 {
+        return Err(args::invalid(format!("DSCP value out of range: {}", rule)));
+    }
+    Ok((args::parse("--dscp", cidr)?, value))
+}
+
+fn parse_pace(pace: &str) -> Result<(String, Pace)> {
+    let (host, rate) = pace
+        .rsplit_once('=')
+        .ok_or_else(|| args::invalid(format!("invalid value for --pace: {}", pace)))?;
+    let (kbit, burst) = match rate.split_once(',') {
+        Some((kbit, burst)) => (kbit, Some(burst)),
+        None => (rate, None),
+    };
+    let kbit: u64 = args::parse("--pace", kbit)?;
+    if kbit == 0 {
+        return Err(args::invalid(format!("invalid value for --pace: {}", pace)));
+    }
+    let rate = kbit * 1000 / 8;
+    let burst = match burst {
+        Some(burst) => args::parse("--pace", burst)?,
+        None => (rate / 100).max(3000),
+    };
+    Ok((host.to_string(), Pace { rate, burst }))
+}
+
+fn parse_schedule(entry: &str) -> Result<(Cron, Target)> {
+    let (cron, target_addr) = entry
+        .trim()
+        .rsplit_once(char::is_whitespace)
+        .ok_or_else(|| args::invalid(format!("invalid value for --schedule: {}", entry)))?;
+    Ok((
+        args::parse("--schedule", cron)?,
+        Target::resolve(target_addr)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = "192.0.2.2:51820";
+
+    fn parse(args: &[&str]) -> Result<Config> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))?
+            .ok_or_else(|| args::invalid("no config".to_string()))
+    }
+
+    #[test]
+    fn test_refused() {
+        let small = "--small runs no threads but the worker, so no";
+        #[cfg(all(feature = "tcp", any(feature = "faketcp", feature = "icmp-tunnel")))]
+        let either =
+            "--dns-target, --dns-clients, --icmp-target, --icmp-clients, --faketcp-target, \
+                      --faketcp-clients, --tcp-target, --tcp-clients and --fallback are either or";
+        let refused: &[(&[&str], String)] = &[
+            (
+                &["--small", "0", TARGET],
+                "--small must be at least 1".into(),
+            ),
+            (
+                &["--small", "4", "--state-file", "counters", TARGET],
+                format!("{} --state-file", small),
+            ),
+            (
+                &["--small", "4", "--pcap", "capture.pcap", TARGET],
+                format!("{} --pcap", small),
+            ),
+            (
+                &["--small", "4", "--pace", "192.0.2.2:51820=8", TARGET],
+                format!("{} --pace", small),
+            ),
+            (
+                &["--small", "4", TARGET, "0.0.0.0:5678", "2"],
+                format!("{} more than one worker", small),
+            ),
+            (
+                &["--strict-source", "--sender-collision", "overwrite", TARGET],
+                "--strict-source takes sessions away from nobody, --sender-collision can only be \
+                 reject with it"
+                    .into(),
+            ),
+            #[cfg(all(feature = "tcp", feature = "faketcp"))]
+            (&["--tcp-target", "--faketcp-target", TARGET], either.into()),
+            #[cfg(all(feature = "tcp", feature = "icmp-tunnel"))]
+            (&["--icmp-clients", "--tcp-clients", TARGET], either.into()),
+            #[cfg(feature = "dns-tunnel")]
+            (
+                &[
+                    "--dns-target",
+                    "t.example.com",
+                    "--dns-clients",
+                    "t.example.com",
+                    TARGET,
+                ],
+                "--dns-target and --dns-clients are either or".into(),
+            ),
+            #[cfg(feature = "tcp")]
+            (
+                &["--tcp-mux", TARGET],
+                "--tcp-mux requires --tcp-target".into(),
+            ),
+            (
+                &["--syslog", "--log-file", "proxy.log", TARGET],
+                "--syslog and --log-file are either or".into(),
+            ),
+        ];
+        for (args, refused) in refused {
+            match parse(args) {
+                Ok(_) => panic!("{:?} parsed", args),
+                Err(e) => assert_eq!(e.to_string(), *refused, "{:?}", args),
+            }
+        }
+    }
+
+    #[test]
+    fn test_accepted() {
+        let config = parse(&["--strict-source", TARGET]).unwrap();
+        assert_eq!(config.sender_collision, Collision::Reject);
+        let config = parse(&["--strict-source", "--sender-collision", "reject", TARGET]).unwrap();
+        assert_eq!(config.sender_collision, Collision::Reject);
+        let config = parse(&["--sender-collision", "idle", TARGET]).unwrap();
+        assert_eq!(config.sender_collision, Collision::Idle);
+        assert_eq!(config.options, ["--sender-collision", "idle"]);
+        #[cfg(feature = "tcp")]
+        {
+            let config = parse(&["--tcp-target", "--tcp-mux", TARGET]).unwrap();
+            assert!(config.tcp_target && config.tcp_mux);
+        }
+    }
+
+    #[test]
+    fn test_derive() {
+        let parent = parse(&[
+            "--log-file",
+            "proxy.log",
+            "--state-file",
+            "counters",
+            "--strict-source",
+            "--relay-envelope",
+            TARGET,
+        ])
+        .unwrap();
+        let derive = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            derive(&parent.options, &args)
+        };
+        // what's per process stays with the first listener, the rest carries over
+        let child = derive(&["192.0.2.3:51820", "127.0.0.1:0"]).unwrap();
+        assert_eq!(child.target.unwrap().host, "192.0.2.3:51820");
+        assert_eq!(child.log_file, None);
+        assert_eq!(child.state_path, None);
+        assert!(child.strict_source && child.relay_envelope);
+        // one with a role of its own takes none of ours
+        let child = derive(&["--accept-envelope", "192.0.2.3:51820"]).unwrap();
+        assert!(child.strict_source && !child.relay_envelope && child.accept_envelope);
+    }
+}
@@ -0,0 +1,252 @@
+// the control messages between two of us on a --tcp-target connection, so each can tell the
+// other proxy being gone from the wireguard target being gone:
+//
+//     CONTROL HELLO <version> <time> <software version>
+//     CONTROL PING <time>
+//     CONTROL PONG <their time> <time> <target refused>
+//
+// times are unix milliseconds, CONTROL is neither a wireguard message type nor a mux frame kind,
+// so an older far end passes them on to its target, which drops them
+//
+// the near end says hello and then pings every HEARTBEAT, the far end answers each, once either
+// heard from the other it closes the connection when nothing came for DEAD_AFTER
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const CONTROL: u8 = 0xff;
+
+const VERSION: u8 = 1;
+
+const HELLO: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+pub const HEARTBEAT: Duration = Duration::from_secs(5);
+pub const DEAD_AFTER: Duration = Duration::from_secs(15);
+
+// clocks further apart than this get a warning, certificates and --schedule go by them
+const MAX_SKEW: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Hello {
+        version: u8,
+        time: u64,
+        software: String,
+    },
+    Ping {
+        time: u64,
+    },
+    Pong {
+        echo: u64,
+        time: u64,
+        /// the far end's target answered with port unreachable last
+        target_refused: bool,
+    },
+}
+
+impl Message {
+    /// None for what isn't a control message, or one of a later version we don't know
+    pub fn parse(frame: &[u8]) -> Option<Message> {
+        if *frame.first()? != CONTROL {
+            return None;
+        }
+        let u64_at = |at: usize| Some(u64::from_be_bytes(frame.get(at..at + 8)?.try_into().ok()?));
+        match *frame.get(1)? {
+            HELLO => Some(Message::Hello {
+                version: *frame.get(2)?,
+                time: u64_at(3)?,
+                software: String::from_utf8_lossy(frame.get(11..)?).into_owned(),
+            }),
+            PING => Some(Message::Ping { time: u64_at(2)? }),
+            PONG => Some(Message::Pong {
+                echo: u64_at(2)?,
+                time: u64_at(10)?,
+                target_refused: *frame.get(18)? != 0,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = vec![CONTROL];
+        match self {
+            Message::Hello {
+                version,
+                time,
+                software,
+            } => {
+                frame.extend_from_slice(&[HELLO, *version]);
+                frame.extend_from_slice(&time.to_be_bytes());
+                frame.extend_from_slice(software.as_bytes());
+            }
+            Message::Ping { time } => {
+                frame.push(PING);
+                frame.extend_from_slice(&time.to_be_bytes());
+            }
+            Message::Pong {
+                echo,
+                time,
+                target_refused,
+            } => {
+                frame.push(PONG);
+                frame.extend_from_slice(&echo.to_be_bytes());
+                frame.extend_from_slice(&time.to_be_bytes());
+                frame.push(*target_refused as u8);
+            }
+        }
+        frame
+    }
+}
+
+pub fn is_control(frame: &[u8]) -> bool {
+    frame.first() == Some(&CONTROL)
+}
+
+/// unix milliseconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+pub fn hello() -> Vec<u8> {
+    Message::Hello {
+        version: VERSION,
+        time: now(),
+        software: env!("CARGO_PKG_VERSION").to_string(),
+    }
+    .encode()
+}
+
+pub fn ping() -> Vec<u8> {
+    Message::Ping { time: now() }.encode()
+}
+
+/// the far end's answer to message, hello for hello and pong for ping
+pub fn answer(message: &Message, target_refused: bool) -> Option<Vec<u8>> {
+    match message {
+        Message::Hello { .. } => Some(hello()),
+        Message::Ping { time } => Some(
+            Message::Pong {
+                echo: *time,
+                time: now(),
+                target_refused,
+            }
+            .encode(),
+        ),
+        Message::Pong { .. } => None,
+    }
+}
+
+/// what the near end heard from the far end of a connection, for telling whoever reads the log
+pub struct Peer {
+    /// how the far end was given, to log
+    name: String,
+    verbose: bool,
+    target_refused: bool,
+    skew_warned: bool,
+}
+
+impl Peer {
+    pub fn new(name: &str, verbose: bool) -> Peer {
+        Peer {
+            name: name.to_string(),
+            verbose,
+            target_refused: false,
+            skew_warned: false,
+        }
+    }
+
+    /// a message came at now, returns the clock skew it showed if it was a pong
+    pub fn heard(&mut self, message: &Message, now: u64) -> Option<i64> {
+        match message {
+            Message::Hello {
+                version, software, ..
+            } => {
+                if self.verbose {
+                    eprintln!(
+                        "peer proxy {} is version {}, control protocol {}",
+                        self.name, software, version
+                    );
+                }
+                None
+            }
+            Message::Ping { .. } => None,
+            Message::Pong {
+                echo,
+                time,
+                target_refused,
+            } => {
+                if *target_refused != self.target_refused {
+                    self.target_refused = *target_refused;
+                    if *target_refused {
+                        eprintln!(
+                            "peer proxy {} is up but its wireguard target is unreachable",
+                            self.name
+                        );
+                    } else {
+                        eprintln!(
+                            "peer proxy {} reaches its wireguard target again",
+                            self.name
+                        );
+                    }
+                }
+                // the far end read its clock half way through the round trip, or so we assume
+                let rtt = now.saturating_sub(*echo);
+                let skew = *time as i64 - (*echo + rtt / 2) as i64;
+                if skew.unsigned_abs() > MAX_SKEW.as_millis() as u64 && !self.skew_warned {
+                    self.skew_warned = true;
+                    eprintln!(
+                        "the clock of peer proxy {} is {:.1}s off ours",
+                        self.name,
+                        skew as f64 / 1000.0
+                    );
+                }
+                Some(skew)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control() {
+        for message in [
+            Message::Hello {
+                version: 1,
+                time: 7,
+                software: "0.1.0".to_string(),
+            },
+            Message::Ping { time: u64::MAX },
+            Message::Pong {
+                echo: 1,
+                time: 2,
+                target_refused: true,
+            },
+        ] {
+            assert!(is_control(&message.encode()));
+            assert_eq!(Message::parse(&message.encode()), Some(message));
+        }
+        assert_eq!(Message::parse(&[CONTROL, PING, 0]), None);
+        assert_eq!(Message::parse(&[CONTROL, 9]), None);
+        assert!(!is_control(&[4, 0, 0, 0]));
+
+        assert!(matches!(
+            Message::parse(&answer(&Message::Ping { time: 5 }, false).unwrap()),
+            Some(Message::Pong { echo: 5, .. })
+        ));
+        let mut peer = Peer::new("far.example.com:443", false);
+        let pong = Message::Pong {
+            echo: 1_000,
+            time: 61_100,
+            target_refused: false,
+        };
+        // 200ms there and back, the far end 60s ahead
+        assert_eq!(peer.heard(&pong, 1_200), Some(60_000));
+        assert!(peer.skew_warned);
+    }
+}
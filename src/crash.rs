@@ -0,0 +1,129 @@
+// a panic hook that leaves a report behind for whoever runs us on a router to send along, what a
+// panic prints scrolls away or goes nowhere under procd: where it happened, a backtrace, and what
+// each listener was up to at the time, sizes and counters only
+//
+// nothing in it says who: no addresses, keys or option values, only the options' names
+//
+// snapshots take their locks with try_ only, the panicking thread may be the one holding them
+
+use crate::poison::Recover;
+
+use std::{
+    backtrace::Backtrace,
+    env,
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, Write as _},
+    panic,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+type Snapshot = Box<dyn Fn(&mut String) + Send + Sync>;
+
+static SNAPSHOTS: Mutex<Vec<Snapshot>> = Mutex::new(Vec::new());
+
+/// from now on every panic also writes a report to dir, after printing as it always did
+pub fn install(dir: PathBuf) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let backtrace = Backtrace::force_capture();
+        let report = report(&info.to_string(), &backtrace.to_string());
+        match write(&dir, &report) {
+            Ok(path) => eprintln!("crash report written to {}", path.display()),
+            Err(e) => eprintln!("writing a crash report to {} failed: {}", dir.display(), e),
+        }
+    }));
+}
+
+/// adds what snapshot writes to every report, once per listener
+pub fn register(snapshot: impl Fn(&mut String) + Send + Sync + 'static) {
+    SNAPSHOTS.lock().recover().push(Box::new(snapshot));
+}
+
+fn report(panic: &str, backtrace: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "wireguard-udp-proxy {} crash report\nthread: {}\n{}\noptions: {}",
+        env!("CARGO_PKG_VERSION"),
+        thread::current().name().unwrap_or("unnamed"),
+        panic,
+        options(env::args().skip(1))
+    );
+    match SNAPSHOTS.try_lock() {
+        Ok(snapshots) => {
+            for (n, snapshot) in snapshots.iter().enumerate() {
+                let _ = write!(out, "listener {}:", n + 1);
+                snapshot(&mut out);
+                out.push('\n');
+            }
+        }
+        Err(_) => out.push_str("listeners: unavailable\n"),
+    }
+    let _ = write!(out, "backtrace:\n{}", backtrace);
+    out
+}
+
+/// the names of the options in args, each once, without the values that could say who we are
+fn options(args: impl Iterator<Item = String>) -> String {
+    let mut names: Vec<String> = args
+        .filter(|arg| arg.starts_with("--"))
+        .map(|arg| arg.split('=').next().unwrap_or_default().to_string())
+        .collect();
+    names.sort();
+    names.dedup();
+    names.join(" ")
+}
+
+/// appends report to this second's report in dir, in case other threads panic with us
+fn write(dir: &Path, report: &str) -> io::Result<PathBuf> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = dir.join(format!(
+        "wireguard-udp-proxy-crash-{}-{}.txt",
+        secs,
+        process::id()
+    ));
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(format!("{}\n", report).as_bytes())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_report() {
+        let args = [
+            "--psk-file",
+            "/etc/psk",
+            "--verbose",
+            "--metrics=0.0.0.0:9090",
+            "--verbose",
+        ];
+        assert_eq!(
+            options(args.iter().map(|a| a.to_string())),
+            "--metrics --psk-file --verbose"
+        );
+
+        register(|out| out.push_str(" sessions 3"));
+        let report = report("panicked at src/proxy.rs:1:1:\noops", "0: main");
+        assert!(report.contains("\noops\n"));
+        assert!(report.contains("listener 1: sessions 3\n"));
+        assert!(report.ends_with("backtrace:\n0: main"));
+
+        let dir = env::temp_dir();
+        let path = write(&dir, &report).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains(&report));
+        fs::remove_file(path).unwrap();
+    }
+}
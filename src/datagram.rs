@@ -0,0 +1,64 @@
+// what the forwarding loops need from their socket, a UdpSocket outside of tests, which can hand
+// the loop packets and collect what it sends instead
+
+use crate::{
+    pktinfo::{self, LocalAddr},
+    platform::{self, IcmpError},
+};
+
+use std::{
+    io::Result,
+    net::{SocketAddr, UdpSocket},
+};
+
+pub trait Datagram: Send + Sync {
+    /// like pktinfo::recv_from
+    fn recv(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)>;
+
+    /// like pktinfo::send_marked
+    fn send(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        local_addr: Option<LocalAddr>,
+        dscp: Option<u8>,
+    ) -> Result<usize>;
+
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    /// like platform::recv_errors
+    fn icmp_errors(&self) -> Result<Vec<IcmpError>>;
+
+    /// like platform::readable
+    fn readable(&self) -> bool {
+        true
+    }
+}
+
+impl Datagram for UdpSocket {
+    fn recv(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)> {
+        pktinfo::recv_from(self, buf)
+    }
+
+    fn send(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        local_addr: Option<LocalAddr>,
+        dscp: Option<u8>,
+    ) -> Result<usize> {
+        pktinfo::send_marked(self, buf, addr, local_addr, dscp)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn icmp_errors(&self) -> Result<Vec<IcmpError>> {
+        platform::recv_errors(self)
+    }
+
+    fn readable(&self) -> bool {
+        platform::readable(self)
+    }
+}
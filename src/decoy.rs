@@ -0,0 +1,197 @@
+// --decoy, what a source without a session gets back for a packet that isn't wireguard, silence
+// on an open port is a fingerprint of its own, a closed port answers with an ICMP port unreachable
+// and some services with a canned reply
+//
+// it's never more than we got, so we can't be used to amplify, and at most MAX_PER_SEC of them go
+// out in all, so we can't be used to flood someone whose address is spoofed either
+
+use crate::{args, error, platform::RawIcmp};
+
+use std::{
+    fs,
+    io::Result,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+const MAX_PER_SEC: u64 = 100;
+
+// how much of the offending datagram an ICMP error quotes at most, what fits in the IPv4 minimum
+// MTU of 576 after both IP headers and the ICMP and UDP ones
+const MAX_QUOTE_LEN: usize = 576 - 20 - 8 - 20 - 8;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Decoy {
+    #[default]
+    Silence,
+    /// what a closed port answers with, needs a raw socket
+    Unreachable,
+    /// the contents of a file
+    Reply(Vec<u8>),
+}
+
+impl FromStr for Decoy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "silence" => Ok(Decoy::Silence),
+            "unreachable" => Ok(Decoy::Unreachable),
+            _ => match s.strip_prefix("reply=") {
+                Some(path) => fs::read(path)
+                    .map(Decoy::Reply)
+                    .map_err(|e| format!("reading --decoy reply {} failed: {}", path, e)),
+                None => Err(format!("unknown decoy: {}", s)),
+            },
+        }
+    }
+}
+
+/// raw sockets for sending ICMP errors in each family we might need them for
+pub struct Icmp {
+    v4: RawIcmp,
+    v6: Option<RawIcmp>,
+}
+
+impl Icmp {
+    pub fn open(v6: bool) -> Result<Icmp> {
+        Ok(Icmp {
+            v4: RawIcmp::open(false)?,
+            v6: v6.then(|| RawIcmp::open(true)).transpose()?,
+        })
+    }
+
+    /// sends what port_unreachable made to to
+    pub fn send(&self, packet: &[u8], to: IpAddr) -> Result<usize> {
+        match (unmapped(to), &self.v6) {
+            (IpAddr::V6(_), None) => Ok(0),
+            (to @ IpAddr::V6(_), Some(v6)) => v6.send(packet, to),
+            (to, _) => self.v4.send(packet, to),
+        }
+    }
+}
+
+/// counts decoys per second so there are at most MAX_PER_SEC
+#[derive(Debug, Default)]
+pub struct Limiter {
+    second: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Limiter {
+    /// whether one more decoy may go out in second
+    pub fn allow(&self, second: u64) -> bool {
+        if self.second.swap(second, Relaxed) != second {
+            self.count.store(0, Relaxed);
+        }
+        self.count.fetch_add(1, Relaxed) < MAX_PER_SEC
+    }
+}
+
+/// an ICMP port unreachable for the datagram with payload from client to ours, v4-mapped addresses
+/// as ICMPv4, None if they aren't the same family
+///
+/// the IPv4 checksum is ours to fill in, the kernel does the ICMPv6 one
+pub fn port_unreachable(payload: &[u8], client: SocketAddr, ours: SocketAddr) -> Option<Vec<u8>> {
+    let quoted = &payload[..payload.len().min(MAX_QUOTE_LEN)];
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(8 + quoted.len());
+    udp.extend_from_slice(&client.port().to_be_bytes());
+    udp.extend_from_slice(&ours.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    // no checksum, allowed for UDP over IPv4 and nobody checks a quoted one
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(quoted);
+
+    match (unmapped(client.ip()), unmapped(ours.ip())) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            // id, don't fragment, ttl, udp, and the checksum that comes later
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            // destination unreachable, port unreachable
+            let mut icmp = vec![3, 3, 0, 0, 0, 0, 0, 0];
+            icmp.extend_from_slice(&ip);
+            icmp.extend_from_slice(&udp);
+            let sum = checksum(&icmp);
+            icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+            Some(icmp)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            // destination unreachable, port unreachable
+            let mut icmp = vec![1, 4, 0, 0, 0, 0, 0, 0];
+            icmp.extend_from_slice(&[0x60, 0, 0, 0]);
+            icmp.extend_from_slice(&udp_len.to_be_bytes());
+            // udp, hop limit
+            icmp.extend_from_slice(&[17, 64]);
+            icmp.extend_from_slice(&src.octets());
+            icmp.extend_from_slice(&dst.octets());
+            icmp.extend_from_slice(&udp);
+            Some(icmp)
+        }
+        _ => None,
+    }
+}
+
+/// the ipv4 address behind a v4-mapped one
+pub fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// the internet checksum, RFC 1071
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// reads --decoy, unlike args::parse with the reason it's invalid
+pub fn parse(decoy: &str) -> error::Result<Decoy> {
+    decoy.parse().map_err(args::invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_unreachable() {
+        let client = "192.0.2.10:1000".parse().unwrap();
+        let ours = "[::ffff:192.0.2.1]:51820".parse().unwrap();
+        let icmp = port_unreachable(b"probe", client, ours).unwrap();
+        assert_eq!(icmp.len(), 8 + 20 + 8 + 5);
+        assert_eq!(icmp[..2], [3, 3]);
+        // a correct checksum sums to 0
+        assert_eq!(checksum(&icmp), 0);
+        assert_eq!(checksum(&icmp[8..28]), 0);
+        assert_eq!(icmp[20..24], [192, 0, 2, 10]);
+        assert_eq!(icmp[28..36], [0x03, 0xe8, 0xca, 0x6c, 0, 13, 0, 0]);
+
+        let client = "[2001:db8::10]:1000".parse().unwrap();
+        let ours = "[2001:db8::1]:51820".parse().unwrap();
+        let icmp = port_unreachable(&[0; 1000], client, ours).unwrap();
+        assert_eq!(icmp.len(), 8 + 40 + 8 + MAX_QUOTE_LEN);
+        assert_eq!(icmp[..2], [1, 4]);
+        assert_eq!(icmp[12..14], 1008u16.to_be_bytes());
+        assert!(port_unreachable(b"", client, "192.0.2.1:1".parse().unwrap()).is_none());
+
+        let limiter = Limiter::default();
+        assert!((0..MAX_PER_SEC).all(|_| limiter.allow(1)));
+        assert!(!limiter.allow(1));
+        assert!(limiter.allow(2));
+    }
+}
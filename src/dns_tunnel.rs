@@ -0,0 +1,892 @@
+// --dns-target and --dns-clients, an experimental last resort for networks that let nothing out but
+// DNS: packets to the target ride in the names of TXT queries for a domain the far end is the
+// authoritative server of, and packets back in the answers
+//
+// a name is a header label, the packet in base32 labels and the domain:
+//
+//     <session, seq, packet, index, count>.<data>...<data>.tunnel.example.com
+//
+// packets too big for one name are split and put back together at the far end, answers carry
+// FRAME_LEN pieces of what the target sent, as many as they have room for
+//
+// only the near end can start an exchange, so it polls: right away while the far end says more is
+// waiting, backing off to POLL_MAX while nothing comes, with at most window queries unanswered,
+// one more for each that is answered and half as many once one times out
+//
+// every byte counts at around 140 of them per query, so the three zero bytes after the message
+// type are left out, and the counter of a data packet, which counts up from 0, is a varint
+
+use crate::{packet::bytes, poison::Recover, WgPacket};
+
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io::Result,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const POLL_MIN: Duration = Duration::from_millis(10);
+const POLL_MAX: Duration = Duration::from_secs(1);
+
+// a query unanswered for this long is lost
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const WINDOW_MAX: usize = 32;
+
+// a session nothing went through for this long is forgotten
+const IDLE_TIME: Duration = Duration::from_secs(60);
+
+// the rest of a split packet must arrive within this
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// pieces of packets from the target in answers, an answer of the classic 512 bytes to a question
+// of the longest name has room for one
+const FRAME_LEN: usize = 180;
+
+// pieces waiting to be sent either way, beyond that packets are dropped
+const QUEUE_LEN: usize = 256;
+
+// the biggest answer we ask for and give, what EDNS suggests to stay below fragmentation
+const ANSWER_MAX: usize = 1232;
+
+const MAX_NAME: usize = 253;
+
+// session, seq, packet, index and count, 16 base32 digits
+const HEADER_LEN: usize = 10;
+const HEADER_DIGITS: usize = 16;
+
+const TXT: u16 = 16;
+const OPT: u16 = 41;
+const IN: u16 = 1;
+
+/// a piece of a packet
+#[derive(Clone, Debug, PartialEq)]
+struct Frame {
+    packet: u16,
+    index: u8,
+    count: u8,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    session: u32,
+    seq: u16,
+    /// count is 0 for queries that only poll
+    frame: Frame,
+}
+
+/// whether domain can carry the tunnel, what's wrong with it if not
+pub fn check(domain: &str) -> std::result::Result<(), String> {
+    let valid = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+    };
+    if !domain.split('.').all(valid) {
+        return Err(format!("invalid domain: {}", domain));
+    }
+    if capacity(domain) < 32 {
+        return Err(format!("{} is too long to leave room in names", domain));
+    }
+    Ok(())
+}
+
+/// bytes of a packet one query for a name under domain carries
+fn capacity(domain: &str) -> usize {
+    let room = MAX_NAME.saturating_sub(domain.len() + HEADER_DIGITS + 2);
+    // labels of 63 digits and a dot
+    let digits = room / 64 * 63 + (room % 64).saturating_sub(1);
+    digits * 5 / 8
+}
+
+fn split(packet: u16, data: &[u8], len: usize) -> Option<Vec<Frame>> {
+    let count = u8::try_from(data.len().div_ceil(len)).ok()?;
+    let frames = data.chunks(len).enumerate().map(|(index, data)| Frame {
+        packet,
+        index: index as u8,
+        count,
+        data: data.to_vec(),
+    });
+    Some(frames.collect())
+}
+
+// when the first piece came, and the pieces so far
+type Partial = (Instant, Vec<Option<Vec<u8>>>);
+
+/// puts split packets back together
+#[derive(Default)]
+struct Reassembly {
+    partial: HashMap<u16, Partial>,
+}
+
+impl Reassembly {
+    /// the packet frame completes, if it does
+    fn add(&mut self, frame: Frame, now: Instant) -> Option<Vec<u8>> {
+        self.partial
+            .retain(|_, (started, _)| now.duration_since(*started) < REASSEMBLY_TIMEOUT);
+        if frame.count == 1 {
+            return Some(frame.data);
+        }
+        if frame.index >= frame.count {
+            return None;
+        }
+        let (_, pieces) = self
+            .partial
+            .entry(frame.packet)
+            .or_insert_with(|| (now, vec![None; frame.count as usize]));
+        // a packet id came round again
+        if pieces.len() != frame.count as usize {
+            return None;
+        }
+        pieces[frame.index as usize] = Some(frame.data);
+        if !pieces.iter().all(Option::is_some) {
+            return None;
+        }
+        let (_, pieces) = self.partial.remove(&frame.packet)?;
+        Some(pieces.into_iter().flatten().flatten().collect())
+    }
+}
+
+fn compress(packet: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(packet.len());
+    match packet {
+        [4, 0, 0, 0, rest @ ..] if rest.len() >= 12 => {
+            out.push(4);
+            out.extend_from_slice(&rest[..4]);
+            let mut counter = u64::from_le_bytes(bytes(rest, 4));
+            while counter >= 0x80 {
+                out.push(counter as u8 | 0x80);
+                counter >>= 7;
+            }
+            out.push(counter as u8);
+            out.extend_from_slice(&rest[12..]);
+        }
+        [message_type @ 1..=3, 0, 0, 0, rest @ ..] => {
+            out.push(*message_type);
+            out.extend_from_slice(rest);
+        }
+        // as it is
+        _ => {
+            out.push(0);
+            out.extend_from_slice(packet);
+        }
+    }
+    out
+}
+
+fn expand(data: &[u8]) -> Option<Vec<u8>> {
+    let (&message_type, rest) = data.split_first()?;
+    let mut out = Vec::with_capacity(data.len() + 10);
+    match message_type {
+        0 => out.extend_from_slice(rest),
+        1..=3 => {
+            out.extend_from_slice(&[message_type, 0, 0, 0]);
+            out.extend_from_slice(rest);
+        }
+        4 => {
+            out.extend_from_slice(&[4, 0, 0, 0]);
+            out.extend_from_slice(rest.get(..4)?);
+            let mut counter = 0u64;
+            let mut at = 4;
+            for shift in (0..64).step_by(7) {
+                let byte = *rest.get(at)?;
+                at += 1;
+                counter |= ((byte & 0x7f) as u64) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            out.extend_from_slice(&counter.to_le_bytes());
+            out.extend_from_slice(&rest[at..]);
+        }
+        _ => return None,
+    }
+    Some(out)
+}
+
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 8 / 5 + 1);
+    let (mut bits, mut count) = (0u32, 0);
+    for &byte in data {
+        bits = bits << 8 | byte as u32;
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            out.push(ALPHABET[(bits >> count) as usize & 31] as char);
+        }
+    }
+    if count > 0 {
+        out.push(ALPHABET[(bits << (5 - count)) as usize & 31] as char);
+    }
+    out
+}
+
+/// resolvers may mix the case of names up to make them harder to spoof, so either case is fine
+fn unbase32(digits: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(digits.len() * 5 / 8);
+    let (mut bits, mut count) = (0u32, 0);
+    for digit in digits.bytes() {
+        let value = match digit.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = bits << 5 | value as u32;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn name(header: &Header, domain: &str) -> String {
+    let frame = &header.frame;
+    let mut raw = Vec::with_capacity(HEADER_LEN);
+    raw.extend_from_slice(&header.session.to_be_bytes());
+    raw.extend_from_slice(&header.seq.to_be_bytes());
+    raw.extend_from_slice(&frame.packet.to_be_bytes());
+    raw.extend_from_slice(&[frame.index, frame.count]);
+    let mut name = base32(&raw);
+    let data = base32(&frame.data);
+    for label in data.as_bytes().chunks(63) {
+        name.push('.');
+        // base32 digits are ascii
+        name.push_str(std::str::from_utf8(label).unwrap_or_default());
+    }
+    name.push('.');
+    name.push_str(domain);
+    name
+}
+
+/// what a name under domain carries, None for names that aren't ours
+fn parse_name(name: &str, domain: &str) -> Option<Header> {
+    let rest = name.len().checked_sub(domain.len() + 1)?;
+    if !name.is_char_boundary(rest)
+        || !name[rest + 1..].eq_ignore_ascii_case(domain)
+        || name.as_bytes()[rest] != b'.'
+    {
+        return None;
+    }
+    let mut labels = name[..rest].split('.');
+    let raw = unbase32(labels.next()?)?;
+    if raw.len() != HEADER_LEN {
+        return None;
+    }
+    let data = unbase32(&labels.collect::<String>())?;
+    Some(Header {
+        session: u32::from_be_bytes(bytes(&raw, 0)),
+        seq: u16::from_be_bytes(bytes(&raw, 4)),
+        frame: Frame {
+            packet: u16::from_be_bytes(bytes(&raw, 6)),
+            index: raw[8],
+            count: raw[9],
+            data,
+        },
+    })
+}
+
+fn put_name(msg: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+}
+
+/// the name at at and where it ends, questions have no compression pointers
+fn get_name(msg: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    loop {
+        let len = *msg.get(at)? as usize;
+        at += 1;
+        if len == 0 {
+            return Some((name, at));
+        }
+        let label = std::str::from_utf8(msg.get(at..at + len)?).ok()?;
+        if len > 63 || name.len() + len >= 255 {
+            return None;
+        }
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(label);
+        at += len;
+    }
+}
+
+/// where the name at at ends, a compression pointer ends it too
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(at + 2),
+            _ => at += 1 + len,
+        }
+    }
+}
+
+fn u16_at(msg: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(at)?, *msg.get(at + 1)?]))
+}
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(MAX_NAME + 30);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, a question and the OPT record
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    put_name(&mut msg, name);
+    msg.extend_from_slice(&TXT.to_be_bytes());
+    msg.extend_from_slice(&IN.to_be_bytes());
+    // the root name, and the answer size we take where the class would be
+    msg.push(0);
+    msg.extend_from_slice(&OPT.to_be_bytes());
+    msg.extend_from_slice(&(ANSWER_MAX as u16).to_be_bytes());
+    msg.extend_from_slice(&[0; 6]);
+    msg
+}
+
+struct Question {
+    name: String,
+    /// where the question ends
+    end: usize,
+    /// how big an answer may be
+    size: usize,
+}
+
+fn question(msg: &[u8]) -> Option<Question> {
+    if u16_at(msg, 4)? != 1 {
+        return None;
+    }
+    let (name, end) = get_name(msg, 12)?;
+    let end = end + 4;
+    if msg.len() < end {
+        return None;
+    }
+    // queries have nothing but the question and maybe an OPT record
+    let size = match u16_at(msg, 10)? {
+        0 => 512,
+        _ if msg.get(end) == Some(&0) && u16_at(msg, end + 1) == Some(OPT) => {
+            u16_at(msg, end + 3)? as usize
+        }
+        _ => 512,
+    };
+    Some(Question {
+        name,
+        end,
+        size: size.clamp(512, ANSWER_MAX),
+    })
+}
+
+fn answer(query: &[u8], question: &Question, payload: &[u8]) -> Vec<u8> {
+    let strings: Vec<&[u8]> = match payload {
+        [] => vec![&[]],
+        payload => payload.chunks(255).collect(),
+    };
+    let len = payload.len() + strings.len();
+    let mut msg = Vec::with_capacity(question.end + 12 + len);
+    msg.extend_from_slice(&query[..2]);
+    // an authoritative answer, recursion desired as the query had it
+    msg.extend_from_slice(&[0x84 | query[2] & 0x01, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]);
+    msg.extend_from_slice(&query[12..question.end]);
+    // the name in the question
+    msg.extend_from_slice(&[0xc0, 12]);
+    msg.extend_from_slice(&TXT.to_be_bytes());
+    msg.extend_from_slice(&IN.to_be_bytes());
+    // never cached
+    msg.extend_from_slice(&[0; 4]);
+    msg.extend_from_slice(&(len as u16).to_be_bytes());
+    for string in strings {
+        msg.push(string.len() as u8);
+        msg.extend_from_slice(string);
+    }
+    msg
+}
+
+/// the name an answer is for and what its TXT records carry, nothing if it's an error
+fn parse_answer(msg: &[u8]) -> Option<(String, Vec<u8>)> {
+    if msg.get(2)? & 0x80 == 0 {
+        return None;
+    }
+    let question = question(msg)?;
+    let mut payload = Vec::new();
+    if msg[3] & 0x0f != 0 {
+        return Some((question.name, payload));
+    }
+    let mut at = question.end;
+    for _ in 0..u16_at(msg, 6)? {
+        at = skip_name(msg, at)?;
+        let rr_type = u16_at(msg, at)?;
+        let len = u16_at(msg, at + 8)? as usize;
+        at += 10;
+        let mut rdata = msg.get(at..at + len)?;
+        at += len;
+        if rr_type != TXT {
+            continue;
+        }
+        while let Some((&len, rest)) = rdata.split_first() {
+            payload.extend_from_slice(rest.get(..len as usize)?);
+            rdata = &rest[len as usize..];
+        }
+    }
+    Some((question.name, payload))
+}
+
+/// what's still waiting at the far end, and the frames in payload
+fn parse_payload(payload: &[u8]) -> Option<(u8, Vec<Frame>)> {
+    let (&pending, mut rest) = payload.split_first()?;
+    let mut frames = Vec::new();
+    while !rest.is_empty() {
+        let len = u16_at(rest, 4)? as usize;
+        frames.push(Frame {
+            packet: u16_at(rest, 0)?,
+            index: *rest.get(2)?,
+            count: *rest.get(3)?,
+            data: rest.get(6..6 + len)?.to_vec(),
+        });
+        rest = &rest[6 + len..];
+    }
+    Some((pending, frames))
+}
+
+fn random() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+struct NearSession {
+    client: SocketAddr,
+    /// pieces of packets for the target waiting for room in the window
+    upload: VecDeque<Frame>,
+    next_packet: u16,
+    reassembly: Reassembly,
+    poll_interval: Duration,
+    next_poll: Instant,
+    active: Instant,
+}
+
+struct NearState {
+    sessions: HashMap<u32, NearSession>,
+    by_client: HashMap<SocketAddr, u32>,
+    /// the session and when of each query not answered yet, by seq, which is its DNS id too
+    unanswered: HashMap<u16, (u32, Instant)>,
+    window: usize,
+    seq: u16,
+}
+
+/// the end wireguard clients talk to, tunneling them in queries to resolver
+pub struct Near {
+    local: UdpSocket,
+    upstream: UdpSocket,
+    resolver: SocketAddr,
+    domain: String,
+    capacity: usize,
+    verbose: bool,
+    state: Mutex<NearState>,
+}
+
+impl Near {
+    pub fn new(
+        local: UdpSocket,
+        resolver: SocketAddr,
+        domain: &str,
+        verbose: bool,
+    ) -> Result<Near> {
+        let upstream = UdpSocket::bind(if resolver.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        })?;
+        Ok(Near {
+            local,
+            upstream,
+            resolver,
+            domain: domain.to_ascii_lowercase(),
+            capacity: capacity(domain),
+            verbose,
+            state: Mutex::new(NearState {
+                sessions: HashMap::new(),
+                by_client: HashMap::new(),
+                unanswered: HashMap::new(),
+                window: 4,
+                seq: random() as u16,
+            }),
+        })
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        thread::spawn(move || self.answers());
+        thread::spawn(move || loop {
+            thread::sleep(POLL_MIN);
+            self.tick(Instant::now());
+        });
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, client) = self.local.recv_from(&mut buf)?;
+            let packet = &buf[..recv];
+            if WgPacket::parse(packet).is_none() {
+                continue;
+            }
+            let now = Instant::now();
+            let mut state = self.state.lock().recover();
+            let state = &mut *state;
+            let id = match state.by_client.get(&client) {
+                Some(id) => *id,
+                None => {
+                    let id = random();
+                    if self.verbose {
+                        eprintln!("dns tunnel session {:08x} for {}", id, client);
+                    }
+                    state.by_client.insert(client, id);
+                    state.sessions.insert(
+                        id,
+                        NearSession {
+                            client,
+                            upload: VecDeque::new(),
+                            next_packet: 0,
+                            reassembly: Reassembly::default(),
+                            poll_interval: POLL_MIN,
+                            next_poll: now,
+                            active: now,
+                        },
+                    );
+                    id
+                }
+            };
+            if let Some(session) = state.sessions.get_mut(&id) {
+                session.active = now;
+                session.poll_interval = POLL_MIN;
+                let frames = split(session.next_packet, &compress(packet), self.capacity);
+                session.next_packet = session.next_packet.wrapping_add(1);
+                if let Some(frames) = frames {
+                    if session.upload.len() + frames.len() <= QUEUE_LEN {
+                        session.upload.extend(frames);
+                    }
+                }
+            }
+            self.pump(state, now);
+        }
+    }
+
+    /// sends queries, with what's waiting to go to the target or to poll, while the window has room
+    fn pump(&self, state: &mut NearState, now: Instant) {
+        while state.unanswered.len() < state.window {
+            let next = state
+                .sessions
+                .iter()
+                .find(|(_, session)| !session.upload.is_empty())
+                .or_else(|| {
+                    state
+                        .sessions
+                        .iter()
+                        .find(|(_, session)| session.next_poll <= now)
+                })
+                .map(|(id, _)| *id);
+            let Some((id, session)) = next.and_then(|id| Some((id, state.sessions.get_mut(&id)?)))
+            else {
+                return;
+            };
+            state.seq = state.seq.wrapping_add(1);
+            let frame = session.upload.pop_front().unwrap_or(Frame {
+                packet: 0,
+                index: 0,
+                count: 0,
+                data: Vec::new(),
+            });
+            session.next_poll = now + session.poll_interval;
+            let header = Header {
+                session: id,
+                seq: state.seq,
+                frame,
+            };
+            state.unanswered.insert(state.seq, (id, now));
+            // a lost query is a lost packet, wireguard copes
+            let _ = self.upstream.send_to(
+                &query(state.seq, &name(&header, &self.domain)),
+                self.resolver,
+            );
+        }
+    }
+
+    /// times out queries and forgets idle sessions
+    fn tick(&self, now: Instant) {
+        let mut state = self.state.lock().recover();
+        let before = state.unanswered.len();
+        state
+            .unanswered
+            .retain(|_, (_, sent)| now.duration_since(*sent) < QUERY_TIMEOUT);
+        if state.unanswered.len() < before {
+            state.window = (state.window / 2).max(1);
+        }
+        let NearState {
+            sessions,
+            by_client,
+            ..
+        } = &mut *state;
+        sessions.retain(|_, session| {
+            let active = now.duration_since(session.active) < IDLE_TIME;
+            if !active {
+                by_client.remove(&session.client);
+            }
+            active
+        });
+        self.pump(&mut state, now);
+    }
+
+    /// passes what answers carry to the clients
+    fn answers(&self) {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, from) = match self.upstream.recv_from(&mut buf) {
+                Ok(received) => received,
+                // ICMP for an earlier query, say
+                Err(_) => continue,
+            };
+            if from != self.resolver {
+                continue;
+            }
+            let Some((name, payload)) = parse_answer(&buf[..recv]) else {
+                continue;
+            };
+            let Some(header) = parse_name(&name, &self.domain) else {
+                continue;
+            };
+            let now = Instant::now();
+            let mut state = self.state.lock().recover();
+            if state.unanswered.remove(&header.seq).is_none() {
+                continue;
+            }
+            state.window = (state.window + 1).min(WINDOW_MAX);
+            let Some(session) = state.sessions.get_mut(&header.session) else {
+                continue;
+            };
+            let (pending, frames) = parse_payload(&payload).unwrap_or_default();
+            if frames.is_empty() && pending == 0 {
+                session.poll_interval = (session.poll_interval * 2).min(POLL_MAX);
+            } else {
+                session.poll_interval = POLL_MIN;
+                session.active = now;
+            }
+            if pending > 0 {
+                session.next_poll = now;
+            }
+            for frame in frames {
+                let packet = session.reassembly.add(frame, now).and_then(|p| expand(&p));
+                if let Some(packet) = packet {
+                    let _ = self.local.send_to(&packet, session.client);
+                }
+            }
+            self.pump(&mut state, now);
+        }
+    }
+}
+
+struct FarSession {
+    socket: Arc<UdpSocket>,
+    /// pieces of packets from the target waiting for a query to answer with
+    download: VecDeque<Frame>,
+    next_packet: u16,
+    reassembly: Reassembly,
+    active: Instant,
+}
+
+/// the authoritative server for domain, passing what queries carry to target and its packets back
+pub struct Far {
+    socket: UdpSocket,
+    target: SocketAddr,
+    domain: String,
+    verbose: bool,
+    sessions: Mutex<HashMap<u32, FarSession>>,
+}
+
+impl Far {
+    pub fn new(socket: UdpSocket, target: SocketAddr, domain: &str, verbose: bool) -> Far {
+        Far {
+            socket,
+            target,
+            domain: domain.to_ascii_lowercase(),
+            verbose,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, from) = self.socket.recv_from(&mut buf)?;
+            let msg = &buf[..recv];
+            // queries only
+            if msg.len() < 12 || msg[2] & 0x80 != 0 {
+                continue;
+            }
+            let Some(question) = question(msg) else {
+                continue;
+            };
+            let Some(header) = parse_name(&question.name, &self.domain) else {
+                continue;
+            };
+            let payload = self.exchange(header, &question)?;
+            let _ = self.socket.send_to(&answer(msg, &question, &payload), from);
+        }
+    }
+
+    /// passes on what header carries, and returns what to answer with
+    fn exchange(&'static self, header: Header, question: &Question) -> Result<Vec<u8>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().recover();
+        if !sessions.contains_key(&header.session) {
+            let socket = UdpSocket::bind(if self.target.is_ipv4() {
+                SocketAddr::from(([0, 0, 0, 0], 0))
+            } else {
+                SocketAddr::from(([0u16; 8], 0))
+            })?;
+            socket.connect(self.target)?;
+            // often enough to notice the session went idle
+            socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+            let socket = Arc::new(socket);
+            let session = header.session;
+            let from_target = Arc::clone(&socket);
+            thread::spawn(move || self.download(session, &from_target));
+            if self.verbose {
+                eprintln!("dns tunnel session {:08x}", session);
+            }
+            sessions.insert(
+                session,
+                FarSession {
+                    socket,
+                    download: VecDeque::new(),
+                    next_packet: 0,
+                    reassembly: Reassembly::default(),
+                    active: now,
+                },
+            );
+        }
+        let Some(session) = sessions.get_mut(&header.session) else {
+            return Ok(Vec::new());
+        };
+        session.active = now;
+        if header.frame.count > 0 {
+            if let Some(packet) = session.reassembly.add(header.frame, now) {
+                if let Some(packet) = expand(&packet) {
+                    // the target being down is its business
+                    let _ = session.socket.send(&packet);
+                }
+            }
+        }
+        // what fits an answer, its TXT strings take a byte each per 255
+        let room = question.size.saturating_sub(question.end + 12);
+        let room = room.saturating_sub(room / 256 + 1);
+        let mut payload = vec![0];
+        while let Some(frame) = session.download.front() {
+            if payload.len() + 6 + frame.data.len() > room {
+                break;
+            }
+            payload.extend_from_slice(&frame.packet.to_be_bytes());
+            payload.extend_from_slice(&[frame.index, frame.count]);
+            payload.extend_from_slice(&(frame.data.len() as u16).to_be_bytes());
+            payload.extend_from_slice(&frame.data);
+            session.download.pop_front();
+        }
+        payload[0] = session.download.len().min(255) as u8;
+        Ok(payload)
+    }
+
+    /// queues what the target sends on socket for session's queries, until it's idle
+    fn download(&self, session: u32, socket: &UdpSocket) {
+        let mut buf = [0u8; 65536];
+        loop {
+            let received = socket.recv(&mut buf);
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().recover();
+            let Some(far) = sessions.get_mut(&session) else {
+                return;
+            };
+            match received {
+                Ok(recv) => {
+                    let frames = split(far.next_packet, &compress(&buf[..recv]), FRAME_LEN);
+                    far.next_packet = far.next_packet.wrapping_add(1);
+                    if let Some(frames) = frames {
+                        if far.download.len() + frames.len() <= QUEUE_LEN {
+                            far.download.extend(frames);
+                        }
+                    }
+                }
+                Err(_) if now.duration_since(far.active) >= IDLE_TIME => {
+                    sessions.remove(&session);
+                    return;
+                }
+                // timed out, or the target refusing an earlier packet
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel() {
+        let data = [4, 0, 0, 0, 1, 2, 3, 4, 0x81, 1, 0, 0, 0, 0, 0, 0, 9, 9];
+        let compressed = compress(&data);
+        assert_eq!(compressed, [4, 1, 2, 3, 4, 0x81, 0x03, 9, 9]);
+        assert_eq!(expand(&compressed).unwrap(), data);
+        for packet in [&[1, 0, 0, 0, 5][..], &[4, 1, 2][..], &[]] {
+            assert_eq!(expand(&compress(packet)).unwrap(), packet);
+        }
+        assert_eq!(unbase32(&base32(b"hello")).unwrap(), b"hello");
+        assert_eq!(
+            unbase32(&base32(b"hello").to_uppercase()).unwrap(),
+            b"hello"
+        );
+
+        let domain = "t.example.com";
+        assert!(check(domain).is_ok());
+        assert!(check("bad_label.example.com").is_err());
+        let packet: Vec<u8> = (0..=255).collect();
+        let frames = split(7, &packet, capacity(domain)).unwrap();
+        assert_eq!(frames.len(), 2);
+        let mut reassembly = Reassembly::default();
+        let now = Instant::now();
+        let mut whole = None;
+        for (seq, frame) in frames.into_iter().enumerate().rev() {
+            let header = Header {
+                session: 0xdeadbeef,
+                seq: seq as u16,
+                frame,
+            };
+            let name = name(&header, domain);
+            assert!(name.len() <= MAX_NAME);
+            // the way a resolver might ask for it
+            let parsed = parse_name(&name.to_uppercase(), domain).unwrap();
+            assert_eq!(parsed, header);
+            whole = reassembly.add(parsed.frame, now);
+        }
+        assert_eq!(whole.unwrap(), packet);
+
+        // an answer carries frames back
+        let msg = query(5, &format!("abc.{}", domain));
+        let question = question(&msg).unwrap();
+        assert_eq!(question.size, ANSWER_MAX);
+        let frame = Frame {
+            packet: 1,
+            index: 0,
+            count: 1,
+            data: vec![1; 300],
+        };
+        let mut payload = vec![2, 0, 1, 0, 1, 1, 44];
+        payload.extend_from_slice(&frame.data);
+        let (name, carried) = parse_answer(&answer(&msg, &question, &payload)).unwrap();
+        assert_eq!(name, format!("abc.{}", domain));
+        assert_eq!(parse_payload(&carried).unwrap(), (2, vec![frame]));
+    }
+}
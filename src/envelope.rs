@@ -0,0 +1,254 @@
+// envelope prepended to packets forwarded between cooperating proxy instances, carrying the address
+// of the original client so later hops know who they are really talking to
+//
+// 1 byte  ENVELOPE_TYPE, never a valid wireguard message type
+// 1 byte  address family, 4 or 6
+// 2 bytes client port, big endian
+// 4 or 16 bytes client ip
+// ... the original wireguard packet
+//
+// with --compact-envelope a client's first packet, its handshakes and a packet every REFRESH get
+// ID_FLAG in the address family and 2 bytes of an id for the client after the ip, the rest carry
+// only that, 4 bytes instead of 8 or 20:
+//
+// 1 byte  ENVELOPE_TYPE
+// 1 byte  0
+// 2 bytes client id, big endian
+// ... the original wireguard packet
+//
+// the next hop drops packets with an id it doesn't know, which after it restarted is until the
+// client's next handshake or REFRESH at most
+
+use crate::{
+    clock::{Tick, TICKS_PER_SEC},
+    packet,
+    poison::Recover,
+};
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+};
+
+pub const ENVELOPE_TYPE: u8 = 0xfe;
+
+/// the longest envelope header, keep this much room in front of packets that might get wrapped
+pub const MAX_HEADER_LEN: usize = 22;
+
+const ID_FLAG: u8 = 0x80;
+
+const SHORT: u8 = 0;
+
+const SHORT_LEN: usize = 4;
+
+const REFRESH: Tick = 10 * TICKS_PER_SEC;
+
+// ids remembered for all previous hops together, past that they start over
+const MAX_KNOWN: usize = 1 << 16;
+
+/// writes the envelope for client right before the packet that starts at buf[start..] and returns
+/// where the wrapped packet now starts, start must be >= MAX_HEADER_LEN
+pub fn wrap(buf: &mut [u8], start: usize, client: SocketAddr) -> usize {
+    write(buf, start, client, None)
+}
+
+fn write(buf: &mut [u8], start: usize, client: SocketAddr, id: Option<u16>) -> usize {
+    let id_len = if id.is_some() { 2 } else { 0 };
+    let header_len = id_len
+        + match client {
+            SocketAddr::V4(_) => 8,
+            SocketAddr::V6(_) => 20,
+        };
+    let header = &mut buf[start - header_len..start];
+    header[0] = ENVELOPE_TYPE;
+    header[2..4].copy_from_slice(&client.port().to_be_bytes());
+    let ip = 4..header_len - id_len;
+    match client.ip() {
+        IpAddr::V4(v4) => {
+            header[1] = 4;
+            header[ip].copy_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            header[1] = 6;
+            header[ip].copy_from_slice(&v6.octets());
+        }
+    }
+    if let Some(id) = id {
+        header[1] |= ID_FLAG;
+        header[header_len - 2..].copy_from_slice(&id.to_be_bytes());
+    }
+    start - header_len
+}
+
+/// the client address, the id it came with and the wireguard packet, only one of the first two
+/// for a short envelope
+fn parse(buf: &[u8]) -> Option<(Option<SocketAddr>, Option<u16>, &[u8])> {
+    if buf.len() < 4 || buf[0] != ENVELOPE_TYPE {
+        return None;
+    }
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    if buf[1] == SHORT {
+        return Some((None, Some(port), &buf[SHORT_LEN..]));
+    }
+    let id_len = if buf[1] & ID_FLAG != 0 { 2 } else { 0 };
+    let (ip, header_len) = match buf[1] & !ID_FLAG {
+        4 if buf.len() >= 8 + id_len => (
+            IpAddr::V4(Ipv4Addr::from(packet::bytes::<4>(buf, 4))),
+            8 + id_len,
+        ),
+        6 if buf.len() >= 20 + id_len => (
+            IpAddr::V6(Ipv6Addr::from(packet::bytes::<16>(buf, 4))),
+            20 + id_len,
+        ),
+        _ => return None,
+    };
+    let id = (id_len != 0).then(|| u16::from_be_bytes(packet::bytes(buf, header_len - 2)));
+    Some((Some(SocketAddr::new(ip, port)), id, &buf[header_len..]))
+}
+
+/// --compact-envelope's ids for the clients we envelope packets for, and when the next hop last
+/// heard which is which
+#[derive(Default)]
+pub struct Compact {
+    ids: Mutex<Ids>,
+}
+
+#[derive(Default)]
+struct Ids {
+    by_client: HashMap<SocketAddr, (u16, Tick)>,
+    clients: HashMap<u16, SocketAddr>,
+    next: u16,
+}
+
+impl Compact {
+    /// like wrap, but with only client's id unless full, client is new or REFRESH passed
+    pub fn wrap(
+        &self,
+        buf: &mut [u8],
+        start: usize,
+        client: SocketAddr,
+        full: bool,
+        now: Tick,
+    ) -> usize {
+        let mut ids = self.ids.lock().recover();
+        let ids = &mut *ids;
+        let (id, full) = match ids.by_client.get_mut(&client) {
+            Some((id, sent)) if !full && now < *sent + REFRESH => (*id, false),
+            Some((id, sent)) => {
+                *sent = now;
+                (*id, true)
+            }
+            None => {
+                // the client that had the id before gets a new one with its next packet
+                let id = ids.next;
+                ids.next = ids.next.wrapping_add(1);
+                if let Some(before) = ids.clients.insert(id, client) {
+                    ids.by_client.remove(&before);
+                }
+                ids.by_client.insert(client, (id, now));
+                (id, true)
+            }
+        };
+        if full {
+            return write(buf, start, client, Some(id));
+        }
+        let header = &mut buf[start - SHORT_LEN..start];
+        header[..2].copy_from_slice(&[ENVELOPE_TYPE, SHORT]);
+        header[2..].copy_from_slice(&id.to_be_bytes());
+        start - SHORT_LEN
+    }
+}
+
+/// the ids previous --compact-envelope hops gave their clients
+#[derive(Default)]
+pub struct Known {
+    clients: Mutex<HashMap<(SocketAddr, u16), SocketAddr>>,
+}
+
+impl Known {
+    /// splits a packet hop enveloped into the original client address and the wireguard packet,
+    /// remembering the ids full envelopes give and looking up those of short ones
+    pub fn unwrap<'a>(&self, hop: SocketAddr, buf: &'a [u8]) -> Option<(SocketAddr, &'a [u8])> {
+        let (client, id, packet) = parse(buf)?;
+        let client = match (client, id) {
+            (Some(client), None) => client,
+            (Some(client), Some(id)) => {
+                let mut clients = self.clients.lock().recover();
+                if clients.len() >= MAX_KNOWN && !clients.contains_key(&(hop, id)) {
+                    clients.clear();
+                }
+                clients.insert((hop, id), client);
+                client
+            }
+            (None, Some(id)) => *self.clients.lock().recover().get(&(hop, id))?,
+            (None, None) => return None,
+        };
+        Some((client, packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let mut buf = [0u8; MAX_HEADER_LEN + 10];
+        buf[MAX_HEADER_LEN] = 4;
+        let start = wrap(&mut buf, MAX_HEADER_LEN, "192.0.2.7:51820".parse().unwrap());
+        assert_eq!(
+            buf[start..start + 9],
+            [ENVELOPE_TYPE, 4, 0xca, 0x6c, 192, 0, 2, 7, 4]
+        );
+        let start = wrap(
+            &mut buf,
+            MAX_HEADER_LEN,
+            "[2001:db8::7]:51820".parse().unwrap(),
+        );
+        assert_eq!(start, MAX_HEADER_LEN - 20);
+        assert_eq!(
+            buf[start..start + 6],
+            [ENVELOPE_TYPE, 6, 0xca, 0x6c, 0x20, 0x01]
+        );
+        let known = Known::default();
+        let hop = "192.0.2.1:5678".parse().unwrap();
+        assert_eq!(
+            known.unwrap(hop, &buf[start..]),
+            Some((
+                "[2001:db8::7]:51820".parse().unwrap(),
+                &buf[MAX_HEADER_LEN..]
+            ))
+        );
+
+        assert_eq!(known.unwrap(hop, &buf[MAX_HEADER_LEN..]), None);
+        assert_eq!(known.unwrap(hop, &[ENVELOPE_TYPE, 4, 0, 0, 1]), None);
+
+        // the address once, then only the id, and the address again after REFRESH
+        let compact = Compact::default();
+        let client = "192.0.2.7:51820".parse().unwrap();
+        let lens: Vec<_> = [
+            (true, 0),
+            (false, 1),
+            (false, REFRESH),
+            (false, REFRESH + 1),
+        ]
+        .into_iter()
+        .map(|(full, now)| {
+            let start = compact.wrap(&mut buf, MAX_HEADER_LEN, client, full, now);
+            assert_eq!(
+                known.unwrap(hop, &buf[start..]),
+                Some((client, &buf[MAX_HEADER_LEN..]))
+            );
+            MAX_HEADER_LEN - start
+        })
+        .collect();
+        assert_eq!(lens, [10, 4, 10, 4]);
+        // from another hop the id means nothing
+        let start = compact.wrap(&mut buf, MAX_HEADER_LEN, client, false, 2);
+        assert_eq!(
+            known.unwrap("192.0.2.2:5678".parse().unwrap(), &buf[start..]),
+            None
+        );
+    }
+}
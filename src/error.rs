@@ -0,0 +1,54 @@
+// what keeps us from starting, or stops us, each a line main prints as is rather than a panic or
+// Debug output
+//
+// what runs on its own threads, sinks, admin and the like, sticks to io::Error, the two convert
+// into each other so ? works either way
+
+use std::io;
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// the command line, or a file it names, doesn't make sense
+    #[error("{0}")]
+    Invalid(String),
+    #[error("can't resolve {addr}: {source}")]
+    Resolve { addr: String, source: io::Error },
+    #[error("can't listen on {addr}: {source}")]
+    Bind { addr: String, source: io::Error },
+    /// a worker thread panicked, which it printed already
+    #[error("a worker thread panicked")]
+    Panicked,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            Error::Invalid(msg) => io::Error::new(io::ErrorKind::InvalidInput, msg),
+            e => io::Error::other(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let e = Error::Resolve {
+            addr: "-v".to_string(),
+            source: io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address"),
+        };
+        assert_eq!(e.to_string(), "can't resolve -v: invalid socket address");
+        let e = io::Error::from(Error::Invalid("--threads 0".to_string()));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "--threads 0");
+    }
+}
@@ -0,0 +1,142 @@
+// --etw, the same events as --webhook-url and --mqtt as Event Tracing for Windows events, the JSON
+// as each one's string, from our own provider:
+//
+//     logman start wireguard-udp-proxy -p {6f1e5a3c-2b7d-4c8e-9a41-d3f0b2c6e857} -o proxy.etl -ets
+//
+// collects them, as does a WPR profile naming it, and Event Viewer and WPA open the .etl after
+//
+// without a manifest to install there's no event log channel of its own, which would need an
+// installer, level says how bad it is, security events are warnings, a target going down or the
+// canary failing an error and a session hijack critical
+
+use crate::events::Deliver;
+
+use std::io::Result;
+
+// TRACE_LEVEL_*
+const CRITICAL: u8 = 1;
+const ERROR: u8 = 2;
+const WARNING: u8 = 3;
+const INFORMATION: u8 = 4;
+
+pub struct Etw {
+    handle: u64,
+}
+
+impl Etw {
+    /// registers our provider, events go nowhere until a session enables it
+    pub fn new() -> Result<Etw> {
+        sys::register().map(|handle| Etw { handle })
+    }
+}
+
+impl Drop for Etw {
+    fn drop(&mut self) {
+        sys::unregister(self.handle);
+    }
+}
+
+impl Deliver for Etw {
+    fn describe(&self) -> String {
+        "etw".to_string()
+    }
+
+    fn deliver(&mut self, event: &str, json: &str) -> Result<()> {
+        sys::write(self.handle, level(event), json)
+    }
+}
+
+/// the TRACE_LEVEL an event is written at
+fn level(event: &str) -> u8 {
+    match event {
+        "session_hijack" => CRITICAL,
+        "target_down" | "canary_failed" => ERROR,
+        "security" => WARNING,
+        _ => INFORMATION,
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::{ffi::c_void, io::Result, ptr};
+
+    #[repr(C)]
+    struct Guid {
+        data1: u32,
+        data2: u16,
+        data3: u16,
+        data4: [u8; 8],
+    }
+
+    // {6f1e5a3c-2b7d-4c8e-9a41-d3f0b2c6e857}
+    const PROVIDER: Guid = Guid {
+        data1: 0x6f1e5a3c,
+        data2: 0x2b7d,
+        data3: 0x4c8e,
+        data4: [0x9a, 0x41, 0xd3, 0xf0, 0xb2, 0xc6, 0xe8, 0x57],
+    };
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn EventRegister(
+            provider: *const Guid,
+            callback: *const c_void,
+            context: *mut c_void,
+            handle: *mut u64,
+        ) -> u32;
+        fn EventUnregister(handle: u64) -> u32;
+        fn EventWriteString(handle: u64, level: u8, keyword: u64, string: *const u16) -> u32;
+    }
+
+    fn check(status: u32) -> Result<()> {
+        match status {
+            0 => Ok(()),
+            code => Err(std::io::Error::from_raw_os_error(code as i32)),
+        }
+    }
+
+    pub fn register() -> Result<u64> {
+        let mut handle = 0;
+        check(unsafe { EventRegister(&PROVIDER, ptr::null(), ptr::null_mut(), &mut handle) })?;
+        Ok(handle)
+    }
+
+    pub fn unregister(handle: u64) {
+        unsafe { EventUnregister(handle) };
+    }
+
+    pub fn write(handle: u64, level: u8, string: &str) -> Result<()> {
+        let string: Vec<u16> = string.encode_utf16().chain([0]).collect();
+        check(unsafe { EventWriteString(handle, level, 0, string.as_ptr()) })
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use std::io::{Error, ErrorKind, Result};
+
+    pub fn register() -> Result<u64> {
+        Err(Error::new(ErrorKind::Unsupported, "--etw needs windows"))
+    }
+
+    pub fn unregister(_handle: u64) {}
+
+    pub fn write(_handle: u64, _level: u8, _string: &str) -> Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "--etw needs windows"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level("session_hijack"), CRITICAL);
+        assert_eq!(level("target_down"), ERROR);
+        assert_eq!(level("security"), WARNING);
+        assert_eq!(level("session_created"), INFORMATION);
+        #[cfg(not(windows))]
+        assert!(Etw::new().is_err());
+    }
+}
@@ -0,0 +1,337 @@
+// things other programs might want to react to, like a new session, a failover or someone up to no
+// good, sent as JSON to whatever --webhook-url and --mqtt point at, and to ETW for --etw
+//
+// each sink delivers from its own thread, a slow or dead endpoint only holds up its own events, and
+// they are dropped once too many are waiting for it
+
+use crate::{config::Config, poison::Recover, schedule};
+#[cfg(feature = "events")]
+use crate::{etw::Etw, mqtt::Mqtt, webhook::Webhook};
+
+use std::{
+    fmt::Write as _,
+    io::Result,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread,
+};
+
+// events waiting for a sink or subscriber before new ones are dropped
+const QUEUE_LEN: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SessionCreated {
+        receiver: u32,
+        client: SocketAddr,
+        target: SocketAddr,
+    },
+    /// when we noticed, either because it timed out or --dead-after
+    SessionExpired {
+        receiver: u32,
+        client: SocketAddr,
+        reason: &'static str,
+    },
+    /// a target stopped answering handshakes
+    TargetDown {
+        target: String,
+    },
+    Failover {
+        from: String,
+        to: String,
+    },
+    /// a switch-target from the target from to to is done, after secs, cut_over is how many
+    /// sessions from still had when its --drain was up
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    TargetDrained {
+        from: String,
+        to: String,
+        secs: u64,
+        cut_over: usize,
+    },
+    /// --canary's handshakes stopped getting through us to the target and back, detail says how
+    #[cfg_attr(not(feature = "canary"), allow(dead_code))]
+    CanaryFailed {
+        detail: String,
+    },
+    #[cfg_attr(not(feature = "canary"), allow(dead_code))]
+    CanaryRecovered,
+    /// the address handshakes took the session with receiver over from, previous, still sending
+    /// for it while client has it, one of them is after the other's return traffic
+    Hijack {
+        receiver: u32,
+        client: SocketAddr,
+        previous: SocketAddr,
+    },
+    /// source sending us what an attacker would, kind says what
+    Security {
+        kind: &'static str,
+        source: SocketAddr,
+        detail: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::SessionCreated { .. } => "session_created",
+            Event::SessionExpired { .. } => "session_expired",
+            Event::TargetDown { .. } => "target_down",
+            Event::Failover { .. } => "failover",
+            Event::TargetDrained { .. } => "target_drained",
+            Event::CanaryFailed { .. } => "canary_failed",
+            Event::CanaryRecovered => "canary_recovered",
+            Event::Hijack { .. } => "session_hijack",
+            Event::Security { .. } => "security",
+        }
+    }
+
+    /// one JSON object, time in seconds since the unix epoch
+    pub fn to_json(&self, time: u64) -> String {
+        let mut json = format!("{{\"event\":\"{}\",\"time\":{}", self.name(), time);
+        let fields: Vec<(&str, String)> = match self {
+            Event::SessionCreated {
+                receiver,
+                client,
+                target,
+            } => vec![
+                ("receiver", format!("{:08x}", receiver)),
+                ("client", client.to_string()),
+                ("target", target.to_string()),
+            ],
+            Event::SessionExpired {
+                receiver,
+                client,
+                reason,
+            } => vec![
+                ("receiver", format!("{:08x}", receiver)),
+                ("client", client.to_string()),
+                ("reason", reason.to_string()),
+            ],
+            Event::TargetDown { target } => vec![("target", target.clone())],
+            Event::Failover { from, to } => vec![("from", from.clone()), ("to", to.clone())],
+            Event::TargetDrained {
+                from,
+                to,
+                secs,
+                cut_over,
+            } => vec![
+                ("from", from.clone()),
+                ("to", to.clone()),
+                ("secs", secs.to_string()),
+                ("cut_over", cut_over.to_string()),
+            ],
+            Event::CanaryFailed { detail } => vec![("detail", detail.clone())],
+            Event::CanaryRecovered => vec![],
+            Event::Hijack {
+                receiver,
+                client,
+                previous,
+            } => vec![
+                ("receiver", format!("{:08x}", receiver)),
+                ("client", client.to_string()),
+                ("previous", previous.to_string()),
+            ],
+            Event::Security {
+                kind,
+                source,
+                detail,
+            } => vec![
+                ("kind", kind.to_string()),
+                ("source", source.to_string()),
+                ("detail", detail.clone()),
+            ],
+        };
+        for (name, value) in fields {
+            let _ = write!(json, ",\"{}\":\"{}\"", name, escape(&value));
+        }
+        json.push('}');
+        json
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// somewhere events go
+pub trait Deliver: Send {
+    /// a human readable name for logs
+    fn describe(&self) -> String;
+
+    fn deliver(&mut self, event: &str, json: &str) -> Result<()>;
+}
+
+/// an event and when it happened
+pub type Stamped = (u64, Event);
+
+// what a sink's thread works on
+struct Delivery {
+    queue: Receiver<Stamped>,
+    to: Box<dyn Deliver>,
+}
+
+struct Sink {
+    queue: SyncSender<Stamped>,
+    // until start hands it to the sink's thread
+    waiting: Mutex<Option<Delivery>>,
+}
+
+#[derive(Default)]
+pub struct Events {
+    sinks: Vec<Sink>,
+    // --grpc's streams of events, which come and go
+    subscribers: Mutex<Vec<SyncSender<Stamped>>>,
+    subscribed: AtomicUsize,
+    /// events a sink or subscriber had no room for
+    pub dropped: AtomicU64,
+}
+
+impl Events {
+    /// the sinks config asks for, which queue events until start
+    #[cfg(feature = "events")]
+    pub fn new(config: &Config) -> Result<Events> {
+        let mut events = Events::default();
+        if let Some(url) = &config.webhook_url {
+            events.add(Box::new(Webhook::parse(url)?));
+        }
+        if let Some(broker) = &config.mqtt_broker {
+            let mqtt = Mqtt::new(broker, &config.mqtt_topic, config.mqtt_credentials.clone());
+            events.add(Box::new(mqtt));
+        }
+        if config.etw {
+            events.add(Box::new(Etw::new()?));
+        }
+        Ok(events)
+    }
+
+    /// built without the events feature there is nowhere to send them
+    #[cfg(not(feature = "events"))]
+    pub fn new(_config: &Config) -> Result<Events> {
+        Ok(Events::default())
+    }
+
+    /// every event from now on, until the receiver is dropped
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub fn subscribe(&self) -> Receiver<Stamped> {
+        let (queue, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let mut subscribers = self.subscribers.lock().recover();
+        subscribers.push(queue);
+        self.subscribed.store(subscribers.len(), Relaxed);
+        receiver
+    }
+
+    #[cfg(feature = "events")]
+    fn add(&mut self, sink: Box<dyn Deliver>) {
+        let (queue, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let delivery = Delivery {
+            queue: receiver,
+            to: sink,
+        };
+        self.sinks.push(Sink {
+            queue,
+            waiting: Mutex::new(Some(delivery)),
+        });
+    }
+
+    /// starts delivering, from a thread per sink
+    pub fn start(&self) {
+        for sink in &self.sinks {
+            let Delivery {
+                queue,
+                to: mut sink,
+            } = match sink.waiting.lock().recover().take() {
+                Some(delivery) => delivery,
+                None => continue,
+            };
+            thread::spawn(move || {
+                let mut failing = false;
+                for (time, event) in queue {
+                    match sink.deliver(event.name(), &event.to_json(time)) {
+                        // only log when it starts or stops failing, not for every event
+                        Err(e) if !failing => {
+                            eprintln!(
+                                "delivering events to {} failed: {}, dropping them until it works again",
+                                sink.describe(),
+                                e
+                            );
+                            failing = true;
+                        }
+                        Ok(()) if failing => {
+                            eprintln!("delivering events to {} works again", sink.describe());
+                            failing = false;
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+    }
+
+    /// sends whatever event makes to every sink and subscriber, it isn't made if there are none
+    pub fn emit<F: FnOnce() -> Event>(&self, event: F) {
+        if self.sinks.is_empty() && self.subscribed.load(Relaxed) == 0 {
+            return;
+        }
+        let time = schedule::unix_time();
+        let event = event();
+        for sink in &self.sinks {
+            if let Err(TrySendError::Full(_)) = sink.queue.try_send((time, event.clone())) {
+                self.dropped.fetch_add(1, Relaxed);
+            }
+        }
+        if self.subscribed.load(Relaxed) > 0 {
+            let mut subscribers = self.subscribers.lock().recover();
+            subscribers.retain(|queue| match queue.try_send((time, event.clone())) {
+                Err(TrySendError::Disconnected(_)) => false,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Relaxed);
+                    true
+                }
+                Ok(()) => true,
+            });
+            self.subscribed.store(subscribers.len(), Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let event = Event::SessionCreated {
+            receiver: 1,
+            client: "192.0.2.10:1000".parse().unwrap(),
+            target: "[2001:db8::1]:51820".parse().unwrap(),
+        };
+        assert_eq!(
+            event.to_json(5),
+            r#"{"event":"session_created","time":5,"receiver":"00000001","client":"192.0.2.10:1000","target":"[2001:db8::1]:51820"}"#
+        );
+        let event = Event::Failover {
+            from: "a \"b\"".to_string(),
+            to: "c\\\n".to_string(),
+        };
+        assert_eq!(
+            event.to_json(0),
+            r#"{"event":"failover","time":0,"from":"a \"b\"","to":"c\\\u000a"}"#
+        );
+    }
+}
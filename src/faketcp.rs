@@ -0,0 +1,570 @@
+// --faketcp-target and --faketcp-clients, for ISPs that throttle or drop UDP: packets between two
+// of us go as the segments of what looks like a TCP connection, made with raw sockets, one packet
+// per segment after a SYN, SYN-ACK, ACK handshake, with sequence and ack numbers that count what
+// was sent like a real connection's would
+//
+// nothing is retransmitted or put back in order, a lost segment is a lost packet, wireguard copes,
+// and there is no congestion control beyond wireguard's own
+//
+// the kernel doesn't know of these connections and resets them, we ignore resets but whatever is
+// on the way may not, a firewall rule dropping them is needed on both ends, rules() is what we
+// suggest at startup
+//
+// the near end starts the handshake over when it hasn't heard from the far end in STALE while it
+// has been sending, the far end having restarted, say, or a NAT on the way forgetting the flow
+//
+// IPv4 only, and linux only, where raw sockets give us every segment to the host
+
+use crate::{decoy, packet::bytes, platform::RawTcp, poison::Recover, WgPacket};
+
+use std::{
+    collections::{
+        hash_map::{Entry, RandomState},
+        HashMap, VecDeque,
+    },
+    hash::{BuildHasher, Hasher},
+    io::Result,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+// what linux advertises before scaling
+const WINDOW: u16 = 64240;
+
+// a SYN or SYN-ACK unanswered for this long is sent again
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+
+// hearing nothing back for this long while sending, the near end starts over
+const STALE: Duration = Duration::from_secs(10);
+
+// a session nothing went through for this long is forgotten
+const IDLE_TIME: Duration = Duration::from_secs(60);
+
+// packets waiting for a handshake to finish, beyond that they are dropped
+const QUEUE_LEN: usize = 16;
+
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq)]
+struct Segment<'a> {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &'a [u8],
+}
+
+/// segment as TCP, options like linux's on a SYN, and its checksum over the pseudo header
+fn build(segment: &Segment) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(40 + segment.payload.len());
+    tcp.extend_from_slice(&segment.src.port().to_be_bytes());
+    tcp.extend_from_slice(&segment.dst.port().to_be_bytes());
+    tcp.extend_from_slice(&segment.seq.to_be_bytes());
+    tcp.extend_from_slice(&segment.ack.to_be_bytes());
+    // the data offset goes in once the options are in
+    tcp.extend_from_slice(&[0, segment.flags]);
+    tcp.extend_from_slice(&WINDOW.to_be_bytes());
+    tcp.extend_from_slice(&[0; 4]);
+    if segment.flags & SYN != 0 {
+        let now = random();
+        // mss 1460, SACK permitted, timestamps, a nop and window scale 7
+        tcp.extend_from_slice(&[2, 4, 0x05, 0xb4, 4, 2, 8, 10]);
+        tcp.extend_from_slice(&now.to_be_bytes());
+        tcp.extend_from_slice(&[0; 4]);
+        tcp.extend_from_slice(&[1, 3, 3, 7]);
+    }
+    tcp[12] = ((tcp.len() / 4) as u8) << 4;
+    tcp.extend_from_slice(segment.payload);
+    let sum = checksum(segment.src.ip(), segment.dst.ip(), &tcp);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+    tcp
+}
+
+/// the TCP checksum of tcp from src to dst, 0 for one that has it right
+fn checksum(src: &Ipv4Addr, dst: &Ipv4Addr, tcp: &[u8]) -> u16 {
+    let mut data = Vec::with_capacity(12 + tcp.len());
+    data.extend_from_slice(&src.octets());
+    data.extend_from_slice(&dst.octets());
+    data.extend_from_slice(&[0, 6]);
+    data.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+    data.extend_from_slice(tcp);
+    decoy::checksum(&data)
+}
+
+/// the TCP segment in an IPv4 packet, if its checksum is right
+fn parse(packet: &[u8]) -> Option<Segment<'_>> {
+    let ihl = (*packet.first()? as usize & 0x0f) * 4;
+    if packet.len() < ihl + 20 || packet[0] >> 4 != 4 || packet[9] != 6 {
+        return None;
+    }
+    let total = (u16::from_be_bytes(bytes(packet, 2)) as usize).min(packet.len());
+    let tcp = packet.get(ihl..total)?;
+    let offset = (tcp.get(12)? >> 4) as usize * 4;
+    if offset < 20 || tcp.len() < offset {
+        return None;
+    }
+    let src = Ipv4Addr::from(bytes::<4>(packet, 12));
+    let dst = Ipv4Addr::from(bytes::<4>(packet, 16));
+    if checksum(&src, &dst, tcp) != 0 {
+        return None;
+    }
+    Some(Segment {
+        src: SocketAddrV4::new(src, u16::from_be_bytes(bytes(tcp, 0))),
+        dst: SocketAddrV4::new(dst, u16::from_be_bytes(bytes(tcp, 2))),
+        seq: u32::from_be_bytes(bytes(tcp, 4)),
+        ack: u32::from_be_bytes(bytes(tcp, 8)),
+        flags: tcp[13],
+        payload: &tcp[offset..],
+    })
+}
+
+/// the firewall rules that keep the kernel from resetting our connections that match, as iptables
+/// and nft take them
+fn rules(iptables: &str, nft: &str) -> String {
+    format!(
+        "the kernel resets connections it doesn't know of, keep it from resetting ours with\n\
+         \x20   iptables -I OUTPUT -p tcp {} --tcp-flags RST RST -j DROP\n\
+         or\n\
+         \x20   nft add table ip wireguard-udp-proxy\n\
+         \x20   nft add chain ip wireguard-udp-proxy output '{{ type filter hook output priority 0; }}'\n\
+         \x20   nft add rule ip wireguard-udp-proxy output {} tcp flags rst drop",
+        iptables, nft
+    )
+}
+
+fn random() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+fn v4(addr: SocketAddr) -> Result<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Ok(addr),
+        SocketAddr::V6(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "faketcp is IPv4 only",
+        )),
+    }
+}
+
+struct NearSession {
+    client: SocketAddr,
+    /// our end of the connection
+    local: SocketAddrV4,
+    /// what we send next, and expect from the far end next
+    seq: u32,
+    ack: u32,
+    established: bool,
+    /// packets for the target waiting for the handshake
+    queue: VecDeque<Vec<u8>>,
+    syn_sent: Instant,
+    heard: Instant,
+    active: Instant,
+}
+
+struct NearState {
+    sessions: HashMap<u16, NearSession>,
+    by_client: HashMap<SocketAddr, u16>,
+}
+
+/// the end wireguard clients talk to, a connection to far for each
+pub struct Near {
+    local: UdpSocket,
+    raw: RawTcp,
+    ip: Ipv4Addr,
+    far: SocketAddrV4,
+    verbose: bool,
+    state: Mutex<NearState>,
+}
+
+impl Near {
+    pub fn new(local: UdpSocket, far: SocketAddr, verbose: bool) -> Result<Near> {
+        let far = v4(far)?;
+        // the address the kernel sends from, which the checksum covers
+        let route = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+        route.connect(far)?;
+        let route = v4(route.local_addr()?)?;
+        let raw = RawTcp::open()?;
+        eprintln!(
+            "{}",
+            rules(
+                &format!("-d {} --dport {}", far.ip(), far.port()),
+                &format!("ip daddr {} tcp dport {}", far.ip(), far.port()),
+            )
+        );
+        Ok(Near {
+            local,
+            raw,
+            ip: *route.ip(),
+            far,
+            verbose,
+            state: Mutex::new(NearState {
+                sessions: HashMap::new(),
+                by_client: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        thread::spawn(move || self.segments());
+        thread::spawn(move || loop {
+            thread::sleep(TICK);
+            self.tick(Instant::now());
+        });
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, client) = self.local.recv_from(&mut buf)?;
+            let packet = &buf[..recv];
+            if WgPacket::parse(packet).is_none() {
+                continue;
+            }
+            let now = Instant::now();
+            let mut state = self.state.lock().recover();
+            let state = &mut *state;
+            let port = match state.by_client.get(&client) {
+                Some(port) => *port,
+                None => {
+                    // an ephemeral port of our own, the kernel has none of its sockets on it
+                    let port = (0..16)
+                        .map(|_| 49152 + (random() % 16384) as u16)
+                        .find(|port| !state.sessions.contains_key(port));
+                    let Some(port) = port else {
+                        continue;
+                    };
+                    let mut session = NearSession {
+                        client,
+                        local: SocketAddrV4::new(self.ip, port),
+                        seq: 0,
+                        ack: 0,
+                        established: false,
+                        queue: VecDeque::new(),
+                        syn_sent: now,
+                        heard: now,
+                        active: now,
+                    };
+                    if self.verbose {
+                        eprintln!("faketcp connection from {} for {}", session.local, client);
+                    }
+                    self.syn(&mut session, now);
+                    state.by_client.insert(client, port);
+                    state.sessions.insert(port, session);
+                    port
+                }
+            };
+            let Some(session) = state.sessions.get_mut(&port) else {
+                continue;
+            };
+            session.active = now;
+            if !session.established {
+                if session.queue.len() < QUEUE_LEN {
+                    session.queue.push_back(packet.to_vec());
+                }
+                continue;
+            }
+            self.data(session, packet);
+        }
+    }
+
+    /// starts a handshake over, with a new initial sequence number
+    fn syn(&self, session: &mut NearSession, now: Instant) {
+        let isn = random();
+        session.seq = isn.wrapping_add(1);
+        session.established = false;
+        session.syn_sent = now;
+        let _ = self.send(session, isn, 0, SYN, &[]);
+    }
+
+    fn data(&self, session: &mut NearSession, packet: &[u8]) {
+        let _ = self.send(session, session.seq, session.ack, PSH | ACK, packet);
+        session.seq = session.seq.wrapping_add(packet.len() as u32);
+    }
+
+    fn send(
+        &self,
+        session: &NearSession,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> Result<usize> {
+        let segment = Segment {
+            src: session.local,
+            dst: self.far,
+            seq,
+            ack,
+            flags,
+            payload,
+        };
+        self.raw.send(&build(&segment), *self.far.ip())
+    }
+
+    /// sends handshakes again, starts stale ones over and forgets idle sessions
+    fn tick(&self, now: Instant) {
+        let mut state = self.state.lock().recover();
+        let NearState {
+            sessions,
+            by_client,
+        } = &mut *state;
+        sessions.retain(|_, session| {
+            let active = now.duration_since(session.active) < IDLE_TIME;
+            if !active {
+                by_client.remove(&session.client);
+            }
+            active
+        });
+        for session in sessions.values_mut() {
+            let retry = now.duration_since(session.syn_sent) >= HANDSHAKE_RETRY;
+            let stale = now.duration_since(session.heard) >= STALE
+                && now.duration_since(session.active) < STALE;
+            if retry && (!session.established || stale) {
+                if session.established && self.verbose {
+                    eprintln!(
+                        "faketcp connection from {} went quiet, starting over",
+                        session.local
+                    );
+                }
+                self.syn(session, now);
+            }
+        }
+    }
+
+    /// finishes handshakes and passes what segments carry to the clients
+    fn segments(&self) {
+        let mut buf = [0u8; 65536];
+        loop {
+            let recv = match self.raw.recv(&mut buf, IDLE_TIME) {
+                Ok(Some(recv)) => recv,
+                Ok(None) | Err(_) => continue,
+            };
+            let Some(segment) = parse(&buf[..recv]) else {
+                continue;
+            };
+            if segment.src != self.far || *segment.dst.ip() != self.ip || segment.flags & RST != 0 {
+                continue;
+            }
+            let now = Instant::now();
+            let mut state = self.state.lock().recover();
+            let Some(session) = state.sessions.get_mut(&segment.dst.port()) else {
+                continue;
+            };
+            if segment.flags & (SYN | ACK) == SYN | ACK {
+                if session.established || segment.ack != session.seq {
+                    continue;
+                }
+                session.ack = segment.seq.wrapping_add(1);
+                session.established = true;
+                session.heard = now;
+                let _ = self.send(session, session.seq, session.ack, ACK, &[]);
+                while let Some(packet) = session.queue.pop_front() {
+                    self.data(session, &packet);
+                }
+                continue;
+            }
+            if !session.established || segment.payload.is_empty() || segment.flags & FIN != 0 {
+                continue;
+            }
+            session.ack = segment.seq.wrapping_add(segment.payload.len() as u32);
+            session.heard = now;
+            let _ = self.local.send_to(segment.payload, session.client);
+        }
+    }
+}
+
+struct FarSession {
+    socket: Arc<UdpSocket>,
+    /// the near end, and us as it addresses us
+    peer: SocketAddrV4,
+    local: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    active: Instant,
+}
+
+/// takes connections on port, passing the packets in them to target and its packets back
+pub struct Far {
+    raw: RawTcp,
+    port: u16,
+    target: SocketAddr,
+    verbose: bool,
+    sessions: Mutex<HashMap<SocketAddrV4, FarSession>>,
+}
+
+impl Far {
+    pub fn new(port: u16, target: SocketAddr, verbose: bool) -> Result<Far> {
+        let raw = RawTcp::open()?;
+        eprintln!(
+            "{}",
+            rules(&format!("--sport {}", port), &format!("tcp sport {}", port))
+        );
+        Ok(Far {
+            raw,
+            port,
+            target,
+            verbose,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let Some(recv) = self.raw.recv(&mut buf, IDLE_TIME)? else {
+                continue;
+            };
+            let Some(segment) = parse(&buf[..recv]) else {
+                continue;
+            };
+            if segment.dst.port() != self.port || segment.flags & RST != 0 {
+                continue;
+            }
+            self.segment(&segment)?;
+        }
+    }
+
+    fn segment(&'static self, segment: &Segment) -> Result<()> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().recover();
+        if segment.flags & (SYN | ACK) == SYN {
+            // a new connection, or the near end starting over on one we have
+            let session = match sessions.entry(segment.src) {
+                Entry::Occupied(session) => session.into_mut(),
+                Entry::Vacant(vacant) => {
+                    let socket = UdpSocket::bind(if self.target.is_ipv4() {
+                        SocketAddr::from(([0, 0, 0, 0], 0))
+                    } else {
+                        SocketAddr::from(([0u16; 8], 0))
+                    })?;
+                    socket.connect(self.target)?;
+                    // often enough to notice the session went idle
+                    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+                    let socket = Arc::new(socket);
+                    let from_target = Arc::clone(&socket);
+                    let peer = segment.src;
+                    thread::spawn(move || self.download(peer, &from_target));
+                    if self.verbose {
+                        eprintln!("faketcp connection from {}", peer);
+                    }
+                    vacant.insert(FarSession {
+                        socket,
+                        peer,
+                        local: segment.dst,
+                        seq: random(),
+                        ack: 0,
+                        active: now,
+                    })
+                }
+            };
+            session.ack = segment.seq.wrapping_add(1);
+            session.active = now;
+            let isn = session.seq;
+            session.seq = isn.wrapping_add(1);
+            let _ = self.send(session, isn, SYN | ACK, &[]);
+            return Ok(());
+        }
+        let Some(session) = sessions.get_mut(&segment.src) else {
+            return Ok(());
+        };
+        session.active = now;
+        if !segment.payload.is_empty() {
+            session.ack = segment.seq.wrapping_add(segment.payload.len() as u32);
+            // the target being down is its business
+            let _ = session.socket.send(segment.payload);
+        }
+        Ok(())
+    }
+
+    fn send(&self, session: &FarSession, seq: u32, flags: u8, payload: &[u8]) -> Result<usize> {
+        let segment = Segment {
+            src: session.local,
+            dst: session.peer,
+            seq,
+            ack: session.ack,
+            flags,
+            payload,
+        };
+        self.raw.send(&build(&segment), *session.peer.ip())
+    }
+
+    /// sends what the target sends on socket to peer, until it's idle
+    fn download(&self, peer: SocketAddrV4, socket: &UdpSocket) {
+        let mut buf = [0u8; 65536];
+        loop {
+            let received = socket.recv(&mut buf);
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().recover();
+            let Some(session) = sessions.get_mut(&peer) else {
+                return;
+            };
+            match received {
+                Ok(recv) => {
+                    let _ = self.send(session, session.seq, PSH | ACK, &buf[..recv]);
+                    session.seq = session.seq.wrapping_add(recv as u32);
+                }
+                Err(_) if now.duration_since(session.active) >= IDLE_TIME => {
+                    sessions.remove(&peer);
+                    return;
+                }
+                // timed out, or the target refusing an earlier packet
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 50000);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 2), 443);
+        let syn = Segment {
+            src,
+            dst,
+            seq: 0xfffffff0,
+            ack: 0,
+            flags: SYN,
+            payload: &[],
+        };
+        let tcp = build(&syn);
+        assert_eq!(tcp.len(), 40);
+        assert_eq!(tcp[12] >> 4, 10);
+        assert_eq!(checksum(src.ip(), dst.ip(), &tcp), 0);
+
+        // the IPv4 header the kernel would put in front
+        let ip = |tcp: &[u8]| {
+            let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0];
+            packet[2..4].copy_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&src.ip().octets());
+            packet.extend_from_slice(&dst.ip().octets());
+            packet.extend_from_slice(tcp);
+            packet
+        };
+        let data = Segment {
+            flags: PSH | ACK,
+            seq: 0xfffffff1,
+            ack: 7,
+            payload: &[4, 0, 0, 0, 1],
+            ..syn
+        };
+        let packet = ip(&build(&data));
+        assert_eq!(parse(&packet), Some(data));
+        let mut corrupt = packet.clone();
+        corrupt[44] ^= 1;
+        assert_eq!(parse(&corrupt), None);
+        assert_eq!(parse(&packet[..30]), None);
+
+        let rules = rules("--sport 443", "tcp sport 443");
+        assert!(rules.contains("iptables -I OUTPUT -p tcp --sport 443 --tcp-flags RST RST -j DROP"));
+        assert!(rules.contains("output tcp sport 443 tcp flags rst drop"));
+    }
+}
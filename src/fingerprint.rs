@@ -0,0 +1,177 @@
+// --fingerprints, what kind of software our clients run, told apart by what they can't help showing:
+// how big their initiations are, whether those carry a mac2, how often they send keepalives, and
+// whether a target ever answered them
+//
+// wireguard's initiations are 148 bytes with mac2 zeroed unless a cookie asked for it, keepalives
+// come every persistent keepalive interval, 25s as usually configured, or 10s after data that got
+// no answer, and something only imitating wireguard to scan for it is never answered
+
+use crate::{
+    clock::{self, Tick},
+    poison::Recover,
+};
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+
+const INITIATION_LEN: usize = 148;
+
+// a data message with nothing in it, the header and the tag
+const KEEPALIVE_LEN: usize = 32;
+
+// clients we keep track of, beyond that new ones aren't classified until old ones went quiet
+const MAX_CLIENTS: usize = 4096;
+
+// how long a client that stopped sending us initiations and keepalives is counted
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+/// what we noticed about a client, the labels a class is counted under
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Class {
+    /// an initiation that wasn't 148 bytes
+    pub odd_size: bool,
+    /// an initiation with a mac2
+    pub mac2: bool,
+    /// seconds between its last two keepalives
+    pub keepalive: Option<u64>,
+    /// a target answered one of its initiations
+    pub answered: bool,
+}
+
+impl Class {
+    /// the labels it's counted under in metrics and on the admin socket
+    #[cfg_attr(not(any(feature = "admin", feature = "metrics")), allow(dead_code))]
+    pub fn labels(&self) -> [(&'static str, &'static str); 4] {
+        [
+            ("initiation", if self.odd_size { "odd" } else { "standard" }),
+            ("mac2", if self.mac2 { "used" } else { "unused" }),
+            (
+                "keepalive",
+                match self.keepalive {
+                    None => "none",
+                    Some(24..=26) => "25s",
+                    Some(9..=11) => "10s",
+                    Some(_) => "other",
+                },
+            ),
+            ("answered", if self.answered { "yes" } else { "no" }),
+        ]
+    }
+}
+
+struct Client {
+    class: Class,
+    last_keepalive: Option<Tick>,
+    seen: Tick,
+}
+
+#[derive(Default)]
+pub struct Fingerprints {
+    clients: Mutex<HashMap<SocketAddr, Client>>,
+}
+
+impl Fingerprints {
+    /// looks at packet from client, only initiations and keepalives tell us anything
+    pub fn observe(&self, client: SocketAddr, packet: &[u8], now: Tick) {
+        match packet[0] {
+            1 => self.update(client, now, |c| {
+                c.class.odd_size |= packet.len() != INITIATION_LEN;
+                c.class.mac2 |= packet[packet.len().saturating_sub(16)..]
+                    .iter()
+                    .any(|b| *b != 0);
+            }),
+            4 if packet.len() == KEEPALIVE_LEN => self.update(client, now, |c| {
+                if let Some(last) = c.last_keepalive.replace(now) {
+                    let interval = clock::duration(now.saturating_sub(last));
+                    c.class.keepalive = Some((interval.as_millis() as u64 + 500) / 1000);
+                }
+            }),
+            _ => {}
+        }
+    }
+
+    /// a target answered an initiation of client's
+    pub fn answered(&self, client: SocketAddr, now: Tick) {
+        self.update(client, now, |c| c.class.answered = true);
+    }
+
+    fn update<F: FnOnce(&mut Client)>(&self, client: SocketAddr, now: Tick, f: F) {
+        let mut clients = self.clients.lock().recover();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            clients.retain(|_, c| !forgotten(c, now));
+            if clients.len() >= MAX_CLIENTS {
+                return;
+            }
+        }
+        let c = clients.entry(client).or_insert(Client {
+            class: Class::default(),
+            last_keepalive: None,
+            seen: now,
+        });
+        c.seen = now;
+        f(c);
+    }
+
+    /// how many clients we saw recently are of each class, the most common first
+    #[cfg_attr(not(any(feature = "admin", feature = "metrics")), allow(dead_code))]
+    pub fn counts(&self, now: Tick) -> Vec<([(&'static str, &'static str); 4], u64)> {
+        let mut clients = self.clients.lock().recover();
+        clients.retain(|_, c| !forgotten(c, now));
+        let mut counts: HashMap<_, u64> = HashMap::new();
+        for c in clients.values() {
+            *counts.entry(c.class.labels()).or_default() += 1;
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+}
+
+fn forgotten(client: &Client, now: Tick) -> bool {
+    now.saturating_sub(client.seen) >= clock::ticks(FORGET_AFTER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprints() {
+        let fingerprints = Fingerprints::default();
+        let client = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        let mut initiation = [0u8; INITIATION_LEN];
+        initiation[0] = 1;
+        let mut keepalive = [0u8; KEEPALIVE_LEN];
+        keepalive[0] = 4;
+        let second = clock::ticks(Duration::from_secs(1));
+
+        for port in [1, 2] {
+            fingerprints.observe(client(port), &initiation, 0);
+            fingerprints.answered(client(port), 0);
+            for keepalives in 0..3 {
+                fingerprints.observe(client(port), &keepalive, keepalives * 25 * second);
+            }
+        }
+        // data that isn't a keepalive says nothing
+        fingerprints.observe(client(2), &[4; 100], 100 * second);
+        initiation[140] = 1;
+        fingerprints.observe(client(3), &initiation[..], 0);
+        fingerprints.observe(client(4), &initiation[..100], 0);
+        let wireguard = [
+            ("initiation", "standard"),
+            ("mac2", "unused"),
+            ("keepalive", "25s"),
+            ("answered", "yes"),
+        ];
+        let counts = fingerprints.counts(100 * second);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[0], (wireguard, 2));
+        assert_eq!(
+            counts[1].0[..2],
+            [("initiation", "odd"), ("mac2", "unused")]
+        );
+        assert_eq!(counts[2].0[1], ("mac2", "used"));
+        assert!(fingerprints
+            .counts(clock::ticks(FORGET_AFTER) * 2)
+            .is_empty());
+    }
+}
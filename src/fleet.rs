@@ -0,0 +1,379 @@
+// --control-plane, for operators running dozens of relays: every --control-plane-every we fetch a
+// document from a central url, check its ed25519 signature against --control-plane-key and apply
+// what's in it, all of it or, if anything is wrong with it, none of it
+//
+// a document is a line per setting, version first and the signature over every byte before it
+// last, like `openssl pkeyutl -sign -rawin -inkey key.pem -in doc | xxd -p -c 64` would sign it:
+//
+//     version 42
+//     target 192.0.2.1:51820
+//     failover 192.0.2.2:51820
+//     max-sessions 192.0.2.2:51820=500
+//     max-session-pps 2000
+//     allow 198.51.100.0/24
+//     deny 0.0.0.0/0
+//     signature 5f0c...
+//
+// each kind of line it has replaces what we had for it, from our options or the document before,
+// kinds it doesn't have stay as they are: target switches new sessions to it, failover lines are
+// the only targets new clients may fail over and overflow to, max-sessions those that are capped,
+// max-session-pps 0 is no limit, and allow and deny are checked in order against clients, the
+// first match wins and a client none matches is allowed; targets it no longer names keep their
+// sessions, clients it no longer allows lose theirs
+//
+// a version no newer than the one applied is left alone, so replaying an old document changes
+// nothing
+
+use crate::{
+    args,
+    cidr::Cidr,
+    error,
+    poison::Recover,
+    tls::{self, Tls, TlsStream},
+};
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use std::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering::Relaxed},
+        Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+/// --control-plane-every's default
+pub const EVERY: Duration = Duration::from_secs(60);
+
+// for connecting, and for each read and write after
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// the most a response may be, headers and all
+const MAX_RESPONSE: u64 = 1 << 20;
+
+pub struct Settings {
+    /// host:port, what we connect to and the Host header
+    host: String,
+    path: String,
+    https: bool,
+    key: [u8; 32],
+    pub every: Duration,
+}
+
+impl Settings {
+    pub fn parse(url: &str, key: &str, every: Duration) -> error::Result<Settings> {
+        let (rest, https) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (rest, true),
+            (_, Some(rest)) => (rest, false),
+            _ => {
+                return Err(args::invalid(format!(
+                    "--control-plane must be an https:// or http:// url: {}",
+                    url
+                )))
+            }
+        };
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(args::invalid(format!("invalid --control-plane: {}", url)));
+        }
+        // the port is optional in urls but not for connecting, a literal ipv6 address is in []
+        let host = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'))
+        {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, if https { 443 } else { 80 })
+        };
+        let key = tls::hex32(key).ok_or_else(|| {
+            args::invalid(format!(
+                "--control-plane-key isn't an ed25519 public key in hex: {}",
+                key
+            ))
+        })?;
+        Ok(Settings {
+            host,
+            path: path.to_string(),
+            https,
+            key,
+            every,
+        })
+    }
+
+    /// the url, for logs
+    pub fn url(&self) -> String {
+        let scheme = if self.https { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.host, self.path)
+    }
+}
+
+/// what a document says, None for the kinds of lines it doesn't have
+#[derive(Debug, Default, PartialEq)]
+pub struct Document {
+    pub version: u64,
+    pub target: Option<String>,
+    pub failover: Option<Vec<String>>,
+    pub max_sessions: Option<Vec<(String, usize)>>,
+    pub max_session_pps: Option<u32>,
+    pub acl: Option<Vec<(Cidr, bool)>>,
+}
+
+impl Document {
+    /// the document in text, if it's signed by key
+    pub fn parse(text: &str, key: &[u8; 32]) -> std::result::Result<Document, String> {
+        let body = text.trim_end_matches('\n');
+        let (signed, signature) = match body.rfind('\n') {
+            Some(newline) => (&text[..newline + 1], &body[newline + 1..]),
+            None => return Err("no signature".to_string()),
+        };
+        let signature = signature
+            .strip_prefix("signature ")
+            .and_then(|hex| unhex(hex.trim()))
+            .ok_or("the last line isn't its signature")?;
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(signed.as_bytes(), &signature)
+            .map_err(|_| "its signature doesn't match --control-plane-key")?;
+
+        let mut lines = signed
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let version = lines
+            .next()
+            .and_then(|line| line.strip_prefix("version "))
+            .and_then(|version| version.trim().parse().ok())
+            .ok_or("the first line isn't its version")?;
+        let mut document = Document {
+            version,
+            ..Document::default()
+        };
+        for line in lines {
+            let invalid = || format!("invalid line: {}", line);
+            let (kind, value) = line.split_once(' ').ok_or_else(invalid)?;
+            let value = value.trim();
+            match kind {
+                "target" => document.target = Some(value.to_string()),
+                "failover" => {
+                    let failover = document.failover.get_or_insert_with(Vec::new);
+                    failover.push(value.to_string());
+                }
+                "max-sessions" => {
+                    let (host, n) = value.rsplit_once('=').ok_or_else(invalid)?;
+                    let n = n.parse().map_err(|_| invalid())?;
+                    let max_sessions = document.max_sessions.get_or_insert_with(Vec::new);
+                    max_sessions.push((host.to_string(), n));
+                }
+                "max-session-pps" => {
+                    document.max_session_pps = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "allow" | "deny" => {
+                    let cidr = value.parse().map_err(|_| invalid())?;
+                    let acl = document.acl.get_or_insert_with(Vec::new);
+                    acl.push((cidr, kind == "allow"));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(document)
+    }
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// --control-plane's settings and what of the last document applied stays with it
+pub struct Fleet {
+    pub settings: Settings,
+    tls: Option<Tls>,
+    version: Mutex<Option<u64>>,
+    // 0 for no limit
+    max_session_pps: AtomicU32,
+    acl: RwLock<Vec<(Cidr, bool)>>,
+    // so clients don't take the lock while there is none
+    has_acl: AtomicBool,
+}
+
+impl Fleet {
+    /// a fleet starting out with our --max-session-pps
+    pub fn new(settings: Settings, max_session_pps: Option<u32>) -> error::Result<Fleet> {
+        let tls = match settings.https {
+            true => Some(tls::web_client().map_err(args::invalid)?),
+            false => None,
+        };
+        Ok(Fleet {
+            settings,
+            tls,
+            version: Mutex::new(None),
+            max_session_pps: AtomicU32::new(max_session_pps.unwrap_or(0)),
+            acl: RwLock::new(Vec::new()),
+            has_acl: AtomicBool::new(false),
+        })
+    }
+
+    /// the version of the document applied last
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn version(&self) -> Option<u64> {
+        *self.version.lock().recover()
+    }
+
+    pub fn max_session_pps(&self) -> Option<u32> {
+        Some(self.max_session_pps.load(Relaxed)).filter(|pps| *pps > 0)
+    }
+
+    /// whether a client at ip may have a session
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        if !self.has_acl.load(Relaxed) {
+            return true;
+        }
+        let acl = self.acl.read().recover();
+        let mut rules = acl.iter();
+        rules
+            .find(|(cidr, _)| cidr.contains(ip))
+            .is_none_or(|(_, allow)| *allow)
+    }
+
+    /// the document that's there now, if it's newer than the one applied
+    pub fn fetch(&self) -> Result<Option<Document>> {
+        let body = self.get()?;
+        let text = String::from_utf8(body)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "a document that isn't utf-8"))?;
+        let document = Document::parse(&text, &self.settings.key)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let newer = self
+            .version()
+            .is_none_or(|version| document.version > version);
+        Ok(newer.then_some(document))
+    }
+
+    /// keeps what of document isn't the targets', those have been applied
+    pub fn applied(&self, document: &Document) {
+        if let Some(pps) = document.max_session_pps {
+            self.max_session_pps.store(pps, Relaxed);
+        }
+        if let Some(acl) = &document.acl {
+            *self.acl.write().recover() = acl.clone();
+            self.has_acl.store(!acl.is_empty(), Relaxed);
+        }
+        *self.version.lock().recover() = Some(document.version);
+    }
+
+    // the body of a GET of our url
+    fn get(&self) -> Result<Vec<u8>> {
+        let Settings { host, path, .. } = &self.settings;
+        let addr = host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address"))?;
+        let socket = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+        // 1.0 so it isn't chunked
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: wireguard-udp-proxy\r\n\r\n",
+            path, host
+        );
+        let mut response = Vec::new();
+        match &self.tls {
+            Some(tls) => {
+                let stream = TlsStream::connect(tls, socket, host)?;
+                stream.write_all(request.as_bytes())?;
+                let mut buf = [0; 16384];
+                loop {
+                    let read = stream.read(&mut buf)?;
+                    if read == 0 || response.len() as u64 > MAX_RESPONSE {
+                        break;
+                    }
+                    response.extend_from_slice(&buf[..read]);
+                }
+            }
+            None => {
+                (&socket).write_all(request.as_bytes())?;
+                socket.take(MAX_RESPONSE).read_to_end(&mut response)?;
+            }
+        }
+        let end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "a response without a body"))?;
+        let status = String::from_utf8_lossy(&response[..end]);
+        let status = status.lines().next().unwrap_or_default();
+        // HTTP/1.1 200 OK
+        match status.split(' ').nth(1) {
+            Some("200") => Ok(response.split_off(end + 4)),
+            _ => Err(Error::other(format!("it answered {}", status))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_parse() {
+        let pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let key: [u8; 32] = pair.public_key().as_ref().try_into().unwrap();
+        let sign = |doc: &str| {
+            let signature = pair.sign(doc.as_bytes());
+            let hex: String = signature
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            format!("{}signature {}\n", doc, hex)
+        };
+        let doc = "version 3\n# the new one\ntarget 192.0.2.1:51820\nfailover 192.0.2.2:51820\n\
+                   failover 192.0.2.3:51820\nmax-sessions 192.0.2.2:51820=500\n\
+                   allow 198.51.100.0/24\ndeny 0.0.0.0/0\n";
+        let document = Document::parse(&sign(doc), &key).unwrap();
+        assert_eq!(
+            document,
+            Document {
+                version: 3,
+                target: Some("192.0.2.1:51820".to_string()),
+                failover: Some(vec![
+                    "192.0.2.2:51820".to_string(),
+                    "192.0.2.3:51820".to_string()
+                ]),
+                max_sessions: Some(vec![("192.0.2.2:51820".to_string(), 500)]),
+                max_session_pps: None,
+                acl: Some(vec![
+                    ("198.51.100.0/24".parse().unwrap(), true),
+                    ("0.0.0.0/0".parse().unwrap(), false)
+                ]),
+            }
+        );
+        // changed after it was signed
+        let forged = sign(doc).replace("version 3", "version 4");
+        assert!(Document::parse(&forged, &key).is_err());
+        assert!(Document::parse(doc, &key).is_err());
+        assert!(Document::parse(&sign("target 192.0.2.1:51820\n"), &key).is_err());
+        assert!(Document::parse(&sign("version 1\nmtu 1280\n"), &key).is_err());
+
+        let settings = Settings::parse("http://127.0.0.1:1/fleet", &"00".repeat(32), EVERY);
+        let fleet = Fleet::new(settings.unwrap(), Some(100)).unwrap();
+        assert_eq!(fleet.max_session_pps(), Some(100));
+        assert!(fleet.admits("203.0.113.1".parse().unwrap()));
+        fleet.applied(&document);
+        assert_eq!(fleet.version(), Some(3));
+        assert_eq!(fleet.max_session_pps(), Some(100));
+        assert!(fleet.admits("198.51.100.7".parse().unwrap()));
+        assert!(!fleet.admits("203.0.113.1".parse().unwrap()));
+        // what isn't ipv4
+        assert!(fleet.admits("2001:db8::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,121 @@
+// follow on the admin socket, a line for every packet of one session as it passes, until whoever
+// asked stops it
+//
+// workers only pay for a lookup while someone follows, and never wait for a follower, lines it
+// doesn't take quickly enough are dropped and counted
+
+use crate::poison::Recover;
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+// lines waiting for a follower before we drop more
+const BACKLOG: usize = 1024;
+
+struct Follower {
+    id: u64,
+    index: u32,
+    lines: SyncSender<(Instant, String)>,
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+pub struct Follows {
+    followers: Mutex<Vec<Follower>>,
+    count: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+/// a follower's end, it stops following once dropped
+pub struct Following<'a> {
+    follows: &'a Follows,
+    id: u64,
+    pub lines: Receiver<(Instant, String)>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Drop for Following<'_> {
+    fn drop(&mut self) {
+        let mut followers = self.follows.followers.lock().recover();
+        followers.retain(|f| f.id != self.id);
+        self.follows.count.store(followers.len(), Relaxed);
+    }
+}
+
+impl Following<'_> {
+    /// lines we were too slow for so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Relaxed)
+    }
+}
+
+impl Follows {
+    /// whether anyone follows any session, before the work of finding out which one a packet is of
+    pub fn any(&self) -> bool {
+        self.count.load(Relaxed) > 0
+    }
+
+    /// follows the session with receiver index
+    pub fn follow(&self, index: u32) -> Following<'_> {
+        let (sender, lines) = mpsc::sync_channel(BACKLOG);
+        let id = self.next_id.fetch_add(1, Relaxed);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut followers = self.followers.lock().recover();
+        followers.push(Follower {
+            id,
+            index,
+            lines: sender,
+            dropped: Arc::clone(&dropped),
+        });
+        self.count.store(followers.len(), Relaxed);
+        Following {
+            follows: self,
+            id,
+            lines,
+            dropped,
+        }
+    }
+
+    /// hands the line line makes to whoever follows index
+    pub fn trace<F: FnOnce() -> String>(&self, index: u32, line: F) {
+        let followers = self.followers.lock().recover();
+        let mut followers = followers.iter().filter(|f| f.index == index).peekable();
+        if followers.peek().is_none() {
+            return;
+        }
+        let line = (Instant::now(), line());
+        for follower in followers {
+            if let Err(TrySendError::Full(_)) = follower.lines.try_send(line.clone()) {
+                follower.dropped.fetch_add(1, Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_follow() {
+        let follows = Follows::default();
+        assert!(!follows.any());
+        let following = follows.follow(7);
+        assert!(follows.any());
+        follows.trace(8, || unreachable!("nobody follows 8"));
+        follows.trace(7, || "data".to_string());
+        assert_eq!(following.lines.try_recv().unwrap().1, "data");
+        for _ in 0..BACKLOG + 1 {
+            follows.trace(7, String::new);
+        }
+        assert_eq!(following.dropped(), 1);
+        drop(following);
+        assert!(!follows.any());
+    }
+}
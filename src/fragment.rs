@@ -0,0 +1,179 @@
+// --fragment-target and --fragment-clients, splitting packets that wrapping made too big for the
+// path between two proxies into fragments that each fit, and putting them back together on the
+// other end, instead of leaving it to ip fragmentation that many middleboxes drop
+//
+// 1 byte  FRAGMENT_TYPE, never a valid wireguard message type
+// 1 byte  index of this fragment
+// 1 byte  how many fragments there are
+// 1 byte  reserved
+// 4 bytes id, the same for every fragment of a packet
+// ...     this fragment's piece of the packet
+
+use crate::{
+    clock::{self, Tick},
+    packet, pmtu,
+    poison::Recover,
+};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+pub const FRAGMENT_TYPE: u8 = 0xfb;
+
+const HEADER_LEN: usize = 8;
+
+/// the smallest mtu we accept, what every ipv4 path must carry
+pub const MIN_MTU: usize = 576;
+
+/// the longest packet we put back together
+pub const MAX_PACKET_LEN: usize = 2048;
+
+// packets being put back together at once, beyond that fragments of new ones are dropped
+const MAX_PARTIAL: usize = 1024;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+struct Partial {
+    started: Tick,
+    pieces: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    len: usize,
+}
+
+#[derive(Default)]
+pub struct Fragments {
+    next_id: AtomicU32,
+    partial: Mutex<HashMap<(SocketAddr, u32), Partial>>,
+    /// packets we gave up on putting back together
+    pub expired: AtomicU64,
+}
+
+impl Fragments {
+    /// the fragments of packet, so none is longer than a path with mtu towards to carries, None if
+    /// it fits as it is
+    pub fn split(&self, packet: &[u8], to: SocketAddr, mtu: usize) -> Option<Vec<Vec<u8>>> {
+        let room = mtu - pmtu::headers(to);
+        if packet.len() <= room {
+            return None;
+        }
+        let pieces = packet.chunks(room - HEADER_LEN);
+        let count = pieces.len() as u8;
+        let id = self.next_id.fetch_add(1, Relaxed).to_be_bytes();
+        Some(
+            pieces
+                .enumerate()
+                .map(|(index, piece)| {
+                    let mut fragment = vec![FRAGMENT_TYPE, index as u8, count, 0];
+                    fragment.extend_from_slice(&id);
+                    fragment.extend_from_slice(piece);
+                    fragment
+                })
+                .collect(),
+        )
+    }
+
+    /// adds fragment from from at now, returns the packet once all of its fragments are there,
+    /// those that don't arrive within timeout of the first one are given up on
+    pub fn add(
+        &self,
+        fragment: &[u8],
+        from: SocketAddr,
+        now: Tick,
+        timeout: Duration,
+    ) -> Option<Vec<u8>> {
+        if fragment.len() <= HEADER_LEN || fragment[0] != FRAGMENT_TYPE {
+            return None;
+        }
+        let (index, count) = (fragment[1] as usize, fragment[2] as usize);
+        if index >= count {
+            return None;
+        }
+        let id = u32::from_be_bytes(packet::bytes(fragment, 4));
+        let mut partial = self.partial.lock().recover();
+        if !partial.contains_key(&(from, id)) {
+            let before = partial.len();
+            partial.retain(|_, p| now.saturating_sub(p.started) < clock::ticks(timeout));
+            self.expired
+                .fetch_add((before - partial.len()) as u64, Relaxed);
+            if partial.len() >= MAX_PARTIAL {
+                return None;
+            }
+        }
+        let p = partial.entry((from, id)).or_insert_with(|| Partial {
+            started: now,
+            pieces: vec![None; count],
+            missing: count,
+            len: 0,
+        });
+        if p.pieces.len() != count {
+            return None;
+        }
+        if p.pieces[index].is_none() {
+            let piece = &fragment[HEADER_LEN..];
+            p.len += piece.len();
+            if p.len > MAX_PACKET_LEN {
+                partial.remove(&(from, id));
+                return None;
+            }
+            p.pieces[index] = Some(piece.to_vec());
+            p.missing -= 1;
+        }
+        if p.missing > 0 {
+            return None;
+        }
+        let p = partial.remove(&(from, id))?;
+        Some(p.pieces.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragments() {
+        let fragments = Fragments::default();
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let packet: Vec<u8> = (0..1500u32).map(|i| i as u8).collect();
+        assert!(fragments.split(&packet, v4, 1528).is_none());
+        let split = fragments.split(&packet, v4, MIN_MTU).unwrap();
+        assert_eq!(split.len(), 3);
+        assert!(split.iter().all(|f| f.len() + 28 <= MIN_MTU));
+        assert_eq!(split[2][..4], [FRAGMENT_TYPE, 2, 3, 0]);
+
+        // in any order, the same fragment twice changes nothing
+        assert_eq!(fragments.add(&split[2], v4, 0, DEFAULT_TIMEOUT), None);
+        assert_eq!(fragments.add(&split[0], v4, 0, DEFAULT_TIMEOUT), None);
+        assert_eq!(fragments.add(&split[0], v4, 0, DEFAULT_TIMEOUT), None);
+        assert_eq!(
+            fragments.add(&split[1], v4, 1, DEFAULT_TIMEOUT),
+            Some(packet.clone())
+        );
+
+        // the rest doesn't come in time
+        let split = fragments.split(&packet, v4, MIN_MTU).unwrap();
+        assert_eq!(fragments.add(&split[0], v4, 0, DEFAULT_TIMEOUT), None);
+        let later = clock::ticks(DEFAULT_TIMEOUT);
+        let other = fragments.split(&packet, v4, MIN_MTU).unwrap();
+        assert_eq!(fragments.add(&other[0], v4, later, DEFAULT_TIMEOUT), None);
+        assert_eq!(fragments.expired.load(Relaxed), 1);
+        assert_eq!(fragments.add(&split[1], v4, later, DEFAULT_TIMEOUT), None);
+
+        assert_eq!(
+            fragments.add(
+                &[FRAGMENT_TYPE, 3, 3, 0, 0, 0, 0, 0, 1],
+                v4,
+                0,
+                DEFAULT_TIMEOUT
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,178 @@
+// counts packets we couldn't make sense of per source address, so whoever is sending us garbage
+// can be found without logging every one of them
+//
+// each source has a score that halves every HALF_LIFE it doesn't send more, old offenders fade out
+// and make room for new ones in the bounded table, once a PURGE_INTERVAL at most, a full table
+// refuses new sources in between
+
+use crate::{
+    clock::{self, Tick},
+    memory,
+    poison::{Recover, TryRecover},
+};
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const HALF_LIFE: Duration = Duration::from_secs(60);
+
+// at most one log line this often
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// sources we keep track of, beyond that new ones are only counted in the total
+const MAX_SOURCES: usize = 4096;
+
+// how often a full table is purged of faded out offenders at most
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Offender {
+    pub ip: IpAddr,
+    /// invalid packets recently, decayed
+    pub score: u64,
+    /// invalid packets since we started tracking it
+    pub total: u64,
+    updated: Tick,
+}
+
+impl Offender {
+    /// the score decayed to now
+    fn decay(&mut self, now: Tick) {
+        let halvings = now.saturating_sub(self.updated) / clock::ticks(HALF_LIFE);
+        self.score = self.score.checked_shr(halvings as u32).unwrap_or(0);
+        self.updated += halvings * clock::ticks(HALF_LIFE);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Sources {
+    offenders: HashMap<IpAddr, Offender>,
+    next_purge: Tick,
+}
+
+impl Sources {
+    /// forgets the offenders whose score faded out by now
+    fn purge(&mut self, now: Tick) {
+        self.offenders.retain(|_, offender| {
+            offender.decay(now);
+            offender.score > 0
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Garbage {
+    sources: Mutex<Sources>,
+    pub total: AtomicU64,
+    // tick after which we may log again
+    next_log: AtomicU64,
+}
+
+impl Garbage {
+    /// counts an invalid packet from ip at now, returns ip's recent score when it's time to log
+    pub fn record(&self, ip: IpAddr, now: Tick) -> Option<u64> {
+        self.total.fetch_add(1, Relaxed);
+        let mut sources = self.sources.lock().recover();
+        if sources.offenders.len() >= MAX_SOURCES && !sources.offenders.contains_key(&ip) {
+            if now < sources.next_purge {
+                return None;
+            }
+            sources.purge(now);
+            sources.next_purge = now + clock::ticks(PURGE_INTERVAL);
+            if sources.offenders.len() >= MAX_SOURCES {
+                return None;
+            }
+        }
+        let offender = sources.offenders.entry(ip).or_insert(Offender {
+            ip,
+            score: 0,
+            total: 0,
+            updated: now,
+        });
+        offender.decay(now);
+        offender.score += 1;
+        offender.total += 1;
+        let score = offender.score;
+        drop(sources);
+
+        let next_log = self.next_log.load(Relaxed);
+        (now >= next_log
+            && self
+                .next_log
+                .compare_exchange(next_log, now + clock::ticks(LOG_INTERVAL), Relaxed, Relaxed)
+                .is_ok())
+        .then_some(score)
+    }
+
+    /// how many sources we keep track of, None while someone has them locked
+    pub fn try_len(&self) -> Option<usize> {
+        self.sources
+            .try_lock()
+            .try_recover()
+            .map(|sources| sources.offenders.len())
+    }
+
+    /// approximately what the sources we keep track of take
+    pub fn memory(&self) -> usize {
+        memory::table::<IpAddr, Offender>(self.sources.lock().recover().offenders.len())
+    }
+
+    /// the n sources with the highest recent score, highest first
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn top(&self, n: usize, now: Tick) -> Vec<Offender> {
+        let mut sources = self.sources.lock().recover();
+        sources.purge(now);
+        let mut top: Vec<Offender> = sources.offenders.values().copied().collect();
+        top.sort_by(|a, b| b.score.cmp(&a.score).then(b.total.cmp(&a.total)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_garbage() {
+        let garbage = Garbage::default();
+        let a = "192.0.2.1".parse().unwrap();
+        let b = "2001:db8::1".parse().unwrap();
+        assert_eq!(garbage.record(a, 0), Some(1));
+        // rate limited
+        assert_eq!(garbage.record(a, 1), None);
+        garbage.record(a, 2);
+        garbage.record(a, 3);
+        garbage.record(b, 4);
+        assert_eq!(garbage.total.load(Relaxed), 5);
+        let top = garbage.top(10, 5);
+        assert_eq!((top[0].ip, top[0].score), (a, 4));
+        assert_eq!((top[1].ip, top[1].score), (b, 1));
+
+        assert_eq!(garbage.record(b, clock::ticks(LOG_INTERVAL)), Some(2));
+        let later = clock::ticks(HALF_LIFE) * 2;
+        let top = garbage.top(1, later);
+        assert_eq!((top[0].ip, top[0].score, top[0].total), (a, 1, 4));
+        assert!(garbage.top(10, later * 10).is_empty());
+
+        // a full table takes no new sources until the next purge makes room
+        let now = later * 10;
+        for n in 0..MAX_SOURCES as u32 {
+            garbage.record(IpAddr::from(n.to_be_bytes()), now);
+        }
+        let faded = now + clock::ticks(HALF_LIFE);
+        garbage.record(a, faded - 1);
+        assert_eq!(garbage.try_len(), Some(MAX_SOURCES));
+        garbage.record(a, faded);
+        assert_eq!(garbage.try_len(), Some(MAX_SOURCES));
+        garbage.record(a, faded - 1 + clock::ticks(PURGE_INTERVAL));
+        assert_eq!(garbage.try_len(), Some(1));
+    }
+}
@@ -0,0 +1,386 @@
+// --grpc, the admin socket's commands and streams of events and counters over gRPC, for control
+// planes managing many proxies that would rather hold a stream open than poll each of them
+//
+// it's plaintext HTTP/2 with prior knowledge, see h2, and the service is wireguard_udp_proxy.Admin
+// of proto/admin.proto; with --admin-tokens a call's authorization metadata is its token, with
+// "Bearer " before it or not, and a tenant's token only reaches that tenant's listener
+//
+// every method streams until it's done or the client cancels the call: Command sends what a
+// command wrote each time it flushes, so follow works as it does on the admin socket, Events
+// every event as it happens and Stats the counters every interval
+
+use crate::{
+    admin::{Output, Scope, Tokens},
+    events::Stamped,
+    h2::{self, Request, Response},
+    schedule,
+};
+
+use std::{
+    io::{Error, ErrorKind, Result, Write},
+    net::TcpListener,
+    sync::{mpsc::Receiver, Arc},
+    thread,
+    time::Duration,
+};
+
+const SERVICE: &str = "/wireguard_udp_proxy.Admin/";
+
+// how often a stream that has nothing to send checks whether it was cancelled
+const POLL: Duration = Duration::from_secs(1);
+
+// the status codes we answer with
+const OK: u32 = 0;
+const INVALID_ARGUMENT: u32 = 3;
+const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+const UNAUTHENTICATED: u32 = 16;
+
+/// a listener's counters as Stats sends them
+pub struct Stats {
+    pub sessions: u64,
+    pub handshakes_pending: u64,
+    pub counters: Vec<(&'static str, u64)>,
+}
+
+/// what --grpc serves
+pub trait Api: Sync + 'static {
+    /// runs an admin command for scope
+    fn command(&self, scope: &Scope, args: &[&str], out: &mut dyn Output) -> Result<()>;
+    /// the events of scope's listener from now on, until it's dropped
+    fn events(&self, scope: &Scope) -> Result<Receiver<Stamped>>;
+    fn stats(&self, scope: &Scope) -> Result<Stats>;
+}
+
+pub fn serve<A: Api>(addr: &str, tokens: Option<Tokens>, api: &'static A) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let tokens = Arc::new(tokens);
+    let handle = Arc::new(move |request: Request, response: Response| {
+        let grpc = request.header("content-type");
+        if !grpc.is_some_and(|grpc| grpc.starts_with("application/grpc")) {
+            let _ = response.headers(&[(":status", "415")], true);
+            return;
+        }
+        let mut call = Call {
+            response,
+            started: false,
+        };
+        let status = match scope(tokens.as_ref().as_ref(), &request) {
+            Ok(scope) => respond(api, &scope, &request, &mut call),
+            Err(status) => Err(status),
+        };
+        // a client that went away can't get a status anymore
+        let _ = match status {
+            Ok(()) => call.finish(OK, ""),
+            Err((code, message)) => call.finish(code, &message),
+        };
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handle = Arc::clone(&handle);
+            thread::spawn(move || {
+                let _ = h2::serve(stream, handle);
+            });
+        }
+    });
+    Ok(())
+}
+
+// a status code that isn't OK and its message
+type Status = (u32, String);
+
+fn scope(tokens: Option<&Tokens>, request: &Request) -> std::result::Result<Scope, Status> {
+    let Some(tokens) = tokens else {
+        return Ok(Scope::Operator);
+    };
+    let token = request.header("authorization").unwrap_or_default();
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    let unknown = || (UNAUTHENTICATED, "unknown token".to_string());
+    tokens.scope(token).ok_or_else(unknown)
+}
+
+fn respond<A: Api>(
+    api: &A,
+    scope: &Scope,
+    request: &Request,
+    call: &mut Call,
+) -> std::result::Result<(), Status> {
+    let path = request.header(":path").unwrap_or_default();
+    let method = path.strip_prefix(SERVICE).unwrap_or_default();
+    let message = message(&request.body)?;
+    match method {
+        "Command" => {
+            let args: Vec<String> = fields(message)?
+                .into_iter()
+                .filter_map(|field| match field {
+                    (1, Value::Bytes(arg)) => Some(String::from_utf8_lossy(arg).into_owned()),
+                    _ => None,
+                })
+                .collect();
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            if args.is_empty() {
+                return Err((INVALID_ARGUMENT, "no command".to_string()));
+            }
+            call.start().map_err(internal)?;
+            let mut out = Stream {
+                call,
+                buf: Vec::new(),
+            };
+            let result = api.command(scope, &args, &mut out);
+            let flushed = out.flush();
+            match result.and(flushed) {
+                Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                    Err((INVALID_ARGUMENT, e.to_string()))
+                }
+                result => result.map_err(internal),
+            }
+        }
+        "Events" => {
+            let events = api.events(scope).map_err(status)?;
+            call.start().map_err(internal)?;
+            while !call.response.cancelled() {
+                let Ok((time, event)) = events.recv_timeout(POLL) else {
+                    continue;
+                };
+                let mut message = Vec::new();
+                put_bytes(&mut message, 1, event.name().as_bytes());
+                put_varint(&mut message, 2, time);
+                put_bytes(&mut message, 3, event.to_json(time).as_bytes());
+                call.send(&message).map_err(internal)?;
+            }
+            Ok(())
+        }
+        "Stats" => {
+            let every = fields(message)?.into_iter().find_map(|field| match field {
+                (1, Value::Varint(secs)) => Some(Duration::from_secs(secs)),
+                _ => None,
+            });
+            call.start().map_err(internal)?;
+            loop {
+                let stats = api.stats(scope).map_err(status)?;
+                let time = schedule::unix_time();
+                let mut message = Vec::new();
+                put_varint(&mut message, 1, time);
+                put_varint(&mut message, 2, stats.sessions);
+                put_varint(&mut message, 3, stats.handshakes_pending);
+                for (name, value) in stats.counters {
+                    let mut entry = Vec::new();
+                    put_bytes(&mut entry, 1, name.as_bytes());
+                    put_varint(&mut entry, 2, value);
+                    put_bytes(&mut message, 4, &entry);
+                }
+                call.send(&message).map_err(internal)?;
+                // none or 0 is a single snapshot
+                let Some(every) = every.filter(|every| !every.is_zero()) else {
+                    return Ok(());
+                };
+                let mut waited = Duration::ZERO;
+                while waited < every {
+                    if call.response.cancelled() {
+                        return Ok(());
+                    }
+                    thread::sleep(POLL.min(every - waited));
+                    waited += POLL;
+                }
+            }
+        }
+        _ => Err((UNIMPLEMENTED, format!("unknown method {}", path))),
+    }
+}
+
+fn status(e: Error) -> Status {
+    match e.kind() {
+        ErrorKind::InvalidInput => (INVALID_ARGUMENT, e.to_string()),
+        _ => internal(e),
+    }
+}
+
+fn internal(e: Error) -> Status {
+    (INTERNAL, e.to_string())
+}
+
+// a call's response, its headers go out before the first message or with the status if there is
+// none
+struct Call {
+    response: Response,
+    started: bool,
+}
+
+impl Call {
+    fn start(&mut self) -> Result<()> {
+        let headers = [(":status", "200"), ("content-type", "application/grpc")];
+        self.response.headers(&headers, false)?;
+        self.started = true;
+        Ok(())
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        let mut data = Vec::with_capacity(5 + message.len());
+        data.push(0);
+        data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        data.extend_from_slice(message);
+        self.response.data(&data)
+    }
+
+    fn finish(&mut self, code: u32, message: &str) -> Result<()> {
+        let code = code.to_string();
+        let message = percent_encode(message);
+        let trailers = [("grpc-status", code.as_str()), ("grpc-message", &message)];
+        if self.started {
+            return self.response.headers(&trailers, true);
+        }
+        let mut headers = vec![(":status", "200"), ("content-type", "application/grpc")];
+        headers.extend_from_slice(&trailers);
+        self.response.headers(&headers, true)
+    }
+}
+
+// where Command's output goes, a CommandOutput message each time the command flushes
+struct Stream<'a> {
+    call: &'a mut Call,
+    buf: Vec<u8>,
+}
+
+impl Write for Stream<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut message = Vec::new();
+        put_bytes(&mut message, 1, &self.buf);
+        self.buf.clear();
+        self.call.send(&message)
+    }
+}
+
+impl Output for Stream<'_> {
+    fn cancelled(&mut self) -> bool {
+        self.call.response.cancelled()
+    }
+}
+
+/// the one message of a request body, uncompressed
+fn message(body: &[u8]) -> std::result::Result<&[u8], Status> {
+    let invalid = || (INTERNAL, "invalid message framing".to_string());
+    let head = body.get(..5).ok_or_else(invalid)?;
+    if head[0] != 0 {
+        return Err((
+            UNIMPLEMENTED,
+            "compressed messages aren't supported".to_string(),
+        ));
+    }
+    let len = u32::from_be_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    body.get(5..5 + len).ok_or_else(invalid)
+}
+
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// the fields of a protobuf message we can read, by number, fixed width ones are skipped
+fn fields(mut message: &[u8]) -> std::result::Result<Vec<(u64, Value<'_>)>, Status> {
+    let invalid = || (INTERNAL, "invalid protobuf".to_string());
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = varint(&mut message).ok_or_else(invalid)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(&mut message).ok_or_else(invalid)?),
+            2 => {
+                let len = varint(&mut message).ok_or_else(invalid)? as usize;
+                let bytes = message.get(..len).ok_or_else(invalid)?;
+                message = &message[len..];
+                Value::Bytes(bytes)
+            }
+            wire @ (1 | 5) => {
+                let len = if wire == 1 { 8 } else { 4 };
+                message = message.get(len..).ok_or_else(invalid)?;
+                continue;
+            }
+            _ => return Err(invalid()),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+fn put_raw_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(0x80 | (n & 0x7f) as u8);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_varint(out: &mut Vec<u8>, field: u64, n: u64) {
+    put_raw_varint(out, field << 3);
+    put_raw_varint(out, n);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_raw_varint(out, (field << 3) | 2);
+    put_raw_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// grpc-message's encoding, anything but printable ascii and % as %XX
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let mut message = Vec::new();
+        put_bytes(&mut message, 1, b"sessions");
+        put_varint(&mut message, 3, 300);
+        // a fixed64 we skip
+        message.extend_from_slice(&[(2 << 3) | 1, 1, 2, 3, 4, 5, 6, 7, 8]);
+        put_bytes(&mut message, 1, b"");
+        assert_eq!(
+            fields(&message).unwrap(),
+            [
+                (1, Value::Bytes(b"sessions")),
+                (3, Value::Varint(300)),
+                (1, Value::Bytes(b"")),
+            ]
+        );
+        assert!(fields(&message[..message.len() - 3]).is_err());
+
+        let mut body = vec![0, 0, 0, 0, message.len() as u8];
+        body.extend_from_slice(&message);
+        assert_eq!(self::message(&body).unwrap(), message);
+        body[0] = 1;
+        assert_eq!(self::message(&body).unwrap_err().0, UNIMPLEMENTED);
+        assert_eq!(percent_encode("50% off\n"), "50%25 off%0A");
+    }
+}
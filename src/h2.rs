@@ -0,0 +1,490 @@
+// just enough of HTTP/2 to serve --grpc: plaintext with prior knowledge, the way gRPC clients
+// speak it without TLS, requests from clients and responses from us, no push and no priorities
+//
+// a request is handed over once its stream ends, to a thread of its own that answers it through a
+// Response, which waits for the client's flow control windows before sending data, what a client
+// sends is let through as fast as it reads it
+
+use crate::{hpack, poison::Recover};
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{BufReader, Error, ErrorKind, Read, Result, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+const PROTOCOL_ERROR: u32 = 0x1;
+const REFUSED_STREAM: u32 = 0x7;
+
+// the largest frame either side may send until told otherwise, we never raise ours
+const FRAME_LEN: usize = 16384;
+
+// every window starts out with this
+const WINDOW: i64 = 65535;
+
+// streams a connection may have open at once
+const MAX_STREAMS: u32 = 100;
+
+// the most a request's header block or body may take
+const MAX_REQUEST: usize = 1 << 20;
+
+fn protocol(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("http/2: {}", msg))
+}
+
+/// a request, once its stream ended
+#[derive(Debug, Default)]
+pub struct Request {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let mut headers = self.headers.iter();
+        headers.find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+// what the client lets us send, on the connection and on each stream we may still answer
+struct Flow {
+    window: i64,
+    streams: HashMap<u32, i64>,
+    initial: i64,
+    max_frame: usize,
+    closed: bool,
+}
+
+struct Shared {
+    out: Mutex<TcpStream>,
+    flow: Mutex<Flow>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn frame(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Result<()> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.out.lock().recover().write_all(&frame)
+    }
+
+    fn reset(&self, stream: u32, code: u32) -> Result<()> {
+        self.frame(RST_STREAM, 0, stream, &code.to_be_bytes())
+    }
+
+    fn window_update(&self, stream: u32, increment: usize) -> Result<()> {
+        self.frame(WINDOW_UPDATE, 0, stream, &(increment as u32).to_be_bytes())
+    }
+}
+
+/// the answer to a request, the stream is closed once this is dropped
+pub struct Response {
+    shared: Arc<Shared>,
+    stream: u32,
+}
+
+impl Response {
+    /// sends headers, the last of the response if end, they have to fit in one frame
+    pub fn headers(&self, headers: &[(&str, &str)], end: bool) -> Result<()> {
+        let block = hpack::encode(headers);
+        if block.len() > FRAME_LEN {
+            return Err(protocol("response headers past a frame"));
+        }
+        let flags = END_HEADERS | if end { END_STREAM } else { 0 };
+        self.shared.frame(HEADERS, flags, self.stream, &block)
+    }
+
+    /// sends data once the client has room for it
+    pub fn data(&self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let len = self.reserve(data.len())?;
+            self.shared.frame(DATA, 0, self.stream, &data[..len])?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    // waits until at least one of up to len bytes may be sent, takes them from the windows
+    fn reserve(&self, len: usize) -> Result<usize> {
+        let mut flow = self.shared.flow.lock().recover();
+        loop {
+            if flow.closed {
+                return Err(Error::new(ErrorKind::BrokenPipe, "connection closed"));
+            }
+            let Some(&stream) = flow.streams.get(&self.stream) else {
+                return Err(Error::new(ErrorKind::ConnectionReset, "stream reset"));
+            };
+            let room = flow.window.min(stream).min(flow.max_frame as i64);
+            if room > 0 {
+                let len = room.min(len as i64);
+                flow.window -= len;
+                flow.streams.insert(self.stream, stream - len);
+                return Ok(len as usize);
+            }
+            flow = self.shared.changed.wait(flow).recover();
+        }
+    }
+
+    /// whether the client reset the stream or went away
+    pub fn cancelled(&self) -> bool {
+        let flow = self.shared.flow.lock().recover();
+        flow.closed || !flow.streams.contains_key(&self.stream)
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        self.shared
+            .flow
+            .lock()
+            .recover()
+            .streams
+            .remove(&self.stream);
+    }
+}
+
+/// speaks HTTP/2 on stream until the client goes away, handle gets each request on a thread of
+/// its own
+pub fn serve<F>(stream: TcpStream, handle: Arc<F>) -> Result<()>
+where
+    F: Fn(Request, Response) + Send + Sync + 'static,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut preface = [0; PREFACE.len()];
+    reader.read_exact(&mut preface)?;
+    if &preface != PREFACE {
+        return Err(protocol("not HTTP/2 with prior knowledge"));
+    }
+    let shared = Arc::new(Shared {
+        out: Mutex::new(stream.try_clone()?),
+        flow: Mutex::new(Flow {
+            window: WINDOW,
+            streams: HashMap::new(),
+            initial: WINDOW,
+            max_frame: FRAME_LEN,
+            closed: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let mut settings = Vec::new();
+    for (id, value) in [
+        (SETTINGS_ENABLE_PUSH, 0),
+        (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+    ] {
+        settings.extend_from_slice(&id.to_be_bytes());
+        settings.extend_from_slice(&value.to_be_bytes());
+    }
+    shared.frame(SETTINGS, 0, 0, &settings)?;
+    let mut last_stream = 0;
+    let result = read(&mut reader, &shared, &handle, &mut last_stream);
+    if let Err(e) = &result {
+        if e.kind() == ErrorKind::InvalidData {
+            let mut goaway = last_stream.to_be_bytes().to_vec();
+            goaway.extend_from_slice(&PROTOCOL_ERROR.to_be_bytes());
+            let _ = shared.frame(GOAWAY, 0, 0, &goaway);
+        }
+    }
+    shared.flow.lock().recover().closed = true;
+    shared.changed.notify_all();
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+fn read<F>(
+    reader: &mut impl Read,
+    shared: &Arc<Shared>,
+    handle: &Arc<F>,
+    last_stream: &mut u32,
+) -> Result<()>
+where
+    F: Fn(Request, Response) + Send + Sync + 'static,
+{
+    let mut decoder = hpack::Decoder::default();
+    // streams whose request hasn't ended yet
+    let mut open: HashMap<u32, Request> = HashMap::new();
+    // a header block waiting for the rest of it: its stream, what came so far, whether it ends
+    // the stream
+    let mut continued: Option<(u32, Vec<u8>, bool)> = None;
+    loop {
+        let mut head = [0; 9];
+        match reader.read_exact(&mut head) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let (kind, flags) = (head[3], head[4]);
+        let id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+        if len > FRAME_LEN {
+            return Err(protocol("frame past the size we allow"));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        if continued
+            .as_ref()
+            .is_some_and(|c| kind != CONTINUATION || id != c.0)
+        {
+            return Err(protocol("header block interrupted"));
+        }
+
+        let block = match kind {
+            HEADERS => {
+                let mut fragment = unpad(&payload, flags)?;
+                if flags & PRIORITY != 0 {
+                    fragment = fragment.get(5..).ok_or_else(|| protocol("short HEADERS"))?;
+                }
+                let block = (id, fragment.to_vec(), flags & END_STREAM != 0);
+                if flags & END_HEADERS == 0 {
+                    continued = Some(block);
+                    continue;
+                }
+                block
+            }
+            CONTINUATION => {
+                let Some(mut block) = continued.take() else {
+                    return Err(protocol("CONTINUATION without HEADERS"));
+                };
+                block.1.extend_from_slice(&payload);
+                if block.1.len() > MAX_REQUEST {
+                    return Err(protocol("header block past the size we allow"));
+                }
+                if flags & END_HEADERS == 0 {
+                    continued = Some(block);
+                    continue;
+                }
+                block
+            }
+            DATA => {
+                // what the client sent is ours to read right away, so it may send it again
+                if len > 0 {
+                    shared.window_update(0, len)?;
+                }
+                let Some(request) = open.get_mut(&id) else {
+                    continue;
+                };
+                request.body.extend_from_slice(unpad(&payload, flags)?);
+                if request.body.len() > MAX_REQUEST {
+                    open.remove(&id);
+                    shared.reset(id, REFUSED_STREAM)?;
+                    continue;
+                }
+                if flags & END_STREAM != 0 {
+                    dispatch(Arc::clone(shared), Arc::clone(handle), id, open.remove(&id));
+                } else if len > 0 {
+                    shared.window_update(id, len)?;
+                }
+                continue;
+            }
+            SETTINGS if flags & ACK == 0 => {
+                settings(shared, &payload)?;
+                shared.frame(SETTINGS, ACK, 0, &[])?;
+                continue;
+            }
+            PING if flags & ACK == 0 => {
+                shared.frame(PING, ACK, 0, &payload)?;
+                continue;
+            }
+            WINDOW_UPDATE => {
+                let increment: [u8; 4] = payload
+                    .get(..4)
+                    .and_then(|i| i.try_into().ok())
+                    .ok_or_else(|| protocol("short WINDOW_UPDATE"))?;
+                let increment = (u32::from_be_bytes(increment) & 0x7fff_ffff) as i64;
+                let mut flow = shared.flow.lock().recover();
+                match id {
+                    0 => flow.window += increment,
+                    id => {
+                        if let Some(window) = flow.streams.get_mut(&id) {
+                            *window += increment;
+                        }
+                    }
+                }
+                shared.changed.notify_all();
+                continue;
+            }
+            RST_STREAM => {
+                open.remove(&id);
+                shared.flow.lock().recover().streams.remove(&id);
+                shared.changed.notify_all();
+                continue;
+            }
+            GOAWAY => return Ok(()),
+            // PRIORITY, acks and whatever else there is
+            _ => continue,
+        };
+
+        // every block is decoded, or the client's dynamic table and ours would differ
+        let (id, block, end) = block;
+        let headers = decoder.decode(&block)?;
+        if let Entry::Vacant(entry) = open.entry(id) {
+            if id.is_multiple_of(2) || id <= *last_stream {
+                return Err(protocol("HEADERS on a stream that isn't new"));
+            }
+            *last_stream = id;
+            let mut flow = shared.flow.lock().recover();
+            if flow.streams.len() >= MAX_STREAMS as usize {
+                drop(flow);
+                shared.reset(id, REFUSED_STREAM)?;
+                continue;
+            }
+            let initial = flow.initial;
+            flow.streams.insert(id, initial);
+            let body = Vec::new();
+            entry.insert(Request { headers, body });
+        }
+        // otherwise they are trailers, which we don't need
+        if end {
+            dispatch(Arc::clone(shared), Arc::clone(handle), id, open.remove(&id));
+        }
+    }
+}
+
+fn dispatch<F>(shared: Arc<Shared>, handle: Arc<F>, stream: u32, request: Option<Request>)
+where
+    F: Fn(Request, Response) + Send + Sync + 'static,
+{
+    let Some(request) = request else {
+        return;
+    };
+    thread::spawn(move || handle(request, Response { shared, stream }));
+}
+
+fn settings(shared: &Shared, payload: &[u8]) -> Result<()> {
+    if !payload.len().is_multiple_of(6) {
+        return Err(protocol("SETTINGS of a size that isn't a multiple of 6"));
+    }
+    let mut flow = shared.flow.lock().recover();
+    for setting in payload.chunks(6) {
+        let id = u16::from_be_bytes([setting[0], setting[1]]);
+        let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+        match id {
+            SETTINGS_INITIAL_WINDOW_SIZE => {
+                // moves every open stream's window along with it
+                let delta = value as i64 - flow.initial;
+                flow.initial = value as i64;
+                for window in flow.streams.values_mut() {
+                    *window += delta;
+                }
+            }
+            SETTINGS_MAX_FRAME_SIZE => flow.max_frame = value as usize,
+            _ => {}
+        }
+    }
+    shared.changed.notify_all();
+    Ok(())
+}
+
+// what's in a DATA or HEADERS frame but its padding
+fn unpad(payload: &[u8], flags: u8) -> Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad, rest) = payload
+        .split_first()
+        .ok_or_else(|| protocol("padded frame without its padding length"))?;
+    let len = rest.len().checked_sub(pad as usize);
+    len.map(|len| &rest[..len])
+        .ok_or_else(|| protocol("padding past the frame"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // the next frame that isn't one of the connection's own
+    fn next(client: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+        loop {
+            let mut head = [0; 9];
+            client.read_exact(&mut head).unwrap();
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            let mut payload = vec![0; len];
+            client.read_exact(&mut payload).unwrap();
+            let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+            if !matches!(head[3], SETTINGS | WINDOW_UPDATE) {
+                return (head[3], head[4], stream, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let handle = Arc::new(|request: Request, response: Response| {
+            let path = request.header(":path").unwrap().to_string();
+            response.headers(&[(":status", "200")], false).unwrap();
+            response.data(&request.body).unwrap();
+            response.headers(&[("path", &path)], true).unwrap();
+        });
+        thread::spawn(move || serve(server, handle));
+
+        client.write_all(PREFACE).unwrap();
+        // a window of a single byte per stream
+        let window = [0, 4, 0, 0, 0, 1];
+        client.write_all(&frame(SETTINGS, 0, 0, &window)).unwrap();
+        let block = hpack::encode(&[(":method", "POST"), (":path", "/echo")]);
+        let mut padded = vec![2];
+        padded.extend_from_slice(&block);
+        padded.extend_from_slice(&[0, 0]);
+        client
+            .write_all(&frame(HEADERS, END_HEADERS | PADDED, 1, &padded))
+            .unwrap();
+        client
+            .write_all(&frame(DATA, END_STREAM, 1, b"hi"))
+            .unwrap();
+
+        let (kind, _, stream, payload) = next(&mut client);
+        assert_eq!((kind, stream), (HEADERS, 1));
+        let headers = hpack::Decoder::default().decode(&payload).unwrap();
+        assert_eq!(headers, [(":status".to_string(), "200".to_string())]);
+        assert_eq!(next(&mut client), (DATA, 0, 1, b"h".to_vec()));
+        client
+            .write_all(&frame(WINDOW_UPDATE, 0, 1, &1u32.to_be_bytes()))
+            .unwrap();
+        assert_eq!(next(&mut client), (DATA, 0, 1, b"i".to_vec()));
+        let (kind, flags, _, payload) = next(&mut client);
+        assert_eq!((kind, flags), (HEADERS, END_HEADERS | END_STREAM));
+        let trailers = hpack::Decoder::default().decode(&payload).unwrap();
+        assert_eq!(trailers, [("path".to_string(), "/echo".to_string())]);
+
+        client.write_all(&frame(PING, 0, 0, &[7; 8])).unwrap();
+        assert_eq!(next(&mut client), (PING, ACK, 0, vec![7; 8]));
+    }
+}
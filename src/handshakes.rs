@@ -0,0 +1,178 @@
+// handshake initiations we forwarded that haven't been answered yet, in their own table so they are
+// forgotten once the initiator has given up on them instead of living as long as a session
+//
+// what becomes of them is counted, which gives the handshake success rate
+
+use crate::{
+    clock::{self, Tick},
+    memory,
+    poison::{Recover, TryRecover},
+};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// REKEY-TIMEOUT, an initiator that got no response by then retries with a new sender index
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+// how often timed out handshakes are purged at most
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Handshake {
+    client: SocketAddr,
+    target: SocketAddr,
+    at: Tick,
+}
+
+#[derive(Default)]
+struct Table {
+    // client sender index -> handshake
+    handshakes: HashMap<u32, Handshake>,
+    // client -> sender index of its latest handshake
+    clients: HashMap<SocketAddr, u32>,
+    next_purge: Tick,
+}
+
+#[derive(Default)]
+pub struct Pending {
+    table: Mutex<Table>,
+    pub initiated: AtomicU64,
+    pub completed: AtomicU64,
+    pub timed_out: AtomicU64,
+}
+
+impl Pending {
+    /// we forwarded an initiation with sender from client to target at now
+    pub fn initiated(&self, sender: u32, client: SocketAddr, target: SocketAddr, now: Tick) {
+        self.initiated.fetch_add(1, Relaxed);
+        let mut table = self.table.lock().recover();
+        if now >= table.next_purge {
+            self.purge(&mut table, now);
+            table.next_purge = now + clock::ticks(PURGE_INTERVAL);
+        }
+        let handshake = Handshake {
+            client,
+            target,
+            at: now,
+        };
+        table.handshakes.insert(sender, handshake);
+        table.clients.insert(client, sender);
+    }
+
+    /// whether a response from target to receiver answers a handshake that is still pending at
+    /// now, it no longer is then
+    pub fn answered(&self, receiver: u32, target: SocketAddr, now: Tick) -> bool {
+        let mut table = self.table.lock().recover();
+        let answers = table
+            .handshakes
+            .get(&receiver)
+            .is_some_and(|h| h.target == target && !timed_out(h, now));
+        if answers {
+            table.handshakes.remove(&receiver);
+            self.completed.fetch_add(1, Relaxed);
+        }
+        answers
+    }
+
+    /// whether a cookie reply from target to receiver is for a handshake that is still pending at
+    /// now, it stays pending, the initiator retries with the cookie
+    pub fn cookie(&self, receiver: u32, target: SocketAddr, now: Tick) -> bool {
+        let table = self.table.lock().recover();
+        table
+            .handshakes
+            .get(&receiver)
+            .is_some_and(|h| h.target == target && !timed_out(h, now))
+    }
+
+    /// whether client has a handshake pending at now
+    pub fn outstanding(&self, client: &SocketAddr, now: Tick) -> bool {
+        let table = self.table.lock().recover();
+        table
+            .clients
+            .get(client)
+            .and_then(|sender| table.handshakes.get(sender))
+            .is_some_and(|h| h.client == *client && !timed_out(h, now))
+    }
+
+    /// how many handshakes are pending at now
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn count(&self, now: Tick) -> usize {
+        let mut table = self.table.lock().recover();
+        self.purge(&mut table, now);
+        table.handshakes.len()
+    }
+
+    /// how many handshakes we have, timed out or not, None while someone has them locked
+    pub fn try_len(&self) -> Option<usize> {
+        self.table
+            .try_lock()
+            .try_recover()
+            .map(|table| table.handshakes.len())
+    }
+
+    /// approximately what the handshakes pending at now take
+    pub fn memory(&self, now: Tick) -> usize {
+        let mut table = self.table.lock().recover();
+        self.purge(&mut table, now);
+        memory::table::<u32, Handshake>(table.handshakes.len())
+            + memory::table::<SocketAddr, u32>(table.clients.len())
+    }
+
+    fn purge(&self, table: &mut Table, now: Tick) {
+        let before = table.handshakes.len();
+        table.handshakes.retain(|_, h| !timed_out(h, now));
+        let Table {
+            handshakes,
+            clients,
+            ..
+        } = table;
+        clients.retain(|_, sender| handshakes.contains_key(sender));
+        self.timed_out
+            .fetch_add((before - handshakes.len()) as u64, Relaxed);
+    }
+}
+
+fn timed_out(handshake: &Handshake, now: Tick) -> bool {
+    now.saturating_sub(handshake.at) >= clock::ticks(TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending() {
+        let client = "192.0.2.10:1".parse().unwrap();
+        let target = "192.0.2.2:1".parse().unwrap();
+        let elsewhere = "192.0.2.3:1".parse().unwrap();
+        let pending = Pending::default();
+        let timeout = clock::ticks(TIMEOUT);
+
+        pending.initiated(1, client, target, 0);
+        assert!(pending.outstanding(&client, timeout - 1));
+        assert!(pending.cookie(1, target, 1));
+        assert!(!pending.cookie(1, elsewhere, 1));
+        assert!(!pending.cookie(2, target, 1));
+        assert!(!pending.answered(1, elsewhere, 1));
+        assert!(pending.answered(1, target, 1));
+        assert!(!pending.answered(1, target, 2));
+        assert!(!pending.outstanding(&client, 2));
+
+        pending.initiated(2, client, target, 1);
+        assert!(!pending.outstanding(&client, timeout + 1));
+        assert!(!pending.answered(2, target, timeout + 1));
+        // purged by the next one
+        pending.initiated(3, client, target, timeout + 1);
+        assert_eq!(pending.count(timeout + 1), 1);
+        assert_eq!(pending.initiated.load(Relaxed), 3);
+        assert_eq!(pending.completed.load(Relaxed), 1);
+        assert_eq!(pending.timed_out.load(Relaxed), 1);
+    }
+}
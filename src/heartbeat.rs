@@ -0,0 +1,167 @@
+// --heartbeat, a line every so often saying how we are doing, for whoever tails our log on a
+// router with nothing to scrape --metrics: rates are since the last line, drops by reason only
+// the reasons there were any for, and the top talker is the client the target sent most to
+//
+// --heartbeat-fields picks and orders what's on it, after the address of the listener it's for
+
+use crate::{args, error};
+
+use std::{net::SocketAddr, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Sessions,
+    Handshakes,
+    Pps,
+    Bps,
+    Drops,
+    Top,
+    Memory,
+}
+
+/// what a line has without --heartbeat-fields
+pub const DEFAULT_FIELDS: [Field; 4] = [Field::Sessions, Field::Pps, Field::Drops, Field::Top];
+
+const NAMES: [(&str, Field); 7] = [
+    ("sessions", Field::Sessions),
+    ("handshakes", Field::Handshakes),
+    ("pps", Field::Pps),
+    ("bps", Field::Bps),
+    ("drops", Field::Drops),
+    ("top", Field::Top),
+    ("memory", Field::Memory),
+];
+
+/// --heartbeat-fields' value, comma separated field names
+pub fn parse(fields: &str) -> error::Result<Vec<Field>> {
+    fields
+        .split(',')
+        .map(|name| {
+            NAMES
+                .iter()
+                .find(|(n, _)| *n == name.trim())
+                .map(|(_, field)| *field)
+                .ok_or_else(|| {
+                    args::invalid(format!(
+                        "invalid --heartbeat-fields: {}, not one of sessions, handshakes, pps, bps, drops, top or memory",
+                        name
+                    ))
+                })
+        })
+        .collect()
+}
+
+/// the running totals a line is the difference of
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Sample {
+    pub sessions: usize,
+    pub handshakes: usize,
+    /// what the tables that grow with clients take, in bytes
+    pub memory: usize,
+    /// packets and bytes to the target and to clients
+    pub packets: (u64, u64),
+    pub bytes: (u64, u64),
+    /// packets dropped so far, by reason
+    pub drops: Vec<(&'static str, u64)>,
+}
+
+/// the line for listener's fields, now since last every apart, top with how many packets it got
+/// since
+pub fn line(
+    listener: &str,
+    fields: &[Field],
+    last: &Sample,
+    now: &Sample,
+    every: Duration,
+    top: Option<(SocketAddr, u64)>,
+) -> String {
+    let secs = every.as_secs_f64().max(0.001);
+    let rate = |now: u64, last: u64| (now.saturating_sub(last) as f64 / secs).round() as u64;
+    let mut line = format!("heartbeat {}", listener);
+    for field in fields {
+        let part = match field {
+            Field::Sessions => format!("sessions {}", now.sessions),
+            Field::Handshakes => format!("handshakes {}", now.handshakes),
+            Field::Memory => format!("memory {}k", now.memory.div_ceil(1024)),
+            Field::Pps => format!(
+                "pps {}/{}",
+                rate(now.packets.0, last.packets.0),
+                rate(now.packets.1, last.packets.1)
+            ),
+            Field::Bps => format!(
+                "bps {}/{}",
+                rate(now.bytes.0, last.bytes.0) * 8,
+                rate(now.bytes.1, last.bytes.1) * 8
+            ),
+            Field::Drops => {
+                let drops: Vec<String> = now
+                    .drops
+                    .iter()
+                    .map(|(reason, count)| {
+                        let before = last.drops.iter().find(|(r, _)| r == reason);
+                        (reason, count.saturating_sub(before.map_or(0, |(_, c)| *c)))
+                    })
+                    .filter(|(_, count)| *count > 0)
+                    .map(|(reason, count)| format!("{}={}", reason, count))
+                    .collect();
+                match drops.is_empty() {
+                    true => "drops 0".to_string(),
+                    false => format!("drops {}", drops.join(",")),
+                }
+            }
+            Field::Top => match top {
+                Some((client, packets)) if packets > 0 => {
+                    format!("top {} {}pps", client, rate(packets, 0))
+                }
+                _ => "top none".to_string(),
+            },
+        };
+        line.push(' ');
+        line.push_str(&part);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        assert_eq!(
+            parse("top, sessions").unwrap(),
+            vec![Field::Top, Field::Sessions]
+        );
+        assert!(parse("sessions,load").is_err());
+
+        let last = Sample {
+            packets: (100, 50),
+            drops: vec![("invalid", 4), ("unknown_receiver", 1)],
+            ..Sample::default()
+        };
+        let now = Sample {
+            sessions: 3,
+            packets: (400, 350),
+            bytes: (1000, 0),
+            drops: vec![("invalid", 24), ("unknown_receiver", 1)],
+            ..Sample::default()
+        };
+        let every = Duration::from_secs(10);
+        let client = "192.0.2.1:51820".parse().unwrap();
+        assert_eq!(
+            line("0.0.0.0:5678", &DEFAULT_FIELDS, &last, &now, every, Some((client, 200))),
+            "heartbeat 0.0.0.0:5678 sessions 3 pps 30/30 drops invalid=20 top 192.0.2.1:51820 20pps"
+        );
+        assert_eq!(
+            line(
+                "[::]:51820",
+                &[Field::Drops, Field::Bps, Field::Top],
+                &now,
+                &now,
+                every,
+                None
+            ),
+            "heartbeat [::]:51820 drops 0 bps 0/0 top none"
+        );
+    }
+}
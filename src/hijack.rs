@@ -0,0 +1,137 @@
+// notices a session's return traffic being claimed by one address while another still uses it
+//
+// wireguard picks a fresh random sender index for every handshake, so one reusing the index of a
+// session from another address is a replayed initiation or a broken client, and --sender-collision
+// overwrite hands the session to it, a client that roamed stops sending from where it was, one
+// that still does within WINDOW after means two endpoints use the same receiver index
+
+use crate::{
+    clock::{self, Tick},
+    poison::Recover,
+};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+pub const WINDOW: Duration = Duration::from_secs(30);
+
+// takeovers we remember, beyond that new ones aren't watched
+const MAX_MOVES: usize = 4096;
+
+// how often takeovers older than WINDOW are forgotten at most
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+struct Moved {
+    receiver: u32,
+    to: SocketAddr,
+    at: Tick,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    /// by the address the session moved away from
+    moves: HashMap<SocketAddr, Moved>,
+    next_purge: Tick,
+}
+
+/// the addresses sessions were taken away from recently
+#[derive(Debug, Default)]
+pub struct Moves {
+    table: Mutex<Table>,
+    // so packets don't take the lock while nothing moved
+    len: AtomicUsize,
+}
+
+impl Moves {
+    /// whether any session moved recently, checking before check is cheap
+    pub fn any(&self) -> bool {
+        self.len.load(Relaxed) > 0
+    }
+
+    /// a handshake from to took the session with receiver over from from at now
+    pub fn moved(&self, receiver: u32, from: SocketAddr, to: SocketAddr, now: Tick) {
+        let moves = &mut self.table.lock().recover().moves;
+        if moves.len() < MAX_MOVES || moves.contains_key(&from) {
+            moves.insert(
+                from,
+                Moved {
+                    receiver,
+                    to,
+                    at: now,
+                },
+            );
+        }
+        self.len.store(moves.len(), Relaxed);
+    }
+
+    /// src sent a packet for the session with receiver at now, the address the session moved to
+    /// if that's where it moved away from within WINDOW, once per takeover
+    pub fn check(&self, src: &SocketAddr, receiver: u32, now: Tick) -> Option<SocketAddr> {
+        let mut table = self.table.lock().recover();
+        if now >= table.next_purge {
+            table.moves.retain(|_, moved| recent(moved, now));
+            table.next_purge = now + clock::ticks(PURGE_INTERVAL);
+        }
+        let moves = &mut table.moves;
+        let hijacked = moves
+            .get(src)
+            .filter(|moved| moved.receiver == receiver && recent(moved, now))
+            .map(|moved| moved.to);
+        if hijacked.is_some() {
+            moves.remove(src);
+        }
+        self.len.store(moves.len(), Relaxed);
+        hijacked
+    }
+}
+
+fn recent(moved: &Moved, now: Tick) -> bool {
+    now.saturating_sub(moved.at) < clock::ticks(WINDOW)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moves() {
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let attacker: SocketAddr = "192.0.2.66:1000".parse().unwrap();
+        let moves = Moves::default();
+        assert!(!moves.any());
+        moves.moved(1, client, attacker, 0);
+        assert!(moves.any());
+        // another session, another address
+        assert_eq!(moves.check(&client, 2, 1), None);
+        assert_eq!(moves.check(&attacker, 1, 1), None);
+        assert_eq!(moves.check(&client, 1, 1), Some(attacker));
+        assert_eq!(moves.check(&client, 1, 1), None);
+        assert!(!moves.any());
+
+        // the client roamed and is gone for good
+        moves.moved(1, client, attacker, 0);
+        assert_eq!(moves.check(&client, 1, clock::ticks(WINDOW)), None);
+        assert!(!moves.any());
+
+        // each forgotten by the first purge once older than WINDOW
+        let now = clock::ticks(WINDOW);
+        moves.moved(1, client, attacker, now);
+        moves.moved(2, attacker, client, now - 1);
+        let later = now + clock::ticks(WINDOW) - 1;
+        assert_eq!(moves.check(&attacker, 2, later), None);
+        assert!(moves.any());
+        assert_eq!(
+            moves.check(&client, 1, later + clock::ticks(PURGE_INTERVAL)),
+            None
+        );
+        assert!(!moves.any());
+    }
+}
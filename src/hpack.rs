@@ -0,0 +1,336 @@
+// HPACK, the header compression of HTTP/2, for --grpc: whatever a client's encoder sends is
+// decoded, our own headers are encoded the simplest way there is, as literals nobody indexes
+//
+// the huffman code strings may be in is canonical, so the length of each symbol's code is all we
+// keep of it
+
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Result},
+};
+
+// the dynamic table size we never raise from the default, so a client can't make us keep more
+const TABLE_SIZE: usize = 4096;
+
+// what each entry counts for in the table besides its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// the length of the huffman code of every byte and then EOS
+const CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+
+const EOS: u16 = 256;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("hpack: {}", msg))
+}
+
+/// the state a connection's header blocks are decoded with, they have to come in order
+#[derive(Default)]
+pub struct Decoder {
+    /// newest first
+    table: VecDeque<(String, String)>,
+    size: usize,
+}
+
+impl Decoder {
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0xe0 == 0x20 {
+                if integer(&mut block, 5)? > TABLE_SIZE {
+                    return Err(invalid("table size past what we allow"));
+                }
+                // we keep to TABLE_SIZE anyway, a client asking for less can't tell
+            } else {
+                // with incremental indexing, or without, or never indexed
+                let indexing = first & 0x40 != 0;
+                let index = integer(&mut block, if indexing { 6 } else { 4 })?;
+                let name = match index {
+                    0 => string(&mut block)?,
+                    index => self.entry(index)?.0,
+                };
+                let header = (name, string(&mut block)?);
+                if indexing {
+                    self.insert(header.clone());
+                }
+                headers.push(header);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String)> {
+        match index {
+            0 => Err(invalid("index 0")),
+            1..=61 => {
+                let (name, value) = STATIC[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            index => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| invalid("index past the table")),
+        }
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        self.size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.table.push_front(header);
+        while self.size > TABLE_SIZE {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// a header block of literals nobody indexes, without huffman
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        block.push(0);
+        for s in [name, value] {
+            put_integer(&mut block, 0, 7, s.len());
+            block.extend_from_slice(s.as_bytes());
+        }
+    }
+    block
+}
+
+/// an integer with a prefix of bits, what's before them in the first byte is ignored
+fn integer(buf: &mut &[u8], bits: u32) -> Result<usize> {
+    let max = (1 << bits) - 1;
+    let (&first, rest) = buf.split_first().ok_or_else(|| invalid("truncated"))?;
+    *buf = rest;
+    let mut n = (first as usize) & max;
+    if n < max {
+        return Ok(n);
+    }
+    for shift in (0..28).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or_else(|| invalid("truncated"))?;
+        *buf = rest;
+        n += ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("integer too large"))
+}
+
+fn put_integer(out: &mut Vec<u8>, flags: u8, bits: u32, mut n: usize) {
+    let max = (1 << bits) - 1;
+    if n < max {
+        out.push(flags | n as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    n -= max;
+    while n >= 0x80 {
+        out.push(0x80 | (n & 0x7f) as u8);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn string(buf: &mut &[u8]) -> Result<String> {
+    let huffman = buf.first().is_some_and(|first| first & 0x80 != 0);
+    let len = integer(buf, 7)?;
+    if len > buf.len() {
+        return Err(invalid("truncated"));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    let bytes = match huffman {
+        true => decode_huffman(bytes)?,
+        false => bytes.to_vec(),
+    };
+    String::from_utf8(bytes).map_err(|_| invalid("header that isn't utf-8"))
+}
+
+/// the canonical code CODE_LENGTHS describe: symbols sorted by the length of their code, and for
+/// each length the first code of it and where its symbols start
+struct Code {
+    symbols: Vec<u16>,
+    first: [u32; 31],
+    start: [usize; 31],
+    count: [usize; 31],
+}
+
+fn code() -> Code {
+    let mut symbols: Vec<u16> = (0..=EOS).collect();
+    symbols.sort_by_key(|&symbol| (CODE_LENGTHS[symbol as usize], symbol));
+    let mut code = Code {
+        symbols,
+        first: [0; 31],
+        start: [0; 31],
+        count: [0; 31],
+    };
+    let (mut next, mut len) = (0u32, CODE_LENGTHS[code.symbols[0] as usize]);
+    for (i, &symbol) in code.symbols.iter().enumerate() {
+        let this = CODE_LENGTHS[symbol as usize];
+        next <<= this - len;
+        len = this;
+        if code.count[len as usize] == 0 {
+            code.first[len as usize] = next;
+            code.start[len as usize] = i;
+        }
+        code.count[len as usize] += 1;
+        next += 1;
+    }
+    code
+}
+
+fn decode_huffman(bytes: &[u8]) -> Result<Vec<u8>> {
+    let code = code();
+    let mut out = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut bits, mut len) = (0u32, 0usize);
+    for byte in bytes {
+        for shift in (0..8).rev() {
+            bits = (bits << 1) | ((byte >> shift) & 1) as u32;
+            len += 1;
+            if len > 30 {
+                return Err(invalid("invalid huffman code"));
+            }
+            let offset = bits.wrapping_sub(code.first[len]) as usize;
+            if code.count[len] == 0 || offset >= code.count[len] {
+                continue;
+            }
+            match code.symbols[code.start[len] + offset] {
+                EOS => return Err(invalid("EOS in a string")),
+                symbol => out.push(symbol as u8),
+            }
+            (bits, len) = (0, 0);
+        }
+    }
+    // what's left is padding, the start of EOS, which is all ones
+    if len > 7 || bits != (1 << len) - 1 {
+        return Err(invalid("invalid huffman padding"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // the requests of RFC 7541 C.4, huffman and with the dynamic table
+        let mut decoder = Decoder::default();
+        let first = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        let headers = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            let list = list.iter();
+            list.map(|(n, v)| (n.to_string(), v.to_string())).collect()
+        };
+        let request = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ];
+        assert_eq!(decoder.decode(&first).unwrap(), headers(&request));
+        let second = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        let mut request = request.to_vec();
+        request.push(("cache-control", "no-cache"));
+        assert_eq!(decoder.decode(&second).unwrap(), headers(&request));
+        assert!(decoder.decode(&[0xc0]).is_err());
+        // padding that isn't the start of EOS
+        assert!(decode_huffman(&[
+            0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0x00
+        ])
+        .is_err());
+
+        let long = "x".repeat(300);
+        let block = encode(&[(":status", "200"), ("grpc-message", &long)]);
+        let decoded = Decoder::default().decode(&block).unwrap();
+        assert_eq!(
+            decoded,
+            headers(&[(":status", "200"), ("grpc-message", &long)])
+        );
+    }
+}
@@ -0,0 +1,537 @@
+// --icmp-target and --icmp-clients, an experimental last resort for networks that let pings out
+// and little else: packets to the target ride in ICMP echo requests to the far end, and packets
+// back in its replies to them, each after a header of our own:
+//
+//     <magic, direction, session, pending> <packet>
+//
+// only the near end can start an exchange, so like the DNS tunnel it polls: right away while the
+// far end says more is waiting, backing off to POLL_MAX while nothing comes, with at most window
+// requests unanswered, one more for each that is answered and half as many once one times out
+//
+// sequence numbers are ours, one per request, the far end echoes them with the id in its reply
+// so NATs on the way match it to the request, replies for requests that timed out are dropped like
+// late duplicates, and so are the kernel's own replies, which carry our request's direction
+//
+// nothing is split, a packet that doesn't fit an echo within --icmp-mtu is dropped, wireguard's
+// mtu should leave room for the IP and ICMP headers and ours
+
+use crate::{decoy, packet::bytes, platform::Ping, poison::Recover, WgPacket};
+
+use std::{
+    collections::{
+        hash_map::{Entry, RandomState},
+        HashMap, VecDeque,
+    },
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::Result,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_MTU: usize = 1500;
+
+const POLL_MIN: Duration = Duration::from_millis(10);
+const POLL_MAX: Duration = Duration::from_secs(1);
+
+// a request unanswered for this long is lost
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const WINDOW_MAX: usize = 32;
+
+// a session nothing went through for this long is forgotten
+const IDLE_TIME: Duration = Duration::from_secs(60);
+
+// packets waiting to be sent either way, beyond that they are dropped
+const QUEUE_LEN: usize = 256;
+
+const MAGIC: [u8; 4] = *b"wgIC";
+const UP: u8 = 1;
+const DOWN: u8 = 2;
+
+const ICMP_LEN: usize = 8;
+// magic, direction, session and pending
+const HEADER_LEN: usize = 10;
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    direction: u8,
+    session: u32,
+    /// packets the far end has waiting after this one
+    pending: u8,
+}
+
+/// an echo in one of ours
+#[derive(Debug, PartialEq)]
+struct Echo<'a> {
+    id: u16,
+    seq: u16,
+    header: Header,
+    packet: &'a [u8],
+}
+
+/// bytes of a packet an echo within mtu to or from ip has room for
+fn room(mtu: usize, ip: IpAddr) -> usize {
+    let ip_header = if ip.is_ipv6() { 40 } else { 20 };
+    mtu.saturating_sub(ip_header + ICMP_LEN + HEADER_LEN)
+}
+
+/// an echo request, or reply, to ip, the kernel does the ICMPv6 checksum and that of unprivileged
+/// ICMP sockets, which pick the id too
+fn echo(ip: IpAddr, reply: bool, echo: &Echo) -> Vec<u8> {
+    let mut msg = vec![0u8; ICMP_LEN];
+    msg[0] = match (ip.is_ipv6(), reply) {
+        (false, false) => 8,
+        (false, true) => 0,
+        (true, false) => 128,
+        (true, true) => 129,
+    };
+    msg[4..6].copy_from_slice(&echo.id.to_be_bytes());
+    msg[6..8].copy_from_slice(&echo.seq.to_be_bytes());
+    msg.extend_from_slice(&MAGIC);
+    msg.push(echo.header.direction);
+    msg.extend_from_slice(&echo.header.session.to_be_bytes());
+    msg.push(echo.header.pending);
+    msg.extend_from_slice(echo.packet);
+    if ip.is_ipv4() {
+        let sum = decoy::checksum(&msg);
+        msg[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    msg
+}
+
+/// one of ours going direction, requests up and replies down, not anyone else's ping
+fn parse(icmp: &[u8], direction: u8) -> Option<Echo<'_>> {
+    let types = if direction == UP { [8, 128] } else { [0, 129] };
+    if icmp.len() < ICMP_LEN + HEADER_LEN
+        || !types.contains(&icmp[0])
+        || icmp[1] != 0
+        || icmp[8..12] != MAGIC
+        || icmp[12] != direction
+    {
+        return None;
+    }
+    Some(Echo {
+        id: u16::from_be_bytes(bytes(icmp, 4)),
+        seq: u16::from_be_bytes(bytes(icmp, 6)),
+        header: Header {
+            direction,
+            session: u32::from_be_bytes(bytes(icmp, 13)),
+            pending: icmp[17],
+        },
+        packet: &icmp[ICMP_LEN + HEADER_LEN..],
+    })
+}
+
+fn random() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+struct NearSession {
+    client: SocketAddr,
+    /// packets for the target waiting for room in the window
+    upload: VecDeque<Vec<u8>>,
+    poll_interval: Duration,
+    next_poll: Instant,
+    active: Instant,
+}
+
+struct NearState {
+    sessions: HashMap<u32, NearSession>,
+    by_client: HashMap<SocketAddr, u32>,
+    /// the session and when of each request not answered yet, by seq
+    unanswered: HashMap<u16, (u32, Instant)>,
+    window: usize,
+    seq: u16,
+}
+
+/// the end wireguard clients talk to, tunneling them in echo requests to far
+pub struct Near {
+    local: UdpSocket,
+    ping: Ping,
+    far: IpAddr,
+    mtu: usize,
+    verbose: bool,
+    state: Mutex<NearState>,
+}
+
+impl Near {
+    pub fn new(local: UdpSocket, far: IpAddr, mtu: usize, verbose: bool) -> Result<Near> {
+        Ok(Near {
+            local,
+            ping: Ping::open(far.is_ipv6())?,
+            far,
+            mtu,
+            verbose,
+            state: Mutex::new(NearState {
+                sessions: HashMap::new(),
+                by_client: HashMap::new(),
+                unanswered: HashMap::new(),
+                window: 4,
+                seq: random() as u16,
+            }),
+        })
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        thread::spawn(move || self.replies());
+        thread::spawn(move || loop {
+            thread::sleep(POLL_MIN);
+            self.tick(Instant::now());
+        });
+        let room = room(self.mtu, self.far);
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, client) = self.local.recv_from(&mut buf)?;
+            let packet = &buf[..recv];
+            if WgPacket::parse(packet).is_none() {
+                continue;
+            }
+            if packet.len() > room {
+                if self.verbose {
+                    eprintln!(
+                        "dropping a {} byte packet from {}, an echo within --icmp-mtu {} has room for {}",
+                        packet.len(),
+                        client,
+                        self.mtu,
+                        room
+                    );
+                }
+                continue;
+            }
+            let now = Instant::now();
+            let mut state = self.state.lock().recover();
+            let state = &mut *state;
+            let id = match state.by_client.get(&client) {
+                Some(id) => *id,
+                None => {
+                    let id = random();
+                    if self.verbose {
+                        eprintln!("icmp tunnel session {:08x} for {}", id, client);
+                    }
+                    state.by_client.insert(client, id);
+                    state.sessions.insert(
+                        id,
+                        NearSession {
+                            client,
+                            upload: VecDeque::new(),
+                            poll_interval: POLL_MIN,
+                            next_poll: now,
+                            active: now,
+                        },
+                    );
+                    id
+                }
+            };
+            if let Some(session) = state.sessions.get_mut(&id) {
+                session.active = now;
+                session.poll_interval = POLL_MIN;
+                if session.upload.len() < QUEUE_LEN {
+                    session.upload.push_back(packet.to_vec());
+                }
+            }
+            self.pump(state, now);
+        }
+    }
+
+    /// sends requests, with what's waiting to go to the target or to poll, while the window has
+    /// room
+    fn pump(&self, state: &mut NearState, now: Instant) {
+        while state.unanswered.len() < state.window {
+            let next = state
+                .sessions
+                .iter()
+                .find(|(_, session)| !session.upload.is_empty())
+                .or_else(|| {
+                    state
+                        .sessions
+                        .iter()
+                        .find(|(_, session)| session.next_poll <= now)
+                })
+                .map(|(id, _)| *id);
+            let Some((id, session)) = next.and_then(|id| Some((id, state.sessions.get_mut(&id)?)))
+            else {
+                return;
+            };
+            state.seq = state.seq.wrapping_add(1);
+            let packet = session.upload.pop_front().unwrap_or_default();
+            session.next_poll = now + session.poll_interval;
+            state.unanswered.insert(state.seq, (id, now));
+            let request = Echo {
+                id: std::process::id() as u16,
+                seq: state.seq,
+                header: Header {
+                    direction: UP,
+                    session: id,
+                    pending: 0,
+                },
+                packet: &packet,
+            };
+            // a lost request is a lost packet, wireguard copes
+            let _ = self.ping.send(&echo(self.far, false, &request), self.far);
+        }
+    }
+
+    /// times out requests and forgets idle sessions
+    fn tick(&self, now: Instant) {
+        let mut state = self.state.lock().recover();
+        let before = state.unanswered.len();
+        state
+            .unanswered
+            .retain(|_, (_, sent)| now.duration_since(*sent) < REPLY_TIMEOUT);
+        if state.unanswered.len() < before {
+            state.window = (state.window / 2).max(1);
+        }
+        let NearState {
+            sessions,
+            by_client,
+            ..
+        } = &mut *state;
+        sessions.retain(|_, session| {
+            let active = now.duration_since(session.active) < IDLE_TIME;
+            if !active {
+                by_client.remove(&session.client);
+            }
+            active
+        });
+        self.pump(&mut state, now);
+    }
+
+    /// passes what replies carry to the clients
+    fn replies(&self) {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, from) = match self.ping.recv(&mut buf, POLL_MAX) {
+                Ok(Some(received)) => received,
+                Ok(None) | Err(_) => continue,
+            };
+            if from != self.far {
+                continue;
+            }
+            let Some(reply) = parse(&buf[..recv], DOWN) else {
+                continue;
+            };
+            let now = Instant::now();
+            let mut state = self.state.lock().recover();
+            if state.unanswered.remove(&reply.seq).is_none() {
+                continue;
+            }
+            state.window = (state.window + 1).min(WINDOW_MAX);
+            let Some(session) = state.sessions.get_mut(&reply.header.session) else {
+                continue;
+            };
+            if reply.packet.is_empty() && reply.header.pending == 0 {
+                session.poll_interval = (session.poll_interval * 2).min(POLL_MAX);
+            } else {
+                session.poll_interval = POLL_MIN;
+                session.active = now;
+            }
+            if reply.header.pending > 0 {
+                session.next_poll = now;
+            }
+            if !reply.packet.is_empty() {
+                let _ = self.local.send_to(reply.packet, session.client);
+            }
+            self.pump(&mut state, now);
+        }
+    }
+}
+
+struct FarSession {
+    socket: Arc<UdpSocket>,
+    /// packets from the target waiting for a request to reply to with
+    download: VecDeque<Vec<u8>>,
+    /// what fits a reply to the near end
+    room: usize,
+    active: Instant,
+}
+
+/// answers echo requests of ours, passing what they carry to target and its packets back
+pub struct Far {
+    v4: Ping,
+    v6: Option<Ping>,
+    target: SocketAddr,
+    mtu: usize,
+    verbose: bool,
+    sessions: Mutex<HashMap<u32, FarSession>>,
+}
+
+impl Far {
+    pub fn new(target: SocketAddr, mtu: usize, verbose: bool) -> Result<Far> {
+        // the kernel answers too, with our request's payload, the near end drops those but they
+        // cost the same again in bandwidth
+        let ignored = fs::read_to_string("/proc/sys/net/ipv4/icmp_echo_ignore_all");
+        if ignored.is_ok_and(|ignored| ignored.trim() == "0") {
+            eprintln!(
+                "the kernel answers pings besides us, sysctl net.ipv4.icmp_echo_ignore_all=1 stops it"
+            );
+        }
+        Ok(Far {
+            v4: Ping::open_raw(false)?,
+            v6: Ping::open_raw(true).ok(),
+            target,
+            mtu,
+            verbose,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        if let Some(v6) = &self.v6 {
+            thread::spawn(move || self.serve(v6));
+        }
+        self.serve(&self.v4)
+    }
+
+    fn serve(&'static self, ping: &Ping) -> Result<()> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let Some((recv, from)) = ping.recv(&mut buf, POLL_MAX)? else {
+                continue;
+            };
+            let Some(request) = parse(&buf[..recv], UP) else {
+                continue;
+            };
+            let (pending, packet) = self.exchange(&request, from)?;
+            let reply = Echo {
+                id: request.id,
+                seq: request.seq,
+                header: Header {
+                    direction: DOWN,
+                    session: request.header.session,
+                    pending,
+                },
+                packet: &packet,
+            };
+            let _ = ping.send(&echo(from, true, &reply), from);
+        }
+    }
+
+    /// passes on what request carries, and returns what to reply with
+    fn exchange(&'static self, request: &Echo, from: IpAddr) -> Result<(u8, Vec<u8>)> {
+        let now = Instant::now();
+        let id = request.header.session;
+        let mut sessions = self.sessions.lock().recover();
+        let session = match sessions.entry(id) {
+            Entry::Occupied(session) => session.into_mut(),
+            Entry::Vacant(vacant) => {
+                let socket = UdpSocket::bind(if self.target.is_ipv4() {
+                    SocketAddr::from(([0, 0, 0, 0], 0))
+                } else {
+                    SocketAddr::from(([0u16; 8], 0))
+                })?;
+                socket.connect(self.target)?;
+                // often enough to notice the session went idle
+                socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+                let socket = Arc::new(socket);
+                let from_target = Arc::clone(&socket);
+                thread::spawn(move || self.download(id, &from_target));
+                if self.verbose {
+                    eprintln!("icmp tunnel session {:08x} from {}", id, from);
+                }
+                vacant.insert(FarSession {
+                    socket,
+                    download: VecDeque::new(),
+                    room: room(self.mtu, from),
+                    active: now,
+                })
+            }
+        };
+        session.active = now;
+        if !request.packet.is_empty() {
+            // the target being down is its business
+            let _ = session.socket.send(request.packet);
+        }
+        let packet = session.download.pop_front().unwrap_or_default();
+        Ok((session.download.len().min(255) as u8, packet))
+    }
+
+    /// queues what the target sends on socket for session's requests, until it's idle
+    fn download(&self, session: u32, socket: &UdpSocket) {
+        let mut buf = [0u8; 65536];
+        loop {
+            let received = socket.recv(&mut buf);
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().recover();
+            let Some(far) = sessions.get_mut(&session) else {
+                return;
+            };
+            match received {
+                Ok(recv) if recv > far.room => {
+                    if self.verbose {
+                        eprintln!(
+                            "dropping a {} byte packet from {}, an echo within --icmp-mtu {} has room for {}",
+                            recv, self.target, self.mtu, far.room
+                        );
+                    }
+                }
+                Ok(recv) => {
+                    if far.download.len() < QUEUE_LEN {
+                        far.download.push_back(buf[..recv].to_vec());
+                    }
+                }
+                Err(_) if now.duration_since(far.active) >= IDLE_TIME => {
+                    sessions.remove(&session);
+                    return;
+                }
+                // timed out, or the target refusing an earlier packet
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo() {
+        let v4 = IpAddr::from([192, 0, 2, 1]);
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(room(1500, v4), 1462);
+        assert_eq!(room(1500, v6), 1442);
+        assert_eq!(room(20, v4), 0);
+
+        let request = Echo {
+            id: 7,
+            seq: 0xfffe,
+            header: Header {
+                direction: UP,
+                session: 0xdeadbeef,
+                pending: 0,
+            },
+            packet: &[4, 0, 0, 0, 9],
+        };
+        let msg = echo(v4, false, &request);
+        assert_eq!(msg[0], 8);
+        assert_eq!(decoy::checksum(&msg), 0);
+        assert_eq!(parse(&msg, UP), Some(request));
+        // the kernel's reply to it is no reply of ours
+        let mut kernel = msg.clone();
+        kernel[0] = 0;
+        assert_eq!(parse(&kernel, DOWN), None);
+        // nor is anyone else's ping
+        let mut ping = msg;
+        ping[8..12].copy_from_slice(b"abcd");
+        assert_eq!(parse(&ping, UP), None);
+
+        let reply = Echo {
+            id: 7,
+            seq: 0xfffe,
+            header: Header {
+                direction: DOWN,
+                session: 0xdeadbeef,
+                pending: 3,
+            },
+            packet: &[],
+        };
+        let msg = echo(v6, true, &reply);
+        assert_eq!(msg[0], 129);
+        assert_eq!(msg.len(), ICMP_LEN + HEADER_LEN);
+        assert_eq!(parse(&msg, DOWN), Some(reply));
+        assert_eq!(parse(&msg[..ICMP_LEN + 4], DOWN), None);
+    }
+}
@@ -0,0 +1,188 @@
+// --allow-servers and --allow-proxies, who we relay for by wireguard identity rather than address,
+// for an open relay several tenants share
+//
+// an initiation's mac1 is keyed with the public key of the server it's meant for, so with
+// --allow-servers an initiation goes on only if its mac1 checks out with one of the keys listed,
+// a client can't get a handshake through us to any other server behind the target; everything
+// else only ever reaches a server through a session one of those handshakes set up
+//
+// --allow-proxies is --seal-clients for the far end of a paired hop with several proxies in front
+// of it, each sealing with a key of its own: packets that open with none of them are dropped, and
+// what goes back to a proxy is sealed with the key it last used
+
+use crate::{args, error, poison::Recover, seal::Seal};
+
+use blake2::{
+    digest::{consts::U16, KeyInit, Mac},
+    Blake2s256, Blake2sMac, Digest,
+};
+use std::{collections::HashMap, fs, net::SocketAddr, sync::RwLock};
+
+const LABEL_MAC1: &[u8] = b"mac1----";
+
+// an initiation's mac1 and what it covers
+const MAC1: std::ops::Range<usize> = 116..132;
+
+/// the lines of path that aren't empty or # comments
+fn lines(path: &str) -> error::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// --allow-servers, the mac1 keys of the servers initiations may be for
+pub struct Servers {
+    keys: Vec<[u8; 32]>,
+}
+
+impl Servers {
+    /// reads the servers' public keys from path, one per line in the base64 wg uses
+    pub fn from_file(path: &str) -> error::Result<Servers> {
+        let keys = lines(path)?
+            .iter()
+            .map(|key| {
+                let public = args::key_from_base64(key)?;
+                Ok(Blake2s256::new()
+                    .chain_update(LABEL_MAC1)
+                    .chain_update(public)
+                    .finalize()
+                    .into())
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Err(args::invalid(format!(
+                "--allow-servers {} has no keys",
+                path
+            )));
+        }
+        Ok(Servers { keys })
+    }
+
+    /// whether initiation is for one of the servers
+    pub fn admits(&self, initiation: &[u8]) -> bool {
+        initiation.len() >= MAC1.end
+            && self.keys.iter().any(|key| {
+                let mut mac1 = <Blake2sMac<U16> as KeyInit>::new(key.into());
+                mac1.update(&initiation[..MAC1.start]);
+                mac1.verify_slice(&initiation[MAC1]).is_ok()
+            })
+    }
+}
+
+/// --allow-proxies, a seal for each of the proxies clients may come through
+pub struct Proxies {
+    seals: Vec<Seal>,
+    // which seal each proxy's address last opened with
+    addrs: RwLock<HashMap<SocketAddr, usize>>,
+}
+
+impl Proxies {
+    /// reads the proxies' pre-shared keys from path, one per line
+    pub fn from_file(path: &str) -> error::Result<Proxies> {
+        let seals: Vec<Seal> = lines(path)?.iter().map(|key| Seal::from_key(key)).collect();
+        if seals.is_empty() {
+            return Err(args::invalid(format!(
+                "--allow-proxies {} has no keys",
+                path
+            )));
+        }
+        Ok(Proxies {
+            seals,
+            addrs: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// opens the packet from in buf[start..end] like Seal::open, with whichever proxy's seal it
+    /// was sealed with, the one from used last first
+    pub fn open(
+        &self,
+        buf: &mut [u8],
+        start: usize,
+        end: usize,
+        from: SocketAddr,
+    ) -> Option<(usize, usize)> {
+        let last = self.addrs.read().recover().get(&from).copied();
+        if let Some(opened) = last.and_then(|last| self.seals[last].open(buf, start, end)) {
+            return Some(opened);
+        }
+        // a packet that doesn't open is left as it was, so the next seal can try
+        let (index, opened) = self
+            .seals
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != last)
+            .find_map(|(index, seal)| Some((index, seal.open(buf, start, end)?)))?;
+        self.addrs.write().recover().insert(from, index);
+        Some(opened)
+    }
+
+    /// seals the packet in buf[start..end] like Seal::seal, for the proxy at to, None if nothing
+    /// from there ever opened
+    pub fn seal(
+        &self,
+        buf: &mut [u8],
+        start: usize,
+        end: usize,
+        to: SocketAddr,
+    ) -> Option<(usize, usize)> {
+        let index = *self.addrs.read().recover().get(&to)?;
+        self.seals[index].seal(buf, start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seal::{HEADER_LEN, TAG_LEN};
+
+    use std::env;
+
+    #[test]
+    fn test_identity() {
+        let path = env::temp_dir().join(format!("identity-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // the public key from the wireguard docs' example config
+        fs::write(
+            path,
+            "# the target\nHIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw= # example\n\n",
+        )
+        .unwrap();
+        let servers = Servers::from_file(path).unwrap();
+        let mut initiation = [1u8; 148];
+        initiation[0] = 1;
+        let mut mac1 = <Blake2sMac<U16> as KeyInit>::new((&servers.keys[0]).into());
+        mac1.update(&initiation[..MAC1.start]);
+        initiation[MAC1].copy_from_slice(&mac1.finalize().into_bytes());
+        assert!(servers.admits(&initiation));
+        initiation[4] ^= 1;
+        assert!(!servers.admits(&initiation));
+        assert!(!servers.admits(&initiation[..100]));
+
+        fs::write(path, "first proxy's key\nsecond proxy's key\n").unwrap();
+        let proxies = Proxies::from_file(path).unwrap();
+        let second = Seal::from_key("second proxy's key");
+        let from: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let mut buf = [0u8; 128];
+        let (start, end) = (HEADER_LEN, HEADER_LEN + 32);
+        buf[start..end].copy_from_slice(&[7; 32]);
+        assert!(proxies.seal(&mut buf, start, end, from).is_none());
+        let (sealed_start, sealed_end) = second.seal(&mut buf, start, end).unwrap();
+        let (start, end) = proxies
+            .open(&mut buf, sealed_start, sealed_end, from)
+            .unwrap();
+        assert_eq!(&buf[start..end], &[7; 32]);
+        // what goes back is sealed for the second proxy
+        let (start, end) = proxies.seal(&mut buf, start, end, from).unwrap();
+        assert_eq!(end - start, 32 + HEADER_LEN + TAG_LEN);
+        assert!(second.open(&mut buf, start, end).is_some());
+
+        fs::write(path, "# nobody\n").unwrap();
+        assert!(Servers::from_file(path).is_err());
+        assert!(Proxies::from_file(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,640 @@
+// --fallback, a ladder of transports to target_addr's peers: clients' packets go to target_addr
+// over plain UDP to start with, once FAIL_AFTER handshake initiations in a row went unanswered
+// that way, over the next one given, and so on, back to the top after the last
+//
+// with --probe, each rung's path is probed too, and of those that answer and haven't failed
+// handshakes lately the one with the best round trip time and loss carries the clients, once it
+// did clearly better for a while
+//
+// each rung other than udp is the near end of its transport, like --tcp-target would run it,
+// started the first time it's used, listening on loopback, we give every client a socket of its
+// own to it so it still tells them apart
+
+use crate::{config::Target, error::Result, poison::Recover, probe::Probes, WgPacket};
+
+#[cfg(feature = "dns-tunnel")]
+use crate::dns_tunnel;
+#[cfg(feature = "faketcp")]
+use crate::faketcp;
+#[cfg(feature = "icmp-tunnel")]
+use crate::icmp_tunnel;
+#[cfg(feature = "tcp")]
+use crate::stream;
+#[cfg(feature = "tls")]
+use crate::tls::Tls;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// handshake initiations in a row without a response before the next rung is tried
+const FAIL_AFTER: u32 = 3;
+
+// a client's socket to a rung nothing came back on for this long is closed
+const IDLE_TIME: Duration = Duration::from_secs(60);
+
+// how long a connection --probe makes to a rung over TCP may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// with --probe, how long a rung handshakes failed on isn't chosen for scoring better, the path to
+// its host may well be fine while the transport is blocked
+const DOWN_TIME: Duration = Duration::from_secs(300);
+
+// what a lost probe costs a rung, in round trip time
+const LOSS_COST: Duration = Duration::from_secs(1);
+
+// probes are averaged over about this many rounds
+const SMOOTHING: u32 = 8;
+
+// against flapping, a rung must cost this many times less than the current one and MIN_GAIN
+// less on top, for ROUNDS probe rounds in a row, to be switched to
+const MARGIN: f64 = 1.25;
+const MIN_GAIN: Duration = Duration::from_millis(5);
+const ROUNDS: u32 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum Kind {
+    Udp,
+    #[cfg(feature = "tcp")]
+    Tcp,
+    #[cfg(feature = "tls")]
+    Tls,
+    #[cfg(feature = "faketcp")]
+    Faketcp,
+    #[cfg(feature = "icmp-tunnel")]
+    Icmp,
+    /// the domain, target being the resolver
+    #[cfg(feature = "dns-tunnel")]
+    Dns(String),
+}
+
+impl Kind {
+    fn over_tcp(&self) -> bool {
+        #[cfg(feature = "tcp")]
+        if *self == Kind::Tcp {
+            return true;
+        }
+        #[cfg(feature = "tls")]
+        if *self == Kind::Tls {
+            return true;
+        }
+        false
+    }
+}
+
+/// a transport to fall back to, and where its other end is
+pub struct Rung {
+    /// as given, to log
+    pub name: String,
+    pub kind: Kind,
+    pub target: Target,
+}
+
+impl Rung {
+    pub fn udp(target: Target) -> Rung {
+        Rung {
+            name: format!("udp={}", target.host),
+            kind: Kind::Udp,
+            target,
+        }
+    }
+
+    /// kind=addr, or dns=domain@resolver
+    pub fn parse(rung: &str) -> Result<Rung> {
+        let invalid = |why: &str| crate::args::invalid(format!("--fallback {}: {}", rung, why));
+        let (kind, target) = rung
+            .split_once('=')
+            .ok_or_else(|| invalid("expected kind=addr"))?;
+        let (kind, target) = match kind {
+            "udp" => (Kind::Udp, target),
+            #[cfg(feature = "tcp")]
+            "tcp" => (Kind::Tcp, target),
+            #[cfg(feature = "tls")]
+            "tls" => (Kind::Tls, target),
+            #[cfg(feature = "faketcp")]
+            "faketcp" => (Kind::Faketcp, target),
+            #[cfg(feature = "icmp-tunnel")]
+            "icmp" => (Kind::Icmp, target),
+            #[cfg(feature = "dns-tunnel")]
+            "dns" => {
+                let (domain, resolver) = target
+                    .split_once('@')
+                    .ok_or_else(|| invalid("expected dns=domain@resolver"))?;
+                dns_tunnel::check(domain).map_err(|e| invalid(&e))?;
+                (Kind::Dns(domain.to_string()), resolver)
+            }
+            #[cfg(not(feature = "tcp"))]
+            "tcp" => {
+                return Err(crate::args::unavailable(
+                    &format!("--fallback {}", rung),
+                    "tcp",
+                ))
+            }
+            #[cfg(not(feature = "tls"))]
+            "tls" => {
+                return Err(crate::args::unavailable(
+                    &format!("--fallback {}", rung),
+                    "tls",
+                ))
+            }
+            #[cfg(not(feature = "faketcp"))]
+            "faketcp" => {
+                return Err(crate::args::unavailable(
+                    &format!("--fallback {}", rung),
+                    "faketcp",
+                ))
+            }
+            #[cfg(not(feature = "icmp-tunnel"))]
+            "icmp" => {
+                return Err(crate::args::unavailable(
+                    &format!("--fallback {}", rung),
+                    "icmp-tunnel",
+                ))
+            }
+            #[cfg(not(feature = "dns-tunnel"))]
+            "dns" => {
+                return Err(crate::args::unavailable(
+                    &format!("--fallback {}", rung),
+                    "dns-tunnel",
+                ))
+            }
+            _ => {
+                return Err(invalid(
+                    "kind is one of udp, tcp, tls, faketcp, icmp and dns",
+                ))
+            }
+        };
+        Ok(Rung {
+            name: rung.to_string(),
+            kind,
+            target: Target::resolve(target)?,
+        })
+    }
+}
+
+/// what --probe found of a rung, and when handshakes last failed on it
+#[derive(Clone, Default)]
+struct Health {
+    /// smoothed round trip time, None until a probe was answered
+    srtt: Option<Duration>,
+    /// smoothed share of probes lost
+    loss: f64,
+    failed: Option<Instant>,
+}
+
+impl Health {
+    fn probed(&mut self, rtt: Option<Duration>) {
+        let lost = if rtt.is_some() { 0.0 } else { 1.0 };
+        self.loss += (lost - self.loss) / SMOOTHING as f64;
+        if let Some(rtt) = rtt {
+            self.srtt = Some(match self.srtt {
+                Some(srtt) => (srtt * (SMOOTHING - 1) + rtt) / SMOOTHING,
+                None => rtt,
+            });
+        }
+    }
+
+    /// lower is better, the round trip time with each lost probe counting as LOSS_COST, None
+    /// if the rung isn't fit to be chosen
+    fn cost(&self, now: Instant) -> Option<f64> {
+        if self.loss >= 0.5 || self.failed.is_some_and(|failed| now - failed < DOWN_TIME) {
+            return None;
+        }
+        Some(self.srtt?.as_secs_f64() + self.loss * LOSS_COST.as_secs_f64())
+    }
+}
+
+/// which rung is in use, and how they're doing
+struct State {
+    current: usize,
+    unanswered: u32,
+    health: Vec<Health>,
+    /// the rung that scored better than the current one, for how many probe rounds in a row
+    better: Option<(usize, u32)>,
+}
+
+impl State {
+    fn new(rungs: usize) -> State {
+        State {
+            current: 0,
+            unanswered: 0,
+            health: vec![Health::default(); rungs],
+            better: None,
+        }
+    }
+
+    /// a client's initiation is about to go on the current rung, the next one if it's the
+    /// FAIL_AFTER + 1st in a row, Some when that switched
+    fn initiated(&mut self, now: Instant) -> Option<usize> {
+        let from = self.current;
+        if self.unanswered >= FAIL_AFTER && self.health.len() > 1 {
+            self.health[from].failed = Some(now);
+            self.go(from + 1);
+        }
+        self.unanswered += 1;
+        (self.current != from).then_some(from)
+    }
+
+    fn answered(&mut self, rung: usize) {
+        if rung == self.current {
+            self.unanswered = 0;
+        }
+    }
+
+    /// the current rung couldn't start, Some with it when there's another to go to
+    fn failed(&mut self, now: Instant) -> Option<usize> {
+        let from = self.current;
+        self.health[from].failed = Some(now);
+        self.go(from + 1);
+        (self.current != from).then_some(from)
+    }
+
+    fn go(&mut self, rung: usize) {
+        self.current = rung % self.health.len();
+        self.unanswered = 0;
+        self.better = None;
+    }
+
+    /// after a round of probes, the rung that costs least if it did for ROUNDS in a row and by
+    /// the margin, Some with the one we switched from
+    fn choose(&mut self, now: Instant) -> Option<usize> {
+        let current = self.health[self.current].cost(now);
+        let best = (0..self.health.len())
+            .filter_map(|rung| Some((rung, self.health[rung].cost(now)?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .filter(|(rung, cost)| {
+                *rung != self.current
+                    && current
+                        .is_none_or(|current| *cost * MARGIN + MIN_GAIN.as_secs_f64() < current)
+            });
+        let Some((best, _)) = best else {
+            self.better = None;
+            return None;
+        };
+        let rounds = match self.better {
+            Some((rung, rounds)) if rung == best => rounds + 1,
+            _ => 1,
+        };
+        self.better = Some((best, rounds));
+        if rounds < ROUNDS {
+            return None;
+        }
+        let from = self.current;
+        self.go(best);
+        Some(from)
+    }
+}
+
+pub struct Ladder {
+    local: UdpSocket,
+    rungs: Vec<Rung>,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    #[cfg(feature = "icmp-tunnel")]
+    icmp_mtu: usize,
+    probes: Option<Probes>,
+    verbose: bool,
+    state: Mutex<State>,
+    /// where each rung that was started takes packets
+    started: Mutex<HashMap<usize, SocketAddr>>,
+    /// each client's socket to each rung it used
+    sockets: Mutex<HashMap<(SocketAddr, usize), Arc<UdpSocket>>>,
+}
+
+impl Ladder {
+    pub fn new(
+        local: UdpSocket,
+        rungs: Vec<Rung>,
+        #[cfg(feature = "tls")] tls: Option<Tls>,
+        #[cfg(feature = "icmp-tunnel")] icmp_mtu: usize,
+        probes: Option<Probes>,
+        verbose: bool,
+    ) -> Ladder {
+        Ladder {
+            state: Mutex::new(State::new(rungs.len())),
+            local,
+            rungs,
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(feature = "icmp-tunnel")]
+            icmp_mtu,
+            probes,
+            verbose,
+            started: Mutex::new(HashMap::new()),
+            sockets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        if let Some(probes) = &self.probes {
+            thread::spawn(move || loop {
+                for (i, rung) in self.rungs.iter().enumerate() {
+                    let rtt = self.probe(probes, rung);
+                    self.state.lock().recover().health[i].probed(rtt);
+                }
+                let mut state = self.state.lock().recover();
+                if let Some(from) = state.choose(Instant::now()) {
+                    let to = state.current;
+                    let rtt = |rung: usize| {
+                        state.health[rung]
+                            .srtt
+                            .map_or("none".to_string(), |srtt| format!("{:?}", srtt))
+                    };
+                    eprintln!(
+                        "transport {} does better, round trip {} and {:.0}% lost against {} and \
+                         {:.0}% for {}, switching",
+                        self.rungs[to].name,
+                        rtt(to),
+                        state.health[to].loss * 100.0,
+                        rtt(from),
+                        state.health[from].loss * 100.0,
+                        self.rungs[from].name
+                    );
+                }
+                drop(state);
+                thread::sleep(probes.every);
+            });
+        }
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, client) = self.local.recv_from(&mut buf)?;
+            let packet = &buf[..recv];
+            let Some(parsed) = WgPacket::parse(packet) else {
+                continue;
+            };
+            let mut state = self.state.lock().recover();
+            if let WgPacket::HandShakeInitiation { .. } = parsed {
+                if let Some(from) = state.initiated(Instant::now()) {
+                    self.switched(from, state.current, "stopped answering handshakes");
+                }
+            }
+            // each rung at most once, none starting being for the next packet to try again
+            let mut socket = None;
+            for _ in 0..self.rungs.len() {
+                let rung = state.current;
+                match self.socket(client, rung) {
+                    Ok(started) => {
+                        socket = Some(started);
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("transport {}: {}", self.rungs[rung].name, e);
+                        match state.failed(Instant::now()) {
+                            Some(from) => self.switched(from, state.current, "failed"),
+                            None => break,
+                        }
+                    }
+                }
+            }
+            drop(state);
+            if let Some(socket) = socket {
+                // the rung being down is for the handshakes to find out
+                let _ = socket.send(packet);
+            }
+        }
+    }
+
+    /// a round trip over rung's path, a connection to the far end for those over TCP, what
+    /// --probe sends for the others
+    fn probe(&self, probes: &Probes, rung: &Rung) -> Option<Duration> {
+        if rung.kind.over_tcp() {
+            let sent = Instant::now();
+            return TcpStream::connect_timeout(&rung.target.addr, PROBE_TIMEOUT)
+                .ok()
+                .map(|_| sent.elapsed());
+        }
+        probes.probe(&rung.target).unwrap_or_else(|e| {
+            if self.verbose {
+                eprintln!("probing transport {} failed: {}", rung.name, e);
+            }
+            None
+        })
+    }
+
+    fn switched(&self, from: usize, to: usize, why: &str) {
+        let how = if to < from {
+            "starting over with"
+        } else {
+            "falling back to"
+        };
+        eprintln!(
+            "transport {} {}, {} {}",
+            self.rungs[from].name, why, how, self.rungs[to].name
+        );
+    }
+
+    /// client's socket to rung, the reader passing back what comes on it
+    fn socket(&'static self, client: SocketAddr, rung: usize) -> Result<Arc<UdpSocket>> {
+        let mut sockets = self.sockets.lock().recover();
+        if let Some(socket) = sockets.get(&(client, rung)) {
+            return Ok(Arc::clone(socket));
+        }
+        let addr = self.start(rung)?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        })?;
+        socket.connect(addr)?;
+        // often enough to notice it's idle
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let socket = Arc::new(socket);
+        sockets.insert((client, rung), Arc::clone(&socket));
+        let reader = Arc::clone(&socket);
+        thread::spawn(move || {
+            let mut buf = [0u8; 65536];
+            let mut last = Instant::now();
+            while last.elapsed() < IDLE_TIME {
+                // timed out, or the rung refusing an earlier packet
+                let Ok(recv) = reader.recv(&mut buf) else {
+                    continue;
+                };
+                last = Instant::now();
+                if let Some(WgPacket::HandShakeResponse { .. }) = WgPacket::parse(&buf[..recv]) {
+                    self.state.lock().recover().answered(rung);
+                }
+                let _ = self.local.send_to(&buf[..recv], client);
+            }
+            let mut sockets = self.sockets.lock().recover();
+            if sockets
+                .get(&(client, rung))
+                .is_some_and(|socket| Arc::ptr_eq(socket, &reader))
+            {
+                sockets.remove(&(client, rung));
+            }
+        });
+        Ok(socket)
+    }
+
+    /// where rung takes packets, starting its near end the first time
+    #[cfg_attr(
+        not(any(
+            feature = "tcp",
+            feature = "faketcp",
+            feature = "icmp-tunnel",
+            feature = "dns-tunnel"
+        )),
+        allow(unreachable_code, unused)
+    )]
+    fn start(&self, rung: usize) -> Result<SocketAddr> {
+        let mut started = self.started.lock().recover();
+        if let Some(addr) = started.get(&rung) {
+            return Ok(*addr);
+        }
+        let Rung { kind, target, .. } = &self.rungs[rung];
+        let local = UdpSocket::bind(if target.addr.is_ipv4() {
+            SocketAddr::from(([127, 0, 0, 1], 0))
+        } else {
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 0))
+        })?;
+        let addr = local.local_addr()?;
+        let near: Box<dyn FnOnce() -> io::Result<()> + Send> = match kind {
+            // nothing in between
+            Kind::Udp => return Ok(target.addr),
+            #[cfg(feature = "tcp")]
+            Kind::Tcp => {
+                let near = Box::leak(Box::new(stream::Near::new(
+                    local,
+                    target.clone(),
+                    #[cfg(feature = "tls")]
+                    None,
+                    false,
+                    self.verbose,
+                )));
+                Box::new(move || near.run())
+            }
+            #[cfg(feature = "tls")]
+            Kind::Tls => {
+                let near = Box::leak(Box::new(stream::Near::new(
+                    local,
+                    target.clone(),
+                    self.tls.clone(),
+                    false,
+                    self.verbose,
+                )));
+                Box::new(move || near.run())
+            }
+            #[cfg(feature = "faketcp")]
+            Kind::Faketcp => {
+                let near = Box::leak(Box::new(faketcp::Near::new(
+                    local,
+                    target.addr,
+                    self.verbose,
+                )?));
+                Box::new(move || near.run())
+            }
+            #[cfg(feature = "icmp-tunnel")]
+            Kind::Icmp => {
+                let near = Box::leak(Box::new(icmp_tunnel::Near::new(
+                    local,
+                    target.addr.ip(),
+                    self.icmp_mtu,
+                    self.verbose,
+                )?));
+                Box::new(move || near.run())
+            }
+            #[cfg(feature = "dns-tunnel")]
+            Kind::Dns(domain) => {
+                let near = Box::leak(Box::new(dns_tunnel::Near::new(
+                    local,
+                    target.addr,
+                    domain,
+                    self.verbose,
+                )?));
+                Box::new(move || near.run())
+            }
+        };
+        let name = self.rungs[rung].name.clone();
+        thread::spawn(move || {
+            if let Err(e) = near() {
+                eprintln!("transport {} ended: {}", name, e);
+            }
+        });
+        if self.verbose {
+            eprintln!("transport {} started", self.rungs[rung].name);
+        }
+        started.insert(rung, addr);
+        Ok(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ladder() {
+        assert_eq!(Rung::parse("udp=127.0.0.1:1").unwrap().kind, Kind::Udp);
+        #[cfg(feature = "dns-tunnel")]
+        {
+            let rung = Rung::parse("dns=t.example.com@127.0.0.1:53").unwrap();
+            assert_eq!(rung.kind, Kind::Dns("t.example.com".to_string()));
+            assert_eq!(rung.target.addr, SocketAddr::from(([127, 0, 0, 1], 53)));
+            assert!(Rung::parse("dns=127.0.0.1:53").is_err());
+        }
+        assert!(Rung::parse("quic=127.0.0.1:1").is_err());
+        assert!(Rung::parse("127.0.0.1:1").is_err());
+
+        let now = Instant::now();
+        let mut state = State::new(3);
+        for _ in 0..FAIL_AFTER {
+            assert_eq!(state.initiated(now), None);
+        }
+        // a response in between starts the count over
+        state.answered(0);
+        for _ in 0..FAIL_AFTER {
+            assert_eq!(state.initiated(now), None);
+        }
+        // late ones from a rung we left don't count
+        state.answered(1);
+        assert_eq!(state.initiated(now), Some(0));
+        assert_eq!(state.current, 1);
+        assert_eq!(state.failed(now), Some(1));
+        for _ in 0..FAIL_AFTER {
+            state.initiated(now);
+        }
+        assert_eq!(state.initiated(now), Some(2));
+        assert_eq!(state.current, 0);
+        // nowhere else to go
+        let mut one = State::new(1);
+        assert_eq!(one.failed(now), None);
+        for _ in 0..=FAIL_AFTER {
+            assert_eq!(one.initiated(now), None);
+        }
+
+        // every rung failed handshakes just now, none is chosen for its probes
+        let ms = Duration::from_millis;
+        let probe = |state: &mut State, rtts: [Option<Duration>; 3]| {
+            for (health, rtt) in state.health.iter_mut().zip(rtts) {
+                health.probed(rtt);
+            }
+        };
+        probe(&mut state, [Some(ms(50)), Some(ms(20)), Some(ms(40))]);
+        assert_eq!(state.choose(now), None);
+        let later = now + DOWN_TIME;
+        // 1 does better, but only the third round in a row switches
+        assert_eq!(state.choose(later), None);
+        probe(&mut state, [Some(ms(50)), Some(ms(20)), Some(ms(40))]);
+        assert_eq!(state.choose(later), None);
+        assert_eq!(state.choose(later), Some(0));
+        assert_eq!(state.current, 1);
+        // 2 ahead by less than the margin isn't worth switching to
+        for _ in 0..20 {
+            probe(&mut state, [Some(ms(50)), Some(ms(30)), Some(ms(25))]);
+            assert_eq!(state.choose(later), None);
+        }
+        // losing probes costs 1 its place
+        for _ in 0..2 {
+            probe(&mut state, [Some(ms(50)), None, Some(ms(25))]);
+            assert_eq!(state.choose(later), None);
+        }
+        probe(&mut state, [Some(ms(50)), None, Some(ms(25))]);
+        assert_eq!(state.choose(later), Some(1));
+        assert_eq!(state.current, 2);
+    }
+}
@@ -0,0 +1,161 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod packet;
+
+pub use packet::WgPacket;
+
+#[cfg(feature = "admin")]
+mod admin;
+#[cfg(feature = "std")]
+mod amplification;
+#[cfg(feature = "std")]
+mod args;
+#[cfg(feature = "std")]
+mod audit;
+#[cfg(feature = "std")]
+mod banner;
+#[cfg(feature = "std")]
+mod camouflage;
+#[cfg(feature = "canary")]
+mod canary;
+#[cfg(feature = "std")]
+mod census;
+#[cfg(feature = "admin")]
+mod chaos;
+#[cfg(feature = "std")]
+mod cidr;
+#[cfg(feature = "std")]
+mod clock;
+#[cfg(feature = "std")]
+mod cold;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "tcp")]
+mod control;
+#[cfg(feature = "std")]
+mod crash;
+#[cfg(feature = "std")]
+mod datagram;
+#[cfg(feature = "std")]
+mod decoy;
+#[cfg(feature = "dns-tunnel")]
+mod dns_tunnel;
+#[cfg(feature = "std")]
+mod envelope;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "events")]
+mod etw;
+#[cfg(feature = "std")]
+mod events;
+#[cfg(feature = "faketcp")]
+mod faketcp;
+#[cfg(feature = "std")]
+mod fingerprint;
+#[cfg(feature = "control-plane")]
+mod fleet;
+#[cfg(feature = "admin")]
+mod follow;
+#[cfg(feature = "std")]
+mod fragment;
+#[cfg(feature = "std")]
+mod garbage;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "grpc")]
+mod h2;
+#[cfg(feature = "std")]
+mod handshakes;
+#[cfg(feature = "std")]
+mod heartbeat;
+#[cfg(feature = "std")]
+mod hijack;
+#[cfg(feature = "grpc")]
+mod hpack;
+#[cfg(feature = "icmp-tunnel")]
+mod icmp_tunnel;
+#[cfg(feature = "identity")]
+mod identity;
+#[cfg(feature = "std")]
+mod ladder;
+#[cfg(feature = "std")]
+mod listeners;
+#[cfg(feature = "std")]
+mod log;
+#[cfg(feature = "std")]
+mod memory;
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "events")]
+mod mqtt;
+#[cfg(feature = "tcp")]
+mod mux;
+#[cfg(feature = "std")]
+mod other;
+#[cfg(feature = "std")]
+mod overload;
+#[cfg(feature = "std")]
+mod pacer;
+#[cfg(feature = "std")]
+mod pcap;
+#[cfg(feature = "peer-relay")]
+mod peer_relay;
+#[cfg(feature = "std")]
+mod pktinfo;
+#[cfg(feature = "std")]
+mod platform;
+#[cfg(feature = "std")]
+mod pmtu;
+#[cfg(feature = "std")]
+mod poison;
+#[cfg(feature = "std")]
+mod ports;
+#[cfg(feature = "std")]
+mod probe;
+#[cfg(feature = "std")]
+mod proxy;
+#[cfg(feature = "std")]
+mod quic;
+#[cfg(feature = "std")]
+mod rate;
+#[cfg(feature = "replay")]
+mod replay;
+#[cfg(feature = "std")]
+mod scale;
+#[cfg(feature = "std")]
+mod schedule;
+#[cfg(feature = "std")]
+mod seal;
+#[cfg(feature = "selftest")]
+mod selftest;
+#[cfg(feature = "signed-config")]
+mod signed;
+#[cfg(feature = "soak")]
+mod soak;
+#[cfg(feature = "std")]
+mod state;
+#[cfg(feature = "tcp")]
+mod stream;
+#[cfg(feature = "std")]
+mod stun;
+#[cfg(feature = "std")]
+mod table;
+#[cfg(feature = "std")]
+mod targets;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "std")]
+mod uci;
+#[cfg(feature = "events")]
+mod webhook;
+
+#[cfg(feature = "std")]
+pub use error::{Error, Result};
+#[cfg(feature = "std")]
+pub use proxy::{run, ExpiringSocket, Proxy, Session, Sessions};
+#[cfg(feature = "replay")]
+pub use replay::replay;
+#[cfg(feature = "selftest")]
+pub use selftest::selftest;
+#[cfg(feature = "soak")]
+pub use soak::soak;
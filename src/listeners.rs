@@ -0,0 +1,192 @@
+// listeners running next to the one we were started with, each another proxy with its own socket,
+// target and workers, the --tenants we were started with and those the admin added since
+//
+// what only makes sense once per process, the admin socket, metrics and --state-file, isn't
+// inherited, see config::derive
+
+use crate::{
+    args,
+    config::Config,
+    poison::Recover,
+    proxy::{self, Proxy},
+};
+
+use std::{
+    collections::HashMap,
+    io::Result,
+    net::SocketAddr,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+};
+
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+struct Listener {
+    tenant: Option<String>,
+    target: String,
+    proxy: &'static Proxy,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+pub struct Listeners {
+    added: Mutex<HashMap<SocketAddr, Listener>>,
+}
+
+impl Listeners {
+    /// starts a listener for config, of tenant if it belongs to one, returns the address it's
+    /// bound to
+    pub fn add(&self, tenant: Option<String>, config: Config) -> Result<SocketAddr> {
+        let bind_addr = config.bind_addr.clone();
+        self.start(tenant, config)
+            .map_err(|e| args::invalid(format!("listening on {} failed: {}", bind_addr, e)).into())
+    }
+
+    fn start(&self, tenant: Option<String>, config: Config) -> Result<SocketAddr> {
+        let target = config.target.as_ref().map(|t| t.host.clone());
+        let Some(target) = target else {
+            return Err(args::invalid("a listener needs a target".to_string()).into());
+        };
+        let thread_count = config.thread_count;
+        let udp_socket = proxy::bind(&config)?;
+        let addr = udp_socket.local_addr()?;
+        let proxy = Proxy::start(udp_socket, config)?;
+        let workers = (0..thread_count)
+            .map(|_| {
+                thread::spawn(move || match proxy.run() {
+                    // a send that raced remove
+                    Err(_) if proxy.stopped() => {}
+                    Err(e) => eprintln!("listener {} stopped: {}", addr, e),
+                    Ok(()) => {}
+                })
+            })
+            .collect();
+        let listener = Listener {
+            tenant,
+            target,
+            proxy,
+            workers,
+        };
+        self.added.lock().recover().insert(addr, listener);
+        Ok(addr)
+    }
+
+    /// stops the listener on addr once its workers are done with the packets they have, and
+    /// gives up its address
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn remove(&self, addr: SocketAddr) -> Result<()> {
+        let listener = self
+            .added
+            .lock()
+            .recover()
+            .remove(&addr)
+            .ok_or_else(|| args::invalid(format!("no listener added on {}", addr)))?;
+        listener.proxy.stop()?;
+        for worker in listener.workers {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+
+    /// the proxy of tenant
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn tenant(&self, tenant: &str) -> Option<&'static Proxy> {
+        let added = self.added.lock().recover();
+        let mut listeners = added.values();
+        listeners
+            .find(|l| l.tenant.as_deref() == Some(tenant))
+            .map(|l| l.proxy)
+    }
+
+    /// every listener, with its tenant, target and how many workers it has
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn list(&self) -> Vec<(SocketAddr, Option<String>, String, usize)> {
+        let mut list: Vec<_> = self
+            .added
+            .lock()
+            .recover()
+            .iter()
+            .map(|(addr, l)| (*addr, l.tenant.clone(), l.target.clone(), l.workers.len()))
+            .collect();
+        list.sort();
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config;
+
+    use std::{fs, net::UdpSocket};
+
+    #[test]
+    fn test_roles() {
+        let options: Vec<String> = ["--fragment-target=1200", "--relay-envelope", "--verbose"]
+            .map(String::from)
+            .into();
+        let derive = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            config::derive(&options, &args).unwrap()
+        };
+        let same = derive(&["127.0.0.1:9"]);
+        assert_eq!(same.fragment_target, Some(1200));
+        assert!(same.relay_envelope);
+        // the other end of the hop, in the same process
+        let other = derive(&[
+            "--fragment-clients",
+            "1200",
+            "--accept-envelope",
+            "127.0.0.1:9",
+        ]);
+        assert_eq!(other.fragment_target, None);
+        assert_eq!(other.fragment_clients, Some(1200));
+        assert!(!other.relay_envelope);
+        assert!(other.accept_envelope);
+        assert!(other.verbose);
+    }
+
+    #[test]
+    fn test_listeners() {
+        let listeners = Listeners::default();
+        let options = ["--verbose".to_string()];
+        let listener = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            config::derive(&options, &args).unwrap()
+        };
+        let addr = listeners
+            .add(None, listener(&["127.0.0.1:9", "127.0.0.1:0", "2"]))
+            .unwrap();
+        assert_eq!(
+            listeners.list(),
+            [(addr, None, "127.0.0.1:9".to_string(), 2)]
+        );
+        // it's ours
+        assert!(UdpSocket::bind(addr).is_err());
+        let again = listener(&["127.0.0.1:9", &addr.to_string()]);
+        assert!(listeners.add(None, again).is_err());
+
+        listeners.remove(addr).unwrap();
+        assert!(listeners.list().is_empty());
+        assert!(listeners.remove(addr).is_err());
+        // and free again
+        UdpSocket::bind(addr).unwrap();
+
+        let path = std::env::temp_dir().join(format!("tenants-test-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# name options target bind\nacme --max-session-pps 10 127.0.0.1:9 127.0.0.1:0\n\n",
+        )
+        .unwrap();
+        let tenants = format!("--tenants={}", path.display());
+        let args = ["--state-file", "/nonexistent", &tenants, "127.0.0.1:8"];
+        let config = Config::from_args(args.map(String::from)).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.tenants.len(), 1);
+        let (name, tenant) = &config.tenants[0];
+        assert_eq!(name, "acme");
+        assert_eq!(tenant.max_session_pps, Some(10));
+        assert_eq!(tenant.state_path, None);
+        assert!(tenant.tenants.is_empty());
+    }
+}
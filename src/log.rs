@@ -0,0 +1,279 @@
+// --log-file, everything we'd print goes to a file instead, and --log-rotate keeps it from growing
+// forever: once it's too big or old it becomes path.1, what was path.1 becomes path.2 and so on,
+// only the newest keep of them are kept
+//
+// rotating is renaming and opening path again, our own output then lands in the new file while
+// anyone still reading the old one keeps reading it, compressing is left to gzip as routers have it
+//
+// --syslog sends it to syslog instead, a message per line
+
+use crate::{args, error};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Result,
+    process::Command,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+// how often we look at how big the file got
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rotate {
+    /// rotate once the file is at least this many bytes
+    pub size: Option<u64>,
+    /// rotate once the file is this old
+    pub every: Option<Duration>,
+    /// how many rotated files to keep
+    pub keep: usize,
+    /// gzip rotated files
+    pub gzip: bool,
+}
+
+impl FromStr for Rotate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut rotate = Rotate {
+            size: None,
+            every: None,
+            keep: 7,
+            gzip: false,
+        };
+        for part in s.split(',') {
+            match part.split_once('=') {
+                Some(("size", size)) => rotate.size = Some(bytes(size)?),
+                Some(("every", every)) => rotate.every = Some(duration(every)?),
+                Some(("keep", keep)) => {
+                    rotate.keep = keep
+                        .parse()
+                        .ok()
+                        .filter(|keep| *keep > 0)
+                        .ok_or_else(|| format!("invalid keep: {}", keep))?
+                }
+                None if part == "gzip" => rotate.gzip = true,
+                _ => return Err(format!("unknown --log-rotate setting: {}", part)),
+            }
+        }
+        if rotate.size.is_none() && rotate.every.is_none() {
+            return Err("--log-rotate needs size= or every=".to_string());
+        }
+        Ok(rotate)
+    }
+}
+
+/// a size like 100M, in bytes
+pub fn bytes(s: &str) -> std::result::Result<u64, String> {
+    let (n, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit {
+        "" => 1,
+        "k" | "K" => 1 << 10,
+        "m" | "M" => 1 << 20,
+        "g" | "G" => 1 << 30,
+        _ => return Err(format!("invalid size: {}", s)),
+    };
+    n.parse::<u64>()
+        .map(|n| n * unit)
+        .map_err(|_| format!("invalid size: {}", s))
+}
+
+/// a duration like 1d, in minutes, hours or days
+fn duration(s: &str) -> std::result::Result<Duration, String> {
+    let (n, unit) = s.split_at(s.len().saturating_sub(1));
+    let unit = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration: {}", s)),
+    };
+    n.parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(|n| Duration::from_secs(n * unit))
+        .ok_or_else(|| format!("invalid duration: {}", s))
+}
+
+/// reads --log-rotate, unlike args::parse with the reason it's invalid
+pub fn parse(rotate: &str) -> error::Result<Rotate> {
+    rotate.parse().map_err(args::invalid)
+}
+
+/// sends stdout and stderr to path from now on, rotating it as rotate says
+pub fn start(path: &str, rotate: Option<Rotate>) -> Result<()> {
+    redirect(&open(path)?)?;
+    if let Some(rotate) = rotate {
+        let path = path.to_string();
+        thread::spawn(move || {
+            let mut opened = Instant::now();
+            loop {
+                thread::sleep(CHECK_INTERVAL);
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                let due = rotate.size.is_some_and(|max| size >= max)
+                    || rotate.every.is_some_and(|every| opened.elapsed() >= every);
+                if !due {
+                    continue;
+                }
+                // where else would we say so
+                if let Err(e) = rotated(&path, &rotate) {
+                    eprintln!("rotating {} failed: {}", path, e);
+                }
+                opened = Instant::now();
+            }
+        });
+    }
+    Ok(())
+}
+
+/// sends stdout and stderr to syslog from now on, what goes to stderr as warnings
+#[cfg(unix)]
+pub fn syslog() -> Result<()> {
+    use std::{
+        ffi::CString,
+        io::{BufRead, BufReader},
+        os::unix::io::FromRawFd,
+    };
+
+    unsafe {
+        libc::openlog(
+            c"wireguard-udp-proxy".as_ptr(),
+            libc::LOG_PID,
+            libc::LOG_DAEMON,
+        )
+    };
+    for (fd, priority) in [
+        (libc::STDOUT_FILENO, libc::LOG_INFO),
+        (libc::STDERR_FILENO, libc::LOG_WARNING),
+    ] {
+        let mut ends = [0; 2];
+        if unsafe { libc::pipe(ends.as_mut_ptr()) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let lines = BufReader::new(unsafe { File::from_raw_fd(ends[0]) });
+        let written = unsafe { libc::dup2(ends[1], fd) };
+        unsafe { libc::close(ends[1]) };
+        if written < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        thread::spawn(move || {
+            for line in lines.split(b'\n').map_while(Result::ok) {
+                // a NUL would cut the message short, not that we print any
+                if let Ok(line) = CString::new(line) {
+                    unsafe { libc::syslog(priority, c"%s".as_ptr(), line.as_ptr()) };
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn syslog() -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--syslog needs unix file descriptors",
+    ))
+}
+
+fn open(path: &str) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// moves path to path.1 and starts writing to a new path, gzipped if rotate says so
+fn rotated(path: &str, rotate: &Rotate) -> Result<()> {
+    let rotated = shift(path, rotate.keep)?;
+    redirect(&open(path)?)?;
+    if rotate.gzip {
+        let status = Command::new("gzip").arg("-f").arg(&rotated).status()?;
+        if !status.success() {
+            eprintln!("gzip {} failed: {}", rotated, status);
+        }
+    }
+    Ok(())
+}
+
+/// shifts path.1 to path.2 and so on, gzipped or not, dropping what's beyond keep, and then path
+/// to path.1, which it returns
+fn shift(path: &str, keep: usize) -> Result<String> {
+    let name = |n: usize, gz: bool| format!("{}.{}{}", path, n, if gz { ".gz" } else { "" });
+    for gz in [false, true] {
+        let _ = fs::remove_file(name(keep, gz));
+    }
+    for n in (1..keep).rev() {
+        for gz in [false, true] {
+            if fs::metadata(name(n, gz)).is_ok() {
+                fs::rename(name(n, gz), name(n + 1, gz))?;
+            }
+        }
+    }
+    fs::rename(path, name(1, false))?;
+    Ok(name(1, false))
+}
+
+#[cfg(unix)]
+fn redirect(file: &File) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn redirect(_file: &File) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--log-file needs unix file descriptors",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        assert_eq!(
+            "size=100M,keep=3,gzip".parse(),
+            Ok(Rotate {
+                size: Some(100 << 20),
+                every: None,
+                keep: 3,
+                gzip: true,
+            })
+        );
+        let daily: Rotate = "every=1d".parse().unwrap();
+        assert_eq!(daily.every, Some(Duration::from_secs(86400)));
+        assert_eq!(daily.keep, 7);
+        assert!("keep=3".parse::<Rotate>().is_err());
+        assert!("size=10X".parse::<Rotate>().is_err());
+        assert!("size=1k,keep=0".parse::<Rotate>().is_err());
+        assert!("every=0h".parse::<Rotate>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        let path = path.to_str().unwrap();
+        let read = |suffix: &str| fs::read_to_string(format!("{}{}", path, suffix)).ok();
+        fs::write(format!("{}.1.gz", path), "0").unwrap();
+        for n in 1..4 {
+            fs::write(path, n.to_string()).unwrap();
+            assert_eq!(shift(path, 2).unwrap(), format!("{}.1", path));
+        }
+        assert_eq!(read(""), None);
+        assert_eq!(read(".1").as_deref(), Some("3"));
+        assert_eq!(read(".2").as_deref(), Some("2"));
+        // gzipped ones move along and drop out the same
+        assert_eq!(read(".2.gz"), None);
+        assert_eq!(read(".3"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+// approximately how much memory the tables that grow with clients take, and --max-memory's cap on
+// it for routers that have little to spare
+//
+// a table is counted as its entries as a HashMap keeps them, with a control byte each and the
+// slack its load factor leaves, none of them point to anything else on the heap; a session's
+// entry includes its circuit breaker, the only rate limiter state that grows with clients
+//
+// past HIGH_WATER percent of the cap the sessions idle longest are evicted until we are under
+// LOW_WATER again, at the cap handshakes for new sessions are refused
+
+use std::mem;
+
+pub const HIGH_WATER: usize = 90;
+pub const LOW_WATER: usize = 80;
+
+/// approximately what len entries of K and V take in a HashMap
+pub const fn table<K, V>(len: usize) -> usize {
+    (mem::size_of::<(K, V)>() + 1) * len * 8 / 7
+}
+
+/// approximately what each table takes, in bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    /// sessions and the indexes and clients they are found by
+    pub sessions: usize,
+    /// handshakes waiting for a response
+    pub handshakes: usize,
+    /// sources of invalid packets
+    pub offenders: usize,
+}
+
+impl Usage {
+    pub fn total(&self) -> usize {
+        self.sessions + self.handshakes + self.offenders
+    }
+
+    /// how many bytes of sessions to evict to be under LOW_WATER of cap, if we are past HIGH_WATER
+    pub fn excess(&self, cap: usize) -> Option<usize> {
+        let total = self.total();
+        (total > cap / 100 * HIGH_WATER).then(|| total - cap / 100 * LOW_WATER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excess() {
+        let usage = |sessions| Usage {
+            sessions,
+            handshakes: 50,
+            offenders: 50,
+        };
+        assert_eq!(usage(700).excess(1000), None);
+        // at HIGH_WATER is still fine
+        assert_eq!(usage(800).excess(1000), None);
+        assert_eq!(usage(801).excess(1000), Some(101));
+        assert_eq!(usage(900).excess(1000), Some(200));
+        assert!(table::<u32, u32>(7) >= 7 * 9);
+    }
+}
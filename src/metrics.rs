@@ -0,0 +1,611 @@
+// prometheus text format metrics over a minimal http server, two read-only endpoints don't need a
+// web framework, the other is /health, the load as JSON for whatever scales replicas of us
+//
+// built without the metrics feature the counters are still kept, the admin socket shows some of
+// them, there is just nothing to render them for
+
+#[cfg(feature = "metrics")]
+use crate::poison::Recover;
+
+#[cfg(feature = "metrics")]
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Result, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+use std::{
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "metrics")]
+const PREFIX: &str = "wireguard_udp_proxy_";
+
+/// counters the forwarding loop bumps, rendered by whoever owns them
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub packets_to_target: AtomicU64,
+    pub bytes_to_target: AtomicU64,
+    pub packets_to_clients: AtomicU64,
+    pub bytes_to_clients: AtomicU64,
+    /// packets from the target for a receiver index we have no session for
+    pub unknown_receiver: AtomicU64,
+    /// handshakes reusing the sender index of another client's session, by what we did
+    pub collisions_overwritten: AtomicU64,
+    pub collisions_rejected: AtomicU64,
+    /// addresses still sending for a session handshakes took away from them
+    pub hijacks: AtomicU64,
+    /// handshake responses --strict-responses dropped
+    pub unexpected_responses: AtomicU64,
+    /// cookie replies from clients, or from the target for no handshake we just forwarded there
+    pub stray_cookies: AtomicU64,
+    /// packets to addresses without a session that --amplification held back
+    pub amplification_capped: AtomicU64,
+    /// packets --strict-source dropped for coming from elsewhere than their session's handshake
+    pub strict_source: AtomicU64,
+    /// packets --pace held back
+    pub paced: AtomicU64,
+    /// and dropped for too many being held back already
+    pub pace_overflow: AtomicU64,
+    /// sessions --dead-after expired because their client was unreachable
+    pub dead_sessions: AtomicU64,
+    /// sessions --max-memory evicted, and handshakes it refused a session
+    pub memory_evictions: AtomicU64,
+    pub memory_refused: AtomicU64,
+    /// probes --quic-camouflage answered
+    pub camouflaged: AtomicU64,
+    /// packets --fragment-target or --fragment-clients split
+    pub fragmented: AtomicU64,
+    /// packets that didn't fit the path mtu, dropped or forwarded by --pmtu
+    pub oversize: AtomicU64,
+    /// packets the kernel took only part of
+    pub short_sends: AtomicU64,
+    /// packets that should have been sealed and didn't open
+    pub forged: AtomicU64,
+    /// --decoy answers
+    pub decoys: AtomicU64,
+    /// --refuse-unreachable answers
+    pub refusals: AtomicU64,
+    /// the kernel's running SO_RXQ_OVFL count for our socket
+    pub rx_queue_dropped: AtomicU64,
+    /// recv to send, for a sample of packets
+    pub processing_time: Histogram,
+}
+
+impl Stats {
+    #[cfg(feature = "metrics")]
+    pub fn render(&self, out: &mut String, socket: &str) {
+        header(out, "packets_total", "counter", "packets forwarded");
+        let packets = [
+            ("to_target", &self.packets_to_target),
+            ("to_client", &self.packets_to_clients),
+        ];
+        for (direction, count) in packets {
+            sample(
+                out,
+                "packets_total",
+                &[("direction", direction)],
+                count.load(Relaxed),
+            );
+        }
+        header(out, "bytes_total", "counter", "bytes forwarded");
+        let bytes = [
+            ("to_target", &self.bytes_to_target),
+            ("to_client", &self.bytes_to_clients),
+        ];
+        for (direction, count) in bytes {
+            sample(
+                out,
+                "bytes_total",
+                &[("direction", direction)],
+                count.load(Relaxed),
+            );
+        }
+        header(
+            out,
+            "unknown_receiver_total",
+            "counter",
+            "packets from the target dropped because we have no session for their receiver index",
+        );
+        sample(
+            out,
+            "unknown_receiver_total",
+            &[],
+            self.unknown_receiver.load(Relaxed),
+        );
+        header(
+            out,
+            "sender_collisions_total",
+            "counter",
+            "handshakes reusing the sender index of another client's session",
+        );
+        let collisions = [
+            ("overwritten", &self.collisions_overwritten),
+            ("rejected", &self.collisions_rejected),
+        ];
+        for (action, count) in collisions {
+            sample(
+                out,
+                "sender_collisions_total",
+                &[("action", action)],
+                count.load(Relaxed),
+            );
+        }
+        header(
+            out,
+            "session_hijacks_total",
+            "counter",
+            "addresses still sending for a session a handshake from elsewhere took over",
+        );
+        sample(
+            out,
+            "session_hijacks_total",
+            &[],
+            self.hijacks.load(Relaxed),
+        );
+        header(
+            out,
+            "unexpected_responses_total",
+            "counter",
+            "handshake responses dropped because they answered no initiation we just forwarded",
+        );
+        sample(
+            out,
+            "unexpected_responses_total",
+            &[],
+            self.unexpected_responses.load(Relaxed),
+        );
+        header(
+            out,
+            "stray_cookies_total",
+            "counter",
+            "cookie replies dropped because they came from a client or answered no initiation we just forwarded",
+        );
+        sample(
+            out,
+            "stray_cookies_total",
+            &[],
+            self.stray_cookies.load(Relaxed),
+        );
+        header(
+            out,
+            "amplification_capped_total",
+            "counter",
+            "packets not sent to an address without a session because they'd be more than --amplification allows",
+        );
+        sample(
+            out,
+            "amplification_capped_total",
+            &[],
+            self.amplification_capped.load(Relaxed),
+        );
+        header(
+            out,
+            "strict_source_drops_total",
+            "counter",
+            "packets dropped because they came from elsewhere than their session's handshake",
+        );
+        sample(
+            out,
+            "strict_source_drops_total",
+            &[],
+            self.strict_source.load(Relaxed),
+        );
+        header(
+            out,
+            "fragmented_packets_total",
+            "counter",
+            "packets split into fragments because they didn't fit the mtu",
+        );
+        sample(
+            out,
+            "fragmented_packets_total",
+            &[],
+            self.fragmented.load(Relaxed),
+        );
+        header(
+            out,
+            "oversize_packets_total",
+            "counter",
+            "packets that didn't fit the path mtu, dropped or fragmented by the kernel by --pmtu",
+        );
+        sample(
+            out,
+            "oversize_packets_total",
+            &[],
+            self.oversize.load(Relaxed),
+        );
+        header(
+            out,
+            "short_sends_total",
+            "counter",
+            "packets the kernel sent only part of",
+        );
+        sample(
+            out,
+            "short_sends_total",
+            &[],
+            self.short_sends.load(Relaxed),
+        );
+        header(
+            out,
+            "forged_packets_total",
+            "counter",
+            "packets dropped because they should have been sealed with our key and weren't",
+        );
+        sample(out, "forged_packets_total", &[], self.forged.load(Relaxed));
+        header(
+            out,
+            "paced_packets_total",
+            "counter",
+            "packets to a target held back to keep to its --pace",
+        );
+        sample(out, "paced_packets_total", &[], self.paced.load(Relaxed));
+        header(
+            out,
+            "pace_overflow_total",
+            "counter",
+            "packets to a target dropped because too many were held back to keep to its --pace",
+        );
+        sample(
+            out,
+            "pace_overflow_total",
+            &[],
+            self.pace_overflow.load(Relaxed),
+        );
+        header(
+            out,
+            "dead_sessions_total",
+            "counter",
+            "sessions expired early because their client was unreachable",
+        );
+        sample(
+            out,
+            "dead_sessions_total",
+            &[],
+            self.dead_sessions.load(Relaxed),
+        );
+        header(
+            out,
+            "memory_evictions_total",
+            "counter",
+            "idle sessions evicted to stay under --max-memory",
+        );
+        sample(
+            out,
+            "memory_evictions_total",
+            &[],
+            self.memory_evictions.load(Relaxed),
+        );
+        header(
+            out,
+            "memory_refused_total",
+            "counter",
+            "handshakes for new sessions dropped because --max-memory was used up",
+        );
+        sample(
+            out,
+            "memory_refused_total",
+            &[],
+            self.memory_refused.load(Relaxed),
+        );
+        header(
+            out,
+            "camouflage_responses_total",
+            "counter",
+            "QUIC probes answered with a version negotiation",
+        );
+        sample(
+            out,
+            "camouflage_responses_total",
+            &[],
+            self.camouflaged.load(Relaxed),
+        );
+        header(
+            out,
+            "decoys_total",
+            "counter",
+            "--decoy answers to packets that weren't wireguard",
+        );
+        sample(out, "decoys_total", &[], self.decoys.load(Relaxed));
+        header(
+            out,
+            "refusals_total",
+            "counter",
+            "--refuse-unreachable answers to wireguard packets we refused",
+        );
+        sample(out, "refusals_total", &[], self.refusals.load(Relaxed));
+        header(
+            out,
+            "rx_queue_dropped_total",
+            "counter",
+            "datagrams the kernel dropped because our receive queue was full",
+        );
+        sample(
+            out,
+            "rx_queue_dropped_total",
+            &[("socket", socket)],
+            self.rx_queue_dropped.load(Relaxed),
+        );
+        self.processing_time.render(
+            out,
+            "processing_seconds",
+            "time from receiving a packet to having sent it on, sampled",
+        );
+    }
+}
+
+/// what one worker thread did, to tell whether they share the load
+#[derive(Debug, Default)]
+pub struct Worker {
+    pub packets: AtomicU64,
+    /// waiting for the sessions while other workers held them
+    pub lock_wait_nanos: AtomicU64,
+    pub send_errors: AtomicU64,
+    /// handling packets rather than waiting for them, only kept for num_threads auto and /health
+    busy_nanos: AtomicU64,
+}
+
+impl Worker {
+    pub fn waited(&self, since: Instant) {
+        self.lock_wait_nanos
+            .fetch_add(since.elapsed().as_nanos() as u64, Relaxed);
+    }
+
+    pub fn busy(&self, since: Instant) {
+        self.busy_nanos
+            .fetch_add(since.elapsed().as_nanos() as u64, Relaxed);
+    }
+
+    pub fn busy_nanos(&self) -> u64 {
+        self.busy_nanos.load(Relaxed)
+    }
+}
+
+/// renders workers, numbered in the order they started
+#[cfg(feature = "metrics")]
+pub fn render_workers(out: &mut String, workers: &[Arc<Worker>]) {
+    header(
+        out,
+        "worker_packets_total",
+        "counter",
+        "packets each worker thread received",
+    );
+    for (i, worker) in workers.iter().enumerate() {
+        let packets = worker.packets.load(Relaxed);
+        sample(
+            out,
+            "worker_packets_total",
+            &[("worker", &i.to_string())],
+            packets,
+        );
+    }
+    header(
+        out,
+        "worker_lock_wait_seconds_total",
+        "counter",
+        "time each worker thread spent waiting for the sessions while others held them",
+    );
+    for (i, worker) in workers.iter().enumerate() {
+        let waited = worker.lock_wait_nanos.load(Relaxed) as f64 / 1_000_000_000.0;
+        let _ = writeln!(
+            out,
+            "{}worker_lock_wait_seconds_total{{worker=\"{}\"}} {}",
+            PREFIX, i, waited
+        );
+    }
+    header(
+        out,
+        "worker_send_errors_total",
+        "counter",
+        "sends that failed in each worker thread",
+    );
+    for (i, worker) in workers.iter().enumerate() {
+        let errors = worker.send_errors.load(Relaxed);
+        sample(
+            out,
+            "worker_send_errors_total",
+            &[("worker", &i.to_string())],
+            errors,
+        );
+    }
+}
+
+// upper bounds in microseconds, a healthy proxy should be in the first few
+const BUCKETS: [u64; 12] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 50000];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    // one more for +Inf
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = BUCKETS
+            .iter()
+            .position(|le| micros <= *le)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "histogram", help);
+        let bucket_name = format!("{}_bucket", name);
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Relaxed);
+            let le = match BUCKETS.get(i) {
+                Some(micros) => format!("{}", *micros as f64 / 1_000_000.0),
+                None => "+Inf".to_string(),
+            };
+            sample(out, &bucket_name, &[("le", &le)], count);
+        }
+        let sum = self.sum_nanos.load(Relaxed) as f64 / 1_000_000_000.0;
+        let _ = writeln!(out, "{}{}_sum {}", PREFIX, name, sum);
+        sample(out, &format!("{}_count", name), &[], count);
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}{} {}", PREFIX, name, kind);
+}
+
+#[cfg(feature = "metrics")]
+pub fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    let _ = write!(out, "{}{}", PREFIX, name);
+    for (i, (label, label_value)) in labels.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}{}=\"{}\"",
+            if i == 0 { "{" } else { "," },
+            label,
+            label_value
+        );
+    }
+    if !labels.is_empty() {
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+// /health's rates are over at least this long, however often it's asked
+#[cfg(feature = "metrics")]
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// packets and busy nanoseconds at two points in time, the later one at least WINDOW old before
+/// it moves up, so rates don't depend on how many ask how often
+#[cfg(feature = "metrics")]
+pub struct Window {
+    samples: Mutex<[(Instant, u64, u64); 2]>,
+}
+
+#[cfg(feature = "metrics")]
+impl Window {
+    pub fn new() -> Window {
+        let start = (Instant::now(), 0, 0);
+        Window {
+            samples: Mutex::new([start; 2]),
+        }
+    }
+
+    /// packets per second and busy seconds per second since the earlier sample
+    pub fn rates(&self, packets: u64, busy_nanos: u64) -> (f64, f64) {
+        let now = (Instant::now(), packets, busy_nanos);
+        let mut samples = self.samples.lock().recover();
+        if now.0 - samples[1].0 >= WINDOW {
+            *samples = [samples[1], now];
+        }
+        let (since, since_packets, since_busy) = samples[0];
+        let secs = (now.0 - since).as_secs_f64();
+        if secs == 0.0 {
+            return (0.0, 0.0);
+        }
+        // retired workers take their counts with them
+        let packets = packets.saturating_sub(since_packets) as f64 / secs;
+        let busy = busy_nanos.saturating_sub(since_busy) as f64 / 1e9 / secs;
+        (packets, busy)
+    }
+}
+
+/// serves whatever render writes at http://addr/metrics and health writes at /health from a
+/// background thread
+#[cfg(feature = "metrics")]
+pub fn serve<F, H>(addr: &str, render: F, health: H) -> Result<()>
+where
+    F: Fn(&mut String) + Send + 'static,
+    H: Fn(&mut String) + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // a broken scrape is the scraper's problem
+            let _ = respond(stream, &render, &health);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+fn respond<F, H>(mut stream: TcpStream, render: &F, health: &H) -> Result<()>
+where
+    F: Fn(&mut String),
+    H: Fn(&mut String),
+{
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // we don't care about the headers, but have to read them before answering
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut body = String::new();
+    let mut content_type = "text/plain; version=0.0.4";
+    let status = match request.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            render(&mut body);
+            "200 OK"
+        }
+        Some("/health") => {
+            health(&mut body);
+            content_type = "application/json";
+            "200 OK"
+        }
+        _ => {
+            body.push_str("not found\n");
+            "404 Not Found"
+        }
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        let mut out = String::new();
+        sample(&mut out, "a", &[], 1);
+        sample(&mut out, "b", &[("x", "1"), ("y", "[::]:2")], 3);
+        assert_eq!(
+            out,
+            "wireguard_udp_proxy_a 1\nwireguard_udp_proxy_b{x=\"1\",y=\"[::]:2\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(3));
+        histogram.observe(Duration::from_micros(30));
+        histogram.observe(Duration::from_secs(1));
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "help");
+        assert!(out.contains("wireguard_udp_proxy_h_bucket{le=\"0.000005\"} 1\n"));
+        assert!(out.contains("wireguard_udp_proxy_h_bucket{le=\"0.00005\"} 2\n"));
+        assert!(out.contains("wireguard_udp_proxy_h_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("wireguard_udp_proxy_h_sum 1.000033\n"));
+        assert!(out.contains("wireguard_udp_proxy_h_count 3\n"));
+    }
+}
@@ -0,0 +1,206 @@
+// --mqtt, publishes each event to topic/event_name on an MQTT 3.1.1 broker, QoS 0 over one long
+// lived plain tcp connection that is made again whenever it breaks
+//
+// keep alive is off, we have nothing to say between events and brokers don't expect pings then
+
+use crate::{args, events::Deliver};
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process,
+    time::Duration,
+};
+
+// for connecting, and for each read and write after
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+
+// clean session, and whether a user name and password follow
+const CLEAN_SESSION: u8 = 0x02;
+const PASSWORD: u8 = 0x40;
+const USER_NAME: u8 = 0x80;
+
+/// a user name and password for the broker, from a file with user:password in it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    user: String,
+    password: String,
+}
+
+impl Credentials {
+    pub fn from_file(path: &str) -> Result<Credentials> {
+        let contents = fs::read_to_string(path)?;
+        let (user, password) = contents
+            .trim_end_matches(['\r', '\n'])
+            .split_once(':')
+            .ok_or_else(|| args::invalid(format!("expected user:password in {}", path)))?;
+        Ok(Credentials {
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+pub struct Mqtt {
+    broker: String,
+    topic: String,
+    credentials: Option<Credentials>,
+    connection: Option<TcpStream>,
+}
+
+impl Mqtt {
+    pub fn new(broker: &str, topic: &str, credentials: Option<Credentials>) -> Mqtt {
+        // the port is optional like in mosquitto_pub -h, a literal ipv6 address is in []
+        let broker = if broker
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'))
+        {
+            broker.to_string()
+        } else {
+            format!("{}:1883", broker)
+        };
+        Mqtt {
+            broker,
+            topic: topic.trim_end_matches('/').to_string(),
+            credentials,
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        // looked up every time, it may move
+        let addr = self
+            .broker
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut body = Vec::new();
+        string(&mut body, "MQTT");
+        // protocol level 4 is 3.1.1
+        body.push(4);
+        body.push(match self.credentials {
+            Some(_) => CLEAN_SESSION | USER_NAME | PASSWORD,
+            None => CLEAN_SESSION,
+        });
+        // keep alive, off
+        body.extend_from_slice(&0u16.to_be_bytes());
+        string(&mut body, &format!("wireguard-udp-proxy-{}", process::id()));
+        if let Some(credentials) = &self.credentials {
+            string(&mut body, &credentials.user);
+            string(&mut body, &credentials.password);
+        }
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        match connack {
+            [CONNACK, 2, _, 0] => Ok(stream),
+            [CONNACK, 2, _, code] => Err(Error::other(format!(
+                "the broker refused the connection, return code {}",
+                code
+            ))),
+            _ => Err(Error::new(ErrorKind::InvalidData, "expected a CONNACK")),
+        }
+    }
+}
+
+impl Deliver for Mqtt {
+    fn describe(&self) -> String {
+        format!("mqtt broker {}", self.broker)
+    }
+
+    fn deliver(&mut self, event: &str, json: &str) -> Result<()> {
+        let mut body = Vec::new();
+        string(&mut body, &format!("{}/{}", self.topic, event));
+        body.extend_from_slice(json.as_bytes());
+        let mut stream = match self.connection.take().filter(|stream| !closed(stream)) {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        stream.write_all(&packet(PUBLISH, &body))?;
+        self.connection = Some(stream);
+        Ok(())
+    }
+}
+
+/// whether the broker hung up, it has nothing else to send us after the CONNACK
+fn closed(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let peeked = stream.peek(&mut buf);
+    stream.set_nonblocking(false).is_err()
+        || !matches!(peeked, Err(e) if e.kind() == ErrorKind::WouldBlock)
+}
+
+/// a control packet, the fixed header with its variable length encoded remaining length and body
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// appends a length prefixed utf-8 string
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_mqtt() {
+        assert_eq!(packet(PUBLISH, &[0; 2]), [PUBLISH, 2, 0, 0]);
+        assert_eq!(packet(PUBLISH, &[0; 200])[..3], [PUBLISH, 0xc8, 0x01]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).unwrap();
+            let mut connect = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut connect).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let mut publish = [0u8; 17];
+            stream.read_exact(&mut publish).unwrap();
+            (header[0], connect, publish)
+        });
+        let credentials = Credentials {
+            user: "u".to_string(),
+            password: "p".to_string(),
+        };
+        let mut mqtt = Mqtt::new(&broker, "wg/", Some(credentials));
+        mqtt.deliver("failover", "{}").unwrap();
+        let (kind, connect, publish) = server.join().unwrap();
+        assert_eq!(kind, CONNECT);
+        assert_eq!(connect[..8], *b"\0\x04MQTT\x04\xc2");
+        assert!(connect.ends_with(b"\0\x01u\0\x01p"));
+        assert_eq!(publish[..2], [PUBLISH, 15]);
+        assert_eq!(publish[2..], *b"\0\x0bwg/failover{}");
+    }
+}
@@ -0,0 +1,290 @@
+// --tcp-mux, every client over one connection between two of us instead of one each, a stream per
+// client, so there's one handshake, one flow for whatever is in between to see, and one connection
+// to keep up
+//
+// each frame on the connection is a kind, a stream id and what it carries:
+//
+//     <kind> <stream> <packet, credit or nothing>
+//
+// so one client sending more than the connection takes can't hold the others up behind it, each
+// stream may only have WINDOW bytes on the way, the other end grants more as it passes them on,
+// and the writer takes a packet from each stream in turn that has any and the credit for it,
+// the rest wait in their stream's queue, dropped once that's full
+//
+// frames ready together go in one write, see stream.rs
+//
+// a connection starts with PREFACE on its own, which no wireguard packet is as short as, that's
+// how the far end tells it from a connection with a client of its own
+
+use crate::{
+    poison::Recover,
+    stream::{Conn, COALESCE},
+};
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+pub const PREFACE: &[u8] = b"mux1";
+
+// bytes a stream may have on the way before the other end grants more
+const WINDOW: usize = 256 * 1024;
+
+// packets a stream has waiting for credit or their turn, beyond that they are dropped
+const QUEUE_LEN: usize = 64;
+
+const DATA: u8 = 0;
+const CREDIT: u8 = 1;
+const CLOSE: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame<'a> {
+    Data(u32, &'a [u8]),
+    /// the other end passed on this many more bytes of ours
+    Credit(u32, u32),
+    /// the other end forgot the stream
+    Close(u32),
+}
+
+impl Frame<'_> {
+    pub fn parse(frame: &[u8]) -> Option<Frame<'_>> {
+        let stream = u32::from_be_bytes(frame.get(1..5)?.try_into().ok()?);
+        match frame[0] {
+            DATA => Some(Frame::Data(stream, &frame[5..])),
+            CREDIT => Some(Frame::Credit(
+                stream,
+                u32::from_be_bytes(frame.get(5..9)?.try_into().ok()?),
+            )),
+            CLOSE => Some(Frame::Close(stream)),
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let (kind, stream, rest) = match self {
+            Frame::Data(stream, packet) => (DATA, stream, packet.to_vec()),
+            Frame::Credit(stream, credit) => (CREDIT, stream, credit.to_be_bytes().to_vec()),
+            Frame::Close(stream) => (CLOSE, stream, Vec::new()),
+        };
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(&rest);
+        frame
+    }
+}
+
+struct Out {
+    queue: VecDeque<Vec<u8>>,
+    /// bytes we may still send
+    credit: usize,
+    /// bytes of the other end's we passed on and haven't granted it yet
+    received: usize,
+}
+
+impl Default for Out {
+    fn default() -> Out {
+        Out {
+            queue: VecDeque::new(),
+            credit: WINDOW,
+            received: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    streams: BTreeMap<u32, Out>,
+    /// credits and closes, which go before any packet
+    control: VecDeque<Vec<u8>>,
+    /// the stream whose packet went last
+    turn: u32,
+    closed: bool,
+}
+
+impl State {
+    /// what to write next, a control frame or the next stream's packet there's credit for
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+        let ready = |out: &&mut Out| out.queue.front().is_some_and(|p| p.len() <= out.credit);
+        let turn = self.turn;
+        let after = self
+            .streams
+            .range_mut((Bound::Excluded(turn), Bound::Unbounded))
+            .find(|(_, out)| ready(out))
+            .map(|(stream, _)| *stream);
+        let stream = match after {
+            Some(stream) => stream,
+            None => {
+                *self
+                    .streams
+                    .range_mut(..=turn)
+                    .find(|(_, out)| ready(out))?
+                    .0
+            }
+        };
+        let out = self.streams.get_mut(&stream)?;
+        let packet = out.queue.pop_front()?;
+        out.credit -= packet.len();
+        self.turn = stream;
+        Some(Frame::Data(stream, &packet).encode())
+    }
+}
+
+/// the writing side of a connection streams share
+pub struct Mux {
+    conn: Arc<Conn>,
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+impl Mux {
+    /// starts the thread writing to conn, which ends with it
+    pub fn new(conn: Arc<Conn>) -> Arc<Mux> {
+        let mux = Arc::new(Mux {
+            conn,
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+        });
+        let writer = Arc::clone(&mux);
+        thread::spawn(move || writer.write());
+        mux
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().recover().closed
+    }
+
+    /// queues packet on stream, false if it's dropped, the queue being full or the connection gone
+    pub fn send(&self, stream: u32, packet: &[u8]) -> bool {
+        let mut state = self.state.lock().recover();
+        if state.closed {
+            return false;
+        }
+        let out = state.streams.entry(stream).or_default();
+        if out.queue.len() >= QUEUE_LEN {
+            return false;
+        }
+        out.queue.push_back(packet.to_vec());
+        self.wake.notify_one();
+        true
+    }
+
+    /// the other end's len bytes on stream were passed on, grants it more once it's half way
+    pub fn received(&self, stream: u32, len: usize) {
+        let mut state = self.state.lock().recover();
+        let out = state.streams.entry(stream).or_default();
+        out.received += len;
+        if out.received >= WINDOW / 2 {
+            let credit = out.received as u32;
+            out.received = 0;
+            state
+                .control
+                .push_back(Frame::Credit(stream, credit).encode());
+            self.wake.notify_one();
+        }
+    }
+
+    pub fn credit(&self, stream: u32, credit: u32) {
+        let mut state = self.state.lock().recover();
+        if let Some(out) = state.streams.get_mut(&stream) {
+            out.credit = out.credit.saturating_add(credit as usize);
+            self.wake.notify_one();
+        }
+    }
+
+    /// forgets stream, telling the other end to if tell
+    pub fn close(&self, stream: u32, tell: bool) {
+        let mut state = self.state.lock().recover();
+        state.streams.remove(&stream);
+        if tell {
+            state.control.push_back(Frame::Close(stream).encode());
+            self.wake.notify_one();
+        }
+    }
+
+    /// ends the writer and the connection
+    pub fn shutdown(&self) {
+        self.state.lock().recover().closed = true;
+        self.wake.notify_one();
+        self.conn.close();
+    }
+
+    fn write(&self) {
+        let mut state = self.state.lock().recover();
+        loop {
+            if state.closed {
+                return;
+            }
+            let Some(frame) = state.next() else {
+                state = self.wake.wait(state).recover();
+                continue;
+            };
+            let mut len = frame.len();
+            let mut frames = vec![frame];
+            while len < COALESCE {
+                let Some(frame) = state.next() else {
+                    break;
+                };
+                len += frame.len();
+                frames.push(frame);
+            }
+            drop(state);
+            if self.conn.send_all(&frames).is_err() {
+                self.shutdown();
+                return;
+            }
+            state = self.state.lock().recover();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mux() {
+        for frame in [
+            Frame::Data(7, &[4, 0, 0, 0, 1]),
+            Frame::Credit(7, 1 << 20),
+            Frame::Close(u32::MAX),
+        ] {
+            assert_eq!(Frame::parse(&frame.encode()), Some(frame));
+        }
+        assert_eq!(Frame::parse(&[DATA, 0, 0]), None);
+        assert_eq!(Frame::parse(&[9, 0, 0, 0, 1]), None);
+
+        let mut state = State::default();
+        // stream 1 stalled, the other side granting nothing more
+        state.streams.insert(
+            1,
+            Out {
+                queue: VecDeque::from([vec![0; 100], vec![0; 100]]),
+                credit: 150,
+                received: 0,
+            },
+        );
+        state.streams.insert(
+            2,
+            Out {
+                queue: VecDeque::from([vec![2; 10], vec![2; 10]]),
+                ..Out::default()
+            },
+        );
+        state.control.push_back(Frame::Close(3).encode());
+        let sent: Vec<_> = std::iter::from_fn(|| state.next()).collect();
+        // the close first, then each stream in turn, and 2's second packet with 1 out of credit
+        assert_eq!(sent.len(), 4);
+        assert_eq!(Frame::parse(&sent[0]), Some(Frame::Close(3)));
+        assert!(matches!(Frame::parse(&sent[1]), Some(Frame::Data(1, _))));
+        assert!(matches!(Frame::parse(&sent[2]), Some(Frame::Data(2, _))));
+        assert!(matches!(Frame::parse(&sent[3]), Some(Frame::Data(2, _))));
+        assert_eq!(state.streams[&1].queue.len(), 1);
+        assert_eq!(state.streams[&1].credit, 50);
+    }
+}
@@ -0,0 +1,236 @@
+// --other-backend, sharing our port with another udp service, datagrams that aren't wireguard go to
+// its backend instead of being dropped
+//
+// like a NAT, each client gets its own socket towards the backend so its answers can be told apart,
+// and a thread that passes them back until the flow has been idle for IDLE_TIME
+
+use crate::{
+    clock::{self, Tick},
+    pktinfo::LocalAddr,
+    poison::Recover,
+};
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Result},
+    net::{SocketAddr, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+// longer than QUIC's usual idle timeout of 30s
+const IDLE_TIME: Duration = Duration::from_secs(60);
+
+// beyond that packets that would start a new flow are dropped
+const MAX_FLOWS: usize = 1024;
+
+/// which packets may start a flow to the backend, once there is one the rest of them follow
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Any,
+    Dns,
+    Quic,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Protocol::Any),
+            "dns" => Ok(Protocol::Dns),
+            "quic" => Ok(Protocol::Quic),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl Protocol {
+    /// whether packet looks like the start of a conversation in it
+    pub fn starts(&self, packet: &[u8]) -> bool {
+        match self {
+            Protocol::Any => true,
+            // a query with at least one question
+            Protocol::Dns => {
+                packet.len() >= 12 && packet[2] & 0x80 == 0 && (packet[4] | packet[5]) != 0
+            }
+            // a long header initial, RFC 9000 17.2.2, which a client's first packets are
+            Protocol::Quic => {
+                packet.len() >= 1200 && packet[0] & 0xc0 == 0xc0 && packet[0] & 0x30 == 0
+            }
+        }
+    }
+}
+
+struct Flow {
+    socket: UdpSocket,
+    active: AtomicU64,
+}
+
+pub struct Other {
+    backend: SocketAddr,
+    protocol: Protocol,
+    flows: Mutex<HashMap<SocketAddr, Arc<Flow>>>,
+    pub to_backend: AtomicU64,
+    pub to_clients: AtomicU64,
+}
+
+impl Other {
+    pub fn new(backend: SocketAddr, protocol: Protocol) -> Other {
+        Other {
+            backend,
+            protocol,
+            flows: Mutex::new(HashMap::new()),
+            to_backend: AtomicU64::new(0),
+            to_clients: AtomicU64::new(0),
+        }
+    }
+
+    /// how many clients we are forwarding for
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn flows(&self) -> usize {
+        self.flows.lock().recover().len()
+    }
+
+    /// forwards packet from client to the backend if it belongs there, returns whether it did,
+    /// a new flow passes the backend's answers to reply
+    pub fn forward<F>(
+        &'static self,
+        packet: &[u8],
+        client: SocketAddr,
+        local_addr: Option<LocalAddr>,
+        reply: F,
+    ) -> Result<bool>
+    where
+        F: Fn(&[u8], SocketAddr, Option<LocalAddr>) -> Result<usize> + Send + 'static,
+    {
+        let now = clock::now();
+        let mut flows = self.flows.lock().recover();
+        let flow = match flows.get(&client) {
+            Some(flow) => Arc::clone(flow),
+            None => {
+                if !self.protocol.starts(packet) || flows.len() >= MAX_FLOWS {
+                    return Ok(false);
+                }
+                let bind_addr: SocketAddr = if self.backend.is_ipv4() {
+                    SocketAddr::from(([0, 0, 0, 0], 0))
+                } else {
+                    SocketAddr::from(([0u16; 8], 0))
+                };
+                let socket = UdpSocket::bind(bind_addr)?;
+                socket.connect(self.backend)?;
+                // often enough to notice the flow went idle
+                socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+                let flow = Arc::new(Flow {
+                    socket,
+                    active: AtomicU64::new(now),
+                });
+                flows.insert(client, Arc::clone(&flow));
+                let answers = Arc::clone(&flow);
+                thread::spawn(move || self.answer(client, local_addr, answers, reply));
+                flow
+            }
+        };
+        drop(flows);
+        flow.active.store(now, Relaxed);
+        // the backend being down is its business
+        if flow.socket.send(packet).is_ok() {
+            self.to_backend.fetch_add(1, Relaxed);
+        }
+        Ok(true)
+    }
+
+    /// passes what the backend sends on flow to client, until it's idle
+    fn answer<F>(
+        &self,
+        client: SocketAddr,
+        local_addr: Option<LocalAddr>,
+        flow: Arc<Flow>,
+        reply: F,
+    ) where
+        F: Fn(&[u8], SocketAddr, Option<LocalAddr>) -> Result<usize>,
+    {
+        let mut buf = [0u8; 65536];
+        loop {
+            match flow.socket.recv(&mut buf) {
+                Ok(recv) => {
+                    flow.active.store(clock::now(), Relaxed);
+                    if reply(&buf[..recv], client, local_addr).is_ok() {
+                        self.to_clients.fetch_add(1, Relaxed);
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let mut flows = self.flows.lock().recover();
+                    if idle(&flow, clock::now()) {
+                        flows.remove(&client);
+                        return;
+                    }
+                }
+                // the backend refusing an earlier packet, it may be back for the next one
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+fn idle(flow: &Flow, now: Tick) -> bool {
+    now.saturating_sub(flow.active.load(Relaxed)) >= clock::ticks(IDLE_TIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_other() {
+        let query = [0x12, 0x34, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        assert!(Protocol::Dns.starts(&query));
+        assert!(!Protocol::Dns.starts(&query[..11]));
+        let mut initial = vec![0u8; 1200];
+        initial[0] = 0xc3;
+        assert!(Protocol::Quic.starts(&initial));
+        assert!(!Protocol::Dns.starts(&initial));
+        assert!(!Protocol::Quic.starts(&query));
+        initial[0] = 0xe3;
+        assert!(!Protocol::Quic.starts(&initial));
+
+        let backend = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other: &Other = Box::leak(Box::new(Other::new(
+            backend.local_addr().unwrap(),
+            Protocol::Dns,
+        )));
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let (replies, replied) = mpsc::channel();
+        let reply = move |buf: &[u8], to, _| {
+            replies.send((buf.to_vec(), to)).unwrap();
+            Ok(buf.len())
+        };
+        assert!(!other
+            .forward(b"garbage", client, None, reply.clone())
+            .unwrap());
+        assert!(other.forward(&query, client, None, reply.clone()).unwrap());
+        // the flow is there now
+        assert!(other.forward(b"more", client, None, reply).unwrap());
+        assert_eq!(other.flows(), 1);
+
+        let mut buf = [0u8; 64];
+        let (recv, from) = backend.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[..recv], query);
+        assert_eq!(backend.recv(&mut buf).unwrap(), 4);
+        backend.send_to(b"answer", from).unwrap();
+        assert_eq!(replied.recv().unwrap(), (b"answer".to_vec(), client));
+        assert_eq!(other.to_backend.load(Relaxed), 2);
+        // counted right after the reply we just got
+        while other.to_clients.load(Relaxed) == 0 {
+            thread::yield_now();
+        }
+    }
+}
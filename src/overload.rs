@@ -0,0 +1,88 @@
+// load shedding, once the kernel has been dropping datagrams on our socket for SUSTAINED_SECS in a
+// row we are clearly not keeping up, so stop taking handshakes from addresses without a session
+// (new clients, or a handshake flood) to keep the tunnels we already forward working
+
+use std::sync::atomic::{
+    AtomicBool, AtomicU32, AtomicU64,
+    Ordering::{Relaxed, SeqCst},
+};
+
+const SUSTAINED_SECS: u64 = 3;
+
+// keep shedding this long after the last second with drops
+const COOLDOWN_SECS: u64 = 10;
+
+#[derive(Debug, Default)]
+pub struct Overload {
+    dropped: AtomicU32,
+    // whether the drop count went up during second
+    dropping: AtomicBool,
+    second: AtomicU64,
+    sustained: AtomicU64,
+    shedding_until: AtomicU64,
+}
+
+impl Overload {
+    /// records the socket's total drop count as of second now, returns Some(true) when shedding
+    /// starts and Some(false) when it stops
+    pub fn update(&self, now: u64, dropped: u32) -> Option<bool> {
+        let increased = self.dropped.swap(dropped, Relaxed) != dropped;
+        let second = self.second.load(SeqCst);
+        if second == now
+            || self
+                .second
+                .compare_exchange(second, now, SeqCst, SeqCst)
+                .is_err()
+        {
+            if increased {
+                self.dropping.store(true, Relaxed);
+            }
+            return None;
+        }
+        // we are the first to see the new second, judge the previous one
+        let dropping = self.dropping.swap(increased, Relaxed);
+        let sustained = match (dropping, now == second + 1) {
+            (false, _) => 0,
+            (true, true) => self.sustained.load(Relaxed) + 1,
+            (true, false) => 1,
+        };
+        self.sustained.store(sustained, Relaxed);
+
+        let was_shedding = second < self.shedding_until.load(Relaxed);
+        if sustained >= SUSTAINED_SECS {
+            self.shedding_until.store(now + COOLDOWN_SECS, Relaxed);
+            return (!was_shedding).then_some(true);
+        }
+        (was_shedding && !self.shedding(now)).then_some(false)
+    }
+
+    pub fn shedding(&self, now: u64) -> bool {
+        now < self.shedding_until.load(Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overload() {
+        let overload = Overload::default();
+        assert_eq!(overload.update(1, 0), None);
+        let mut dropped = 0;
+        for now in 2..=SUSTAINED_SECS + 1 {
+            dropped += 5;
+            assert_eq!(overload.update(now, dropped), None);
+            assert!(!overload.shedding(now));
+        }
+        dropped += 5;
+        assert_eq!(overload.update(SUSTAINED_SECS + 2, dropped), Some(true));
+        assert!(overload.shedding(SUSTAINED_SECS + 2));
+
+        // no more drops, stops after the cooldown
+        let last = SUSTAINED_SECS + 2 + COOLDOWN_SECS;
+        assert_eq!(overload.update(last - 1, dropped), None);
+        assert_eq!(overload.update(last, dropped), Some(false));
+        assert!(!overload.shedding(last));
+    }
+}
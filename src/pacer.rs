@@ -0,0 +1,170 @@
+// --pace, smooths bursts towards a target to a steady rate so a policer on a rate limited link in
+// between doesn't drop them, packets over the burst allowance are held back until it's their turn
+//
+// this is GCRA, a token bucket with the bucket kept as the theoretical arrival time of the next
+// packet, which fits in one atomic shared by every worker thread
+//
+// a worker doesn't wait for a held back packet's turn, it leaves it to a thread of the pacer's own
+// that sends what's held in the order it's due and ends once nothing is
+
+use crate::poison::Recover;
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// packets held back at most, beyond that they're dropped
+const MAX_HELD: usize = 1024;
+
+/// sends a held back packet
+pub type Deferred = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Held {
+    // by when they're due
+    sends: VecDeque<(Instant, Deferred)>,
+    // the pacer's thread is running
+    sending: bool,
+}
+
+/// a --pace rate and burst size, in bytes per second and bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pace {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+pub struct Pacer {
+    pace: Pace,
+    started: Instant,
+    // nanoseconds after started
+    theoretical_arrival: AtomicU64,
+    held: Mutex<Held>,
+    // something was held back that's due before what the thread waits for
+    sooner: Condvar,
+}
+
+impl Pacer {
+    pub fn new(pace: Pace) -> Pacer {
+        Pacer {
+            pace,
+            started: Instant::now(),
+            theoretical_arrival: AtomicU64::new(0),
+            held: Mutex::default(),
+            sooner: Condvar::new(),
+        }
+    }
+
+    /// how long to wait at now before sending len bytes, the bytes are accounted for already
+    pub fn delay(&self, len: usize, now: Instant) -> Duration {
+        let now = now.saturating_duration_since(self.started).as_nanos() as u64;
+        let cost = self.nanos(len as u64);
+        let tolerance = self.nanos(self.pace.burst);
+        let mut tat = self.theoretical_arrival.load(Relaxed);
+        loop {
+            let next = tat.max(now) + cost;
+            match self
+                .theoretical_arrival
+                .compare_exchange_weak(tat, next, Relaxed, Relaxed)
+            {
+                Ok(_) => return Duration::from_nanos(next.saturating_sub(now + tolerance)),
+                Err(current) => tat = current,
+            }
+        }
+    }
+
+    /// has send run on the pacer's thread at due, false if too much is held back already
+    pub fn hold(self: &Arc<Self>, due: Instant, send: Deferred) -> bool {
+        let mut held = self.held.lock().recover();
+        if held.sends.len() >= MAX_HELD {
+            return false;
+        }
+        // workers racing may hold their packets back in a different order than they're due
+        let at = held
+            .sends
+            .iter()
+            .rposition(|(other, _)| *other <= due)
+            .map_or(0, |other| other + 1);
+        held.sends.insert(at, (due, send));
+        if at == 0 {
+            self.sooner.notify_one();
+        }
+        if !held.sending {
+            held.sending = true;
+            let pacer = Arc::clone(self);
+            thread::spawn(move || pacer.send());
+        }
+        true
+    }
+
+    /// sends what's held back as it comes due, until nothing is
+    fn send(&self) {
+        let mut held = self.held.lock().recover();
+        loop {
+            let wait = match held.sends.front() {
+                Some((due, _)) => due.saturating_duration_since(Instant::now()),
+                None => {
+                    held.sending = false;
+                    return;
+                }
+            };
+            if !wait.is_zero() {
+                held = self.sooner.wait_timeout(held, wait).recover().0;
+                continue;
+            }
+            if let Some((_, send)) = held.sends.pop_front() {
+                drop(held);
+                send();
+                held = self.held.lock().recover();
+            }
+        }
+    }
+
+    /// how long sending bytes takes at our rate
+    fn nanos(&self, bytes: u64) -> u64 {
+        (bytes as u128 * 1_000_000_000 / self.pace.rate as u128) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer() {
+        // 1000 bytes a millisecond, with room for two of them at once
+        let pacer = Pacer::new(Pace {
+            rate: 1_000_000,
+            burst: 2000,
+        });
+        let start = pacer.started;
+        assert_eq!(pacer.delay(1000, start), Duration::ZERO);
+        assert_eq!(pacer.delay(1000, start), Duration::ZERO);
+        assert_eq!(pacer.delay(1000, start), Duration::from_millis(1));
+        assert_eq!(pacer.delay(1000, start), Duration::from_millis(2));
+        // caught up after a pause
+        let later = start + Duration::from_millis(10);
+        assert_eq!(pacer.delay(1000, later), Duration::ZERO);
+        assert_eq!(pacer.delay(1000, later), Duration::ZERO);
+        assert_eq!(pacer.delay(1000, later), Duration::from_millis(1));
+
+        // sent in the order they're due, however they were held back
+        let pacer = Arc::new(pacer);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let now = Instant::now();
+        for (n, due) in [(2, 20), (0, 0), (1, 10)] {
+            let tx = tx.clone();
+            let due = now + Duration::from_millis(due);
+            assert!(pacer.hold(due, Box::new(move || tx.send(n).unwrap())));
+        }
+        let sent: Vec<i32> = rx.iter().take(3).collect();
+        assert_eq!(sent, [0, 1, 2]);
+        assert!(now.elapsed() >= Duration::from_millis(20));
+    }
+}
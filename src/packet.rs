@@ -0,0 +1,171 @@
+// wireguard message parsing, only needs core and never allocates, so it is usable without the std
+// feature by anything else that has to make sense of wireguard packets
+
+// https://www.wireguard.com/protocol/
+// https://medium.com/asecuritysite-when-bob-met-alice/the-new-way-to-create-a-secure-tunnel-the-wireguard-protocol-89efe954af02
+
+use WgPacket::{Cookie, Data, HandShakeInitiation, HandShakeResponse, Unknown};
+
+#[derive(Debug, PartialEq)]
+pub enum WgPacket {
+    HandShakeInitiation { sender: u32 },
+    HandShakeResponse { sender: u32, receiver: u32 },
+    Data { receiver: u32 },
+    Cookie { receiver: u32 },
+    Unknown { message_type: u8, receiver: u32 },
+}
+
+/// the N bytes of buf at at, which the caller checked are there
+pub fn bytes<const N: usize>(buf: &[u8], at: usize) -> [u8; N] {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&buf[at..at + N]);
+    bytes
+}
+
+impl WgPacket {
+    pub fn parse(buf: &[u8]) -> Option<WgPacket> {
+        let recv = buf.len();
+        // smallest packet is cookie which is 10 bytes
+        if recv < 10 {
+            return None;
+        }
+        match buf[0] {
+            1 => Some(HandShakeInitiation {
+                sender: u32::from_le_bytes(bytes(buf, 4)),
+            }),
+            2 => {
+                if recv < 12 {
+                    None
+                } else {
+                    Some(HandShakeResponse {
+                        sender: u32::from_le_bytes(bytes(buf, 4)),
+                        receiver: u32::from_le_bytes(bytes(buf, 8)),
+                    })
+                }
+            }
+            3 => Some(Cookie {
+                receiver: u32::from_le_bytes(bytes(buf, 4)),
+            }),
+            4 => Some(Data {
+                receiver: u32::from_le_bytes(bytes(buf, 4)),
+            }),
+            _ => None,
+        }
+    }
+
+    /// like parse, but message types wireguard doesn't have are Unknown instead of None, for forks
+    /// that add their own, we assume they carry a receiver index where data does
+    pub fn parse_unknown(buf: &[u8]) -> Option<WgPacket> {
+        match WgPacket::parse(buf) {
+            None if buf.len() >= 10 && !(1..=4).contains(&buf[0]) => Some(Unknown {
+                message_type: buf[0],
+                receiver: u32::from_le_bytes(bytes(buf, 4)),
+            }),
+            packet => packet,
+        }
+    }
+
+    pub fn receiver(&self) -> Option<&u32> {
+        match self {
+            HandShakeInitiation { .. } => None,
+            HandShakeResponse { receiver, .. } => Some(receiver),
+            Data { receiver } => Some(receiver),
+            Cookie { receiver } => Some(receiver),
+            Unknown { receiver, .. } => Some(receiver),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wg_parse() {
+        let sender = 3927566598u32;
+        let sender_bytes = sender.to_le_bytes();
+        let receiver = 350987235u32;
+        let receiver_bytes = receiver.to_le_bytes();
+
+        let packet = [
+            1,
+            0,
+            0,
+            0,
+            sender_bytes[0],
+            sender_bytes[1],
+            sender_bytes[2],
+            sender_bytes[3],
+            0,
+            0,
+        ];
+        assert_eq!(
+            WgPacket::parse(&packet),
+            Some(HandShakeInitiation { sender })
+        );
+
+        let packet = [
+            2,
+            0,
+            0,
+            0,
+            sender_bytes[0],
+            sender_bytes[1],
+            sender_bytes[2],
+            sender_bytes[3],
+            receiver_bytes[0],
+            receiver_bytes[1],
+            receiver_bytes[2],
+            receiver_bytes[3],
+            0,
+            0,
+        ];
+        assert_eq!(
+            WgPacket::parse(&packet),
+            Some(HandShakeResponse { sender, receiver })
+        );
+
+        let packet = [
+            3,
+            0,
+            0,
+            0,
+            receiver_bytes[0],
+            receiver_bytes[1],
+            receiver_bytes[2],
+            receiver_bytes[3],
+            0,
+            0,
+        ];
+        assert_eq!(WgPacket::parse(&packet), Some(Cookie { receiver }));
+
+        let packet = [
+            4,
+            0,
+            0,
+            0,
+            receiver_bytes[0],
+            receiver_bytes[1],
+            receiver_bytes[2],
+            receiver_bytes[3],
+            0,
+            0,
+        ];
+        assert_eq!(WgPacket::parse(&packet), Some(Data { receiver }));
+
+        let mut packet = packet;
+        packet[0] = 5;
+        assert_eq!(WgPacket::parse(&packet), None);
+        assert_eq!(
+            WgPacket::parse_unknown(&packet),
+            Some(Unknown {
+                message_type: 5,
+                receiver
+            })
+        );
+        assert_eq!(WgPacket::parse_unknown(&packet[..9]), None);
+        // a short response is still invalid, not unknown
+        packet[0] = 2;
+        assert_eq!(WgPacket::parse_unknown(&packet), None);
+    }
+}
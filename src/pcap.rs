@@ -0,0 +1,401 @@
+// --pcap, a capture of the wireguard packets we forward, each as a plain udp datagram from whoever
+// sent it to where we listen, for wireshark and tcpdump to read
+//
+// --pcap-filter picks which go in by what we parsed of them:
+//
+//     type==handshake or peer==203.0.113.0/24 and not direction==from-target
+//
+// fields: type (initiation, response, cookie, data, keepalive, handshake for the first three, or
+// a message type number), peer (the client's or target's address, or a range of them), port,
+// index (a sender or receiver index in hex) and direction (to-target, from-target), compared
+// with == or !=, joined with and, or, not and parentheses, and before or
+//
+// workers never wait for the disk, records the writer doesn't take quickly enough are dropped and
+// counted
+
+use crate::{args, cidr::Cidr, error::Result, packet::WgPacket};
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// records waiting for the writer before we drop more
+const BACKLOG: usize = 1024;
+
+// the ip header is all there is to a DLT_RAW packet
+const LINKTYPE_RAW: u32 = 101;
+
+const KEEPALIVE_LEN: usize = 32;
+
+/// what a filter sees of a packet
+pub struct Meta<'a> {
+    pub packet: &'a WgPacket,
+    pub buf: &'a [u8],
+    /// the client or target it's from
+    pub peer: SocketAddr,
+    pub from_target: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Kind {
+    Initiation,
+    Response,
+    Cookie,
+    Data,
+    Keepalive,
+    Handshake,
+    Type(u8),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Field {
+    Type(Kind),
+    Peer(Cidr),
+    Port(u16),
+    Index(u32),
+    FromTarget(bool),
+}
+
+impl Field {
+    fn parse(field: &str, value: &str) -> std::result::Result<Field, String> {
+        let bad = || format!("{}=={} isn't something a filter knows", field, value);
+        Ok(match field {
+            "type" => Field::Type(match value {
+                "initiation" => Kind::Initiation,
+                "response" => Kind::Response,
+                "cookie" => Kind::Cookie,
+                "data" => Kind::Data,
+                "keepalive" => Kind::Keepalive,
+                "handshake" => Kind::Handshake,
+                _ => Kind::Type(value.parse().map_err(|_| bad())?),
+            }),
+            "peer" => Field::Peer(value.parse()?),
+            "port" => Field::Port(value.parse().map_err(|_| bad())?),
+            "index" => Field::Index(u32::from_str_radix(value, 16).map_err(|_| bad())?),
+            "direction" => Field::FromTarget(match value {
+                "to-target" => false,
+                "from-target" => true,
+                _ => return Err(bad()),
+            }),
+            _ => return Err(bad()),
+        })
+    }
+
+    fn matches(&self, meta: &Meta) -> bool {
+        match self {
+            Field::Type(kind) => match (kind, meta.packet) {
+                (Kind::Initiation, WgPacket::HandShakeInitiation { .. })
+                | (Kind::Response, WgPacket::HandShakeResponse { .. })
+                | (Kind::Cookie, WgPacket::Cookie { .. })
+                | (Kind::Data, WgPacket::Data { .. }) => true,
+                (Kind::Keepalive, WgPacket::Data { .. }) => meta.buf.len() == KEEPALIVE_LEN,
+                (Kind::Handshake, packet) => {
+                    !matches!(packet, WgPacket::Data { .. } | WgPacket::Unknown { .. })
+                }
+                (Kind::Type(number), _) => meta.buf.first() == Some(number),
+                _ => false,
+            },
+            Field::Peer(cidr) => cidr.contains(meta.peer.ip()),
+            Field::Port(port) => meta.peer.port() == *port,
+            Field::Index(index) => {
+                let sender = match meta.packet {
+                    WgPacket::HandShakeInitiation { sender }
+                    | WgPacket::HandShakeResponse { sender, .. } => Some(sender),
+                    _ => None,
+                };
+                sender == Some(index) || meta.packet.receiver() == Some(index)
+            }
+            Field::FromTarget(from_target) => meta.from_target == *from_target,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    Is(Field, bool),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Filter> {
+        let invalid = |reason: String| args::invalid(format!("--pcap-filter: {}", reason));
+        let tokens = tokens(expression);
+        let mut at = 0;
+        let filter = or(&tokens, &mut at).map_err(invalid)?;
+        match tokens.get(at) {
+            None => Ok(filter),
+            Some(token) => Err(invalid(format!("{} where it should have ended", token))),
+        }
+    }
+
+    pub fn matches(&self, meta: &Meta) -> bool {
+        match self {
+            Filter::Is(field, equal) => field.matches(meta) == *equal,
+            Filter::Not(filter) => !filter.matches(meta),
+            Filter::And(a, b) => a.matches(meta) && b.matches(meta),
+            Filter::Or(a, b) => a.matches(meta) || b.matches(meta),
+        }
+    }
+}
+
+type Parsed = std::result::Result<Filter, String>;
+
+/// words, parentheses and the operators in between, which need no spaces around them
+fn tokens(expression: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '(' | ')' => Some(c.to_string()),
+            '=' | '!' if chars.peek() == Some(&'=') => {
+                chars.next();
+                Some(format!("{}=", c))
+            }
+            c if c.is_whitespace() => None,
+            c => {
+                word.push(c);
+                continue;
+            }
+        };
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        tokens.extend(token);
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+fn or(tokens: &[String], at: &mut usize) -> Parsed {
+    let mut filter = and(tokens, at)?;
+    while tokens.get(*at).is_some_and(|t| t == "or") {
+        *at += 1;
+        filter = Filter::Or(Box::new(filter), Box::new(and(tokens, at)?));
+    }
+    Ok(filter)
+}
+
+fn and(tokens: &[String], at: &mut usize) -> Parsed {
+    let mut filter = not(tokens, at)?;
+    while tokens.get(*at).is_some_and(|t| t == "and") {
+        *at += 1;
+        filter = Filter::And(Box::new(filter), Box::new(not(tokens, at)?));
+    }
+    Ok(filter)
+}
+
+fn not(tokens: &[String], at: &mut usize) -> Parsed {
+    let token = tokens.get(*at).ok_or("it ended early")?;
+    *at += 1;
+    match token.as_str() {
+        "not" => Ok(Filter::Not(Box::new(not(tokens, at)?))),
+        "(" => {
+            let filter = or(tokens, at)?;
+            match tokens.get(*at) {
+                Some(close) if close == ")" => {
+                    *at += 1;
+                    Ok(filter)
+                }
+                _ => Err("a ( isn't closed".to_string()),
+            }
+        }
+        field => {
+            let equal = match tokens.get(*at).map(String::as_str) {
+                Some("==") => true,
+                Some("!=") => false,
+                _ => return Err(format!("{} isn't followed by == or !=", field)),
+            };
+            let value = tokens.get(*at + 1).ok_or("it ended early")?;
+            *at += 2;
+            Ok(Filter::Is(Field::parse(field, value)?, equal))
+        }
+    }
+}
+
+pub struct Pcap {
+    filter: Option<Filter>,
+    /// where we listen, what captured packets are addressed to
+    local: SocketAddr,
+    records: SyncSender<Vec<u8>>,
+    pub dropped: AtomicU64,
+}
+
+impl Pcap {
+    /// writes the capture file's header and starts the writer
+    pub fn create(path: &str, filter: Option<Filter>, local: SocketAddr) -> Result<Pcap> {
+        let file =
+            File::create(path).map_err(|e| args::invalid(format!("--pcap {}: {}", path, e)))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        // timezone and timestamp accuracy, then the snapshot length
+        file.write_all(&[0; 8])?;
+        file.write_all(&65535u32.to_le_bytes())?;
+        file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        file.flush()?;
+        let (records, written) = mpsc::sync_channel(BACKLOG);
+        thread::spawn(move || write(file, written));
+        Ok(Pcap {
+            filter,
+            local,
+            records,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// captures the packet in meta if the filter takes it, to is where we got it at if we know
+    pub fn capture(&self, meta: &Meta, to: Option<IpAddr>) {
+        if self.filter.as_ref().is_some_and(|f| !f.matches(meta)) {
+            return;
+        }
+        let to = SocketAddr::new(to.unwrap_or(self.local.ip()), self.local.port());
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let datagram = datagram(meta.peer, to, meta.buf);
+        let mut record = Vec::with_capacity(16 + datagram.len());
+        record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        record.extend_from_slice(&datagram);
+        if self.records.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Relaxed);
+        }
+    }
+}
+
+fn write(mut file: BufWriter<File>, records: Receiver<Vec<u8>>) {
+    while let Ok(record) = records.recv() {
+        let mut written = file.write_all(&record);
+        // flushed whenever it's caught up, so what's on disk is never far behind
+        while let (Ok(()), Ok(record)) = (&written, records.try_recv()) {
+            written = file.write_all(&record);
+        }
+        if let Err(e) = written.and_then(|()| file.flush()) {
+            eprintln!("--pcap stopped capturing: {}", e);
+            return;
+        }
+    }
+}
+
+/// payload in an ip and udp header from from to to, in v6 if either isn't v4
+pub fn datagram(from: SocketAddr, to: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let v4 = |ip: IpAddr| match ip {
+        IpAddr::V4(v4) => Some(v4),
+        IpAddr::V6(v6) => v6.to_ipv4_mapped(),
+    };
+    let udp_len = 8 + payload.len();
+    let mut datagram = match (v4(from.ip()), v4(to.ip())) {
+        (Some(src), Some(dst)) => {
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            // id, fragment, ttl 64, udp and the checksum, filled in below
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let sum = header
+                .chunks(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+                .sum::<u32>();
+            let sum = (sum & 0xffff) + (sum >> 16);
+            let sum = !((sum & 0xffff) + (sum >> 16)) as u16;
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            header
+        }
+        _ => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            header.extend_from_slice(&[17, 64]);
+            header.extend_from_slice(&v6(from.ip()).octets());
+            header.extend_from_slice(&v6(to.ip()).octets());
+            header
+        }
+    };
+    datagram.extend_from_slice(&from.port().to_be_bytes());
+    datagram.extend_from_slice(&to.port().to_be_bytes());
+    datagram.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    // no checksum, which wireshark doesn't check by default
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse(
+            "type==handshake or peer==203.0.113.0/24 and not(direction==from-target)",
+        )
+        .unwrap();
+        let initiation = WgPacket::HandShakeInitiation { sender: 0xabcd };
+        let data = WgPacket::Data { receiver: 7 };
+        let meta = |packet, peer: &str, from_target| Meta {
+            packet,
+            buf: &[4; KEEPALIVE_LEN],
+            peer: peer.parse().unwrap(),
+            from_target,
+        };
+        assert!(filter.matches(&meta(&initiation, "192.0.2.1:51820", true)));
+        assert!(filter.matches(&meta(&data, "203.0.113.7:51820", false)));
+        assert!(!filter.matches(&meta(&data, "203.0.113.7:51820", true)));
+        assert!(!filter.matches(&meta(&data, "192.0.2.1:51820", false)));
+
+        let keepalives = Filter::parse("type == keepalive and index != abcd").unwrap();
+        assert!(keepalives.matches(&meta(&data, "192.0.2.1:51820", false)));
+        assert!(!Filter::parse("index==abcd").unwrap().matches(&meta(
+            &data,
+            "192.0.2.1:51820",
+            false
+        )));
+        assert!(Filter::parse("type==4")
+            .unwrap()
+            .matches(&meta(&data, "192.0.2.1:51820", false)));
+
+        for bad in [
+            "",
+            "type",
+            "type==",
+            "(type==data",
+            "type==data port==1",
+            "colour==red",
+        ] {
+            assert!(Filter::parse(bad).is_err(), "{}", bad);
+        }
+
+        let datagram = datagram(
+            "192.0.2.1:51820".parse().unwrap(),
+            "[::ffff:198.51.100.1]:5678".parse().unwrap(),
+            &[1, 2],
+        );
+        assert_eq!(datagram.len(), 30);
+        // a header that sums to all ones with its checksum
+        let sum = datagram[..20]
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+            .sum::<u32>();
+        assert_eq!((sum & 0xffff) + (sum >> 16), 0xffff);
+        assert_eq!(datagram[20..24], [0xca, 0x6c, 0x16, 0x2e]);
+    }
+}
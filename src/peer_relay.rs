@@ -0,0 +1,236 @@
+// peer relay mode, instead of forwarding to a fixed target relay wireguard packets between peers that
+// registered with us using a pre-shared key, for peers that can't reach each other directly
+//
+// peers register by sending REGISTER_TYPE datagrams from the address they want packets relayed to,
+// in practice another wireguard-udp-proxy run with --psk-file and us as the target, which re-registers
+// every REGISTER_INTERVAL
+//
+// handshake initiations are relayed to every other registered peer, wireguard silently drops
+// initiations that aren't meant for it (mac1 doesn't verify), everything else is routed by receiver
+// index, learned from the sender index of the initiations and responses we relayed
+
+use crate::{
+    clock::{self, Tick},
+    packet::bytes,
+    pktinfo::{self, LocalAddr},
+    poison::Recover,
+    stun, ExpiringSocket,
+    WgPacket::{self, Cookie, Data, HandShakeInitiation, HandShakeResponse, Unknown},
+};
+
+use blake2::{
+    digest::{KeyInit, Mac},
+    Blake2s256, Blake2sMac256, Digest,
+};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::Result,
+    net::{SocketAddr, UdpSocket},
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const REGISTER_TYPE: u8 = 0xfd;
+
+// type, 3 reserved bytes, 8 byte unix timestamp, 8 byte nonce, 32 byte mac of the first 20 bytes
+const REGISTER_LEN: usize = 52;
+
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(20);
+
+const REGISTRATION_VALID_TIME: Duration = Duration::from_secs(20 * 3);
+
+// registrations with timestamps further than this from our clock are rejected
+const MAX_CLOCK_SKEW: u64 = 60;
+
+#[derive(Clone)]
+pub struct Psk([u8; 32]);
+
+impl Psk {
+    /// reads a pre-shared key of any length from path, surrounding whitespace is ignored
+    pub fn from_file(path: &str) -> Result<Psk> {
+        let key = fs::read_to_string(path)?;
+        Ok(Psk(Blake2s256::digest(key.trim().as_bytes()).into()))
+    }
+
+    fn mac(&self) -> Blake2sMac256 {
+        <Blake2sMac256 as KeyInit>::new(&self.0.into())
+    }
+
+    pub fn register_packet(&self) -> [u8; REGISTER_LEN] {
+        let mut packet = [0u8; REGISTER_LEN];
+        packet[0] = REGISTER_TYPE;
+        packet[4..12].copy_from_slice(&unix_time().to_be_bytes());
+        // only has to be unique, so peers registering in the same second don't look like replays
+        packet[12..20].copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+        let mut mac = self.mac();
+        mac.update(&packet[..20]);
+        packet[20..].copy_from_slice(&mac.finalize().into_bytes());
+        packet
+    }
+
+    fn verify(&self, packet: &[u8]) -> bool {
+        if packet.len() != REGISTER_LEN || packet[0] != REGISTER_TYPE {
+            return false;
+        }
+        let timestamp = u64::from_be_bytes(bytes(packet, 4));
+        if timestamp.abs_diff(unix_time()) > MAX_CLOCK_SKEW {
+            return false;
+        }
+        let mut mac = self.mac();
+        mac.update(&packet[..20]);
+        mac.verify_slice(&packet[20..]).is_ok()
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+struct Peer {
+    local_addr: Option<LocalAddr>,
+    expires: Tick,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<SocketAddr, Peer>,
+    receivers: HashMap<u32, ExpiringSocket>,
+    // macs of registrations we have accepted, so a captured one can't be replayed from elsewhere
+    seen: HashMap<[u8; 32], Tick>,
+}
+
+pub struct PeerRelay {
+    udp_socket: UdpSocket,
+    psk: Psk,
+    stun: bool,
+    state: RwLock<State>,
+}
+
+impl PeerRelay {
+    pub fn new(udp_socket: UdpSocket, psk: Psk, stun: bool) -> Result<Self> {
+        pktinfo::enable(&udp_socket)?;
+        clock::start();
+        Ok(PeerRelay {
+            udp_socket,
+            psk,
+            stun,
+            state: RwLock::new(State::default()),
+        })
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let mut buf = [0u8; 2048];
+        let mut to_addrs = Vec::new();
+        loop {
+            let (recv, src_addr, local_addr, _) = pktinfo::recv_from(&self.udp_socket, &mut buf)?;
+            let buf = &buf[..recv];
+
+            if self.stun && stun::respond(&self.udp_socket, buf, src_addr, local_addr)? {
+                continue;
+            }
+
+            if buf.first() == Some(&REGISTER_TYPE) {
+                self.register(buf, src_addr, local_addr);
+                continue;
+            }
+
+            let packet = match WgPacket::parse(buf) {
+                None => continue, // ignore invalid packets
+                Some(p) => p,
+            };
+
+            let now = clock::now();
+            to_addrs.clear();
+            {
+                let state = self.state.read().recover();
+                if state.peers.get(&src_addr).is_none_or(|p| p.expires <= now) {
+                    continue; // only registered peers may use us
+                }
+                match packet {
+                    HandShakeInitiation { .. } => to_addrs.extend(
+                        state
+                            .peers
+                            .iter()
+                            .filter(|(addr, peer)| **addr != src_addr && peer.expires > now)
+                            .map(|(addr, peer)| (*addr, peer.local_addr)),
+                    ),
+                    HandShakeResponse { receiver, .. }
+                    | Data { receiver }
+                    | Cookie { receiver }
+                    | Unknown { receiver, .. } => to_addrs.extend(
+                        state
+                            .receivers
+                            .get(&receiver)
+                            .filter(|s| s.expires > now)
+                            .map(|s| (s.socket, s.local_addr)),
+                    ),
+                }
+            }
+
+            if let HandShakeInitiation { sender } | HandShakeResponse { sender, .. } = packet {
+                let mut state = self.state.write().recover();
+                state
+                    .receivers
+                    .retain(|_, expiring_socket| expiring_socket.expires > now);
+                let local_addr = state.peers.get(&src_addr).and_then(|p| p.local_addr);
+                state
+                    .receivers
+                    .insert(sender, ExpiringSocket::new(src_addr, local_addr, now));
+            }
+
+            for (to_addr, from_addr) in &to_addrs {
+                pktinfo::send_to(&self.udp_socket, buf, *to_addr, *from_addr)?;
+            }
+        }
+    }
+
+    fn register(&self, buf: &[u8], src_addr: SocketAddr, local_addr: Option<LocalAddr>) {
+        if !self.psk.verify(buf) {
+            return;
+        }
+        let now = clock::now();
+        let mac: [u8; 32] = bytes(buf, 20);
+        let mut state = self.state.write().recover();
+        state.seen.retain(|_, expires| *expires > now);
+        if state.seen.contains_key(&mac) {
+            return;
+        }
+        // the timestamp in it is only accepted for MAX_CLOCK_SKEW either side of now
+        state
+            .seen
+            .insert(mac, now + MAX_CLOCK_SKEW * 2 * clock::TICKS_PER_SEC);
+        state.peers.retain(|_, peer| peer.expires > now);
+        state.peers.insert(
+            src_addr,
+            Peer {
+                local_addr,
+                expires: now + clock::ticks(REGISTRATION_VALID_TIME),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_packet() {
+        let psk = Psk(Blake2s256::digest(b"secret").into());
+        let packet = psk.register_packet();
+        assert!(psk.verify(&packet));
+
+        let other = Psk(Blake2s256::digest(b"other").into());
+        assert!(!other.verify(&packet));
+
+        let mut tampered = packet;
+        tampered[11] ^= 1;
+        assert!(!psk.verify(&tampered));
+        assert!(!psk.verify(&packet[..REGISTER_LEN - 1]));
+    }
+}
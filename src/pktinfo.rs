@@ -0,0 +1,80 @@
+// IP_PKTINFO / IPV6_PKTINFO support, when bound to a wildcard address on a multi-homed host the kernel
+// picks the source address of replies by route lookup, which might not be the address the client sent
+// to, strict firewalls/NATs on the client side then drop the reply, so we record where each packet
+// arrived and send replies from exactly there
+//
+// since we are calling recvmsg anyway this is also where the SO_RXQ_OVFL count of datagrams the
+// kernel dropped because our receive queue was full comes from, and sendmsg is where a per packet
+// IP_TOS / IPV6_TCLASS goes for --dscp
+//
+// the calls that need the OS are in platform, which degrades to what std has where they don't exist
+
+use crate::platform;
+
+use std::{
+    io::Result,
+    net::{IpAddr, SocketAddr, UdpSocket},
+};
+
+/// local address (and interface) a datagram arrived on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalAddr {
+    pub ip: IpAddr,
+    pub ifindex: u32,
+}
+
+/// ask the kernel to tell us which local address each datagram arrived on, only worth doing if
+/// bound to a wildcard address, otherwise there is only one possible answer
+pub fn enable(udp_socket: &UdpSocket) -> Result<bool> {
+    if udp_socket.local_addr()?.ip().is_unspecified() {
+        platform::enable_pktinfo(udp_socket)?;
+        Ok(platform::CAPABILITIES.pktinfo)
+    } else {
+        Ok(false)
+    }
+}
+
+/// ask the kernel to report how many datagrams it dropped on this socket with every recv_from,
+/// a no-op where SO_RXQ_OVFL isn't supported
+pub fn enable_rxq_ovfl(udp_socket: &UdpSocket) -> Result<()> {
+    platform::enable_rxq_ovfl(udp_socket)
+}
+
+/// like UdpSocket::recv_from but also returns the local address the datagram arrived on and the
+/// total count of datagrams the kernel dropped on this socket so far, if known
+pub fn recv_from(
+    udp_socket: &UdpSocket,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)> {
+    platform::recv_from(udp_socket, buf)
+}
+
+/// like UdpSocket::send_to but sends from local_addr, if given
+#[cfg(feature = "peer-relay")]
+pub fn send_to(
+    udp_socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    local_addr: Option<LocalAddr>,
+) -> Result<usize> {
+    platform::send_to(udp_socket, buf, addr, local_addr, None)
+}
+
+/// send_to, with the DSCP field of the IP header set to dscp if given, ignored where per packet
+/// IP_TOS isn't supported
+pub fn send_marked(
+    udp_socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    local_addr: Option<LocalAddr>,
+    dscp: Option<u8>,
+) -> Result<usize> {
+    // DSCP is the upper 6 bits of the old TOS byte, leave ECN to the kernel
+    platform::send_to(
+        udp_socket,
+        buf,
+        addr,
+        local_addr,
+        dscp.map(|dscp| dscp << 2),
+    )
+}
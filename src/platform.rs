@@ -0,0 +1,132 @@
+// what we do to sockets beyond what std offers, picked per OS at compile time, linux gets
+// recvmsg/sendmsg with ancillary data and everything else a portable backend with plain
+// recv_from/send_to, anything that depends on the difference asks CAPABILITIES instead of checking
+// target_os itself
+//
+// macOS and windows have their own ways to get at the local address and TOS, they would go here as
+// further backends
+
+use crate::{config::Config, decoy::Decoy};
+
+use std::net::{SocketAddr, SocketAddrV6};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+use linux as backend;
+
+#[cfg(not(target_os = "linux"))]
+mod portable;
+#[cfg(not(target_os = "linux"))]
+use portable as backend;
+
+#[cfg(feature = "faketcp")]
+pub use backend::RawTcp;
+pub use backend::{
+    bind_dual_stack, block_termination, enable_busy_poll, enable_pktinfo, enable_pmtu,
+    enable_recverr, enable_rxq_ovfl, lease_flow_labels, on_termination, readable, recv_errors,
+    recv_from, release, send_to, set_buffers, too_big, Mapped, Ping, RawIcmp, CAPABILITIES, NAME,
+};
+
+/// what the backend can do, everything it can't is silently skipped
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    /// learn the local address each datagram arrived on, and reply from it
+    pub pktinfo: bool,
+    /// learn how many datagrams the kernel dropped because our receive queue was full
+    pub rx_queue_overflow: bool,
+    /// set IP_TOS / IPV6_TCLASS per packet
+    pub tos: bool,
+    /// learn which destinations ICMP said are unreachable
+    pub icmp_errors: bool,
+    /// send ICMP errors of our own, given CAP_NET_RAW
+    pub raw_icmp: bool,
+    /// set DF and learn path mtus from ICMP
+    pub pmtu: bool,
+    /// spin waiting for packets and have the driver polled meanwhile
+    pub busy_poll: bool,
+    /// send ipv6 packets with flow labels of our choosing
+    pub flow_labels: bool,
+    /// map files into memory
+    pub mapped_files: bool,
+}
+
+/// what an ICMP error told us about a destination we sent to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IcmpError {
+    /// nothing listens there, or there's no route to it
+    Unreachable(SocketAddr),
+    /// what we send there must fit this mtu
+    TooBig(SocketAddr, usize),
+}
+
+/// a warning for each thing config asks for that this platform can't do, the proxy runs without
+pub fn unsupported(config: &Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if !config.dscp.is_empty() && !CAPABILITIES.tos {
+        warnings.push(format!(
+            "--dscp isn't supported on {}, packets go out unmarked",
+            NAME
+        ));
+    }
+    if config.shed_load && !CAPABILITIES.rx_queue_overflow {
+        warnings.push(format!(
+            "--shed-load needs the kernel's drop count, which {} doesn't give us, it never sheds",
+            NAME
+        ));
+    }
+    if config.dead_after.is_some() && !CAPABILITIES.icmp_errors {
+        warnings.push(format!(
+            "--dead-after only notices sends that fail on {}, not ICMP unreachable replies",
+            NAME
+        ));
+    }
+    if config.pmtu.is_some() && !CAPABILITIES.pmtu {
+        warnings.push(format!(
+            "--pmtu isn't supported on {}, path mtus are left to the kernel",
+            NAME
+        ));
+    }
+    if config.flow_labels && !CAPABILITIES.flow_labels {
+        warnings.push(format!(
+            "--flow-labels isn't supported on {}, the kernel labels packets as it likes",
+            NAME
+        ));
+    }
+    if config.cold_store.is_some() && !CAPABILITIES.mapped_files {
+        warnings.push(format!(
+            "--cold-store can't map its file on {}, cold sessions are read and written with file io",
+            NAME
+        ));
+    }
+    if config.busy_poll.is_some() && !CAPABILITIES.busy_poll {
+        warnings.push(format!(
+            "--busy-poll isn't supported on {}, workers block in recv as usual",
+            NAME
+        ));
+    }
+    if config.decoy == Decoy::Unreachable && !CAPABILITIES.raw_icmp {
+        warnings.push(format!(
+            "--decoy unreachable needs raw sockets, which {} doesn't have, we stay silent",
+            NAME
+        ));
+    }
+    if config.refuse_unreachable && !CAPABILITIES.raw_icmp {
+        warnings.push(format!(
+            "--refuse-unreachable needs raw sockets, which {} doesn't have, we refuse silently",
+            NAME
+        ));
+    }
+    warnings
+}
+
+/// addr with label as the flow label to send with, one lease_flow_labels leased
+pub fn flow_labeled(addr: SocketAddrV6, label: u32) -> SocketAddr {
+    // it goes into sin6_flowinfo as it is, which is in network byte order
+    SocketAddr::V6(SocketAddrV6::new(
+        *addr.ip(),
+        addr.port(),
+        label.to_be(),
+        addr.scope_id(),
+    ))
+}
@@ -0,0 +1,833 @@
+// linux, recvmsg/sendmsg with IP_PKTINFO / IPV6_PKTINFO, SO_RXQ_OVFL and a per packet IP_TOS / IPV6_TCLASS,
+// and ICMP errors from IP_RECVERR / IPV6_RECVERR's error queue
+
+use super::{Capabilities, IcmpError};
+use crate::pktinfo::LocalAddr;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Result},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    ptr, thread,
+    time::Duration,
+};
+
+pub const NAME: &str = "linux";
+
+pub const CAPABILITIES: Capabilities = Capabilities {
+    pktinfo: true,
+    rx_queue_overflow: true,
+    tos: true,
+    icmp_errors: true,
+    raw_icmp: true,
+    pmtu: true,
+    busy_poll: true,
+    flow_labels: true,
+    mapped_files: true,
+};
+
+// in6_pktinfo plus an int, 64 bytes (aligned for cmsghdr) is just enough for both
+type CmsgBuf = [u64; 8];
+
+pub fn enable_pktinfo(udp_socket: &UdpSocket) -> Result<()> {
+    let (level, name) = match udp_socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+    };
+    set_on(udp_socket, level, name)
+}
+
+pub fn enable_rxq_ovfl(udp_socket: &UdpSocket) -> Result<()> {
+    set_on(udp_socket, libc::SOL_SOCKET, libc::SO_RXQ_OVFL)
+}
+
+/// queue ICMP errors for recv_errors, the next recv or send after one fails with it too
+pub fn enable_recverr(udp_socket: &UdpSocket) -> Result<()> {
+    // v4-mapped destinations of a v6 socket get ICMPv4 errors
+    set_on(udp_socket, libc::IPPROTO_IP, libc::IP_RECVERR)?;
+    if udp_socket.local_addr()?.is_ipv6() {
+        set_on(udp_socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR)?;
+    }
+    Ok(())
+}
+
+/// sets DF on everything we send, with df the kernel refuses what doesn't fit the path mtu it
+/// knows, without it fragments that itself, ICMP about the path mtu ends up in recv_errors either
+/// way
+pub fn enable_pmtu(udp_socket: &UdpSocket, df: bool) -> Result<()> {
+    let (v4, v6) = if df {
+        (libc::IP_PMTUDISC_DO, libc::IPV6_PMTUDISC_DO)
+    } else {
+        (libc::IP_PMTUDISC_WANT, libc::IPV6_PMTUDISC_WANT)
+    };
+    set(udp_socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, v4)?;
+    if udp_socket.local_addr()?.is_ipv6() {
+        set(udp_socket, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, v6)?;
+    }
+    Ok(())
+}
+
+/// has the kernel poll the device driver for packets for up to busy_poll while a recv waits,
+/// beyond net.core.busy_read that needs CAP_NET_ADMIN
+pub fn enable_busy_poll(udp_socket: &UdpSocket, busy_poll: Duration) -> Result<()> {
+    let usecs = busy_poll.as_micros().min(libc::c_int::MAX as u128) as libc::c_int;
+    set(udp_socket, libc::SOL_SOCKET, libc::SO_BUSY_POLL, usecs)
+}
+
+/// has the kernel queue at most bytes of datagrams for udp_socket each way, it doubles that for
+/// its bookkeeping
+pub fn set_buffers(udp_socket: &UdpSocket, bytes: usize) -> Result<()> {
+    let bytes = bytes.min(libc::c_int::MAX as usize / 2) as libc::c_int;
+    set(udp_socket, libc::SOL_SOCKET, libc::SO_RCVBUF, bytes)?;
+    set(udp_socket, libc::SOL_SOCKET, libc::SO_SNDBUF, bytes)
+}
+
+// what procd, systemd and Ctrl-C stop us with
+fn termination() -> libc::sigset_t {
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+    }
+    set
+}
+
+/// blocks SIGTERM and SIGINT in this thread and the threads it starts from then on, so
+/// on_termination's thread is the one that gets them
+pub fn block_termination() -> Result<()> {
+    let e = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &termination(), ptr::null_mut()) };
+    match e {
+        0 => Ok(()),
+        e => Err(Error::from_raw_os_error(e)),
+    }
+}
+
+/// calls done with the signal's name from a thread of its own once SIGTERM or SIGINT arrives
+pub fn on_termination<F: FnOnce(&'static str) + Send + 'static>(done: F) {
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&termination(), &mut signal) };
+        done(if signal == libc::SIGINT {
+            "SIGINT"
+        } else {
+            "SIGTERM"
+        });
+    });
+}
+
+/// whether a recv on udp_socket would return at once
+pub fn readable(udp_socket: &UdpSocket) -> bool {
+    let mut poll = libc::pollfd {
+        fd: udp_socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // an error is for recv to report
+    unsafe { libc::poll(&mut poll, 1, 0) != 0 }
+}
+
+/// whether e is a send refused for not fitting the path mtu, or ICMP saying it didn't
+pub fn too_big(e: &Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// wakes whoever waits in recv on udp_socket and gives up its address, which it keeps no longer
+/// than they take to notice, udp_socket is left with an unbound socket whose recv returns at once
+pub fn release(udp_socket: &UdpSocket) -> Result<()> {
+    let fd = udp_socket.as_raw_fd();
+    // that it isn't connected is fine, readers wake up all the same
+    unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+    let family = match udp_socket.local_addr()? {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let unbound = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if unbound < 0 {
+        return Err(Error::last_os_error());
+    }
+    // for whoever only gets to recv after this
+    unsafe { libc::shutdown(unbound, libc::SHUT_RDWR) };
+    let ret = unsafe { libc::dup3(unbound, fd, libc::O_CLOEXEC) };
+    unsafe { libc::close(unbound) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// an ipv6 socket on port of every address that takes ipv4 too, as v4-mapped addresses, whatever
+/// net.ipv6.bindv6only says
+pub fn bind_dual_stack(port: u16) -> Result<UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let udp_socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    set(&udp_socket, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0)?;
+    let (name, namelen) = to_sockaddr(SocketAddr::from(([0u16; 8], port)));
+    let ret = unsafe { libc::bind(fd, &name as *const _ as *const libc::sockaddr, namelen) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(udp_socket)
+}
+
+// struct in6_flowlabel_req, which libc doesn't have
+#[repr(C)]
+struct FlowLabelRequest {
+    dst: [u8; 16],
+    label: u32,
+    action: u8,
+    share: u8,
+    flags: u16,
+    expires: u16,
+    linger: u16,
+    pad: u32,
+}
+
+const IPV6_FL_A_GET: u8 = 0;
+const IPV6_FL_S_EXCL: u8 = 1;
+const IPV6_FL_F_CREATE: u16 = 1;
+
+/// leases labels for sends on udp_socket to carry, the kernel refuses those it didn't lease us,
+/// and lets us have 32 without CAP_NET_ADMIN
+pub fn lease_flow_labels(udp_socket: &UdpSocket, labels: &[u32]) -> Result<()> {
+    set_on(udp_socket, libc::IPPROTO_IPV6, libc::IPV6_FLOWINFO_SEND)?;
+    for label in labels {
+        // the kernel wants a destination, but doesn't check it when sending
+        let request = FlowLabelRequest {
+            dst: Ipv6Addr::LOCALHOST.octets(),
+            label: label.to_be(),
+            action: IPV6_FL_A_GET,
+            share: IPV6_FL_S_EXCL,
+            flags: IPV6_FL_F_CREATE,
+            expires: 0,
+            linger: 0,
+            pad: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                udp_socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_FLOWLABEL_MGR,
+                &request as *const _ as *const libc::c_void,
+                mem::size_of_val(&request) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// a file mapped into our memory, shared, so the kernel writes what isn't being used back to it
+/// and drops it from memory when it needs the room
+pub struct Mapped {
+    file: File,
+    ptr: *mut u8,
+    len: usize,
+}
+
+// nothing else has the pointer, and reads and writes need the Mapped
+unsafe impl Send for Mapped {}
+unsafe impl Sync for Mapped {}
+
+impl Mapped {
+    /// path, emptied, with nothing mapped yet
+    pub fn create(path: &str) -> Result<Mapped> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Mapped {
+            file,
+            ptr: ptr::null_mut(),
+            len: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// grows the file to len bytes, all mapped
+    pub fn grow(&mut self, len: usize) -> Result<()> {
+        self.file.set_len(len as u64)?;
+        let ptr = unsafe {
+            if self.ptr.is_null() {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    self.file.as_raw_fd(),
+                    0,
+                )
+            } else {
+                libc::mremap(
+                    self.ptr as *mut libc::c_void,
+                    self.len,
+                    len,
+                    libc::MREMAP_MAYMOVE,
+                )
+            }
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        self.ptr = ptr as *mut u8;
+        self.len = len;
+        Ok(())
+    }
+
+    /// fills buf from at, which must be within len
+    pub fn read(&mut self, at: usize, buf: &mut [u8]) -> Result<()> {
+        assert!(at + buf.len() <= self.len);
+        unsafe { ptr::copy_nonoverlapping(self.ptr.add(at), buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// writes buf at at, which must be within len
+    pub fn write(&mut self, at: usize, buf: &[u8]) -> Result<()> {
+        assert!(at + buf.len() <= self.len);
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.add(at), buf.len()) };
+        Ok(())
+    }
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+/// drains the error queue, returns what ICMP told us about where we sent to
+pub fn recv_errors(udp_socket: &UdpSocket) -> Result<Vec<IcmpError>> {
+    let mut errors = Vec::new();
+    loop {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        // the packet that caused it, which we don't need
+        let mut buf = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // sock_extended_err and the offender's sockaddr_in6 just fit too
+        let mut cmsg_buf: CmsgBuf = [0; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&name) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+
+        let recv = unsafe {
+            libc::recvmsg(
+                udp_socket.as_raw_fd(),
+                &mut msg,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if recv < 0 {
+            let e = Error::last_os_error();
+            if e.kind() == ErrorKind::WouldBlock {
+                return Ok(errors);
+            }
+            return Err(e);
+        }
+        // the mtu for a too big one
+        let mut error: Option<Option<usize>> = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if let (libc::IPPROTO_IP, libc::IP_RECVERR)
+                | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) =
+                    ((*cmsg).cmsg_level, (*cmsg).cmsg_type)
+                {
+                    let err: libc::sock_extended_err =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                    let icmp = matches!(
+                        err.ee_origin,
+                        libc::SO_EE_ORIGIN_ICMP | libc::SO_EE_ORIGIN_ICMP6
+                    );
+                    // a local EMSGSIZE knows the mtu too, the kernel learned it from ICMP earlier
+                    error = match err.ee_errno as libc::c_int {
+                        libc::ECONNREFUSED | libc::EHOSTUNREACH | libc::ENETUNREACH if icmp => {
+                            Some(None)
+                        }
+                        libc::EMSGSIZE if icmp || err.ee_origin == libc::SO_EE_ORIGIN_LOCAL => {
+                            Some(Some(err.ee_info as usize))
+                        }
+                        _ => None,
+                    };
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        match error {
+            Some(None) => errors.push(IcmpError::Unreachable(from_sockaddr(&name)?)),
+            Some(Some(mtu)) => errors.push(IcmpError::TooBig(from_sockaddr(&name)?, mtu)),
+            None => {}
+        }
+    }
+}
+
+/// a raw ICMP or ICMPv6 socket, for sending errors of our own
+pub struct RawIcmp(OwnedFd);
+
+impl RawIcmp {
+    /// needs CAP_NET_RAW
+    pub fn open(v6: bool) -> Result<RawIcmp> {
+        let (domain, protocol) = if v6 {
+            (libc::AF_INET6, libc::IPPROTO_ICMPV6)
+        } else {
+            (libc::AF_INET, libc::IPPROTO_ICMP)
+        };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let socket = RawIcmp(unsafe { OwnedFd::from_raw_fd(fd) });
+        // it gets a copy of every ICMP message, which we never read, the kernel's minimum will do
+        let size: libc::c_int = 0;
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &size as *const _ as *const libc::c_void,
+                mem::size_of_val(&size) as libc::socklen_t,
+            )
+        };
+        Ok(socket)
+    }
+
+    /// sends an ICMP message to to, the kernel adds the IP header
+    pub fn send(&self, packet: &[u8], to: IpAddr) -> Result<usize> {
+        let (name, namelen) = to_sockaddr(SocketAddr::new(to, 0));
+        let sent = unsafe {
+            libc::sendto(
+                self.0.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &name as *const _ as *const libc::sockaddr,
+                namelen,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+/// an ICMP or ICMPv6 socket for echo requests of our own and their replies
+pub struct Ping {
+    socket: OwnedFd,
+    // raw ones get every ICMP message, IPv4 ones with the IP header in front
+    raw: bool,
+    v6: bool,
+}
+
+impl Ping {
+    /// unprivileged where net.ipv4.ping_group_range lets us, otherwise raw, which needs
+    /// CAP_NET_RAW
+    pub fn open(v6: bool) -> Result<Ping> {
+        Ping::open_kinds(v6, &[(libc::SOCK_DGRAM, false), (libc::SOCK_RAW, true)])
+    }
+
+    /// raw only, which gets others' echo requests too, needs CAP_NET_RAW
+    #[cfg(feature = "icmp-tunnel")]
+    pub fn open_raw(v6: bool) -> Result<Ping> {
+        Ping::open_kinds(v6, &[(libc::SOCK_RAW, true)])
+    }
+
+    fn open_kinds(v6: bool, kinds: &[(libc::c_int, bool)]) -> Result<Ping> {
+        let (domain, protocol) = if v6 {
+            (libc::AF_INET6, libc::IPPROTO_ICMPV6)
+        } else {
+            (libc::AF_INET, libc::IPPROTO_ICMP)
+        };
+        for &(kind, raw) in kinds {
+            let fd = unsafe { libc::socket(domain, kind | libc::SOCK_CLOEXEC, protocol) };
+            if fd >= 0 {
+                let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+                return Ok(Ping { socket, raw, v6 });
+            }
+        }
+        Err(Error::last_os_error())
+    }
+
+    /// sends an ICMP message to to, the kernel adds the IP header
+    pub fn send(&self, packet: &[u8], to: IpAddr) -> Result<usize> {
+        let (name, namelen) = to_sockaddr(SocketAddr::new(to, 0));
+        let sent = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &name as *const _ as *const libc::sockaddr,
+                namelen,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// waits up to timeout for an ICMP message, returns how long it is without any IP header, at
+    /// the start of buf, and who sent it
+    pub fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<(usize, IpAddr)>> {
+        let mut poll = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_millis().clamp(1, i32::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut poll, 1, millis) } {
+            0 => return Ok(None),
+            ready if ready < 0 => return Err(Error::last_os_error()),
+            _ => {}
+        }
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut namelen = mem::size_of_val(&name) as libc::socklen_t;
+        let recv = unsafe {
+            libc::recvfrom(
+                self.socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+                &mut name as *mut _ as *mut libc::sockaddr,
+                &mut namelen,
+            )
+        };
+        if recv < 0 {
+            let e = Error::last_os_error();
+            return match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mut len = recv as usize;
+        if self.raw && !self.v6 {
+            let header = (buf[0] as usize & 0x0f) * 4;
+            if header > len {
+                return Ok(None);
+            }
+            buf.copy_within(header..len, 0);
+            len -= header;
+        }
+        Ok(Some((len, from_sockaddr(&name)?.ip())))
+    }
+}
+
+/// a raw TCP socket for segments of our own making, IPv4 only
+#[cfg(feature = "faketcp")]
+pub struct RawTcp(OwnedFd);
+
+#[cfg(feature = "faketcp")]
+impl RawTcp {
+    /// needs CAP_NET_RAW, it gets a copy of every TCP segment to us
+    pub fn open() -> Result<RawTcp> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::IPPROTO_TCP,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(RawTcp(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// sends a TCP segment to to, the kernel adds the IP header
+    pub fn send(&self, segment: &[u8], to: Ipv4Addr) -> Result<usize> {
+        let (name, namelen) = to_sockaddr(SocketAddr::from((to, 0)));
+        let sent = unsafe {
+            libc::sendto(
+                self.0.as_raw_fd(),
+                segment.as_ptr() as *const libc::c_void,
+                segment.len(),
+                0,
+                &name as *const _ as *const libc::sockaddr,
+                namelen,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// waits up to timeout for a segment, returns how long it is with the IP header it came in
+    pub fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        let mut poll = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_millis().clamp(1, i32::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut poll, 1, millis) } {
+            0 => return Ok(None),
+            ready if ready < 0 => return Err(Error::last_os_error()),
+            _ => {}
+        }
+        let recv = unsafe {
+            libc::recv(
+                self.0.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if recv < 0 {
+            let e = Error::last_os_error();
+            return match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            };
+        }
+        Ok(Some(recv as usize))
+    }
+}
+
+fn set_on(udp_socket: &UdpSocket, level: libc::c_int, name: libc::c_int) -> Result<()> {
+    set(udp_socket, level, name, 1)
+}
+
+fn set(
+    udp_socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            udp_socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn recv_from(
+    udp_socket: &UdpSocket,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)> {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = [0; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&name) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+
+    let recv = unsafe { libc::recvmsg(udp_socket.as_raw_fd(), &mut msg, 0) };
+    if recv < 0 {
+        return Err(Error::last_os_error());
+    }
+    let src_addr = from_sockaddr(&name)?;
+
+    let mut local_addr = None;
+    let mut dropped = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info: libc::in_pktinfo =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                    local_addr = Some(LocalAddr {
+                        ip: IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr))),
+                        ifindex: info.ipi_ifindex as u32,
+                    });
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info: libc::in6_pktinfo =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                    local_addr = Some(LocalAddr {
+                        ip: IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)),
+                        ifindex: info.ipi6_ifindex,
+                    });
+                }
+                (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                    dropped = Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const u32));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((recv as usize, src_addr, local_addr, dropped))
+}
+
+pub fn send_to(
+    udp_socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    local_addr: Option<LocalAddr>,
+    tos: Option<u8>,
+) -> Result<usize> {
+    if local_addr.is_none() && tos.is_none() {
+        return udp_socket.send_to(buf, addr);
+    }
+    let (mut name, namelen) = to_sockaddr(addr);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = [0; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = namelen;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+
+    let pktinfo_len = match local_addr.map(|local_addr| local_addr.ip) {
+        None => 0,
+        Some(IpAddr::V4(_)) => mem::size_of::<libc::in_pktinfo>() as u32,
+        Some(IpAddr::V6(_)) => mem::size_of::<libc::in6_pktinfo>() as u32,
+    };
+    let tos_len = mem::size_of::<libc::c_int>() as u32;
+    unsafe {
+        let mut controllen = 0;
+        if local_addr.is_some() {
+            controllen += libc::CMSG_SPACE(pktinfo_len);
+        }
+        if tos.is_some() {
+            controllen += libc::CMSG_SPACE(tos_len);
+        }
+        msg.msg_controllen = controllen as _;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if let Some(local_addr) = local_addr {
+            (*cmsg).cmsg_len = libc::CMSG_LEN(pktinfo_len) as _;
+            match local_addr.ip {
+                IpAddr::V4(ip) => {
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                    let info = libc::in_pktinfo {
+                        // let routing pick the interface, we only care about the source address
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: libc::in_addr {
+                            s_addr: u32::from(ip).to_be(),
+                        },
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    };
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut _, info);
+                }
+                IpAddr::V6(ip) => {
+                    (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                    (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                    let info = libc::in6_pktinfo {
+                        ipi6_addr: libc::in6_addr {
+                            s6_addr: ip.octets(),
+                        },
+                        // link-local addresses are meaningless without their interface
+                        ipi6_ifindex: if ip.segments()[0] & 0xffc0 == 0xfe80 {
+                            local_addr.ifindex
+                        } else {
+                            0
+                        },
+                    };
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut _, info);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if let Some(tos) = tos {
+            (*cmsg).cmsg_len = libc::CMSG_LEN(tos_len) as _;
+            // v4-mapped destinations go out as ipv4, which only looks at IP_TOS
+            let v4 = match addr {
+                SocketAddr::V4(_) => true,
+                SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_some(),
+            };
+            if v4 {
+                (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                (*cmsg).cmsg_type = libc::IP_TOS;
+            } else {
+                (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                (*cmsg).cmsg_type = libc::IPV6_TCLASS;
+            }
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, tos as _);
+        }
+    }
+
+    let sent = unsafe { libc::sendmsg(udp_socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+fn from_sockaddr(name: &libc::sockaddr_storage) -> Result<SocketAddr> {
+    match name.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let name: &libc::sockaddr_in = unsafe { &*(name as *const _ as *const _) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr)),
+                u16::from_be(name.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let name: &libc::sockaddr_in6 = unsafe { &*(name as *const _ as *const _) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(name.sin6_addr.s6_addr),
+                u16::from_be(name.sin6_port),
+                name.sin6_flowinfo,
+                name.sin6_scope_id,
+            )))
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, "unknown address family")),
+    }
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin: &mut libc::sockaddr_in = unsafe { &mut *(&mut name as *mut _ as *mut _) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6: &mut libc::sockaddr_in6 = unsafe { &mut *(&mut name as *mut _ as *mut _) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (name, len as libc::socklen_t)
+}
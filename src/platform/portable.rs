@@ -0,0 +1,210 @@
+// everywhere else, only what std's UdpSocket does, replies go out from whatever source address the
+// kernel picks and there is no drop count, per packet TOS or ICMP errors
+
+use super::{Capabilities, IcmpError};
+use crate::pktinfo::LocalAddr;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+pub const NAME: &str = "portable";
+
+pub const CAPABILITIES: Capabilities = Capabilities {
+    pktinfo: false,
+    rx_queue_overflow: false,
+    tos: false,
+    icmp_errors: false,
+    raw_icmp: false,
+    pmtu: false,
+    busy_poll: false,
+    flow_labels: false,
+    mapped_files: false,
+};
+
+pub fn enable_pktinfo(_udp_socket: &UdpSocket) -> Result<()> {
+    Ok(())
+}
+
+pub fn enable_rxq_ovfl(_udp_socket: &UdpSocket) -> Result<()> {
+    Ok(())
+}
+
+pub fn enable_recverr(_udp_socket: &UdpSocket) -> Result<()> {
+    Ok(())
+}
+
+pub fn recv_errors(_udp_socket: &UdpSocket) -> Result<Vec<IcmpError>> {
+    Ok(Vec::new())
+}
+
+pub fn enable_pmtu(_udp_socket: &UdpSocket, _df: bool) -> Result<()> {
+    Ok(())
+}
+
+pub fn enable_busy_poll(_udp_socket: &UdpSocket, _busy_poll: Duration) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_buffers(_udp_socket: &UdpSocket, _bytes: usize) -> Result<()> {
+    Ok(())
+}
+
+pub fn block_termination() -> Result<()> {
+    Ok(())
+}
+
+/// signals keep their default action here, done is never called
+pub fn on_termination<F: FnOnce(&'static str) + Send + 'static>(_done: F) {}
+
+pub fn readable(_udp_socket: &UdpSocket) -> bool {
+    true
+}
+
+pub fn too_big(_e: &Error) -> bool {
+    false
+}
+
+pub fn release(_udp_socket: &UdpSocket) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "removing listeners isn't supported here",
+    ))
+}
+
+/// an ipv6 socket on port of every address, whether ipv4 arrives on it too is up to the system
+pub fn bind_dual_stack(port: u16) -> Result<UdpSocket> {
+    UdpSocket::bind(SocketAddr::from(([0u16; 8], port)))
+}
+
+pub fn lease_flow_labels(_udp_socket: &UdpSocket, _labels: &[u32]) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "flow labels aren't supported here",
+    ))
+}
+
+/// a file read and written where it is, without mmap the OS's cache is what keeps it in memory
+pub struct Mapped {
+    file: File,
+    len: usize,
+}
+
+impl Mapped {
+    /// path, emptied
+    pub fn create(path: &str) -> Result<Mapped> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Mapped { file, len: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// grows the file to len bytes
+    pub fn grow(&mut self, len: usize) -> Result<()> {
+        self.file.set_len(len as u64)?;
+        self.len = len;
+        Ok(())
+    }
+
+    /// fills buf from at, which must be within len
+    pub fn read(&mut self, at: usize, buf: &mut [u8]) -> Result<()> {
+        assert!(at + buf.len() <= self.len);
+        self.file.seek(SeekFrom::Start(at as u64))?;
+        self.file.read_exact(buf)
+    }
+
+    /// writes buf at at, which must be within len
+    pub fn write(&mut self, at: usize, buf: &[u8]) -> Result<()> {
+        assert!(at + buf.len() <= self.len);
+        self.file.seek(SeekFrom::Start(at as u64))?;
+        self.file.write_all(buf)
+    }
+}
+
+pub struct RawIcmp;
+
+impl RawIcmp {
+    pub fn open(_v6: bool) -> Result<RawIcmp> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("raw ICMP sockets aren't supported on {}", NAME),
+        ))
+    }
+
+    pub fn send(&self, _packet: &[u8], _to: IpAddr) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+pub struct Ping;
+
+impl Ping {
+    pub fn open(_v6: bool) -> Result<Ping> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("ICMP sockets aren't supported on {}", NAME),
+        ))
+    }
+
+    #[cfg(feature = "icmp-tunnel")]
+    pub fn open_raw(_v6: bool) -> Result<Ping> {
+        Ping::open(false)
+    }
+
+    pub fn send(&self, _packet: &[u8], _to: IpAddr) -> Result<usize> {
+        Ok(0)
+    }
+
+    pub fn recv(&self, _buf: &mut [u8], _timeout: Duration) -> Result<Option<(usize, IpAddr)>> {
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "faketcp")]
+pub struct RawTcp;
+
+#[cfg(feature = "faketcp")]
+impl RawTcp {
+    pub fn open() -> Result<RawTcp> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("raw TCP sockets aren't supported on {}", NAME),
+        ))
+    }
+
+    pub fn send(&self, _segment: &[u8], _to: std::net::Ipv4Addr) -> Result<usize> {
+        Ok(0)
+    }
+
+    pub fn recv(&self, _buf: &mut [u8], _timeout: Duration) -> Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+pub fn recv_from(
+    udp_socket: &UdpSocket,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)> {
+    let (recv, src_addr) = udp_socket.recv_from(buf)?;
+    Ok((recv, src_addr, None, None))
+}
+
+pub fn send_to(
+    udp_socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    _local_addr: Option<LocalAddr>,
+    _tos: Option<u8>,
+) -> Result<usize> {
+    udp_socket.send_to(buf, addr)
+}
@@ -0,0 +1,130 @@
+// --pmtu, path mtu discovery we can see, the kernel sets DF on what we send and tells us through
+// ICMP fragmentation needed / packet too big what fits each destination, packets that don't are
+// dropped and counted or sent anyway for the kernel to fragment, by policy
+//
+// what we learned expires like the kernel's own route mtu does, paths change
+
+use crate::{
+    clock::{self, Tick},
+    poison::Recover,
+};
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::RwLock,
+    time::Duration,
+};
+
+// net.ipv4.route.mtu_expires
+const EXPIRE_TIME: Duration = Duration::from_secs(600);
+
+// destinations we keep track of, beyond that new ones are left to the kernel
+const MAX_PATHS: usize = 4096;
+
+/// what to do with a packet that doesn't fit the path mtu
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// drop and count it, DF stays set on everything
+    Drop,
+    /// count it and let the kernel fragment it
+    Forward,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Policy::Drop),
+            "forward" => Ok(Policy::Forward),
+            _ => Err(format!("unknown pmtu policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Paths {
+    // the mtu to each destination and when we learned it
+    mtus: RwLock<HashMap<IpAddr, (usize, Tick)>>,
+}
+
+impl Paths {
+    /// ICMP told us at now that packets to ip must fit mtu, returns whether that's news
+    pub fn learned(&self, ip: IpAddr, mtu: usize, now: Tick) -> bool {
+        let mut mtus = self.mtus.write().recover();
+        if mtus.len() >= MAX_PATHS && !mtus.contains_key(&ip) {
+            mtus.retain(|_, (_, learned)| !expired(*learned, now));
+            if mtus.len() >= MAX_PATHS {
+                return false;
+            }
+        }
+        mtus.insert(ip, (mtu, now))
+            .is_none_or(|(old, learned)| old != mtu || expired(learned, now))
+    }
+
+    /// the mtu of the path to ip, if we know it
+    pub fn mtu(&self, ip: IpAddr, now: Tick) -> Option<usize> {
+        self.mtus
+            .read()
+            .recover()
+            .get(&ip)
+            .filter(|(_, learned)| !expired(*learned, now))
+            .map(|(mtu, _)| *mtu)
+    }
+
+    /// whether a datagram of len bytes doesn't fit the path to to
+    pub fn exceeds(&self, len: usize, to: SocketAddr, now: Tick) -> bool {
+        self.mtu(to.ip(), now)
+            .is_some_and(|mtu| len + headers(to) > mtu)
+    }
+
+    /// every destination we know the mtu of, with it
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn list(&self, now: Tick) -> Vec<(IpAddr, usize)> {
+        let mut list: Vec<_> = self
+            .mtus
+            .read()
+            .recover()
+            .iter()
+            .filter(|(_, (_, learned))| !expired(*learned, now))
+            .map(|(ip, (mtu, _))| (*ip, *mtu))
+            .collect();
+        list.sort();
+        list
+    }
+}
+
+/// the ip and udp headers in front of a datagram to to
+pub fn headers(to: SocketAddr) -> usize {
+    if to.is_ipv4() {
+        20 + 8
+    } else {
+        40 + 8
+    }
+}
+
+fn expired(learned: Tick, now: Tick) -> bool {
+    now.saturating_sub(learned) >= clock::ticks(EXPIRE_TIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths() {
+        let paths = Paths::default();
+        let to: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        assert!(!paths.exceeds(1500, to, 0));
+        assert!(paths.learned(to.ip(), 1400, 0));
+        assert!(!paths.learned(to.ip(), 1400, 0));
+        assert!(!paths.exceeds(1400 - 28, to, 1));
+        assert!(paths.exceeds(1400 - 27, to, 1));
+        assert_eq!(paths.list(1), vec![(to.ip(), 1400)]);
+        // it's forgotten again
+        assert!(!paths.exceeds(1500, to, clock::ticks(EXPIRE_TIME)));
+        assert_eq!(paths.list(clock::ticks(EXPIRE_TIME)), vec![]);
+    }
+}
@@ -0,0 +1,61 @@
+// locks a panicking thread left poisoned are taken anyway: what they guard are tables a panic
+// leaves no worse than a lost packet would, and one worker's panic shouldn't take every other
+// worker down with it the next time they lock the same table
+
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+pub trait Recover<T> {
+    /// the guard, poisoned or not
+    fn recover(self) -> T;
+}
+
+impl<T> Recover<T> for LockResult<T> {
+    fn recover(self) -> T {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub trait TryRecover<T> {
+    /// the guard, poisoned or not, None if someone holds the lock
+    fn try_recover(self) -> Option<T>;
+}
+
+impl<T> TryRecover<T> for TryLockResult<T> {
+    fn try_recover(self) -> Option<T> {
+        match self {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::{Arc, RwLock},
+        thread,
+    };
+
+    #[test]
+    fn test_recover() {
+        let lock = Arc::new(RwLock::new(1));
+        let panicking = Arc::clone(&lock);
+        let _ = thread::spawn(move || {
+            let mut held = panicking.write().unwrap();
+            *held = 2;
+            panic!("holding the lock");
+        })
+        .join();
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.read().recover(), 2);
+        *lock.write().recover() = 3;
+        assert_eq!(*lock.read().recover(), 3);
+        let held = lock.write().recover();
+        assert!(lock.try_read().try_recover().is_none());
+        drop(held);
+        assert_eq!(lock.try_read().try_recover().as_deref(), Some(&3));
+    }
+}
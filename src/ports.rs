@@ -0,0 +1,167 @@
+// --source-ports, each client's packets to the target from a port of its own drawn at random from a
+// range, so ECMP and RSS at the target's end spread clients across paths and queues like the
+// wireguard endpoints they are, and firewalls in between can police each one
+//
+// by client rather than session, the index changes with every rekey and the port shouldn't, the
+// target would see it roam; like --other-backend's flows each port's socket has a thread of its own
+// taking what the target answers through the forwarding loop, until it's idle for IDLE_TIME
+
+use crate::{
+    args,
+    clock::{self, Tick},
+    error,
+    poison::Recover,
+};
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+// REJECT-AFTER-TIME, a client still there has rekeyed by then
+const IDLE_TIME: Duration = Duration::from_secs(180);
+
+// how long a port's thread waits for the target before it looks whether the port went idle
+const POLL: Duration = Duration::from_secs(1);
+
+// random ports tried before the client's packets go out of ours instead
+const ATTEMPTS: u32 = 16;
+
+/// --source-ports' value, first-last
+pub fn parse(range: &str) -> error::Result<(u16, u16)> {
+    let invalid = || args::invalid(format!("invalid --source-ports, not first-last: {}", range));
+    let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+    let first: u16 = first.parse().map_err(|_| invalid())?;
+    let last: u16 = last.parse().map_err(|_| invalid())?;
+    match first > 0 && first <= last {
+        true => Ok((first, last)),
+        false => Err(invalid()),
+    }
+}
+
+/// a client's socket towards the target
+pub struct Port {
+    pub socket: UdpSocket,
+    client: SocketAddr,
+    active: AtomicU64,
+}
+
+pub struct Ports {
+    first: u16,
+    last: u16,
+    ports: Mutex<HashMap<SocketAddr, Arc<Port>>>,
+    random: RandomState,
+}
+
+impl Ports {
+    pub fn new((first, last): (u16, u16)) -> Ports {
+        Ports {
+            first,
+            last,
+            ports: Mutex::new(HashMap::new()),
+            random: RandomState::new(),
+        }
+    }
+
+    /// client's port for packets to target, a new one handed to start first if it has none, None
+    /// if no port we tried was free
+    pub fn port<F>(&self, client: SocketAddr, target: SocketAddr, start: F) -> Option<Arc<Port>>
+    where
+        F: FnOnce(Arc<Port>),
+    {
+        let now = clock::now();
+        let mut ports = self.ports.lock().recover();
+        if let Some(port) = ports.get(&client) {
+            port.active.store(now, Relaxed);
+            return Some(Arc::clone(port));
+        }
+        let span = (self.last - self.first) as u64 + 1;
+        let socket = (0..ATTEMPTS).find_map(|attempt| {
+            let port = self.first + (self.random.hash_one((client, attempt)) % span) as u16;
+            let ip = match target {
+                SocketAddr::V4(_) => [0u8; 4].into(),
+                SocketAddr::V6(_) => [0u16; 8].into(),
+            };
+            UdpSocket::bind(SocketAddr::new(ip, port)).ok()
+        })?;
+        socket.set_read_timeout(Some(POLL)).ok()?;
+        let port = Arc::new(Port {
+            socket,
+            client,
+            active: AtomicU64::new(now),
+        });
+        ports.insert(client, Arc::clone(&port));
+        start(Arc::clone(&port));
+        Some(port)
+    }
+
+    /// keeps port from going idle, once the target answered on it
+    pub fn answered(&self, port: &Port) {
+        port.active.store(clock::now(), Relaxed);
+    }
+
+    /// whether port went idle at now and is forgotten, its thread should stop then
+    pub fn expired(&self, port: &Port, now: Tick) -> bool {
+        let idle = now.saturating_sub(port.active.load(Relaxed)) >= clock::ticks(IDLE_TIME);
+        if idle {
+            self.forget(port);
+        }
+        idle
+    }
+
+    /// stops handing out port, once nothing reads it
+    pub fn forget(&self, port: &Port) {
+        let mut ports = self.ports.lock().recover();
+        // the client may have a new one already
+        if ports
+            .get(&port.client)
+            .is_some_and(|p| std::ptr::eq(&**p, port))
+        {
+            ports.remove(&port.client);
+        }
+    }
+
+    /// how many clients have a port
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn len(&self) -> usize {
+        self.ports.lock().recover().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ports() {
+        assert_eq!(parse("40000-40099").unwrap(), (40000, 40099));
+        assert!(parse("40099-40000").is_err());
+        assert!(parse("0-10").is_err());
+        assert!(parse("40000").is_err());
+
+        let ports = Ports::new((47100, 47199));
+        let target: SocketAddr = "127.0.0.1:51820".parse().unwrap();
+        let client = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        let mut started = 0;
+        let first = ports.port(client(1), target, |_| started += 1).unwrap();
+        let again = ports.port(client(1), target, |_| started += 1).unwrap();
+        let other = ports.port(client(2), target, |_| started += 1).unwrap();
+        assert_eq!(started, 2);
+        let port = |p: &Port| p.socket.local_addr().unwrap().port();
+        assert_eq!(port(&first), port(&again));
+        assert_ne!(port(&first), port(&other));
+        assert!((47100..=47199).contains(&port(&other)));
+        assert_eq!(ports.len(), 2);
+
+        let now = first.active.load(Relaxed);
+        assert!(!ports.expired(&first, now));
+        assert!(ports.expired(&first, now + clock::ticks(IDLE_TIME)));
+        assert_eq!(ports.len(), 1);
+    }
+}
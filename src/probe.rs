@@ -0,0 +1,284 @@
+// --probe, finding out whether the path to each target works without waiting for clients to tell
+// us, every so often we send the target's host something that asks for an answer and can't be
+// taken for wireguard, an ICMP echo request, or a datagram to a port nothing listens on for the
+// port unreachable that comes back where ICMP echo isn't permitted
+//
+// the round trip times and losses go to metrics and the admin socket, and a path that answered
+// before and then lost too many probes in a row gets its target checked and failed over like
+// --blackhole-after does
+
+use crate::{args, config::Target, decoy, error, platform::Ping, poison::Recover};
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{ErrorKind, Result},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// how long we wait for an answer before a probe counts as lost
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// probes lost in a row before we think the path is down
+const DOWN_AFTER: u32 = 5;
+
+// an echo request, its checksum, id and sequence number, and the token we know our replies by
+const ECHO_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe {
+    /// how often each target is probed
+    pub every: Duration,
+    /// probe this udp port instead of sending ICMP echo requests
+    pub udp: Option<u16>,
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let every = parts
+            .next()
+            .and_then(|secs| secs.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("invalid probe interval: {}", s))?;
+        let mut probe = Probe { every, udp: None };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("udp", port)) => {
+                    probe.udp = Some(
+                        port.parse()
+                            .ok()
+                            .filter(|port| *port > 0)
+                            .ok_or_else(|| format!("invalid udp port: {}", port))?,
+                    )
+                }
+                _ => return Err(format!("unknown --probe setting: {}", part)),
+            }
+        }
+        Ok(probe)
+    }
+}
+
+/// reads --probe, unlike args::parse with the reason it's invalid
+pub fn parse(probe: &str) -> error::Result<Probe> {
+    probe.parse().map_err(args::invalid)
+}
+
+/// what probing a target's path found so far
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Path {
+    /// the round trip time of the last probe answered
+    pub rtt: Option<Duration>,
+    pub sent: u64,
+    pub lost: u64,
+    lost_in_a_row: u32,
+    answered: bool,
+}
+
+pub struct Probes {
+    pub every: Duration,
+    udp: Option<u16>,
+    v4: Option<Ping>,
+    v6: Option<Ping>,
+    token: [u8; 8],
+    seq: AtomicU16,
+    paths: Mutex<HashMap<String, Path>>,
+}
+
+impl Probes {
+    /// opens the ICMP sockets probe needs, failing if neither family can have one
+    pub fn open(probe: Probe) -> Result<Probes> {
+        let (mut v4, mut v6) = (None, None);
+        if probe.udp.is_none() {
+            let opened = Ping::open(false);
+            v6 = Ping::open(true).ok();
+            v4 = match opened {
+                Ok(ping) => Some(ping),
+                Err(_) if v6.is_some() => None,
+                Err(e) => {
+                    return Err(args::invalid(format!(
+                        "--probe can't send ICMP echo requests here ({}), give it udp=port",
+                        e
+                    ))
+                    .into())
+                }
+            };
+        }
+        Ok(Probes {
+            every: probe.every,
+            udp: probe.udp,
+            v4,
+            v6,
+            token: RandomState::new().build_hasher().finish().to_le_bytes(),
+            seq: AtomicU16::new(0),
+            paths: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// probes target's host once, returns the round trip time, None if the probe was lost
+    pub fn probe(&self, target: &Target) -> Result<Option<Duration>> {
+        let ip = decoy::unmapped(target.addr.ip());
+        match self.udp {
+            Some(port) => probe_udp(SocketAddr::new(ip, port)),
+            None => self.echo(ip),
+        }
+    }
+
+    fn echo(&self, ip: IpAddr) -> Result<Option<Duration>> {
+        let ping = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        let ping = ping.as_ref().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::Unsupported, "no ICMP socket for its family")
+        })?;
+        let seq = self.seq.fetch_add(1, Relaxed);
+        let request = echo_request(ip, seq, self.token);
+        let sent = Instant::now();
+        ping.send(&request, ip)?;
+        let mut buf = [0u8; 1500];
+        while let Some(left) = TIMEOUT.checked_sub(sent.elapsed()) {
+            match ping.recv(&mut buf, left)? {
+                Some((len, from)) if from == ip && is_reply(&buf[..len], seq, self.token) => {
+                    return Ok(Some(sent.elapsed()))
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// records what probing host found, returns whether its path just went down
+    pub fn record(&self, host: &str, rtt: Option<Duration>) -> bool {
+        let mut paths = self.paths.lock().recover();
+        let path = paths.entry(host.to_string()).or_default();
+        path.sent += 1;
+        match rtt {
+            Some(rtt) => {
+                path.rtt = Some(rtt);
+                path.lost_in_a_row = 0;
+                path.answered = true;
+            }
+            None => {
+                path.lost += 1;
+                path.lost_in_a_row += 1;
+            }
+        }
+        // a path that never answered may just not let probes through
+        path.answered && path.lost_in_a_row == DOWN_AFTER
+    }
+
+    /// every target we probed, by name
+    #[cfg_attr(not(any(feature = "admin", feature = "metrics")), allow(dead_code))]
+    pub fn report(&self) -> Vec<(String, Path)> {
+        let paths = self.paths.lock().recover();
+        let mut report: Vec<_> = paths
+            .iter()
+            .map(|(host, path)| (host.clone(), path.clone()))
+            .collect();
+        report.sort_by(|a, b| a.0.cmp(&b.0));
+        report
+    }
+}
+
+/// an ICMP or ICMPv6 echo request to ip, the kernel does the ICMPv6 checksum and that of
+/// unprivileged ICMP sockets, which pick the id too
+fn echo_request(ip: IpAddr, seq: u16, token: [u8; 8]) -> [u8; ECHO_LEN] {
+    let mut request = [0u8; ECHO_LEN];
+    request[0] = if ip.is_ipv6() { 128 } else { 8 };
+    request[4..6].copy_from_slice(&(std::process::id() as u16).to_be_bytes());
+    request[6..8].copy_from_slice(&seq.to_be_bytes());
+    request[8..].copy_from_slice(&token);
+    if ip.is_ipv4() {
+        let sum = decoy::checksum(&request);
+        request[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    request
+}
+
+/// whether icmp is the echo reply to our request seq, not anyone else's
+fn is_reply(icmp: &[u8], seq: u16, token: [u8; 8]) -> bool {
+    icmp.len() >= ECHO_LEN
+        && matches!(icmp[0], 0 | 129)
+        && icmp[1] == 0
+        && icmp[6..8] == seq.to_be_bytes()
+        && icmp[8..ECHO_LEN] == token
+}
+
+/// a zero byte, not a wireguard message, to addr, where the port unreachable that comes back from
+/// a port nothing listens on fails a connected socket's recv
+fn probe_udp(addr: SocketAddr) -> Result<Option<Duration>> {
+    let bind: SocketAddr = if addr.is_ipv6() {
+        SocketAddr::from(([0u16; 8], 0))
+    } else {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(addr)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    let sent = Instant::now();
+    socket.send(&[0])?;
+    match socket.recv(&mut [0u8; 64]) {
+        // something listening there answered, just as good
+        Ok(_) => Ok(Some(sent.elapsed())),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(Some(sent.elapsed())),
+        // timed out, or a router on the way said it can't get there, no answer from the target
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        assert_eq!(
+            "10,udp=9".parse(),
+            Ok(Probe {
+                every: Duration::from_secs(10),
+                udp: Some(9),
+            })
+        );
+        assert_eq!("5".parse::<Probe>().unwrap().udp, None);
+        assert!("0".parse::<Probe>().is_err());
+        assert!("5,udp=0".parse::<Probe>().is_err());
+        assert!("5,tcp=9".parse::<Probe>().is_err());
+
+        let token = *b"12345678";
+        let mut reply = echo_request("192.0.2.1".parse().unwrap(), 7, token);
+        assert_eq!(decoy::checksum(&reply), 0);
+        assert!(!is_reply(&reply, 7, token));
+        reply[0] = 0;
+        assert!(is_reply(&reply, 7, token));
+        assert!(!is_reply(&reply, 8, token));
+        assert!(!is_reply(&reply, 7, *b"87654321"));
+
+        let probes = Probes::open("1,udp=9".parse().unwrap()).unwrap();
+        // lost from the start, it may never have let probes through
+        for _ in 0..DOWN_AFTER * 2 {
+            assert!(!probes.record("a", None));
+        }
+        let ms = Duration::from_millis;
+        assert!(!probes.record("b", Some(ms(20))));
+        let down: Vec<_> = (0..DOWN_AFTER * 2)
+            .map(|_| probes.record("b", None))
+            .collect();
+        assert_eq!(down.iter().filter(|down| **down).count(), 1);
+        assert!(down[DOWN_AFTER as usize - 1]);
+        let report = probes.report();
+        assert_eq!(report[0].1.lost, DOWN_AFTER as u64 * 2);
+        assert_eq!(report[1].1.rtt, Some(ms(20)));
+        assert_eq!(report[1].1.sent, DOWN_AFTER as u64 * 2 + 1);
+    }
+}
@@ -0,0 +1,4684 @@
+#[cfg(feature = "canary")]
+use crate::canary::Canary;
+#[cfg(feature = "dns-tunnel")]
+use crate::dns_tunnel::{Far, Near};
+#[cfg(feature = "faketcp")]
+use crate::faketcp;
+#[cfg(feature = "control-plane")]
+use crate::fleet::{Document, Fleet};
+#[cfg(feature = "icmp-tunnel")]
+use crate::icmp_tunnel;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+#[cfg(feature = "peer-relay")]
+use crate::peer_relay::{self, PeerRelay};
+#[cfg(feature = "tcp")]
+use crate::stream;
+#[cfg(feature = "admin")]
+use crate::{
+    admin::{self, Output, Scope},
+    chaos::Chaos,
+    follow::Follows,
+};
+use crate::{
+    amplification::Amplification,
+    args,
+    audit::Audit,
+    banner, camouflage,
+    census::Census,
+    clock::{self, Clock, Coarse, Tick},
+    cold::{self, Cold},
+    config::{self, Collision, Config, Target},
+    crash,
+    datagram::Datagram,
+    decoy::{self, Decoy, Limiter},
+    envelope, error,
+    events::{Event, Events},
+    fingerprint::Fingerprints,
+    fragment::{self, Fragments},
+    garbage::Garbage,
+    handshakes::Pending,
+    heartbeat,
+    hijack::Moves,
+    ladder::{Ladder, Rung},
+    listeners::Listeners,
+    log,
+    memory::{self, Usage},
+    metrics::{Stats, Worker},
+    other::Other,
+    overload::Overload,
+    pcap::{self, Pcap},
+    pktinfo::{self, LocalAddr},
+    platform::{self, IcmpError},
+    pmtu::{Paths, Policy},
+    poison::{Recover, TryRecover},
+    ports::{Port, Ports},
+    probe::Probes,
+    quic,
+    rate::{self, CircuitBreaker, Verdict},
+    scale::{self, Bounds},
+    schedule, seal, state, stun,
+    table::Table,
+    targets::Targets,
+    WgPacket::{self, Cookie, HandShakeInitiation, HandShakeResponse, Unknown},
+};
+#[cfg(feature = "grpc")]
+use crate::{events::Stamped, grpc};
+
+use std::{
+    cmp::Reverse,
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        BinaryHeap, HashMap,
+    },
+    hash::{BuildHasher, Hash, Hasher},
+    hint,
+    io::{Error, ErrorKind, Result},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// measure processing time of every this many packets per thread when serving metrics
+const LATENCY_SAMPLE_EVERY: u32 = 64;
+
+// room in front of received packets for wrapping them
+const HEADROOM: usize = envelope::MAX_HEADER_LEN + seal::HEADER_LEN;
+
+// the largest packet we take, bigger ones are cut short
+pub const MAX_PACKET: usize = 2048;
+
+// REJECT-AFTER-TIME from https://www.wireguard.com/papers/wireguard.pdf
+//const SESSION_VALID_TIME: Duration = Duration::from_secs(180 * 3);
+const SESSION_VALID_TIME: Duration = Duration::from_secs(180);
+
+// --sender-collision idle, longer than wireguard's usual 25s persistent keepalive
+const COLLISION_IDLE_TIME: Duration = Duration::from_secs(30);
+
+// --flow-labels leases, as many as the kernel leases a socket without CAP_NET_ADMIN
+const FLOW_LABELS: u32 = 32;
+
+// flow labels the kernel leases are below it, the rest are for those it makes up itself
+const STATEFUL_LABELS: u32 = 0x80000;
+
+// what one more session takes in the tables it's in, as --max-memory counts it
+const SESSION_MEMORY: usize = memory::table::<u32, Session>(1)
+    + memory::table::<u32, u32>(1)
+    + memory::table::<SocketAddr, Tick>(1)
+    + 2 * mem::size_of::<Reverse<(Tick, Expiry)>>()
+    // its census and --cold-store's look at it
+    + 2 * memory::table::<SocketAddr, usize>(1)
+    + mem::size_of::<(SocketAddr, u32)>()
+    + mem::size_of::<(u32, u32)>()
+    + mem::size_of::<Reverse<(Tick, u32)>>();
+
+#[derive(Debug)]
+pub struct ExpiringSocket {
+    pub(crate) socket: SocketAddr,
+    pub(crate) local_addr: Option<LocalAddr>,
+    pub(crate) expires: Tick,
+}
+
+impl ExpiringSocket {
+    /// a socket that expires SESSION_VALID_TIME after now
+    pub fn new(socket: SocketAddr, local_addr: Option<LocalAddr>, now: Tick) -> Self {
+        ExpiringSocket {
+            socket,
+            local_addr,
+            expires: now + clock::ticks(SESSION_VALID_TIME),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Session {
+    client: ExpiringSocket,
+    /// the target that was active when it started
+    target: SocketAddr,
+    breaker: CircuitBreaker,
+    /// the admin fixed the client, handshakes reusing the index from elsewhere don't move it
+    pinned: bool,
+    /// --freeze-hijacked stopped forwarding for it until the admin pins or unfreezes it
+    frozen: bool,
+    /// the target answered its handshake
+    answered: bool,
+    /// tick of the last packet from the target for it
+    last_seen: AtomicU64,
+    /// packets from the target in a row that couldn't reach the client, since we last heard from it
+    failures: AtomicU32,
+    /// packets from the target for it since --heartbeat last looked
+    talked: AtomicU64,
+}
+
+impl Session {
+    pub fn new(client: ExpiringSocket, target: SocketAddr) -> Self {
+        // the client was made just now
+        let now = client
+            .expires
+            .saturating_sub(clock::ticks(SESSION_VALID_TIME));
+        Session {
+            client,
+            target,
+            breaker: CircuitBreaker::default(),
+            pinned: false,
+            frozen: false,
+            answered: false,
+            last_seen: AtomicU64::new(now),
+            failures: AtomicU32::new(0),
+            talked: AtomicU64::new(0),
+        }
+    }
+
+    /// whether the target sent nothing for it for idle before now
+    fn idle(&self, now: Tick, idle: Duration) -> bool {
+        now.saturating_sub(self.last_seen.load(Ordering::Relaxed)) >= clock::ticks(idle)
+    }
+
+    /// what --cold-store keeps of it
+    fn record(&self) -> cold::Record {
+        cold::Record {
+            client: self.client.socket,
+            local_addr: self.client.local_addr,
+            expires: self.client.expires,
+            target: self.target,
+            last_seen: self.last_seen.load(Ordering::Relaxed),
+            pinned: self.pinned,
+            frozen: self.frozen,
+        }
+    }
+
+    /// the session --cold-store kept record of
+    fn warmed(record: cold::Record) -> Self {
+        Session {
+            client: ExpiringSocket {
+                socket: record.client,
+                local_addr: record.local_addr,
+                expires: record.expires,
+            },
+            target: record.target,
+            breaker: CircuitBreaker::default(),
+            pinned: record.pinned,
+            frozen: record.frozen,
+            answered: false,
+            last_seen: AtomicU64::new(record.last_seen),
+            failures: AtomicU32::new(0),
+            talked: AtomicU64::new(0),
+        }
+    }
+}
+
+/// what expires at a tick, a session with its receiver and client, or the target's index of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Expiry {
+    Session(u32, SocketAddr),
+    Target(u32),
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    /// client sender index -> session
+    receivers: Table<u32, Session>,
+    /// target sender index -> client sender index, learned from handshake responses
+    targets: Table<u32, u32>,
+    /// addresses with a session, and until when
+    clients: Table<SocketAddr, Tick>,
+    /// clients the admin forced onto a target, for good
+    forced: HashMap<SocketAddr, SocketAddr>,
+    /// how many sessions each client and target has, and which target indexes lead to each
+    census: Census,
+    /// --cold-store's, the sessions idle too long to keep in receivers
+    cold: Option<Cold>,
+    /// and how long that is
+    cold_after: Duration,
+    // when idle sessions move there next
+    next_spill: Tick,
+    /// when to look whether a session went idle, soonest first, only with --cold-store
+    spills: BinaryHeap<Reverse<(Tick, u32)>>,
+    /// what expires when, soonest first, so expiring takes no scan of the tables; entries for
+    /// what is gone already or was replaced are skipped
+    expiries: BinaryHeap<Reverse<(Tick, Expiry)>>,
+    // when a full --small table may be purged next
+    next_purge: Tick,
+}
+
+impl Sessions {
+    /// with room for capacity sessions only, for --small
+    fn fixed(capacity: usize) -> Sessions {
+        Sessions {
+            receivers: Table::fixed(capacity),
+            targets: Table::fixed(capacity),
+            clients: Table::fixed(capacity),
+            ..Sessions::default()
+        }
+    }
+
+    /// whether the session receiver belongs to is in --cold-store
+    fn is_cold(&self, receiver: &u32) -> bool {
+        self.cold
+            .as_ref()
+            .is_some_and(|cold| cold.contains(receiver))
+    }
+
+    /// moves the sessions the target sent nothing for in cold_after to --cold-store, at most once
+    /// a second, returns how many
+    fn spill(&mut self, now: Tick) -> Result<usize> {
+        if self.cold.is_none() || now < self.next_spill {
+            return Ok(0);
+        }
+        self.next_spill = now + clock::ticks(Duration::from_secs(1));
+        let after = clock::ticks(self.cold_after);
+        let mut spilled = 0;
+        while let Some(&Reverse((due, receiver))) = self.spills.peek() {
+            if due > now {
+                break;
+            }
+            self.spills.pop();
+            let Some(session) = self.receivers.get(&receiver) else {
+                continue;
+            };
+            // it's on its way out anyway
+            if session.client.expires <= now {
+                continue;
+            }
+            let last_seen = session.last_seen.load(Ordering::Relaxed);
+            if last_seen + after > now {
+                self.spills.push(Reverse((last_seen + after, receiver)));
+                continue;
+            }
+            let record = session.record();
+            if let Some(cold) = self.cold.as_mut() {
+                if let Err(e) = cold.spill(receiver, &record) {
+                    self.spills.push(Reverse((now + after, receiver)));
+                    return Err(e);
+                }
+            }
+            self.take(&receiver);
+            spilled += 1;
+        }
+        Ok(spilled)
+    }
+
+    /// brings the session receiver belongs to back from --cold-store, if it's there
+    fn warm(&mut self, receiver: u32) -> Result<()> {
+        let record = match &mut self.cold {
+            Some(cold) => cold.take(receiver)?,
+            None => None,
+        };
+        if let Some(record) = record {
+            self.put(receiver, Session::warmed(record));
+        }
+        Ok(())
+    }
+
+    /// the session receiver belongs to
+    pub fn get(&self, receiver: &u32) -> Option<&Session> {
+        self.receivers.get(receiver)
+    }
+
+    /// the session a packet from its client addressed to the target's receiver belongs to
+    fn by_target_index(&self, receiver: &u32) -> Option<&Session> {
+        self.targets
+            .get(receiver)
+            .and_then(|receiver| self.receivers.get(receiver))
+    }
+
+    /// whether src_addr has a session that hasn't expired at now
+    pub fn has_client(&self, src_addr: &SocketAddr, now: Tick) -> bool {
+        self.clients
+            .get(src_addr)
+            .is_some_and(|expires| *expires > now)
+    }
+
+    /// forgets every session that expired at now, returns their receiver and client, which those
+    /// whose --cold-store record didn't read back have none of
+    pub fn expire(&mut self, now: Tick) -> Vec<(u32, Option<SocketAddr>)> {
+        let mut expired = Vec::new();
+        while let Some(&Reverse((at, expiry))) = self.expiries.peek() {
+            if at > now {
+                break;
+            }
+            self.expiries.pop();
+            match expiry {
+                Expiry::Session(receiver, client) => {
+                    if self
+                        .clients
+                        .get(&client)
+                        .is_some_and(|expires| *expires <= now)
+                    {
+                        self.clients.remove(&client);
+                    }
+                    if let Some(client) = self.expire_session(receiver, at) {
+                        expired.push((receiver, client));
+                    }
+                }
+                Expiry::Target(index) => {
+                    let Some(receiver) = self.targets.get(&index).copied() else {
+                        continue;
+                    };
+                    // its session was replaced by one that expires later
+                    match self.expires(receiver).filter(|expires| *expires > now) {
+                        Some(expires) => self
+                            .expiries
+                            .push(Reverse((expires, Expiry::Target(index)))),
+                        None => self.forget_index(&index),
+                    }
+                }
+            }
+        }
+        expired
+    }
+
+    /// forgets the session with receiver, in memory or --cold-store, if it still expires at,
+    /// returns Some of its client then
+    fn expire_session(&mut self, receiver: u32, at: Tick) -> Option<Option<SocketAddr>> {
+        if self
+            .receivers
+            .get(&receiver)
+            .is_some_and(|s| s.client.expires == at)
+        {
+            return Some(self.take(&receiver).map(|s| s.client.socket));
+        }
+        let cold = self
+            .cold
+            .as_mut()
+            .filter(|cold| cold.expires(&receiver) == Some(at))?;
+        Some(
+            cold.take(receiver)
+                .ok()
+                .flatten()
+                .map(|record| record.client),
+        )
+    }
+
+    /// when the session with receiver expires, if it's in memory or --cold-store
+    fn expires(&self, receiver: u32) -> Option<Tick> {
+        match self.receivers.get(&receiver) {
+            Some(session) => Some(session.client.expires),
+            None => self.cold.as_ref()?.expires(&receiver),
+        }
+    }
+
+    /// approximately what the sessions take
+    fn memory(&self) -> usize {
+        self.receivers.memory()
+            + self.targets.memory()
+            + self.clients.memory()
+            + memory::table::<SocketAddr, SocketAddr>(self.forced.len())
+            + self.census.memory()
+            + self.cold.as_ref().map_or(0, |cold| cold.memory())
+            + self.spills.capacity() * mem::size_of::<Reverse<(Tick, u32)>>()
+            + self.expiries.capacity() * mem::size_of::<Reverse<(Tick, Expiry)>>()
+    }
+
+    /// forgets the sessions idle longest but keep's and those the admin pinned, until about
+    /// bytes less are taken, returns them
+    ///
+    /// this one sorts them all, it's only once we are past memory::HIGH_WATER and takes us down
+    /// to LOW_WATER
+    fn evict(&mut self, bytes: usize, keep: u32) -> Vec<(u32, Session)> {
+        let mut idle: Vec<(Tick, u32)> = self
+            .receivers
+            .iter()
+            .filter(|(receiver, session)| **receiver != keep && !session.pinned)
+            .map(|(receiver, session)| (session.last_seen.load(Ordering::Relaxed), *receiver))
+            .collect();
+        idle.sort_unstable();
+        let evicted: Vec<(u32, Session)> = idle
+            .into_iter()
+            .take(bytes.div_ceil(SESSION_MEMORY))
+            .filter_map(|(_, receiver)| Some((receiver, self.take(&receiver)?)))
+            .collect();
+        // a client whose sessions are all cold keeps its entry until it expires
+        let cold = self.cold.as_ref().is_some_and(|cold| !cold.is_empty());
+        for (receiver, session) in &evicted {
+            for index in self.census.indexes(*receiver) {
+                self.forget_index(&index);
+            }
+            let client = session.client.socket;
+            if !cold && self.census.sessions(&client) == 0 {
+                self.clients.remove(&client);
+            }
+        }
+        evicted
+    }
+
+    /// forgets the session receiver belongs to, ahead of its time
+    fn remove(&mut self, receiver: u32) -> Option<Session> {
+        let session = self.take(&receiver)?;
+        for index in self.census.indexes(receiver) {
+            self.forget_index(&index);
+        }
+        let client = session.client.socket;
+        if self.census.sessions(&client) == 0 {
+            self.clients.remove(&client);
+        }
+        Some(session)
+    }
+
+    /// how many clients have a session with target, and whether client is one of them
+    fn clients_of(&self, target: SocketAddr, client: SocketAddr) -> (usize, bool) {
+        self.census.clients_of(&target, &client)
+    }
+
+    /// whether a session with sender fits, --small's may be full
+    fn has_room(&self, sender: &u32) -> bool {
+        !self.receivers.is_full() || self.receivers.contains_key(sender)
+    }
+
+    /// a new session from the client that initiated it with sender at now, if has_room says it
+    /// fits
+    pub fn insert(&mut self, sender: u32, session: Session, now: Tick) {
+        let (client, expires) = (session.client.socket, session.client.expires);
+        if !self.add(sender, session) {
+            return;
+        }
+        if self.clients.is_full() {
+            self.purge(now);
+        }
+        self.clients.insert(client, expires);
+    }
+
+    /// forgets the clients and target indexes of --small's full tables that lost their sessions
+    /// early, once a PURGE_INTERVAL at most
+    fn purge(&mut self, now: Tick) {
+        if now < self.next_purge {
+            return;
+        }
+        self.next_purge = now + clock::ticks(PURGE_INTERVAL);
+        // never more clients than sessions, but some may have lost theirs early
+        let census = &self.census;
+        self.clients.retain(|client, _| census.sessions(client) > 0);
+        let (receivers, census) = (&self.receivers, &mut self.census);
+        self.targets.retain(|index, r| {
+            let keep = receivers.contains_key(r);
+            if !keep {
+                census.unindexed(*r, *index);
+            }
+            keep
+        });
+    }
+
+    /// the session with sender, to expire when it does, false if there's no room for it
+    fn add(&mut self, sender: u32, session: Session) -> bool {
+        let expiry = Expiry::Session(sender, session.client.socket);
+        let expires = session.client.expires;
+        if !self.put(sender, session) {
+            return false;
+        }
+        self.expiries.push(Reverse((expires, expiry)));
+        true
+    }
+
+    /// session in receivers under receiver, counted in the census, false if there's no room
+    fn put(&mut self, receiver: u32, session: Session) -> bool {
+        // what it replaces isn't counted any more
+        self.take(&receiver);
+        let (client, target) = (session.client.socket, session.target);
+        let due = session.last_seen.load(Ordering::Relaxed) + clock::ticks(self.cold_after);
+        if !self.receivers.insert(receiver, session) {
+            return false;
+        }
+        self.census.added(receiver, client, target);
+        if self.cold.is_some() {
+            self.spills.push(Reverse((due, receiver)));
+        }
+        true
+    }
+
+    /// the session receiver belongs to out of receivers and the census, its target indexes stay
+    fn take(&mut self, receiver: &u32) -> Option<Session> {
+        let session = self.receivers.remove(receiver)?;
+        self.census
+            .removed(*receiver, session.client.socket, session.target);
+        if session.answered {
+            self.census.unanswered(session.client.socket);
+        }
+        Some(session)
+    }
+
+    /// whether a handshake of client's was answered, for a session it still has
+    fn was_answered(&self, client: &SocketAddr) -> bool {
+        self.census.was_answered(client)
+    }
+
+    /// forgets the target's index
+    fn forget_index(&mut self, index: &u32) {
+        if let Some(receiver) = self.targets.remove(index) {
+            self.census.unindexed(receiver, *index);
+        }
+    }
+
+    /// the admin moved the session with receiver from one client and target to another, answered
+    /// says whether the target answered its handshake
+    #[cfg(feature = "admin")]
+    fn moved(
+        &mut self,
+        receiver: u32,
+        (client, target): (SocketAddr, SocketAddr),
+        to: (SocketAddr, SocketAddr),
+        answered: bool,
+    ) {
+        self.census.removed(receiver, client, target);
+        self.census.added(receiver, to.0, to.1);
+        if answered {
+            self.census.unanswered(client);
+            self.census.answered(to.0);
+        }
+    }
+
+    /// the target answered the initiation of the session with receiver as sender at now, false if
+    /// there's no room to remember it
+    fn answered(&mut self, sender: u32, receiver: u32, now: Tick) -> bool {
+        if self.targets.is_full() {
+            self.purge(now);
+        }
+        if let Some(previous) = self.targets.get(&sender).copied() {
+            self.census.unindexed(previous, sender);
+        }
+        if !self.targets.insert(sender, receiver) {
+            return false;
+        }
+        self.census.indexed(receiver, sender);
+        if let Some(session) = self.receivers.get_mut(&receiver).filter(|s| !s.answered) {
+            session.answered = true;
+            self.census.answered(session.client.socket);
+        }
+        // without a session it goes with the next expiry
+        let expires = self.expires(receiver).unwrap_or_default();
+        self.expiries
+            .push(Reverse((expires, Expiry::Target(sender))));
+        true
+    }
+}
+
+pub struct Proxy<D = UdpSocket, C = Coarse> {
+    socket: D,
+    clock: C,
+    targets: RwLock<Targets>,
+    config: Config,
+    started: Tick,
+    sessions: RwLock<Sessions>,
+    overload: Overload,
+    garbage: Garbage,
+    // sessions handshakes took away from an address, to notice it still sending for them
+    moves: Moves,
+    // --freeze-hijacked froze a session since we started, packets from clients look theirs up
+    frozen: AtomicBool,
+    // --index-audit's
+    audit: Option<Audit>,
+    // --fingerprints'
+    fingerprints: Option<Fingerprints>,
+    // --pcap's, or the admin's, what workers look at only while capturing says there is one
+    pcap: RwLock<Option<Pcap>>,
+    capturing: AtomicBool,
+    // --source-ports', by client
+    ports: Option<Ports>,
+    // --verbose, or what the admin set it to since
+    verbose: AtomicBool,
+    // the loss the admin's chaos command injects
+    #[cfg(feature = "admin")]
+    chaos: Chaos,
+    // sessions the admin follows
+    #[cfg(feature = "admin")]
+    follows: Follows,
+    // switch-target's, until the sessions left on their old target are gone, what watches them
+    // waits on draining while there are none
+    #[cfg(feature = "admin")]
+    drains: Mutex<Vec<Drain>>,
+    #[cfg(feature = "admin")]
+    draining: Condvar,
+    pending: Pending,
+    events: Events,
+    other: Option<Other>,
+    // what --decoy unreachable sends with, only for a real socket
+    icmp: Option<decoy::Icmp>,
+    decoys: Limiter,
+    // what addresses without a session sent us and got back, for --amplification
+    amplification: Option<Amplification>,
+    fragments: Fragments,
+    // the client ids --compact-envelope gave, and those the previous hop gave
+    compact: envelope::Compact,
+    known: envelope::Known,
+    paths: Paths,
+    // --probe's sockets and what it measured
+    probes: Option<Probes>,
+    #[cfg(feature = "canary")]
+    canary: Option<Canary>,
+    // --control-plane's, with what the last document it applied changed
+    #[cfg(feature = "control-plane")]
+    fleet: Option<Fleet>,
+    // our socket is ipv6, ipv4 addresses go through it v4-mapped and are unmapped for everything else
+    v6: bool,
+    // what --flow-labels leased, for sessions to pick from
+    flow_labels: Vec<u32>,
+    // what /health's rates are measured against
+    #[cfg(feature = "metrics")]
+    load: metrics::Window,
+    stats: Stats,
+    // each thread running us, in the order they started
+    workers: Mutex<Vec<Arc<Worker>>>,
+    // registered with a peer relay, its other peers can initiate handshakes with our client, which
+    // goes to whoever talked to us last, same for unknown sessions during --startup-grace
+    last_client: RwLock<Option<(SocketAddr, Option<LocalAddr>)>>,
+    // the admin paused reading our socket, workers wait on resumed until it's false again, it only
+    // changes with pause_lock held
+    paused: AtomicBool,
+    // run returns once it's set, for listeners being removed
+    stopped: AtomicBool,
+    // a thread of serve's expires sessions, so handshakes needn't with the sessions lock held
+    housekeeping: AtomicBool,
+    // how many workers num_threads auto wants to be done, the next that many to finish a packet
+    retiring: AtomicUsize,
+    // what the admin added, only ever on the listener we were started with
+    listeners: Listeners,
+    pause_lock: Mutex<()>,
+    resumed: Condvar,
+}
+
+impl Proxy {
+    fn new(udp_socket: UdpSocket, config: Config) -> Result<Self> {
+        if config.small.is_some() {
+            // the worker keeps it up to date
+            clock::update();
+            platform::set_buffers(&udp_socket, SMALL_BUFFERS)?;
+        } else {
+            clock::start();
+        }
+        pktinfo::enable(&udp_socket)?;
+        pktinfo::enable_rxq_ovfl(&udp_socket)?;
+        if config.dead_after.is_some() || config.pmtu.is_some() {
+            platform::enable_recverr(&udp_socket)?;
+        }
+        if let Some(policy) = config.pmtu {
+            platform::enable_pmtu(&udp_socket, policy == Policy::Drop)?;
+        }
+        if let Some(busy_poll) = config.busy_poll {
+            if let Err(e) = platform::enable_busy_poll(&udp_socket, busy_poll) {
+                eprintln!(
+                    "--busy-poll can't have the driver polled ({}), only spinning",
+                    e
+                );
+            }
+        }
+        for warning in platform::unsupported(&config) {
+            eprintln!("{}", warning);
+        }
+        let v6 = udp_socket.local_addr()?.is_ipv6();
+        let flow_labels = match (config.flow_labels, v6) {
+            (true, true) if platform::CAPABILITIES.flow_labels => {
+                let random = RandomState::new().build_hasher().finish() as u32;
+                let first = random % (STATEFUL_LABELS - FLOW_LABELS) + 1;
+                let labels: Vec<u32> = (first..first + FLOW_LABELS).collect();
+                match platform::lease_flow_labels(&udp_socket, &labels) {
+                    Ok(()) => labels,
+                    Err(e) => {
+                        eprintln!(
+                            "--flow-labels couldn't lease flow labels ({}), the kernel labels packets as it likes",
+                            e
+                        );
+                        Vec::new()
+                    }
+                }
+            }
+            (true, false) => {
+                eprintln!("--flow-labels only labels ipv6 packets, and we listen on ipv4");
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        let unreachable = match (&config.decoy, config.refuse_unreachable) {
+            (Decoy::Unreachable, _) => Some("--decoy unreachable"),
+            (_, true) => Some("--refuse-unreachable"),
+            _ => None,
+        };
+        let icmp = if let (Some(option), true) = (unreachable, platform::CAPABILITIES.raw_icmp) {
+            let icmp = decoy::Icmp::open(v6).map_err(|e| {
+                Error::new(e.kind(), format!("{} needs a raw socket: {}", option, e))
+            })?;
+            Some(icmp)
+        } else {
+            None
+        };
+        #[cfg(feature = "peer-relay")]
+        if let (Some(psk), Some(target)) = (&config.psk, &config.target) {
+            // registration has to come from our socket so the peer relay knows where to send
+            let target_addr = target.addr;
+            let udp_socket = udp_socket.try_clone()?;
+            let packet = psk.register_packet();
+            udp_socket.send_to(&packet, target_addr)?;
+            let psk = psk.clone();
+            thread::spawn(move || loop {
+                thread::sleep(peer_relay::REGISTER_INTERVAL);
+                if let Err(e) = udp_socket.send_to(&psk.register_packet(), target_addr) {
+                    eprintln!("registering with peer relay {} failed: {}", target_addr, e);
+                }
+            });
+        }
+        let mut proxy = Proxy::with(udp_socket, Coarse, config)?;
+        proxy.icmp = icmp;
+        proxy.flow_labels = flow_labels;
+        Ok(proxy)
+    }
+
+    /// creates a Proxy that lives forever, and the background services that need it
+    pub fn start(udp_socket: UdpSocket, mut config: Config) -> Result<&'static Proxy> {
+        let tenants = mem::take(&mut config.tenants);
+        let proxy: &Proxy = Box::leak(Box::new(Proxy::new(udp_socket, config)?));
+        proxy.serve()?;
+        for (name, tenant) in tenants {
+            let addr = proxy.listeners.add(Some(name.clone()), tenant)?;
+            if proxy.verbose() {
+                eprintln!("tenant {} listening on {}", name, addr);
+            }
+        }
+        Ok(proxy)
+    }
+
+    /// makes every worker's run return as soon as it's done with its packet, and gives up our
+    /// address
+    pub fn stop(&self) -> Result<()> {
+        self.stopped.store(true, Ordering::Relaxed);
+        // under the lock, or the drains watcher could miss it between looking and waiting
+        #[cfg(feature = "admin")]
+        {
+            let _drains = self.drains.lock().recover();
+            self.draining.notify_all();
+        }
+        platform::release(&self.socket)
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// runs start workers, then every scale::INTERVAL adds or retires one as their load says,
+    /// within bounds
+    fn scale(&'static self, start: usize, bounds: Bounds) -> Result<()> {
+        let (done, exited) = mpsc::channel();
+        let spawn = || {
+            let done = done.clone();
+            thread::spawn(move || done.send(self.run()));
+        };
+        for _ in 0..start {
+            spawn();
+        }
+        let mut workers = start;
+        let sample = || {
+            let busy: u64 = self
+                .workers
+                .lock()
+                .recover()
+                .iter()
+                .map(|worker| worker.busy_nanos())
+                .sum();
+            let dropped = self.stats.rx_queue_dropped.load(Ordering::Relaxed);
+            (Instant::now(), busy, dropped)
+        };
+        let mut last = sample();
+        loop {
+            match exited.recv_timeout(scale::INTERVAL) {
+                Ok(Err(e)) => return Err(e),
+                Ok(Ok(())) if self.stopped() => return Ok(()),
+                // one we retired, or woken early
+                _ if last.0.elapsed() < scale::INTERVAL => continue,
+                _ => {}
+            }
+            let now = sample();
+            let busy = Duration::from_nanos(now.1 - last.1);
+            let dropped = now.2.saturating_sub(last.2);
+            let load = busy.as_secs_f64() / (now.0 - last.0).as_secs_f64() / workers as f64;
+            match scale::decide(workers, busy, now.0 - last.0, dropped, bounds) {
+                Some(true) => {
+                    spawn();
+                    workers += 1;
+                }
+                Some(false) => {
+                    self.retiring.fetch_add(1, Ordering::Relaxed);
+                    workers -= 1;
+                }
+                None => {
+                    last = now;
+                    continue;
+                }
+            }
+            eprintln!(
+                "{} workers now, they were busy {:.0}% of the time and the kernel dropped {} packets",
+                workers,
+                load * 100.0,
+                dropped
+            );
+            last = now;
+        }
+    }
+}
+
+impl<D: Datagram, C: Clock> Proxy<D, C> {
+    /// a Proxy on socket and clock, which starts no threads until serve
+    pub fn with(socket: D, clock: C, mut config: Config) -> Result<Self> {
+        let Some(target) = config.target.clone() else {
+            return Err(args::invalid("a proxy needs a target".to_string()).into());
+        };
+        let mut targets = Targets::new(target);
+        for (_, target) in &config.schedule {
+            targets.add(target.clone(), false);
+        }
+        for target in &config.failover {
+            targets.add(target.clone(), true);
+        }
+        for (host, pace) in &config.pace {
+            if !targets.pace(host, *pace) {
+                return Err(args::invalid(format!("--pace for unknown target: {}", host)).into());
+            }
+        }
+        for (host, max_sessions) in &config.max_sessions {
+            if !targets.limit(host, *max_sessions) {
+                return Err(
+                    args::invalid(format!("--max-sessions for unknown target: {}", host)).into(),
+                );
+            }
+        }
+        let events = Events::new(&config)?;
+        let mut sessions = match config.small {
+            Some(capacity) => Sessions::fixed(capacity),
+            None => Sessions::default(),
+        };
+        if let Some((path, after)) = &config.cold_store {
+            sessions.cold = Some(Cold::create(path)?);
+            sessions.cold_after = *after;
+        }
+        let other = config
+            .other_backend
+            .map(|backend| Other::new(backend, config.other_protocol));
+        let audit = config.index_audit.then(Audit::default);
+        let fingerprints = config.fingerprints.then(Fingerprints::default);
+        let pcap = match &config.pcap {
+            Some(path) => {
+                let local = config::resolve(&config.bind_addr)?;
+                Some(Pcap::create(path, config.pcap_filter.take(), local)?)
+            }
+            None => None,
+        };
+        let probes = config.probe.map(Probes::open).transpose()?;
+        let amplification = config.amplification.map(Amplification::new);
+        let v6 = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        #[cfg(feature = "canary")]
+        let canary = match config.canary.take() {
+            Some(settings) => Some(Canary::open(settings, config::resolve(&config.bind_addr)?)?),
+            None => None,
+        };
+        #[cfg(feature = "control-plane")]
+        let fleet = match config.control_plane.take() {
+            Some(settings) => Some(Fleet::new(settings, config.max_session_pps)?),
+            None => None,
+        };
+        let verbose = AtomicBool::new(config.verbose);
+        let ports = config.source_ports.map(Ports::new);
+        let proxy = Proxy {
+            started: clock.now(),
+            socket,
+            clock,
+            targets: RwLock::new(targets),
+            config,
+            sessions: RwLock::new(sessions),
+            overload: Overload::default(),
+            garbage: Garbage::default(),
+            moves: Moves::default(),
+            frozen: AtomicBool::new(false),
+            audit,
+            fingerprints,
+            capturing: AtomicBool::new(pcap.is_some()),
+            pcap: RwLock::new(pcap),
+            ports,
+            verbose,
+            #[cfg(feature = "admin")]
+            chaos: Chaos::default(),
+            #[cfg(feature = "admin")]
+            follows: Follows::default(),
+            #[cfg(feature = "admin")]
+            drains: Mutex::new(Vec::new()),
+            #[cfg(feature = "admin")]
+            draining: Condvar::new(),
+            pending: Pending::default(),
+            events,
+            other,
+            icmp: None,
+            decoys: Limiter::default(),
+            amplification,
+            fragments: Fragments::default(),
+            compact: envelope::Compact::default(),
+            known: envelope::Known::default(),
+            paths: Paths::default(),
+            probes,
+            #[cfg(feature = "canary")]
+            canary,
+            #[cfg(feature = "control-plane")]
+            fleet,
+            v6,
+            flow_labels: Vec::new(),
+            #[cfg(feature = "metrics")]
+            load: metrics::Window::new(),
+            stats: Stats::default(),
+            workers: Mutex::default(),
+            last_client: RwLock::new(None),
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            housekeeping: AtomicBool::new(false),
+            retiring: AtomicUsize::new(0),
+            listeners: Listeners::default(),
+            pause_lock: Mutex::new(()),
+            resumed: Condvar::new(),
+        };
+        if let Some(state_path) = &proxy.config.state_path {
+            let saved = state::load(state_path)?;
+            for (name, counter) in proxy.counters() {
+                if let Some(value) = saved.get(name) {
+                    counter.store(*value, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(proxy)
+    }
+
+    /// starts the background services the config asks for
+    pub fn serve(&'static self) -> Result<()> {
+        let proxy = self;
+        proxy.events.start();
+        crash::register(move |out| proxy.crash_snapshot(out));
+        if let Some(camouflage) = proxy.config.tcp_camouflage {
+            let addr = proxy.socket.local_addr()?;
+            camouflage::serve(addr, camouflage).map_err(|e| {
+                Error::new(e.kind(), format!("--tcp-camouflage on {}: {}", addr, e))
+            })?;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_addr) = &proxy.config.metrics_addr {
+            metrics::serve(
+                metrics_addr,
+                move |out| proxy.render_metrics(out),
+                move |out| proxy.render_health(out),
+            )?;
+        }
+        #[cfg(feature = "admin")]
+        if let Some(admin_path) = &proxy.config.admin_path {
+            let tokens = proxy.config.admin_tokens.clone();
+            admin::serve(admin_path, tokens, move |scope, args, out| {
+                proxy.admin(scope, args, out)
+            })?;
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_addr) = &proxy.config.grpc_addr {
+            let tokens = proxy.config.admin_tokens.clone();
+            grpc::serve(grpc_addr, tokens, proxy)?;
+        }
+        // --small has no thread to spare, its handshakes do it
+        if proxy.config.small.is_none() {
+            proxy.housekeeping.store(true, Ordering::Relaxed);
+            thread::spawn(move || {
+                while !proxy.stopped.load(Ordering::Relaxed) {
+                    thread::sleep(HOUSEKEEPING);
+                    let now = proxy.clock.now();
+                    proxy.housekeep(&mut proxy.sessions.write().recover(), now);
+                }
+            });
+        }
+        // only admin commands start drains, and --small takes none
+        #[cfg(feature = "admin")]
+        if proxy.config.admin_path.is_some() || proxy.config.grpc_addr.is_some() {
+            thread::spawn(move || loop {
+                let drains = proxy.drains.lock().recover();
+                if proxy.stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                if drains.is_empty() {
+                    drop(proxy.draining.wait(drains).recover());
+                    continue;
+                }
+                drop(drains);
+                thread::sleep(DRAIN_POLL);
+                proxy.check_drains();
+            });
+        }
+        if let Some((every, fields)) = &proxy.config.heartbeat {
+            thread::spawn(move || {
+                let mut last = proxy.heartbeat();
+                loop {
+                    thread::sleep(*every);
+                    let now = proxy.heartbeat();
+                    let top = proxy.top_talker();
+                    let listener = &proxy.config.bind_addr;
+                    eprintln!(
+                        "{}",
+                        heartbeat::line(listener, fields, &last, &now, *every, top)
+                    );
+                    last = now;
+                }
+            });
+        }
+        if let Some(state_path) = &proxy.config.state_path {
+            thread::spawn(move || loop {
+                thread::sleep(state::SAVE_INTERVAL);
+                if let Err(e) = proxy.save_state(state_path) {
+                    eprintln!("saving counters to {} failed: {}", state_path, e);
+                }
+            });
+        }
+        if !proxy.config.schedule.is_empty() {
+            if let Some(target) = schedule::current(&proxy.config.schedule, schedule::unix_time()) {
+                proxy.switch_target(&target.host);
+            }
+            thread::spawn(move || loop {
+                // wake up at the start of every minute, cron's resolution
+                let now = schedule::unix_time();
+                thread::sleep(Duration::from_secs(60 - now % 60));
+                if let Some(target) = schedule::due(&proxy.config.schedule, now + 60 - now % 60) {
+                    proxy.switch_target(&target.host);
+                }
+            });
+        }
+        if let Some(probes) = &proxy.probes {
+            thread::spawn(move || loop {
+                let targets: Vec<Target> = proxy.targets.read().recover().all().cloned().collect();
+                for target in targets {
+                    let rtt = probes.probe(&target).unwrap_or_else(|e| {
+                        if proxy.verbose() {
+                            eprintln!("probing target {} failed: {}", target.host, e);
+                        }
+                        None
+                    });
+                    if !probes.record(&target.host, rtt) {
+                        continue;
+                    }
+                    let suspect = proxy
+                        .targets
+                        .read()
+                        .recover()
+                        .suspect(&target.host)
+                        .cloned();
+                    if let Some(target) = suspect {
+                        proxy.blackholed(target, "stopped answering probes");
+                    }
+                }
+                thread::sleep(probes.every);
+            });
+        }
+        #[cfg(feature = "canary")]
+        if let Some(canary) = &proxy.canary {
+            thread::spawn(move || loop {
+                thread::sleep(canary.every);
+                let result = canary.check();
+                match (canary.record(&result), result) {
+                    (Some(true), Err(detail)) => {
+                        eprintln!(
+                            "canary handshakes stopped getting through us to the target and back: {}",
+                            detail
+                        );
+                        proxy.events.emit(|| Event::CanaryFailed { detail });
+                    }
+                    (Some(false), _) => {
+                        eprintln!("canary handshakes get through us to the target and back again");
+                        proxy.events.emit(|| Event::CanaryRecovered);
+                    }
+                    (_, Err(detail)) if proxy.verbose() => {
+                        eprintln!("canary handshake failed: {}", detail)
+                    }
+                    _ => {}
+                }
+            });
+        }
+        #[cfg(feature = "control-plane")]
+        if let Some(fleet) = &proxy.fleet {
+            thread::spawn(move || {
+                let mut failing = false;
+                loop {
+                    match fleet.fetch() {
+                        Ok(Some(document)) => {
+                            let version = document.version;
+                            match proxy.apply(fleet, document) {
+                                Ok(()) => eprintln!(
+                                    "applied version {} of the --control-plane configuration",
+                                    version
+                                ),
+                                Err(e) => eprintln!(
+                                    "version {} of the --control-plane configuration can't be applied: {}",
+                                    version, e
+                                ),
+                            }
+                            failing = false;
+                        }
+                        Ok(None) => failing = false,
+                        // only logged when it starts failing, not every time until it works
+                        Err(e) if !failing || proxy.verbose() => {
+                            eprintln!(
+                                "fetching the --control-plane configuration from {} failed: {}",
+                                fleet.settings.url(),
+                                e
+                            );
+                            failing = true;
+                        }
+                        Err(_) => {}
+                    }
+                    thread::sleep(fleet.settings.every);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// applies a --control-plane document, all of it or, if its targets don't resolve or make
+    /// sense, none of it
+    #[cfg(feature = "control-plane")]
+    fn apply(&self, fleet: &Fleet, document: Document) -> Result<()> {
+        let resolve = |host: &String| Target::resolve(host).map_err(Error::from);
+        let target = document.target.as_ref().map(resolve).transpose()?;
+        let failover = match &document.failover {
+            Some(hosts) => Some(hosts.iter().map(resolve).collect::<Result<Vec<_>>>()?),
+            None => None,
+        };
+        {
+            let mut targets = self.targets.write().recover();
+            let max_sessions = document.max_sessions.as_deref();
+            let previous = targets
+                .apply(target, failover, max_sessions)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if let Some(previous) = previous {
+                eprintln!(
+                    "switching target from {} to {}",
+                    previous.host,
+                    targets
+                        .all()
+                        .find(|t| t.addr == targets.active())
+                        .map_or("", |t| &t.host)
+                );
+            }
+            fleet.applied(&document);
+        }
+        if document.acl.is_none() {
+            return Ok(());
+        }
+        let mut sessions = self.sessions.write().recover();
+        let denied: Vec<u32> = sessions
+            .receivers
+            .iter()
+            .filter(|(_, session)| !fleet.admits(session.client.socket.ip()))
+            .map(|(receiver, _)| *receiver)
+            .collect();
+        for receiver in denied {
+            if let Some(session) = sessions.remove(receiver) {
+                self.events.emit(|| Event::SessionExpired {
+                    receiver,
+                    client: session.client.socket,
+                    reason: "control_plane",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// how many sessions that haven't expired are with target
+    #[cfg(feature = "admin")]
+    fn sessions_with(&self, target: SocketAddr) -> usize {
+        let now = self.clock.now();
+        let sessions = self.sessions.read().recover();
+        let sessions = sessions.receivers.values();
+        sessions
+            .filter(|s| s.target == target && s.client.expires > now)
+            .count()
+    }
+
+    /// ends the drains whose old target has no sessions left, or whose --drain is up
+    #[cfg(feature = "admin")]
+    fn check_drains(&self) {
+        let now = clock::now();
+        let active = self.targets.read().recover().active();
+        self.drains.lock().recover().retain(|drain| {
+            if active == drain.from.addr {
+                eprintln!(
+                    "target {} is active again, done draining it",
+                    drain.from.host
+                );
+                return false;
+            }
+            let left = self.sessions_with(drain.from.addr);
+            if left > 0 && now < drain.until {
+                return true;
+            }
+            let secs = clock::duration(now - drain.started).as_secs();
+            match left {
+                0 => eprintln!("target {} drained in {}s", drain.from.host, secs),
+                _ => eprintln!(
+                    "target {} drained in {}s, its last {} sessions cut over to {}",
+                    drain.from.host, secs, left, drain.to
+                ),
+            }
+            self.events.emit(|| Event::TargetDrained {
+                from: drain.from.host.clone(),
+                to: drain.to.clone(),
+                secs,
+                cut_over: left,
+            });
+            false
+        });
+    }
+
+    fn switch_target(&self, host: &str) {
+        if let Some(previous) = self.targets.write().recover().switch(host) {
+            eprintln!("switching target from {} to {}", previous.host, host);
+        }
+    }
+
+    /// a target stopped answering handshakes, or probes as why says, look it up again in case it
+    /// moved, or else fail over if it's the active one
+    fn blackholed(&'static self, target: Target, why: &'static str) {
+        thread::spawn(move || {
+            let addr = match config::resolve(&target.host) {
+                Ok(addr) if addr != target.addr => {
+                    eprintln!(
+                        "target {} {}, its address changed from {} to {}",
+                        target.host, why, target.addr, addr
+                    );
+                    addr
+                }
+                resolved => {
+                    if let Err(e) = resolved {
+                        eprintln!("looking up target {} failed: {}", target.host, e);
+                    }
+                    self.events.emit(|| Event::TargetDown {
+                        target: target.host.clone(),
+                    });
+                    let mut targets = self.targets.write().recover();
+                    let next = targets
+                        .next_failover(&target.host)
+                        .filter(|_| targets.active() == target.addr)
+                        .map(|next| next.host.clone());
+                    match next {
+                        Some(next) => {
+                            eprintln!("target {} {}, failing over to {}", target.host, why, next);
+                            targets.switch(&next);
+                            self.events.emit(|| Event::Failover {
+                                from: target.host.clone(),
+                                to: next,
+                            });
+                        }
+                        None => eprintln!("target {} {}", target.host, why),
+                    }
+                    target.addr
+                }
+            };
+            self.targets.write().recover().checked(&target.host, addr);
+        });
+    }
+
+    /// the counters --state-file keeps across restarts, by the name they are saved as
+    fn counters(&self) -> [(&'static str, &AtomicU64); 17] {
+        let stats = &self.stats;
+        [
+            ("packets_to_target", &stats.packets_to_target),
+            ("bytes_to_target", &stats.bytes_to_target),
+            ("packets_to_clients", &stats.packets_to_clients),
+            ("bytes_to_clients", &stats.bytes_to_clients),
+            ("handshakes_initiated", &self.pending.initiated),
+            ("handshakes_completed", &self.pending.completed),
+            ("handshakes_timed_out", &self.pending.timed_out),
+            ("invalid_packets", &self.garbage.total),
+            ("unknown_receiver", &stats.unknown_receiver),
+            ("collisions_overwritten", &stats.collisions_overwritten),
+            ("collisions_rejected", &stats.collisions_rejected),
+            ("unexpected_responses", &stats.unexpected_responses),
+            ("stray_cookies", &stats.stray_cookies),
+            ("amplification_capped", &stats.amplification_capped),
+            ("strict_source", &stats.strict_source),
+            ("dead_sessions", &stats.dead_sessions),
+            ("paced", &stats.paced),
+        ]
+    }
+
+    /// the totals for --heartbeat's next line
+    fn heartbeat(&self) -> heartbeat::Sample {
+        let now = self.clock.now();
+        let sessions = self.sessions.read().recover();
+        let stats = &self.stats;
+        let drops = [
+            ("invalid", &self.garbage.total),
+            ("unknown_receiver", &stats.unknown_receiver),
+            ("collisions_rejected", &stats.collisions_rejected),
+            ("unexpected_responses", &stats.unexpected_responses),
+            ("stray_cookies", &stats.stray_cookies),
+            ("amplification_capped", &stats.amplification_capped),
+            ("strict_source", &stats.strict_source),
+            ("memory_refused", &stats.memory_refused),
+            ("forged", &stats.forged),
+            ("short_sends", &stats.short_sends),
+            ("pace_overflow", &stats.pace_overflow),
+            ("rx_queue", &stats.rx_queue_dropped),
+        ];
+        heartbeat::Sample {
+            sessions: sessions.receivers.len(),
+            handshakes: self.pending.count(now),
+            memory: self.memory(&sessions, now).total(),
+            packets: (
+                stats.packets_to_target.load(Ordering::Relaxed),
+                stats.packets_to_clients.load(Ordering::Relaxed),
+            ),
+            bytes: (
+                stats.bytes_to_target.load(Ordering::Relaxed),
+                stats.bytes_to_clients.load(Ordering::Relaxed),
+            ),
+            drops: drops
+                .iter()
+                .map(|(reason, count)| (*reason, count.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    /// the client the target sent most to since we last asked, and how many packets
+    fn top_talker(&self) -> Option<(SocketAddr, u64)> {
+        self.sessions
+            .read()
+            .recover()
+            .receivers
+            .iter()
+            .map(|(_, s)| (s.client.socket, s.talked.swap(0, Ordering::Relaxed)))
+            .max_by_key(|(_, packets)| *packets)
+    }
+
+    fn save_state(&self, state_path: &str) -> Result<()> {
+        let counters: Vec<(&str, u64)> = self
+            .counters()
+            .iter()
+            .map(|(name, counter)| (*name, counter.load(Ordering::Relaxed)))
+            .collect();
+        state::save(state_path, &counters)
+    }
+
+    /// the load for /health, utilization being the larger of the sessions' share of the capacity
+    /// there is for them, if there's a limit, and the workers' share of the time busy, counting
+    /// those num_threads auto could still add
+    #[cfg(feature = "metrics")]
+    fn render_health(&self, out: &mut String) {
+        let sessions = self.sessions.read().recover().receivers.len();
+        let capacity = self.targets.read().recover().capacity();
+        let capacity = match (capacity, self.config.small) {
+            (Some(targets), Some(small)) => Some(targets.min(small)),
+            (capacity, small) => capacity.or(small),
+        };
+        let (workers, packets, busy) = {
+            let workers = self.workers.lock().recover();
+            let packets = workers
+                .iter()
+                .map(|w| w.packets.load(Ordering::Relaxed))
+                .sum();
+            let busy = workers.iter().map(|w| w.busy_nanos()).sum();
+            (workers.len(), packets, busy)
+        };
+        let (pps, busy) = self.load.rates(packets, busy);
+        let max_workers = self.config.threads.map_or(workers, |bounds| bounds.max);
+        let busy = busy / max_workers.max(1) as f64;
+        let utilization = match capacity {
+            Some(capacity) => busy.max(sessions as f64 / capacity.max(1) as f64),
+            None => busy,
+        };
+        let capacity = capacity.map_or("null".to_string(), |c| c.to_string());
+        out.push_str(&format!(
+            "{{\"sessions\":{},\"session_capacity\":{},\"packets_per_second\":{:.1},\"workers\":{},\"max_workers\":{},\"busy\":{:.3},\"utilization\":{:.3}",
+            sessions, capacity, pps, workers, max_workers, busy, utilization
+        ));
+        // the version of the --control-plane document applied, null until one is
+        #[cfg(feature = "control-plane")]
+        if let Some(fleet) = &self.fleet {
+            let version = fleet
+                .version()
+                .map_or("null".to_string(), |v| v.to_string());
+            out.push_str(&format!(",\"config_version\":{}", version));
+        }
+        out.push('}');
+    }
+
+    #[cfg(feature = "metrics")]
+    fn render_metrics(&self, out: &mut String) {
+        metrics::header(out, "sessions", "gauge", "sessions we know the client of");
+        let sessions = self.sessions.read().recover().receivers.len();
+        metrics::sample(out, "sessions", &[], sessions as u64);
+        if let Some(cold) = &self.sessions.read().recover().cold {
+            metrics::header(
+                out,
+                "cold_sessions",
+                "gauge",
+                "sessions --cold-store keeps out of memory until their next packet",
+            );
+            metrics::sample(out, "cold_sessions", &[], cold.len() as u64);
+        }
+        metrics::header(
+            out,
+            "memory_bytes",
+            "gauge",
+            "approximately what the tables that grow with clients take, what --max-memory caps",
+        );
+        let usage = self.memory(&self.sessions.read().recover(), self.clock.now());
+        let tables = [
+            ("sessions", usage.sessions),
+            ("handshakes", usage.handshakes),
+            ("offenders", usage.offenders),
+        ];
+        for (table, bytes) in tables {
+            metrics::sample(out, "memory_bytes", &[("table", table)], bytes as u64);
+        }
+        if let Some(cap) = self.config.max_memory {
+            metrics::header(out, "memory_limit_bytes", "gauge", "--max-memory");
+            metrics::sample(out, "memory_limit_bytes", &[], cap as u64);
+        }
+        let socket = self
+            .socket
+            .local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        self.stats.render(out, &socket);
+        metrics::render_workers(out, &self.workers.lock().recover());
+        metrics::header(
+            out,
+            "invalid_packets_total",
+            "counter",
+            "packets dropped because they weren't wireguard, or of a type we don't forward",
+        );
+        let invalid = self.garbage.total.load(Ordering::Relaxed);
+        metrics::sample(out, "invalid_packets_total", &[], invalid);
+        if let Some(audit) = &self.audit {
+            metrics::header(
+                out,
+                "nonrandom_index_clients_total",
+                "counter",
+                "clients --index-audit found sender indexes that don't look random from",
+            );
+            let flagged = audit.flagged.load(Ordering::Relaxed);
+            metrics::sample(out, "nonrandom_index_clients_total", &[], flagged);
+        }
+        if let Some(fingerprints) = &self.fingerprints {
+            metrics::header(
+                out,
+                "client_fingerprints",
+                "gauge",
+                "clients seen in the last hour, by the traits --fingerprints tells implementations apart by",
+            );
+            for (labels, count) in fingerprints.counts(self.clock.now()) {
+                metrics::sample(out, "client_fingerprints", &labels, count);
+            }
+        }
+        if let Some(probes) = &self.probes {
+            let report = probes.report();
+            metrics::header(
+                out,
+                "path_rtt_ms",
+                "gauge",
+                "round trip time of the last --probe of each target's path that was answered",
+            );
+            for (host, path) in &report {
+                if let Some(rtt) = path.rtt {
+                    let rtt = rtt.as_millis() as u64;
+                    metrics::sample(out, "path_rtt_ms", &[("target", host)], rtt);
+                }
+            }
+            metrics::header(
+                out,
+                "path_probes_total",
+                "counter",
+                "--probe probes sent to each target's path, and what became of them",
+            );
+            for (host, path) in &report {
+                let results = [("answered", path.sent - path.lost), ("lost", path.lost)];
+                for (result, count) in results {
+                    let labels = [("target", host.as_str()), ("result", result)];
+                    metrics::sample(out, "path_probes_total", &labels, count);
+                }
+            }
+        }
+        #[cfg(feature = "canary")]
+        if let Some(canary) = &self.canary {
+            let state = canary.state();
+            metrics::header(
+                out,
+                "canary_up",
+                "gauge",
+                "whether --canary handshakes get through us to the target and back",
+            );
+            metrics::sample(out, "canary_up", &[], state.up() as u64);
+            if let Some(rtt) = state.rtt {
+                metrics::header(
+                    out,
+                    "canary_rtt_ms",
+                    "gauge",
+                    "round trip time of the last --canary handshake answered",
+                );
+                metrics::sample(out, "canary_rtt_ms", &[], rtt.as_millis() as u64);
+            }
+            metrics::header(
+                out,
+                "canary_rounds_total",
+                "counter",
+                "--canary handshakes sent, and what became of them",
+            );
+            let results = [
+                ("answered", state.sent - state.failed),
+                ("failed", state.failed),
+            ];
+            for (result, count) in results {
+                metrics::sample(out, "canary_rounds_total", &[("result", result)], count);
+            }
+        }
+        metrics::header(
+            out,
+            "handshakes_total",
+            "counter",
+            "handshake initiations we forwarded, and what became of them",
+        );
+        let handshakes = [
+            ("initiated", &self.pending.initiated),
+            ("completed", &self.pending.completed),
+            ("timed_out", &self.pending.timed_out),
+        ];
+        for (result, count) in handshakes {
+            let count = count.load(Ordering::Relaxed);
+            metrics::sample(out, "handshakes_total", &[("result", result)], count);
+        }
+        metrics::header(
+            out,
+            "handshakes_pending",
+            "gauge",
+            "handshake initiations we forwarded that are waiting for a response",
+        );
+        let pending = self.pending.count(self.clock.now()) as u64;
+        metrics::sample(out, "handshakes_pending", &[], pending);
+        metrics::header(
+            out,
+            "events_dropped_total",
+            "counter",
+            "events dropped because too many were waiting to be delivered",
+        );
+        let dropped = self.events.dropped.load(Ordering::Relaxed);
+        metrics::sample(out, "events_dropped_total", &[], dropped);
+        if self.config.fragment_target.is_some() || self.config.fragment_clients.is_some() {
+            metrics::header(
+                out,
+                "reassembly_timeouts_total",
+                "counter",
+                "fragmented packets whose fragments didn't all arrive in --reassembly-timeout",
+            );
+            let expired = self.fragments.expired.load(Ordering::Relaxed);
+            metrics::sample(out, "reassembly_timeouts_total", &[], expired);
+        }
+        if let Some(other) = &self.other {
+            metrics::header(
+                out,
+                "other_packets_total",
+                "counter",
+                "datagrams that weren't wireguard forwarded to and from --other-backend",
+            );
+            let packets = [
+                ("to_backend", &other.to_backend),
+                ("to_client", &other.to_clients),
+            ];
+            for (direction, count) in packets {
+                let count = count.load(Ordering::Relaxed);
+                metrics::sample(
+                    out,
+                    "other_packets_total",
+                    &[("direction", direction)],
+                    count,
+                );
+            }
+            metrics::header(
+                out,
+                "other_flows",
+                "gauge",
+                "clients we are forwarding to --other-backend for",
+            );
+            metrics::sample(out, "other_flows", &[], other.flows() as u64);
+        }
+        if let Some(ports) = &self.ports {
+            metrics::header(
+                out,
+                "source_ports",
+                "gauge",
+                "clients with a --source-ports port of their own towards the target",
+            );
+            metrics::sample(out, "source_ports", &[], ports.len() as u64);
+        }
+        if let Some(pcap) = &*self.pcap.read().recover() {
+            metrics::header(
+                out,
+                "pcap_dropped_total",
+                "counter",
+                "packets --pcap left out because writing them fell behind",
+            );
+            let dropped = pcap.dropped.load(Ordering::Relaxed);
+            metrics::sample(out, "pcap_dropped_total", &[], dropped);
+        }
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        self.forward(None)
+    }
+
+    /// the forwarding loop, for what arrives on our port, or with via on a --source-ports port,
+    /// where only the target's packets are taken, until it goes idle
+    fn forward(&'static self, via: Option<Arc<Port>>) -> Result<()> {
+        // leave room in front of the packet for an envelope and a seal, and behind it for the tag
+        let mut buf = [0u8; HEADROOM + MAX_PACKET + seal::TAG_LEN];
+        let mut packet_count = 0u32;
+        let worker = Arc::new(Worker::default());
+        // a port's thread isn't one of our workers, and isn't retired like one
+        if via.is_none() {
+            self.workers.lock().recover().push(Arc::clone(&worker));
+        }
+        let source: &dyn Datagram = match &via {
+            Some(port) => &port.socket,
+            None => &self.socket,
+        };
+        // since the last recv returned, only kept for num_threads auto and /health
+        let mut busy_since: Option<Instant> = None;
+        loop {
+            if let Some(since) = busy_since.take() {
+                worker.busy(since);
+            }
+            let retire = |n: usize| n.checked_sub(1);
+            if via.is_none()
+                && self.retiring.load(Ordering::Relaxed) > 0
+                && self
+                    .retiring
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, retire)
+                    .is_ok()
+            {
+                return Ok(());
+            }
+            // leave packets to the kernel while paused, a worker already waiting in recv still
+            // forwards one more
+            if self.paused.load(Ordering::Relaxed) {
+                let mut lock = self.pause_lock.lock().recover();
+                while self.paused.load(Ordering::Relaxed) {
+                    lock = self.resumed.wait(lock).recover();
+                }
+            }
+
+            if let Some(busy_poll) = self.config.busy_poll {
+                let since = Instant::now();
+                while !source.readable() && since.elapsed() < busy_poll {
+                    hint::spin_loop();
+                }
+            }
+            let received = source.recv(&mut buf[HEADROOM..HEADROOM + MAX_PACKET]);
+            if self.config.small.is_some() {
+                clock::update();
+            }
+            if self.stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            if self.config.threads.is_some() || self.config.metrics_addr.is_some() {
+                busy_since = Some(Instant::now());
+            }
+            let (recv, src_addr, local_addr, dropped) = match (received, &via) {
+                (Ok(received), _) => received,
+                (Err(e), Some(port)) => {
+                    let Some(ports) = &self.ports else {
+                        return Err(Error::other("a port without --source-ports"));
+                    };
+                    let idle = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+                    if idle && ports.expired(port, clock::now()) {
+                        return Ok(());
+                    }
+                    // or the target refusing what we sent from it, it may be back for the next one
+                    continue;
+                }
+                (Err(e), None) if self.collects_icmp() && icmp_error(&e) => {
+                    self.collect_icmp_errors()?;
+                    continue;
+                }
+                (Err(e), None) => return Err(e),
+            };
+            let src_addr = self.unmapped(src_addr);
+
+            packet_count = packet_count.wrapping_add(1);
+            worker.packets.fetch_add(1, Ordering::Relaxed);
+            let received = (self.config.metrics_addr.is_some()
+                && packet_count.is_multiple_of(LATENCY_SAMPLE_EVERY))
+            .then(Instant::now);
+
+            if let Some(dropped) = dropped {
+                self.stats
+                    .rx_queue_dropped
+                    .store(dropped as u64, Ordering::Relaxed);
+            }
+            let shedding = self.config.shed_load && self.shedding(dropped);
+
+            //println!("udp got len: {} from src_addr: {}", recv, src_addr);
+
+            let start = HEADROOM;
+            let end = start + recv;
+
+            if self.config.stun && via.is_none() {
+                let mapped = self.mapped(src_addr);
+                match stun::respond(&self.socket, &buf[start..end], mapped, local_addr) {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    // a spoofed source, likely
+                    Err(e) => {
+                        self.send_failed(&worker, src_addr, &e);
+                        continue;
+                    }
+                }
+            }
+
+            let targets = self.targets.read().recover();
+            let from_target = targets.contains(&src_addr);
+            if let Some(port) = &via {
+                // nobody but the target has any business sending to a client's port
+                if !from_target {
+                    continue;
+                }
+                if let Some(ports) = &self.ports {
+                    ports.answered(port);
+                }
+            }
+
+            let fragmented = if from_target {
+                self.config.fragment_target.is_some()
+            } else {
+                self.config.fragment_clients.is_some()
+            };
+            let (start, end) = if fragmented && buf[start] == fragment::FRAGMENT_TYPE {
+                let now = self.clock.now();
+                let timeout = self.config.reassembly_timeout;
+                match self.fragments.add(&buf[start..end], src_addr, now, timeout) {
+                    // the last one, it's all there now
+                    Some(packet) => {
+                        buf[HEADROOM..HEADROOM + packet.len()].copy_from_slice(&packet);
+                        (HEADROOM, HEADROOM + packet.len())
+                    }
+                    None => continue,
+                }
+            } else {
+                (start, end)
+            };
+
+            let opened = match (from_target, &self.config.seal_target) {
+                (true, Some(seal)) => seal.open(&mut buf, start, end),
+                (true, None) => Some((start, end)),
+                (false, _) => self.open_from_client(&mut buf, start, end, src_addr),
+            };
+            let (start, end) = match opened {
+                Some(opened) => opened,
+                None => {
+                    // whoever is in the middle, or someone who isn't the other proxy
+                    self.stats.forged.fetch_add(1, Ordering::Relaxed);
+                    if !from_target {
+                        self.refuse(&buf[start..end], src_addr, local_addr);
+                    }
+                    continue;
+                }
+            };
+
+            // the client a previous hop enveloped this packet for, or whoever sent it to us
+            let (start, client_addr) = if self.config.accept_envelope && !from_target {
+                match self.known.unwrap(src_addr, &buf[start..end]) {
+                    // we were told every client is another proxy
+                    None => {
+                        self.refuse(&buf[start..end], src_addr, local_addr);
+                        continue;
+                    }
+                    Some((client_addr, packet)) => (end - packet.len(), client_addr),
+                }
+            } else {
+                (start, src_addr)
+            };
+
+            let parsed = match &self.config.unknown_types {
+                None => WgPacket::parse(&buf[start..end]),
+                Some(types) => WgPacket::parse_unknown(&buf[start..end]).filter(|p| match p {
+                    Unknown { message_type, .. } => {
+                        types.is_empty() || types.contains(message_type)
+                    }
+                    _ => true,
+                }),
+            };
+            if let (Some(amplification), false) = (&self.amplification, from_target) {
+                if !matches!(parsed, Some(WgPacket::Data { .. })) {
+                    amplification.received(src_addr.ip(), recv, self.clock.now());
+                }
+            }
+            let packet = match parsed {
+                None => {
+                    if let (Some(other), false) = (&self.other, from_target) {
+                        let socket = &self.socket;
+                        let reply = move |buf: &[u8], to: SocketAddr, from| {
+                            if !self.amplifies(buf.len(), to) {
+                                return Ok(0);
+                            }
+                            socket.send(buf, self.mapped(to), from, None)
+                        };
+                        match other.forward(&buf[start..end], src_addr, local_addr, reply) {
+                            Ok(true) => continue,
+                            Ok(false) => {}
+                            Err(e) => eprintln!(
+                                "forwarding {} to --other-backend failed: {}",
+                                src_addr, e
+                            ),
+                        }
+                    }
+                    let camouflage = (self.config.quic_camouflage && !from_target)
+                        .then(|| quic::version_negotiation(&buf[start..end]))
+                        .flatten();
+                    if let Some(reply) = camouflage {
+                        if self.send(&reply, src_addr, local_addr, None).is_ok() {
+                            self.stats.camouflaged.fetch_add(1, Ordering::Relaxed);
+                        }
+                    } else if self.config.decoy != Decoy::Silence
+                        && !from_target
+                        && !self
+                            .sessions
+                            .read()
+                            .recover()
+                            .has_client(&src_addr, self.clock.now())
+                        && self.decoys.allow(self.clock.seconds())
+                    {
+                        self.decoy(&buf[start..end], src_addr, local_addr);
+                    }
+                    // ignore invalid packets, but keep track of who sends them
+                    if let Some(score) = self.garbage.record(client_addr.ip(), self.clock.now()) {
+                        eprintln!(
+                            "ignoring invalid packets from {}, {} recently, see offenders on the admin socket",
+                            client_addr.ip(),
+                            score
+                        );
+                        self.events.emit(|| Event::Security {
+                            kind: "invalid_packets",
+                            source: client_addr,
+                            detail: format!("{} invalid packets recently", score),
+                        });
+                    }
+                    continue;
+                }
+                Some(p) => p,
+            };
+            if let (Some(fingerprints), false) = (&self.fingerprints, from_target) {
+                fingerprints.observe(client_addr, &buf[start..end], self.clock.now());
+            }
+            #[cfg(feature = "admin")]
+            if self.follows.any() {
+                self.trace(&packet, from_target, src_addr, end - start);
+            }
+            if self.capturing.load(Ordering::Relaxed) {
+                if let Some(pcap) = &*self.pcap.read().recover() {
+                    let meta = pcap::Meta {
+                        packet: &packet,
+                        buf: &buf[start..end],
+                        peer: src_addr,
+                        from_target,
+                    };
+                    pcap.capture(&meta, local_addr.map(|local| local.ip));
+                }
+            }
+
+            //println!("valid {:?}", packet);
+
+            if self.config.cold_store.is_some() {
+                self.warm(&worker, &packet, from_target);
+            }
+
+            // registered with a peer relay, whose other peers may initiate with our client too
+            #[cfg(feature = "peer-relay")]
+            let mesh = self.config.psk.is_some();
+            #[cfg(not(feature = "peer-relay"))]
+            let mesh = false;
+
+            // the session a packet from the target goes to the client of
+            let mut client_session = None;
+            let mut frozen = false;
+
+            let (to_addr, from_addr) = if from_target {
+                if !targets.current(src_addr, self.config.schedule_transition) {
+                    continue; // sessions with it were cut over to the active target
+                }
+                let to_addr = match packet {
+                    HandShakeInitiation { .. } if mesh => *self.last_client.read().recover(),
+                    // target isn't allowed to initiate
+                    _ => packet
+                        .receiver()
+                        .and_then(|receiver| {
+                            self.read_sessions(&worker).get(receiver).map(|s| {
+                                client_session = Some(*receiver);
+                                frozen = s.frozen;
+                                s.last_seen.store(self.clock.now(), Ordering::Relaxed);
+                                if self.config.heartbeat.is_some() {
+                                    s.talked.fetch_add(1, Ordering::Relaxed);
+                                }
+                                (s.client.socket, s.client.local_addr)
+                            })
+                        })
+                        // a session from before we started, most likely with whoever is talking
+                        .or_else(|| {
+                            self.in_grace()
+                                .then(|| *self.last_client.read().recover())?
+                        }),
+                };
+                let to_addr = match (to_addr, packet.receiver()) {
+                    (Some(to_addr), _) => to_addr,
+                    (None, Some(receiver)) => {
+                        // lots of these mean we restarted or expired sessions the target still has
+                        let count = self.stats.unknown_receiver.fetch_add(1, Ordering::Relaxed) + 1;
+                        if self.verbose() && count % UNKNOWN_RECEIVER_LOG_EVERY == 1 {
+                            eprintln!(
+                                "dropping packet from target {} for unknown receiver {:08x}, {} so far",
+                                src_addr, receiver, count
+                            );
+                        }
+                        continue;
+                    }
+                    (None, None) => continue,
+                };
+                if frozen {
+                    // until the admin says which client is the real one
+                    continue;
+                }
+                if let Cookie { receiver } = packet {
+                    if !self.pending.cookie(receiver, src_addr, self.clock.now()) {
+                        self.stray_cookie(src_addr, receiver);
+                        continue;
+                    }
+                }
+                if let HandShakeResponse { sender, receiver } = packet {
+                    let answered = self.pending.answered(receiver, src_addr, self.clock.now());
+                    if self.config.strict_responses && !answered {
+                        self.stats
+                            .unexpected_responses
+                            .fetch_add(1, Ordering::Relaxed);
+                        self.events.emit(|| Event::Security {
+                            kind: "unexpected_response",
+                            source: src_addr,
+                            detail: format!("handshake response to {:08x}", receiver),
+                        });
+                        if self.verbose() {
+                            eprintln!(
+                                "dropping handshake response from {} to {:08x}, it answers no initiation we just forwarded there",
+                                src_addr, receiver
+                            );
+                        }
+                        continue;
+                    }
+                    targets.answered(src_addr);
+                    if let Some(fingerprints) = &self.fingerprints {
+                        fingerprints.answered(to_addr.0, self.clock.now());
+                    }
+                    let now = self.clock.now();
+                    if !self
+                        .sessions
+                        .write()
+                        .recover()
+                        .answered(sender, receiver, now)
+                    {
+                        if self.verbose() {
+                            eprintln!(
+                                "--small has no room for another session, dropping handshake response from {} to {:08x}",
+                                src_addr, receiver
+                            );
+                        }
+                        continue;
+                    }
+                }
+                to_addr
+            } else {
+                if let Cookie { receiver } = packet {
+                    // only a responder under load sends them, and clients only initiate through us
+                    self.stray_cookie(src_addr, receiver);
+                    continue;
+                }
+                let mut hairpin = None;
+                if mesh || self.in_grace() {
+                    let last_client = *self.last_client.read().recover();
+                    if last_client != Some((src_addr, local_addr)) {
+                        *self.last_client.write().recover() = Some((src_addr, local_addr));
+                    }
+                    if mesh && self.config.hairpin {
+                        // the peer relay never sends packets back where they came from, so peers
+                        // that are both our clients (behind the same NAT) can only reach each
+                        // other if we short-circuit it
+                        hairpin = match packet {
+                            HandShakeInitiation { .. } => {
+                                last_client.filter(|(addr, _)| *addr != src_addr)
+                            }
+                            _ => packet.receiver().and_then(|receiver| {
+                                self.sessions
+                                    .read()
+                                    .recover()
+                                    .get(receiver)
+                                    .filter(|s| s.client.socket != src_addr)
+                                    .map(|s| (s.client.socket, s.client.local_addr))
+                            }),
+                        };
+                    }
+                }
+                let mut initiated_to = None;
+                match packet {
+                    HandShakeInitiation { sender } => {
+                        // we are going to expire things now todo: only after SESSION_TIME elapsed?
+                        let now = self.clock.now();
+                        if shedding
+                            && (!self.read_sessions(&worker).has_client(&src_addr, now)
+                                || self.pending.outstanding(&src_addr, now))
+                        {
+                            // overloaded, only existing clients may handshake, and only once
+                            // their last one timed out like wireguard waits for it to
+                            continue;
+                        }
+                        #[cfg(feature = "control-plane")]
+                        if let Some(fleet) = &self.fleet {
+                            if !fleet.admits(client_addr.ip()) {
+                                if self.verbose() {
+                                    eprintln!(
+                                        "--control-plane doesn't allow {}, dropping handshake",
+                                        client_addr
+                                    );
+                                }
+                                self.refuse_client(
+                                    &buf[start..end],
+                                    client_addr,
+                                    src_addr,
+                                    local_addr,
+                                );
+                                continue;
+                            }
+                        }
+                        #[cfg(feature = "identity")]
+                        if let Some(servers) = &self.config.allow_servers {
+                            if !servers.admits(&buf[start..end]) {
+                                if self.verbose() {
+                                    eprintln!(
+                                        "{}'s handshake isn't for any of --allow-servers, dropping it",
+                                        client_addr
+                                    );
+                                }
+                                self.refuse_client(
+                                    &buf[start..end],
+                                    client_addr,
+                                    src_addr,
+                                    local_addr,
+                                );
+                                continue;
+                            }
+                        }
+                        if let Some(verdict) = self
+                            .audit
+                            .as_ref()
+                            .and_then(|audit| audit.record(client_addr, sender, now))
+                        {
+                            if self.verbose() {
+                                eprintln!("sender indexes of {} {}", client_addr, verdict);
+                            }
+                        }
+                        let mut sessions = self.write_sessions(&worker);
+                        if !self.housekeeping.load(Ordering::Relaxed) {
+                            self.housekeep(&mut sessions, now);
+                        }
+
+                        // where it was taken over from, handshaking again
+                        let hijacked = self
+                            .moves
+                            .any()
+                            .then(|| self.moves.check(&src_addr, sender, now))
+                            .flatten();
+                        if let Some(client) = hijacked {
+                            if self.hijack(&mut sessions, sender, client, src_addr) {
+                                continue;
+                            }
+                        }
+                        if sessions.get(&sender).is_some_and(|s| s.frozen) {
+                            continue;
+                        }
+
+                        let mut rejected = false;
+                        if let Some(existing) = sessions
+                            .get(&sender)
+                            .filter(|s| s.client.socket != src_addr && !s.pinned)
+                        {
+                            // same index from another address, someone might be after its return traffic
+                            let overwrite = match self.config.sender_collision {
+                                Collision::Overwrite => true,
+                                Collision::Reject => false,
+                                Collision::Idle => existing.idle(now, COLLISION_IDLE_TIME),
+                            };
+                            let count = if overwrite {
+                                &self.stats.collisions_overwritten
+                            } else {
+                                &self.stats.collisions_rejected
+                            };
+                            count.fetch_add(1, Ordering::Relaxed);
+                            if overwrite {
+                                self.moves
+                                    .moved(sender, existing.client.socket, src_addr, now);
+                            }
+                            let action = if overwrite {
+                                "taking the session over"
+                            } else {
+                                "dropping it"
+                            };
+                            if self.verbose() {
+                                eprintln!(
+                                    "handshake from {} reuses sender index {:08x} of {}, {}",
+                                    src_addr, sender, existing.client.socket, action
+                                );
+                            }
+                            self.events.emit(|| Event::Security {
+                                kind: "sender_collision",
+                                source: src_addr,
+                                detail: format!(
+                                    "reuses sender index {:08x} of {}, {}",
+                                    sender, existing.client.socket, action
+                                ),
+                            });
+                            rejected = !overwrite;
+                        }
+                        if rejected {
+                            drop(sessions);
+                            self.refuse_client(&buf[start..end], client_addr, src_addr, local_addr);
+                            continue;
+                        }
+
+                        // the admin pinned it, a handshake from elsewhere doesn't move it
+                        let pinned = sessions.get(&sender).filter(|s| s.pinned);
+                        let client = match pinned {
+                            Some(s) => {
+                                ExpiringSocket::new(s.client.socket, s.client.local_addr, now)
+                            }
+                            None => ExpiringSocket::new(src_addr, local_addr, now),
+                        };
+                        let pinned = pinned.is_some();
+                        let target = match self.pick_target(&targets, &sessions, client.socket) {
+                            Some(target) => target,
+                            None => {
+                                if self.verbose() {
+                                    eprintln!(
+                                        "every target is at --max-sessions, dropping handshake from {}",
+                                        client_addr
+                                    );
+                                }
+                                drop(sessions);
+                                self.refuse_client(
+                                    &buf[start..end],
+                                    client_addr,
+                                    src_addr,
+                                    local_addr,
+                                );
+                                continue;
+                            }
+                        };
+                        if let Some(cap) = self.config.max_memory {
+                            if !self.make_room(&mut sessions, sender, cap, now) {
+                                self.stats.memory_refused.fetch_add(1, Ordering::Relaxed);
+                                if self.verbose() {
+                                    eprintln!(
+                                        "--max-memory is used up, dropping handshake from {}",
+                                        client_addr
+                                    );
+                                }
+                                drop(sessions);
+                                self.refuse_client(
+                                    &buf[start..end],
+                                    client_addr,
+                                    src_addr,
+                                    local_addr,
+                                );
+                                continue;
+                            }
+                        }
+                        if !sessions.has_room(&sender) {
+                            if self.verbose() {
+                                eprintln!(
+                                    "--small has no room for another session, dropping handshake from {}",
+                                    client_addr
+                                );
+                            }
+                            drop(sessions);
+                            self.refuse_client(&buf[start..end], client_addr, src_addr, local_addr);
+                            continue;
+                        }
+                        let new = sessions.get(&sender).is_none();
+                        if self.verbose() && new {
+                            if client_addr == src_addr {
+                                eprintln!("new session {:08x} from {}", sender, client_addr);
+                            } else {
+                                eprintln!(
+                                    "new session {:08x} from {} via {}",
+                                    sender, client_addr, src_addr
+                                );
+                            }
+                        }
+                        if new {
+                            self.events.emit(|| Event::SessionCreated {
+                                receiver: sender,
+                                client: client.socket,
+                                target,
+                            });
+                        }
+                        let mut session = Session::new(client, target);
+                        session.pinned = pinned;
+                        sessions.insert(sender, session, now);
+                        self.pending.initiated(sender, src_addr, target, now);
+                        initiated_to = Some(target);
+                    }
+                    // our client answering a peer that initiated through the peer relay
+                    HandShakeResponse { sender, .. } if mesh => {
+                        let now = self.clock.now();
+                        let client = ExpiringSocket::new(src_addr, local_addr, now);
+                        self.sessions.write().recover().insert(
+                            sender,
+                            Session::new(client, targets.active()),
+                            now,
+                        );
+                    }
+                    // only target is allowed to respond to a handshake
+                    HandShakeResponse { .. } => {
+                        self.refuse_client(&buf[start..end], client_addr, src_addr, local_addr);
+                        continue;
+                    }
+                    // whatever a fork uses it for, it's only ours to pass on within a session
+                    Unknown { .. }
+                        if !self
+                            .sessions
+                            .read()
+                            .recover()
+                            .has_client(&src_addr, self.clock.now()) =>
+                    {
+                        continue
+                    }
+                    _ => {}
+                }
+                if self.hijacked(&packet, src_addr) || self.strays(&worker, &packet, src_addr) {
+                    continue;
+                }
+                if let (Some(receiver), Some(_)) = (packet.receiver(), self.config.expiry_grace) {
+                    // only the target may still send for a session in its grace
+                    let now = self.clock.now();
+                    if self
+                        .read_sessions(&worker)
+                        .by_target_index(receiver)
+                        .is_some_and(|s| s.client.expires <= now)
+                    {
+                        continue;
+                    }
+                }
+                if let (Some(receiver), Some(_)) = (packet.receiver(), self.config.dead_after) {
+                    // it's alive after all
+                    if let Some(s) = self.read_sessions(&worker).by_target_index(receiver) {
+                        s.failures.store(0, Ordering::Relaxed);
+                    }
+                }
+                // sessions stay with their target until the transition after a switch is over
+                let target_addr = match packet {
+                    HandShakeInitiation { .. } => initiated_to.unwrap_or_else(|| targets.active()),
+                    _ if targets.several() => packet
+                        .receiver()
+                        .and_then(|receiver| {
+                            self.sessions
+                                .read()
+                                .recover()
+                                .by_target_index(receiver)
+                                .map(|s| s.target)
+                        })
+                        .filter(|target| targets.current(*target, self.config.schedule_transition))
+                        .unwrap_or_else(|| targets.active()),
+                    _ => targets.active(),
+                };
+                match hairpin {
+                    // might be for a peer on the other side of the relay too
+                    Some((to_addr, from_addr)) if matches!(packet, HandShakeInitiation { .. }) => {
+                        let dscp = self.dscp(client_addr);
+                        let mtu = self.config.fragment_clients;
+                        if self.seals_clients() {
+                            // the original still goes to the target
+                            let mut copy = buf;
+                            if let Some((start, end)) =
+                                self.seal_for_client(&mut copy, start, end, to_addr)
+                            {
+                                let sent = self.send_fragmented(
+                                    &copy[start..end],
+                                    to_addr,
+                                    from_addr,
+                                    dscp,
+                                    mtu,
+                                    None,
+                                );
+                                if let Err(e) = sent {
+                                    self.send_failed(&worker, to_addr, &e);
+                                }
+                            }
+                        } else {
+                            let sent = self.send_fragmented(
+                                &buf[start..end],
+                                to_addr,
+                                from_addr,
+                                dscp,
+                                mtu,
+                                None,
+                            );
+                            if let Err(e) = sent {
+                                self.send_failed(&worker, to_addr, &e);
+                            }
+                        }
+                        (target_addr, None)
+                    }
+                    Some(to_addr) => to_addr,
+                    // otherwise it's always the target, and the kernel picks the source address for that
+                    None => (target_addr, None),
+                }
+            };
+
+            let to_target = targets.contains(&to_addr);
+            let pacer = to_target.then(|| targets.pacer(&to_addr)).flatten();
+            drop(targets);
+
+            if !self.allowed(&packet, from_target) {
+                continue;
+            }
+            #[cfg(feature = "admin")]
+            if self.chaos.drops(to_target) {
+                continue;
+            }
+
+            let start = if self.config.compact_envelope && to_target {
+                // the next hop only learns the id from a full envelope, handshakes get one
+                let full = !matches!(packet, WgPacket::Data { .. });
+                let now = self.clock.now();
+                self.compact.wrap(&mut buf, start, client_addr, full, now)
+            } else if self.config.relay_envelope && to_target {
+                // keep the original client when we are a middle hop
+                envelope::wrap(&mut buf, start, client_addr)
+            } else {
+                start
+            };
+            let sealed = match (to_target, &self.config.seal_target) {
+                (true, Some(seal)) => seal.seal(&mut buf, start, end),
+                (true, None) => Some((start, end)),
+                (false, _) => self.seal_for_client(&mut buf, start, end, to_addr),
+            };
+            let (start, end) = match sealed {
+                Some(sealed) => sealed,
+                None => continue,
+            };
+            if !to_target
+                && !matches!(packet, WgPacket::Data { .. })
+                && !self.amplifies(end - start, to_addr)
+            {
+                continue;
+            }
+
+            //println!("sending to: {}", to_addr);
+            //println!("receivers: {:?}", self.sessions.read().recover().receivers);
+
+            // now reply back to src_addr to make sure other direction works
+            let dscp = self.dscp(client_addr);
+            let mtu = if to_target {
+                self.config.fragment_target
+            } else {
+                self.config.fragment_clients
+            };
+            let now = self.clock.now();
+            let mtu = match (mtu, self.config.pmtu) {
+                // fragment to what the path takes when that's less
+                (Some(mtu), Some(_)) => Some(
+                    self.paths
+                        .mtu(to_addr.ip(), now)
+                        .map_or(mtu, |path| path.clamp(fragment::MIN_MTU, mtu)),
+                ),
+                (None, Some(policy)) if self.paths.exceeds(end - start, to_addr, now) => {
+                    self.stats.oversize.fetch_add(1, Ordering::Relaxed);
+                    if policy == Policy::Drop {
+                        continue;
+                    }
+                    None
+                }
+                (mtu, _) => mtu,
+            };
+            let client = if from_target { to_addr } else { src_addr };
+            let labeled = self.labeled(client, to_addr);
+            let port = match &self.ports {
+                Some(ports) if to_target => ports.port(client_addr, to_addr, |port| {
+                    thread::spawn(move || {
+                        if let Err(e) = self.forward(Some(Arc::clone(&port))) {
+                            eprintln!("--source-ports stopped reading a port: {}", e);
+                        }
+                        ports.forget(&port);
+                    });
+                }),
+                _ => None,
+            };
+            // held back, the pacer's thread sends it once it's its turn
+            let delay = pacer.as_ref().map_or(Duration::ZERO, |pacer| {
+                pacer.delay(end - start, Instant::now())
+            });
+            if let (Some(pacer), false) = (&pacer, delay.is_zero()) {
+                self.stats.paced.fetch_add(1, Ordering::Relaxed);
+                let packet = buf[start..end].to_vec();
+                let (worker, port) = (Arc::clone(&worker), port.clone());
+                let send = move || {
+                    let sent = self.send_fragmented(
+                        &packet,
+                        labeled,
+                        from_addr,
+                        dscp,
+                        mtu,
+                        port.as_deref(),
+                    );
+                    match sent {
+                        Ok(sent) => self.count_sent(true, packet.len(), sent, to_addr),
+                        Err(e) => self.send_failed(&worker, to_addr, &e),
+                    }
+                };
+                if !pacer.hold(Instant::now() + delay, Box::new(send)) {
+                    self.stats.pace_overflow.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            if delay.is_zero() {
+                let sent = self
+                    .send_fragmented(
+                        &buf[start..end],
+                        labeled,
+                        from_addr,
+                        dscp,
+                        mtu,
+                        port.as_deref(),
+                    )
+                    .inspect_err(|e| self.send_failed(&worker, to_addr, e));
+                let sent = match sent {
+                    Ok(sent) => sent,
+                    // the kernel knew better than we did
+                    Err(e) if self.config.pmtu.is_some() && platform::too_big(&e) => {
+                        self.stats.oversize.fetch_add(1, Ordering::Relaxed);
+                        self.collect_icmp_errors()?;
+                        continue;
+                    }
+                    // one client we can't reach is no reason to stop forwarding for the rest
+                    Err(_) => {
+                        if let (Some(receiver), Some(limit)) =
+                            (client_session, self.config.dead_after)
+                        {
+                            self.failed(receiver, limit);
+                        }
+                        continue;
+                    }
+                };
+                self.count_sent(to_target, end - start, sent, to_addr);
+            }
+
+            if let (HandShakeInitiation { .. }, true, Some(limit)) =
+                (&packet, to_target, self.config.blackhole_after)
+            {
+                // only from clients the target answered before, wireguard drops initiations that
+                // don't check out without a word, which would let anyone have it blackholed
+                let blackholed = self
+                    .sessions
+                    .read()
+                    .recover()
+                    .was_answered(&client_addr)
+                    .then(|| {
+                        let targets = self.targets.read().recover();
+                        targets.initiated(to_addr, limit).cloned()
+                    })
+                    .flatten();
+                if let Some(target) = blackholed {
+                    self.blackholed(target, "stopped answering handshakes");
+                }
+            }
+
+            if let Some(received) = received {
+                self.stats.processing_time.observe(received.elapsed());
+            }
+        }
+    }
+
+    /// counts sending to to_addr failing with e against worker
+    fn send_failed(&self, worker: &Worker, to_addr: SocketAddr, e: &Error) {
+        worker.send_errors.fetch_add(1, Ordering::Relaxed);
+        if self.verbose() {
+            eprintln!("sending to {} failed: {}", to_addr, e);
+        }
+    }
+
+    /// counts a packet of len bytes to to_addr the kernel took sent of
+    fn count_sent(&self, to_target: bool, len: usize, sent: usize, to_addr: SocketAddr) {
+        if sent != len {
+            self.stats.short_sends.fetch_add(1, Ordering::Relaxed);
+            eprintln!("sent {} of {} bytes to {} only", sent, len, to_addr);
+        }
+        let (packets, bytes) = if to_target {
+            (&self.stats.packets_to_target, &self.stats.bytes_to_target)
+        } else {
+            (&self.stats.packets_to_clients, &self.stats.bytes_to_clients)
+        };
+        packets.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(sent as u64, Ordering::Relaxed);
+    }
+
+    /// whether --amplification lets us send an address that has no session len bytes that
+    /// aren't transport data, counting what it doesn't
+    fn amplifies(&self, len: usize, to: SocketAddr) -> bool {
+        let allowed = self
+            .amplification
+            .as_ref()
+            .is_none_or(|amplification| amplification.allows(to.ip(), len, self.clock.now()));
+        if !allowed {
+            self.stats
+                .amplification_capped
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// answers packet from src_addr, which isn't wireguard, with --decoy
+    fn decoy(&self, packet: &[u8], src_addr: SocketAddr, local_addr: Option<LocalAddr>) {
+        let sent = match &self.config.decoy {
+            Decoy::Silence => return,
+            // never more than we got
+            Decoy::Reply(reply) if reply.len() > packet.len() => return,
+            Decoy::Reply(reply) => self.send(reply, src_addr, local_addr, None),
+            Decoy::Unreachable => match self.unreachable(packet, src_addr, local_addr) {
+                Some(sent) => sent,
+                None => return,
+            },
+        };
+        if sent.is_ok() {
+            self.stats.decoys.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// for --refuse-unreachable, tells a client we won't forward packet the port is closed
+    fn refuse(&self, packet: &[u8], src_addr: SocketAddr, local_addr: Option<LocalAddr>) {
+        if self.config.refuse_unreachable
+            && self.decoys.allow(self.clock.seconds())
+            && self
+                .unreachable(packet, src_addr, local_addr)
+                .is_some_and(|sent| sent.is_ok())
+        {
+            self.stats.refusals.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// opens the packet from the client at from in buf[start..end] like --seal-clients or
+    /// --allow-proxies say, None if it doesn't open
+    #[cfg_attr(not(feature = "identity"), allow(unused_variables))]
+    fn open_from_client(
+        &self,
+        buf: &mut [u8],
+        start: usize,
+        end: usize,
+        from: SocketAddr,
+    ) -> Option<(usize, usize)> {
+        #[cfg(feature = "identity")]
+        if let Some(proxies) = &self.config.allow_proxies {
+            return proxies.open(buf, start, end, from);
+        }
+        match &self.config.seal_clients {
+            Some(seal) => seal.open(buf, start, end),
+            None => Some((start, end)),
+        }
+    }
+
+    /// seals the packet in buf[start..end] for the client at to like --seal-clients or
+    /// --allow-proxies say, None if it can't be
+    #[cfg_attr(not(feature = "identity"), allow(unused_variables))]
+    fn seal_for_client(
+        &self,
+        buf: &mut [u8],
+        start: usize,
+        end: usize,
+        to: SocketAddr,
+    ) -> Option<(usize, usize)> {
+        #[cfg(feature = "identity")]
+        if let Some(proxies) = &self.config.allow_proxies {
+            return proxies.seal(buf, start, end, to);
+        }
+        match &self.config.seal_clients {
+            Some(seal) => seal.seal(buf, start, end),
+            None => Some((start, end)),
+        }
+    }
+
+    /// whether packets to clients are sealed
+    fn seals_clients(&self) -> bool {
+        #[cfg(feature = "identity")]
+        if self.config.allow_proxies.is_some() {
+            return true;
+        }
+        self.config.seal_clients.is_some()
+    }
+
+    /// refuse for client's packet, unless a previous hop sent it for them, which would take the
+    /// port being closed for every client of its
+    fn refuse_client(
+        &self,
+        packet: &[u8],
+        client_addr: SocketAddr,
+        src_addr: SocketAddr,
+        local_addr: Option<LocalAddr>,
+    ) {
+        if client_addr == src_addr {
+            self.refuse(packet, src_addr, local_addr);
+        }
+    }
+
+    /// sends the ICMP port unreachable for packet, None without a raw socket or if the addresses
+    /// aren't of the same family
+    fn unreachable(
+        &self,
+        packet: &[u8],
+        src_addr: SocketAddr,
+        local_addr: Option<LocalAddr>,
+    ) -> Option<Result<usize>> {
+        let (icmp, ours) = match (&self.icmp, self.socket.local_addr()) {
+            (Some(icmp), Ok(ours)) => (icmp, ours),
+            _ => return None,
+        };
+        // the address it was sent to, not the wildcard we are bound to
+        let ours = match local_addr {
+            Some(local_addr) => SocketAddr::new(local_addr.ip, ours.port()),
+            None => ours,
+        };
+        let unreachable = decoy::port_unreachable(packet, src_addr, ours)?;
+        Some(icmp.send(&unreachable, src_addr.ip()))
+    }
+
+    /// send, split into fragments if it doesn't fit mtu, returns how much of packet was sent
+    fn send_fragmented(
+        &self,
+        packet: &[u8],
+        to: SocketAddr,
+        from: Option<LocalAddr>,
+        dscp: Option<u8>,
+        mtu: Option<usize>,
+        via: Option<&Port>,
+    ) -> Result<usize> {
+        // a --source-ports port is of the target's family, and picks its own source address
+        let send = |packet: &[u8]| match via {
+            Some(port) => Datagram::send(&port.socket, packet, to, None, dscp),
+            None => self.send(packet, to, from, dscp),
+        };
+        let fragments = match mtu.and_then(|mtu| self.fragments.split(packet, to, mtu)) {
+            Some(fragments) => fragments,
+            None => return send(packet),
+        };
+        for fragment in fragments {
+            send(&fragment)?;
+        }
+        self.stats.fragmented.fetch_add(1, Ordering::Relaxed);
+        Ok(packet.len())
+    }
+
+    /// the sessions to read, any wait for other workers changing them counted against worker
+    fn read_sessions(&self, worker: &Worker) -> RwLockReadGuard<'_, Sessions> {
+        if let Ok(sessions) = self.sessions.try_read() {
+            return sessions;
+        }
+        let since = Instant::now();
+        let sessions = self.sessions.read().recover();
+        worker.waited(since);
+        sessions
+    }
+
+    /// brings the session packet is for back from --cold-store first, if that's where it is
+    fn warm(&self, worker: &Worker, packet: &WgPacket, from_target: bool) {
+        let receiver = {
+            let sessions = self.read_sessions(worker);
+            let receiver = match packet {
+                HandShakeInitiation { sender } => Some(*sender),
+                _ if from_target => packet.receiver().copied(),
+                // the client addresses the target's index
+                _ => packet
+                    .receiver()
+                    .and_then(|receiver| sessions.targets.get(receiver).copied()),
+            };
+            match receiver.filter(|receiver| sessions.is_cold(receiver)) {
+                Some(receiver) => receiver,
+                None => return,
+            }
+        };
+        if let Err(e) = self.write_sessions(worker).warm(receiver) {
+            eprintln!(
+                "--cold-store couldn't bring back session {:08x}: {}",
+                receiver, e
+            );
+        }
+    }
+
+    /// the sessions to change, any wait for other workers using them counted against worker
+    fn write_sessions(&self, worker: &Worker) -> RwLockWriteGuard<'_, Sessions> {
+        if let Ok(sessions) = self.sessions.try_write() {
+            return sessions;
+        }
+        let since = Instant::now();
+        let sessions = self.sessions.write().recover();
+        worker.waited(since);
+        sessions
+    }
+
+    /// socket.send, with --dead-after or --pmtu an error an ICMP error left on the socket is
+    /// collected and the send tried again
+    fn send(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        local_addr: Option<LocalAddr>,
+        dscp: Option<u8>,
+    ) -> Result<usize> {
+        let addr = self.mapped(addr);
+        match self.socket.send(buf, addr, local_addr, dscp) {
+            Err(e) if self.collects_icmp() && icmp_error(&e) => {
+                self.collect_icmp_errors()?;
+                self.socket.send(buf, addr, local_addr, dscp)
+            }
+            sent => sent,
+        }
+    }
+
+    /// addr with the flow label of client's sessions, if it's ipv6 and --flow-labels leased some
+    ///
+    /// by client rather than session index, the index changes with every rekey and the path
+    /// shouldn't
+    fn labeled(&self, client: SocketAddr, addr: SocketAddr) -> SocketAddr {
+        let v6 = match addr {
+            SocketAddr::V6(v6) if !self.flow_labels.is_empty() => v6,
+            _ => return addr,
+        };
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        let label = self.flow_labels[hasher.finish() as usize % self.flow_labels.len()];
+        platform::flow_labeled(v6, label)
+    }
+
+    /// addr as the ipv4 address it is if it's v4-mapped, what our ipv6 socket gives us for those
+    fn unmapped(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V6(v6) if self.v6 => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::from((ip, v6.port())),
+                None => addr,
+            },
+            _ => addr,
+        }
+    }
+
+    /// addr as our socket sends to it, ipv4 addresses v4-mapped if it's ipv6
+    fn mapped(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(v4) if self.v6 => {
+                SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port()))
+            }
+            _ => addr,
+        }
+    }
+
+    /// whether we asked the kernel for ICMP errors
+    fn collects_icmp(&self) -> bool {
+        self.config.dead_after.is_some() || self.config.pmtu.is_some()
+    }
+
+    /// learns from the ICMP errors since we last asked, with --dead-after counts a failure for
+    /// every session whose client is unreachable, with --pmtu remembers path mtus
+    fn collect_icmp_errors(&self) -> Result<()> {
+        for error in self.socket.icmp_errors()? {
+            match (error, self.config.dead_after) {
+                (IcmpError::Unreachable(addr), Some(limit)) => {
+                    self.collect_unreachable(self.unmapped(addr), limit)
+                }
+                (IcmpError::TooBig(addr, mtu), _) if self.config.pmtu.is_some() => {
+                    let addr = self.unmapped(addr);
+                    let new = self.paths.learned(addr.ip(), mtu, self.clock.now());
+                    if self.verbose() && new {
+                        eprintln!("the path mtu to {} is {}", addr.ip(), mtu);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// counts a failure for every session whose client is addr
+    fn collect_unreachable(&self, addr: SocketAddr, limit: u32) {
+        let receivers = self.sessions.read().recover().census.receivers(addr);
+        for receiver in receivers {
+            self.failed(receiver, limit);
+        }
+    }
+
+    /// forgets the sessions that expired by now, or --expiry-grace before it, and moves those
+    /// gone idle to --cold-store
+    fn housekeep(&self, sessions: &mut Sessions, now: Tick) {
+        // --expiry-grace's sessions still get what the target had on the way
+        let cutoff = match self.config.expiry_grace {
+            Some(grace) => now.saturating_sub(clock::ticks(grace)),
+            None => now,
+        };
+        for (receiver, client) in sessions.expire(cutoff) {
+            if let Some(client) = client {
+                self.events.emit(|| Event::SessionExpired {
+                    receiver,
+                    client,
+                    reason: "expired",
+                });
+            }
+        }
+        if let Err(e) = sessions.spill(now) {
+            eprintln!("--cold-store couldn't take an idle session: {}", e);
+        }
+    }
+
+    /// approximately what the tables that grow with clients take at now
+    fn memory(&self, sessions: &Sessions, now: Tick) -> Usage {
+        Usage {
+            sessions: sessions.memory(),
+            handshakes: self.pending.memory(now),
+            offenders: self.garbage.memory(),
+        }
+    }
+
+    /// whether the session with sender fits under --max-memory's cap at now, evicting the
+    /// sessions idle longest once we are past memory::HIGH_WATER
+    fn make_room(&self, sessions: &mut Sessions, sender: u32, cap: usize, now: Tick) -> bool {
+        if let Some(excess) = self.memory(sessions, now).excess(cap) {
+            let evicted = sessions.evict(excess, sender);
+            self.stats
+                .memory_evictions
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+            if self.verbose() && !evicted.is_empty() {
+                eprintln!(
+                    "--max-memory is nearly used up, evicted the {} sessions idle longest",
+                    evicted.len()
+                );
+            }
+            for (receiver, session) in evicted {
+                self.events.emit(|| Event::SessionExpired {
+                    receiver,
+                    client: session.client.socket,
+                    reason: "memory",
+                });
+            }
+        }
+        sessions.get(&sender).is_some()
+            || self.memory(sessions, now).total() + SESSION_MEMORY <= cap
+    }
+
+    /// a packet from the target for session receiver couldn't reach its client, which expires
+    /// the session once that happened limit times in a row
+    fn failed(&self, receiver: u32, limit: u32) {
+        let dead = self
+            .sessions
+            .read()
+            .recover()
+            .get(&receiver)
+            .is_some_and(|s| s.failures.fetch_add(1, Ordering::Relaxed) + 1 >= limit);
+        if !dead {
+            return;
+        }
+        if let Some(session) = self.sessions.write().recover().remove(receiver) {
+            self.stats.dead_sessions.fetch_add(1, Ordering::Relaxed);
+            self.events.emit(|| Event::SessionExpired {
+                receiver,
+                client: session.client.socket,
+                reason: "unreachable",
+            });
+            if self.verbose() {
+                eprintln!(
+                    "expiring session {:08x}, its client {} is unreachable",
+                    receiver, session.client.socket
+                );
+            }
+        }
+    }
+
+    /// counts a cookie reply from src_addr to receiver we are dropping, which isn't the target's
+    /// for a handshake we just forwarded there
+    fn stray_cookie(&self, src_addr: SocketAddr, receiver: u32) {
+        self.stats.stray_cookies.fetch_add(1, Ordering::Relaxed);
+        self.events.emit(|| Event::Security {
+            kind: "stray_cookie",
+            source: src_addr,
+            detail: format!("cookie reply to {:08x}", receiver),
+        });
+        if self.verbose() {
+            eprintln!(
+                "dropping cookie reply from {} to {:08x}, it's for no handshake we just forwarded from there",
+                src_addr, receiver
+            );
+        }
+    }
+
+    /// whether to drop packet, from client src_addr for a session under its target's index,
+    /// because --freeze-hijacked froze the session, or does now as src_addr is where a handshake
+    /// just took it away from
+    fn hijacked(&self, packet: &WgPacket, src_addr: SocketAddr) -> bool {
+        if !self.moves.any() && !self.frozen.load(Ordering::Relaxed) {
+            return false;
+        }
+        let receiver = match packet {
+            HandShakeInitiation { .. } => return false,
+            _ => match packet.receiver() {
+                Some(receiver) => receiver,
+                None => return false,
+            },
+        };
+        let sessions = self.sessions.read().recover();
+        let Some((&sender, session)) = sessions
+            .targets
+            .get(receiver)
+            .and_then(|sender| sessions.receivers.get_key_value(sender))
+        else {
+            return false;
+        };
+        if session.frozen {
+            return true;
+        }
+        if session.client.socket == src_addr || !self.moves.any() {
+            return false;
+        }
+        let Some(client) = self.moves.check(&src_addr, sender, self.clock.now()) else {
+            return false;
+        };
+        drop(sessions);
+        self.hijack(
+            &mut self.sessions.write().recover(),
+            sender,
+            client,
+            src_addr,
+        )
+    }
+
+    /// whether --strict-source drops packet from client src_addr, for a session under its target's
+    /// index whose handshake came from elsewhere
+    fn strays(&self, worker: &Worker, packet: &WgPacket, src_addr: SocketAddr) -> bool {
+        let receiver = match packet {
+            _ if !self.config.strict_source => return false,
+            HandShakeInitiation { .. } => return false,
+            _ => match packet.receiver() {
+                Some(receiver) => receiver,
+                None => return false,
+            },
+        };
+        let client = self
+            .read_sessions(worker)
+            .by_target_index(receiver)
+            .map(|s| s.client.socket)
+            .filter(|client| *client != src_addr);
+        let Some(client) = client else {
+            return false;
+        };
+        let count = self.stats.strict_source.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.verbose() && count % STRAY_LOG_EVERY == 1 {
+            eprintln!(
+                "dropping packet from {} for the session of {}, --strict-source, {} so far",
+                src_addr, client, count
+            );
+        }
+        true
+    }
+
+    /// previous still sends for the session with receiver that client took over from it, freezes
+    /// the session if --freeze-hijacked says to, returns whether it did
+    fn hijack(
+        &self,
+        sessions: &mut Sessions,
+        receiver: u32,
+        client: SocketAddr,
+        previous: SocketAddr,
+    ) -> bool {
+        self.stats.hijacks.fetch_add(1, Ordering::Relaxed);
+        let freeze = self.config.freeze_hijacked;
+        eprintln!(
+            "{} still sends for session {:08x} a handshake from {} took over, one of them is after the other's return traffic{}",
+            previous,
+            receiver,
+            client,
+            if freeze {
+                ", freezing it until the admin pins or unfreezes it"
+            } else {
+                ""
+            }
+        );
+        self.events.emit(|| Event::Hijack {
+            receiver,
+            client,
+            previous,
+        });
+        match sessions.receivers.get_mut(&receiver) {
+            Some(session) if freeze => {
+                session.frozen = true;
+                self.frozen.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// hands a line about packet, len bytes from src_addr, to whoever follows its session
+    #[cfg(feature = "admin")]
+    fn trace(&self, packet: &WgPacket, from_target: bool, src_addr: SocketAddr, len: usize) {
+        let index = match (packet, from_target) {
+            (HandShakeInitiation { sender }, false) => Some(*sender),
+            // a peer of our peer relay's, no session of ours yet
+            (HandShakeInitiation { .. }, true) => None,
+            (_, true) => packet.receiver().copied(),
+            // clients address the target's index, the session is under theirs
+            (_, false) => packet.receiver().and_then(|receiver| {
+                self.sessions
+                    .read()
+                    .recover()
+                    .targets
+                    .get(receiver)
+                    .copied()
+            }),
+        };
+        let index = match index {
+            Some(index) => index,
+            None => return,
+        };
+        self.follows.trace(index, || {
+            use WgPacket::{Cookie, Data};
+            let kind = match packet {
+                HandShakeInitiation { .. } => "initiation".to_string(),
+                HandShakeResponse { .. } => "response".to_string(),
+                Cookie { .. } => "cookie reply".to_string(),
+                Data { .. } if len == KEEPALIVE_LEN => "keepalive".to_string(),
+                Data { .. } => "data".to_string(),
+                Unknown { message_type, .. } => format!("type {}", message_type),
+            };
+            let from = if from_target { "target" } else { "client" };
+            format!("from {} {} {} {} bytes", from, src_addr, kind, len)
+        });
+    }
+
+    /// runs an admin command for scope, a tenant's go to its listener
+    #[cfg(feature = "admin")]
+    fn admin(&self, scope: &Scope, args: &[&str], out: &mut dyn Output) -> Result<()> {
+        let tenant = match scope {
+            Scope::Operator => return self.command(args, out),
+            Scope::Tenant(tenant) => tenant,
+        };
+        if let [command @ ("listen" | "unlisten" | "listeners" | "pcap"), ..] = args {
+            return Err(admin::invalid(format!("{} is for the operator", command)));
+        }
+        self.tenant(tenant)?.command(args, out)
+    }
+
+    /// the listener of tenant
+    #[cfg(feature = "admin")]
+    fn tenant(&self, tenant: &str) -> Result<&'static Proxy> {
+        self.listeners
+            .tenant(tenant)
+            .ok_or_else(|| admin::invalid(format!("tenant {} isn't running", tenant)))
+    }
+
+    /// what a crash report says about us, sizes and counters, tables the panic left locked skipped
+    fn crash_snapshot(&self, out: &mut String) {
+        match self.sessions.try_read().try_recover() {
+            Some(sessions) => out.push_str(&format!(
+                " sessions {} cold {} forced {} memory {}",
+                sessions.receivers.len(),
+                sessions.cold.as_ref().map_or(0, |cold| cold.len()),
+                sessions.forced.len(),
+                sessions.memory()
+            )),
+            None => out.push_str(" sessions locked"),
+        }
+        let locked = |len: Option<usize>| len.map_or("locked".to_string(), |n| n.to_string());
+        out.push_str(&format!(
+            " handshakes {} offenders {} threads {}",
+            locked(self.pending.try_len()),
+            locked(self.garbage.try_len()),
+            self.config.thread_count
+        ));
+        for (name, counter) in self.counters() {
+            out.push_str(&format!(" {} {}", name, counter.load(Ordering::Relaxed)));
+        }
+    }
+
+    /// our counters for --grpc's Stats
+    #[cfg(feature = "grpc")]
+    fn snapshot(&self) -> grpc::Stats {
+        let counters = self.counters();
+        grpc::Stats {
+            sessions: self.sessions.read().recover().receivers.len() as u64,
+            handshakes_pending: self.pending.count(self.clock.now()) as u64,
+            counters: counters
+                .iter()
+                .map(|(name, counter)| (*name, counter.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    /// handles a command from the --admin socket
+    #[cfg(feature = "admin")]
+    fn command(&self, args: &[&str], out: &mut dyn Output) -> Result<()> {
+        match args {
+            ["sessions"] => {
+                let sessions = self.sessions.read().recover();
+                let now = self.clock.now();
+                for (receiver, session) in sessions.receivers.iter() {
+                    write!(
+                        out,
+                        "{:08x} client {} target {} expires {}s",
+                        receiver,
+                        session.client.socket,
+                        session.target,
+                        clock::duration(session.client.expires.saturating_sub(now)).as_secs()
+                    )?;
+                    if session.pinned {
+                        write!(out, " pinned")?;
+                    }
+                    if session.frozen {
+                        write!(out, " frozen")?;
+                    }
+                    if let Some(target) = sessions.forced.get(&session.client.socket) {
+                        write!(out, " forced {}", target)?;
+                    }
+                    writeln!(out)?;
+                }
+                if let Some(cold) = sessions.cold.as_ref().filter(|cold| !cold.is_empty()) {
+                    writeln!(out, "and {} idle in --cold-store", cold.len())?;
+                }
+            }
+            ["pin", index, client] => {
+                let client = client
+                    .parse()
+                    .map_err(|_| admin::invalid(format!("invalid address: {}", client)))?;
+                let receiver = admin::parse_index(index)?;
+                let mut sessions = self.sessions.write().recover();
+                let session = admin_session(&mut sessions, index)?;
+                let (previous, target) = (session.client.socket, session.target);
+                let answered = session.answered;
+                session.client.socket = client;
+                // it might not be reachable from where the old one was, let the kernel pick
+                session.client.local_addr = None;
+                session.pinned = true;
+                session.frozen = false;
+                sessions.moved(receiver, (previous, target), (client, target), answered);
+            }
+            ["unpin", index] => {
+                let mut sessions = self.sessions.write().recover();
+                admin_session(&mut sessions, index)?.pinned = false;
+            }
+            ["unfreeze", index] => {
+                let mut sessions = self.sessions.write().recover();
+                admin_session(&mut sessions, index)?.frozen = false;
+            }
+            ["force", index, target] => {
+                let target = self
+                    .targets
+                    .read()
+                    .recover()
+                    .find(target)
+                    .ok_or_else(|| admin::invalid(format!("unknown target: {}", target)))?;
+                let receiver = admin::parse_index(index)?;
+                let mut sessions = self.sessions.write().recover();
+                let session = admin_session(&mut sessions, index)?;
+                let (previous, answered) = (session.target, session.answered);
+                session.target = target;
+                let client = session.client.socket;
+                sessions.forced.insert(client, target);
+                sessions.moved(receiver, (client, previous), (client, target), answered);
+            }
+            ["unforce", index] => {
+                let mut sessions = self.sessions.write().recover();
+                let client = admin_session(&mut sessions, index)?.client.socket;
+                sessions.forced.remove(&client);
+            }
+            ["offenders"] | ["offenders", _] => {
+                let n = match args.get(1) {
+                    Some(n) => n
+                        .parse()
+                        .map_err(|_| admin::invalid(format!("invalid count: {}", n)))?,
+                    None => 10,
+                };
+                for offender in self.garbage.top(n, self.clock.now()) {
+                    writeln!(
+                        out,
+                        "{} {} recently, {} in all",
+                        offender.ip, offender.score, offender.total
+                    )?;
+                }
+            }
+            ["listen", bind_addr, target] | ["listen", bind_addr, target, _] => {
+                let thread_count: usize = match args.get(3) {
+                    Some(n) => n
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| admin::invalid(format!("invalid count: {}", n)))?,
+                    None => 1,
+                };
+                let args = ["--relay", target, bind_addr, &thread_count.to_string()];
+                let config = config::derive(&self.config.options, &args.map(String::from))
+                    .map_err(|e| admin::invalid(format!("invalid listener: {}", e)))?;
+                let addr = self.listeners.add(None, config)?;
+                writeln!(out, "listening on {}", addr)?;
+            }
+            ["unlisten", bind_addr] => {
+                let addr = bind_addr
+                    .parse()
+                    .map_err(|_| admin::invalid(format!("invalid address: {}", bind_addr)))?;
+                self.listeners.remove(addr)?;
+            }
+            ["listeners"] => {
+                for (addr, tenant, target, workers) in self.listeners.list() {
+                    match tenant {
+                        Some(tenant) => write!(out, "{} tenant {} ", addr, tenant)?,
+                        None => write!(out, "{} ", addr)?,
+                    }
+                    writeln!(out, "{} {} workers", target, workers)?;
+                }
+            }
+            ["index-audit"] => {
+                let audit = self
+                    .audit
+                    .as_ref()
+                    .ok_or_else(|| admin::invalid("not started with --index-audit".to_string()))?;
+                for (client, samples, verdict) in audit.report() {
+                    let verdict = verdict.unwrap_or("look random");
+                    writeln!(out, "{} {} indexes {}", client, samples, verdict)?;
+                }
+            }
+            ["fingerprints"] => {
+                let fingerprints = self
+                    .fingerprints
+                    .as_ref()
+                    .ok_or_else(|| admin::invalid("not started with --fingerprints".to_string()))?;
+                for (labels, count) in fingerprints.counts(self.clock.now()) {
+                    write!(out, "{}", count)?;
+                    for (label, value) in labels {
+                        write!(out, " {}={}", label, value)?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+            ["follow", index] => {
+                let index = admin::parse_index(index)?;
+                let started = Instant::now();
+                let following = self.follows.follow(index);
+                writeln!(
+                    out,
+                    "following {:08x}, send a line or hang up to stop",
+                    index
+                )?;
+                out.flush()?;
+                let mut last = started;
+                while !out.cancelled() {
+                    // it's our Follows that has the other end, so it never hangs up
+                    if let Ok((at, line)) = following.lines.recv_timeout(FOLLOW_POLL) {
+                        let since_start = at.duration_since(started).as_secs_f64();
+                        let since_last = at.duration_since(last).as_millis();
+                        writeln!(out, "+{:.3}s +{}ms {}", since_start, since_last, line)?;
+                        out.flush()?;
+                        last = at;
+                    }
+                }
+                if following.dropped() > 0 {
+                    writeln!(
+                        out,
+                        "{} packets not shown, too many too fast",
+                        following.dropped()
+                    )?;
+                }
+            }
+            ["switch-target", old, new] | ["switch-target", old, new, "--drain", _] => {
+                let drain = match args.get(4) {
+                    Some(drain) => admin::parse_duration(drain)?,
+                    None => self.config.schedule_transition,
+                };
+                let unknown = |name: &str| admin::invalid(format!("unknown target: {}", name));
+                let mut targets = self.targets.write().recover();
+                let from = targets.named(old).ok_or_else(|| unknown(old))?.clone();
+                let to = targets.named(new).ok_or_else(|| unknown(new))?.host.clone();
+                if from.addr != targets.active() {
+                    return Err(admin::invalid(format!("{} isn't the active target", old)));
+                }
+                if targets.switch(&to).is_none() {
+                    return Err(admin::invalid(format!("{} is the active target", new)));
+                }
+                targets.drain(&from.host, drain);
+                drop(targets);
+                eprintln!(
+                    "switching target from {} to {} on admin request",
+                    from.host, to
+                );
+                writeln!(
+                    out,
+                    "new sessions go to {}, {} left on {}, cut over in {}s at the latest",
+                    to,
+                    self.sessions_with(from.addr),
+                    from.host,
+                    drain.as_secs()
+                )?;
+                let started = clock::now();
+                let until = started + clock::ticks(drain);
+                let drain = Drain {
+                    from,
+                    to,
+                    started,
+                    until,
+                };
+                self.drains.lock().recover().push(drain);
+                self.draining.notify_all();
+            }
+            ["drains"] => {
+                let now = clock::now();
+                for drain in self.drains.lock().recover().iter() {
+                    writeln!(
+                        out,
+                        "{} to {}, {} sessions left, cut over in {}s",
+                        drain.from.host,
+                        drain.to,
+                        self.sessions_with(drain.from.addr),
+                        clock::duration(drain.until.saturating_sub(now)).as_secs()
+                    )?;
+                }
+            }
+            ["probes"] => {
+                let probes = self
+                    .probes
+                    .as_ref()
+                    .ok_or_else(|| admin::invalid("not started with --probe".to_string()))?;
+                for (host, path) in probes.report() {
+                    match path.rtt {
+                        Some(rtt) => {
+                            write!(out, "{} rtt {:.1}ms", host, rtt.as_secs_f64() * 1000.0)?
+                        }
+                        None => write!(out, "{} never answered", host)?,
+                    }
+                    writeln!(out, ", {} of {} probes lost", path.lost, path.sent)?;
+                }
+            }
+            ["workers"] => {
+                for (i, worker) in self.workers.lock().recover().iter().enumerate() {
+                    let waited = worker.lock_wait_nanos.load(Ordering::Relaxed) / 1_000_000;
+                    writeln!(
+                        out,
+                        "worker {} {} packets, {}ms waiting for sessions, {} send errors",
+                        i,
+                        worker.packets.load(Ordering::Relaxed),
+                        waited,
+                        worker.send_errors.load(Ordering::Relaxed)
+                    )?;
+                }
+            }
+            ["paths"] => {
+                for (ip, mtu) in self.paths.list(self.clock.now()) {
+                    writeln!(out, "{} {}", ip, mtu)?;
+                }
+            }
+            [command @ ("pause" | "resume")] => {
+                let pause = *command == "pause";
+                let _lock = self.pause_lock.lock().recover();
+                if self.paused.swap(pause, Ordering::Relaxed) != pause {
+                    self.resumed.notify_all();
+                    let addr = self.socket.local_addr()?;
+                    eprintln!("{}d reading {} on admin request", command, addr);
+                }
+            }
+            ["verbose"] => {
+                let verbose = if self.verbose() { "on" } else { "off" };
+                writeln!(out, "{}", verbose)?;
+            }
+            ["verbose", state @ ("on" | "off")] => {
+                self.verbose.store(*state == "on", Ordering::Relaxed);
+            }
+            ["pcap"] => match &*self.pcap.read().recover() {
+                Some(pcap) => writeln!(
+                    out,
+                    "capturing, {} packets left out",
+                    pcap.dropped.load(Ordering::Relaxed)
+                )?,
+                None => writeln!(out, "off")?,
+            },
+            ["pcap", "off"] => {
+                self.capturing.store(false, Ordering::Relaxed);
+                // the writer has what was captured, and finishes writing it
+                self.pcap.write().recover().take();
+            }
+            ["pcap", path, filter @ ..] => {
+                let filter = match filter {
+                    [] => None,
+                    filter => Some(pcap::Filter::parse(&filter.join(" "))?),
+                };
+                let local = config::resolve(&self.config.bind_addr)?;
+                let pcap = Pcap::create(path, filter, local)?;
+                *self.pcap.write().recover() = Some(pcap);
+                self.capturing.store(true, Ordering::Relaxed);
+            }
+            ["chaos"] => writeln!(out, "{}", self.chaos.describe())?,
+            ["chaos", args @ ..] => self.chaos.set(args)?,
+            ["reset-stats"] => {
+                for (_, counter) in self.counters() {
+                    counter.store(0, Ordering::Relaxed);
+                }
+                if let Some(state_path) = &self.config.state_path {
+                    self.save_state(state_path)?;
+                }
+                eprintln!("counters reset on admin request");
+            }
+            ["help"] => writeln!(out, "{}", ADMIN_USAGE)?,
+            _ => {
+                return Err(admin::invalid(format!(
+                    "unknown command: {}, try help",
+                    args.join(" ")
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// feeds the kernel drop count from the last recv into overload detection, returns whether we
+    /// are shedding load
+    fn shedding(&self, dropped: Option<u32>) -> bool {
+        let now = self.clock.seconds();
+        if let Some(dropped) = dropped {
+            match self.overload.update(now, dropped) {
+                Some(true) => eprintln!(
+                    "receive queue overflowing, dropping handshakes from addresses without a session"
+                ),
+                Some(false) => eprintln!("receive queue recovered, accepting all handshakes again"),
+                None => {}
+            }
+        }
+        self.overload.shedding(now)
+    }
+
+    /// which target a handshake from client goes to, the active one unless --max-sessions says
+    /// it's full, None if all of them are
+    fn pick_target(
+        &self,
+        targets: &Targets,
+        sessions: &Sessions,
+        client: SocketAddr,
+    ) -> Option<SocketAddr> {
+        if let Some(target) = sessions.forced.get(&client) {
+            if targets.contains(target) {
+                return Some(*target);
+            }
+        }
+        if !targets.limited() {
+            return Some(targets.active());
+        }
+        targets
+            .candidates()
+            .find(|(target, max_sessions)| match max_sessions {
+                None => true,
+                Some(max_sessions) => {
+                    let (clients, ours) = sessions.clients_of(*target, client);
+                    ours || clients < *max_sessions
+                }
+            })
+            .map(|(target, _)| target)
+    }
+
+    /// the --dscp value for packets from client_addr, if any
+    fn dscp(&self, client_addr: SocketAddr) -> Option<u8> {
+        self.config
+            .dscp
+            .iter()
+            .find(|(cidr, _)| cidr.contains(client_addr.ip()))
+            .map(|(_, dscp)| *dscp)
+    }
+
+    /// whether to log what --verbose does
+    fn verbose(&self) -> bool {
+        self.verbose.load(Ordering::Relaxed)
+    }
+
+    /// whether we are still within --startup-grace
+    fn in_grace(&self) -> bool {
+        self.config
+            .startup_grace
+            .is_some_and(|grace| self.clock.now() - self.started < clock::ticks(grace))
+    }
+
+    /// --max-session-pps, or what --control-plane made it
+    fn max_session_pps(&self) -> Option<u32> {
+        #[cfg(feature = "control-plane")]
+        if let Some(fleet) = &self.fleet {
+            return fleet.max_session_pps();
+        }
+        self.config.max_session_pps
+    }
+
+    /// whether the session packet belongs to is under its --max-session-pps circuit breaker
+    fn allowed(&self, packet: &WgPacket, from_target: bool) -> bool {
+        let limit = match self.max_session_pps() {
+            None => return true,
+            Some(limit) => limit,
+        };
+        let sessions = self.sessions.read().recover();
+        let receiver = match packet {
+            HandShakeInitiation { sender } if !from_target => Some(sender),
+            _ if from_target => packet.receiver(),
+            // from the client, addressed with the target's index
+            _ => packet
+                .receiver()
+                .and_then(|receiver| sessions.targets.get(receiver)),
+        };
+        let (receiver, session) = match receiver.and_then(|r| sessions.receivers.get_key_value(r)) {
+            None => return true,
+            Some(session) => session,
+        };
+        match session.breaker.check(self.clock.seconds(), limit) {
+            Verdict::Forward => true,
+            Verdict::Paused => false,
+            Verdict::Tripped => {
+                self.events.emit(|| Event::Security {
+                    kind: "rate_limited",
+                    source: session.client.socket,
+                    detail: format!("session {:08x} over {} packets/s", receiver, limit),
+                });
+                eprintln!(
+                    "session {:08x} from {} over {} packets/s for {}s, pausing it for {}s",
+                    receiver,
+                    session.client.socket,
+                    limit,
+                    rate::SUSTAINED_SECS,
+                    rate::PAUSE_SECS
+                );
+                false
+            }
+        }
+    }
+}
+
+/// whether e is what an ICMP error for something we sent earlier looks like
+fn icmp_error(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable
+    ) || platform::too_big(e)
+}
+
+/// with --verbose, log one in this many packets from the target for unknown receivers
+const UNKNOWN_RECEIVER_LOG_EVERY: u64 = 100;
+
+/// and one in this many packets --strict-source drops
+const STRAY_LOG_EVERY: u64 = 100;
+
+#[cfg(feature = "grpc")]
+impl<D: Datagram + 'static, C: Clock + 'static> grpc::Api for Proxy<D, C> {
+    fn command(&self, scope: &Scope, args: &[&str], out: &mut dyn Output) -> Result<()> {
+        self.admin(scope, args, out)
+    }
+
+    fn events(&self, scope: &Scope) -> Result<mpsc::Receiver<Stamped>> {
+        Ok(match scope {
+            Scope::Operator => self.events.subscribe(),
+            Scope::Tenant(tenant) => self.tenant(tenant)?.events.subscribe(),
+        })
+    }
+
+    fn stats(&self, scope: &Scope) -> Result<grpc::Stats> {
+        Ok(match scope {
+            Scope::Operator => self.snapshot(),
+            Scope::Tenant(tenant) => self.tenant(tenant)?.snapshot(),
+        })
+    }
+}
+
+// how often follow looks whether it was stopped while nothing passes
+#[cfg(feature = "admin")]
+const FOLLOW_POLL: Duration = Duration::from_millis(250);
+
+// how often a switch-target's old target is looked at for sessions left
+#[cfg(feature = "admin")]
+const DRAIN_POLL: Duration = Duration::from_secs(1);
+
+// how often expired sessions are forgotten, and idle ones moved to --cold-store
+const HOUSEKEEPING: Duration = Duration::from_secs(1);
+
+// how often a full --small table is purged of clients and target indexes left without a session at
+// most, new ones are refused in between
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+// a switch-target waiting for the sessions left on from to be gone, they are cut over to the active
+// target at until
+#[cfg(feature = "admin")]
+struct Drain {
+    from: Target,
+    to: String,
+    started: Tick,
+    until: Tick,
+}
+
+// what --small has the kernel queue for our socket each way, a few dozen full size packets
+pub const SMALL_BUFFERS: usize = 64 * 1024;
+
+// a data message with nothing in it, the header and the tag
+#[cfg(feature = "admin")]
+const KEEPALIVE_LEN: usize = 32;
+
+#[cfg(feature = "admin")]
+const ADMIN_USAGE: &str = "auth token                    with --admin-tokens, who we are, first thing on a connection
+sessions                      list sessions, by receiver index
+pin index client_addr         send the session's packets to client_addr, whatever handshakes say
+unpin index                   follow handshakes again
+unfreeze index                forward for a session --freeze-hijacked froze again, as it is
+force index target            send the session's client to target, one of ours, from now on
+unforce index                 let the session's client go to whichever target is due again
+switch-target old new [--drain t]
+                              send new sessions to new instead of old, the active target, at once,
+                              and cut those left on old over once t (default: --schedule-transition)
+                              is up, like 600, 90s, 10m or 1h, a target_drained event says when old
+                              has none left or they were cut over
+drains                        the switch-targets still draining, with the sessions left on the old
+                              target and how long until they are cut over
+offenders [n]                 the n (default 10) addresses sending the most invalid packets lately
+paths                         the path mtus --pmtu learned, by destination
+workers                       what each worker thread did, how long it waited for others and how
+                              many of its sends failed, to see whether they share the load
+probes                        the round trip time and loss --probe measured to each target
+follow index                  a line for each packet of the session as it passes, with its direction,
+                              type, size and timing, until the next line we get or hanging up
+fingerprints                  how many recent clients --fingerprints put in each class, most first
+index-audit                   the clients --index-audit watches, those whose indexes don't look
+                              random first
+listen bind_addr target [n]   forward what arrives on bind_addr to target with n (default 1) workers,
+                              with the options we were started with
+unlisten bind_addr            stop a listener added with listen
+listeners                     list the listeners added with listen and those of --tenants
+                              listen, unlisten and listeners are for the operator only
+verbose [on|off]              log what --verbose does from now on, or not, without arguments whether
+pcap [path [filter] | off]    capture to path like --pcap, filtered like --pcap-filter, from now on,
+                              replacing a capture already going, or stop it, for the operator only
+chaos [loss n% [dir] | off]   drop n% of the packets we forward at random, dir is both (default),
+                              to-target or to-clients, without arguments what it's doing
+pause                         stop reading our socket, the kernel queues packets until it's full
+resume                        read our socket again
+reset-stats                   start the cumulative counters over from 0, in --state-file too";
+
+/// the session with the receiver index the admin gave
+#[cfg(feature = "admin")]
+fn admin_session<'a>(sessions: &'a mut Sessions, index: &str) -> Result<&'a mut Session> {
+    let index = admin::parse_index(index)?;
+    sessions
+        .receivers
+        .get_mut(&index)
+        .ok_or_else(|| admin::invalid(format!("no session {:08x}", index)))
+}
+
+// the proxy once it started, whose counters termination saves to --state-file
+static STARTED: OnceLock<&'static Proxy> = OnceLock::new();
+
+/// once SIGTERM or SIGINT arrives, saves --state-file if a proxy started and exits, whichever mode
+/// run is in
+fn exit_on_termination() {
+    platform::on_termination(|signal| {
+        eprintln!("{}, exiting", signal);
+        if let Some(proxy) = STARTED.get() {
+            if let Some(state_path) = &proxy.config.state_path {
+                if let Err(e) = proxy.save_state(state_path) {
+                    eprintln!("saving counters to {} failed: {}", state_path, e);
+                }
+            }
+        }
+        process::exit(0);
+    });
+}
+
+fn main_single(udp_socket: UdpSocket, config: Config) -> error::Result<()> {
+    let proxy = Proxy::start(udp_socket, config)?;
+    let _ = STARTED.set(proxy);
+    Ok(proxy.run()?)
+}
+
+fn main_threaded(udp_socket: UdpSocket, config: Config, thread_count: usize) -> error::Result<()> {
+    let proxy = Proxy::start(udp_socket, config)?;
+    let _ = STARTED.set(proxy);
+    match proxy.config.threads {
+        Some(bounds) => Ok(proxy.scale(thread_count, bounds)?),
+        None => run_threads(thread_count, move || proxy.run()),
+    }
+}
+
+fn run_threads<F>(thread_count: usize, run: F) -> error::Result<()>
+where
+    F: Fn() -> Result<()> + Copy + Send + 'static,
+{
+    let mut threads = Vec::with_capacity(thread_count);
+    for _id in 0..thread_count {
+        threads.push(thread::spawn(run));
+    }
+    for thread in threads {
+        thread.join().map_err(|_| error::Error::Panicked)??;
+    }
+    Ok(())
+}
+
+/// our socket at config's bind_addr, with --dual-stack one taking both families on its port
+pub fn bind(config: &Config) -> Result<UdpSocket> {
+    if !config.dual_stack {
+        return UdpSocket::bind(&config.bind_addr);
+    }
+    // config made sure it's an address
+    let port = config
+        .bind_addr
+        .parse::<SocketAddr>()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        .port();
+    platform::bind_dual_stack(port)
+}
+
+/// runs whichever mode config asks for, forever
+pub fn run(config: Config) -> error::Result<()> {
+    if config.print_config {
+        for line in banner::lines(&config) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    // before any thread starts, they all inherit it, --small has no thread to take them and
+    // leaves them killing us
+    if config.small.is_none() {
+        platform::block_termination()?;
+        exit_on_termination();
+    }
+    if let Some(log_file) = &config.log_file {
+        log::start(log_file, config.log_rotate.clone())?;
+    }
+    if config.syslog {
+        log::syslog()?;
+    }
+    crash::install(config.crash_dir.clone().into());
+    for line in banner::lines(&config) {
+        eprintln!("config {}", line);
+    }
+    let udp_socket = bind(&config).map_err(|source| error::Error::Bind {
+        addr: config.bind_addr.clone(),
+        source,
+    })?;
+    let thread_count = config.thread_count;
+    #[cfg(feature = "peer-relay")]
+    if config.peer_relay {
+        let Some(psk) = config.psk else {
+            return Err(args::invalid(
+                "--peer-relay requires --psk-file".to_string(),
+            ));
+        };
+        let peer_relay: &PeerRelay =
+            Box::leak(Box::new(PeerRelay::new(udp_socket, psk, config.stun)?));
+        return run_threads(thread_count, move || peer_relay.run());
+    }
+    #[cfg(feature = "dns-tunnel")]
+    if config.dns_target.is_some() || config.dns_clients.is_some() {
+        let target = config.target.as_ref().map(|target| target.addr);
+        let Some(target) = target else {
+            return Err(args::invalid("a dns tunnel needs a target".to_string()));
+        };
+        if let Some(domain) = &config.dns_target {
+            let near = Near::new(udp_socket, target, domain, config.verbose)?;
+            return Ok(Box::leak(Box::new(near)).run()?);
+        }
+        if let Some(domain) = &config.dns_clients {
+            let far = Far::new(udp_socket, target, domain, config.verbose);
+            return Ok(Box::leak(Box::new(far)).run()?);
+        }
+    }
+    #[cfg(feature = "icmp-tunnel")]
+    if config.icmp_target || config.icmp_clients {
+        let target = config.target.as_ref().map(|target| target.addr);
+        let Some(target) = target else {
+            return Err(args::invalid("an icmp tunnel needs a target".to_string()));
+        };
+        if config.icmp_target {
+            let near =
+                icmp_tunnel::Near::new(udp_socket, target.ip(), config.icmp_mtu, config.verbose)?;
+            return Ok(Box::leak(Box::new(near)).run()?);
+        }
+        let far = icmp_tunnel::Far::new(target, config.icmp_mtu, config.verbose)?;
+        return Ok(Box::leak(Box::new(far)).run()?);
+    }
+    #[cfg(feature = "faketcp")]
+    if config.faketcp_target || config.faketcp_clients {
+        let target = config.target.as_ref().map(|target| target.addr);
+        let Some(target) = target else {
+            return Err(args::invalid("faketcp needs a target".to_string()));
+        };
+        if config.faketcp_target {
+            let near = faketcp::Near::new(udp_socket, target, config.verbose)?;
+            return Ok(Box::leak(Box::new(near)).run()?);
+        }
+        let port = udp_socket.local_addr()?.port();
+        let far = faketcp::Far::new(port, target, config.verbose)?;
+        return Ok(Box::leak(Box::new(far)).run()?);
+    }
+    #[cfg(feature = "tcp")]
+    if config.tcp_target || config.tcp_clients {
+        let Some(target) = config.target.clone() else {
+            return Err(args::invalid("a tcp transport needs a target".to_string()));
+        };
+        #[cfg(feature = "tls")]
+        let tls = config.tls;
+        if config.tcp_target {
+            let near = stream::Near::new(
+                udp_socket,
+                target,
+                #[cfg(feature = "tls")]
+                tls,
+                config.tcp_mux,
+                config.verbose,
+            );
+            return Ok(Box::leak(Box::new(near)).run()?);
+        }
+        let bind = udp_socket.local_addr()?;
+        drop(udp_socket);
+        let far = stream::Far::new(
+            bind,
+            target.addr,
+            #[cfg(feature = "tls")]
+            tls,
+            config.verbose,
+        )?;
+        return Ok(Box::leak(Box::new(far)).run()?);
+    }
+    if !config.fallback.is_empty() {
+        let Some(target) = config.target.clone() else {
+            return Err(args::invalid("--fallback needs a target".to_string()));
+        };
+        let mut rungs = vec![Rung::udp(target)];
+        rungs.extend(config.fallback);
+        let ladder = Ladder::new(
+            udp_socket,
+            rungs,
+            #[cfg(feature = "tls")]
+            config.tls,
+            #[cfg(feature = "icmp-tunnel")]
+            config.icmp_mtu,
+            config.probe.map(Probes::open).transpose()?,
+            config.verbose,
+        );
+        return Box::leak(Box::new(ladder)).run();
+    }
+    if thread_count == 1 && config.threads.is_none() {
+        main_single(udp_socket, config)
+    } else {
+        main_threaded(udp_socket, config, thread_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        collections::{HashSet, VecDeque},
+        io::ErrorKind,
+        sync::{atomic::AtomicU64, Mutex},
+    };
+
+    /// hands the proxy queued packets and keeps what it sends, WouldBlock once it ran out
+    #[derive(Default)]
+    struct MockSocket {
+        inbox: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+        sent: Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+        /// sending there fails
+        unroutable: Mutex<HashSet<SocketAddr>>,
+        /// sending there sends all but the last byte
+        short: Mutex<HashSet<SocketAddr>>,
+        /// ICMP errors to report, recv fails until they are collected
+        icmp: Mutex<Vec<IcmpError>>,
+        /// where it's bound, 127.0.0.1:5678 unless given
+        bound: Option<SocketAddr>,
+    }
+
+    impl Datagram for MockSocket {
+        fn recv(
+            &self,
+            buf: &mut [u8],
+        ) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)> {
+            if !self.icmp.lock().unwrap().is_empty() {
+                return Err(ErrorKind::ConnectionRefused.into());
+            }
+            let (packet, from) = self
+                .inbox
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(ErrorKind::WouldBlock)?;
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok((packet.len(), from, None, None))
+        }
+
+        fn send(
+            &self,
+            buf: &[u8],
+            addr: SocketAddr,
+            _local_addr: Option<LocalAddr>,
+            _dscp: Option<u8>,
+        ) -> Result<usize> {
+            if self.unroutable.lock().unwrap().contains(&addr) {
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+            let len = match self.short.lock().unwrap().contains(&addr) {
+                true => buf.len() - 1,
+                false => buf.len(),
+            };
+            self.sent.lock().unwrap().push((buf[..len].to_vec(), addr));
+            Ok(len)
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr> {
+            Ok(self
+                .bound
+                .unwrap_or_else(|| "127.0.0.1:5678".parse().unwrap()))
+        }
+
+        fn icmp_errors(&self) -> Result<Vec<IcmpError>> {
+            Ok(self.icmp.lock().unwrap().drain(..).collect())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockClock(AtomicU64);
+
+    impl Clock for MockClock {
+        fn now(&self) -> Tick {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    type MockProxy = Proxy<MockSocket, MockClock>;
+
+    fn proxy(args: &[&str]) -> &'static MockProxy {
+        let config = Config::from_args(args.iter().map(|arg| arg.to_string()))
+            .unwrap()
+            .unwrap();
+        let proxy = Proxy::with(MockSocket::default(), MockClock::default(), config).unwrap();
+        Box::leak(Box::new(proxy))
+    }
+
+    /// feeds proxy packets, returns the type of each one it sent and where to
+    fn forward(
+        proxy: &'static MockProxy,
+        packets: &[(Vec<u8>, SocketAddr)],
+    ) -> Vec<(u8, SocketAddr)> {
+        proxy
+            .socket
+            .inbox
+            .lock()
+            .unwrap()
+            .extend(packets.iter().cloned());
+        assert_eq!(proxy.run().unwrap_err().kind(), ErrorKind::WouldBlock);
+        let mut sent = proxy.socket.sent.lock().unwrap();
+        sent.drain(..).map(|(packet, to)| (packet[0], to)).collect()
+    }
+
+    fn packet(message_type: u8, first: u32, second: u32) -> Vec<u8> {
+        let mut packet = vec![0u8; 148];
+        packet[0] = message_type;
+        packet[4..8].copy_from_slice(&first.to_le_bytes());
+        packet[8..12].copy_from_slice(&second.to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let roamed: SocketAddr = "192.0.2.11:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.12:1000".parse().unwrap();
+        let proxy = proxy(&["--max-session-pps", "10", "192.0.2.2:51820"]);
+        let set_time = |tick| proxy.clock.0.store(tick, Ordering::Relaxed);
+        let init = |sender| packet(1, sender, 0);
+        let response = |sender, receiver| packet(2, sender, receiver);
+        let data = |receiver| packet(4, receiver, 0);
+
+        assert_eq!(forward(proxy, &[(init(1), client)]), [(1, target)]);
+        assert_eq!(forward(proxy, &[(response(9, 1), target)]), [(2, client)]);
+        assert_eq!(
+            forward(proxy, &[(data(9), client), (data(1), target)]),
+            [(4, target), (4, client)]
+        );
+
+        // handshaking again from elsewhere moves the session
+        assert_eq!(
+            forward(proxy, &[(init(1), roamed), (response(9, 1), target)]),
+            [(1, target), (2, roamed)]
+        );
+
+        // over the limit every second until the breaker trips
+        let flood = vec![(data(9), roamed); 11];
+        for second in 1..=rate::SUSTAINED_SECS {
+            set_time(second * clock::TICKS_PER_SEC);
+            assert_eq!(forward(proxy, &flood).len(), 11);
+        }
+        set_time((rate::SUSTAINED_SECS + 1) * clock::TICKS_PER_SEC);
+        assert!(forward(proxy, &[(data(9), roamed)]).is_empty());
+
+        // the next handshake expires it
+        set_time(clock::ticks(SESSION_VALID_TIME));
+        assert_eq!(forward(proxy, &[(init(2), other)]), [(1, target)]);
+        assert!(forward(proxy, &[(data(1), target)]).is_empty());
+        assert_eq!(proxy.stats.unknown_receiver.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let config = Config::from_args(["192.0.2.2:51820".to_string()])
+            .unwrap()
+            .unwrap();
+        let socket = MockSocket {
+            bound: Some("[::]:5678".parse().unwrap()),
+            ..MockSocket::default()
+        };
+        let proxy = Proxy::with(socket, MockClock::default(), config).unwrap();
+        let proxy: &'static MockProxy = Box::leak(Box::new(proxy));
+        let target: SocketAddr = "[::ffff:192.0.2.2]:51820".parse().unwrap();
+        let v6_client: SocketAddr = "[2001:db8::10]:1000".parse().unwrap();
+        let v4_client: SocketAddr = "[::ffff:192.0.2.10]:1000".parse().unwrap();
+
+        assert_eq!(
+            forward(proxy, &[(packet(1, 1, 0), v6_client)]),
+            [(1, target)]
+        );
+        assert_eq!(
+            forward(proxy, &[(packet(2, 9, 1), target)]),
+            [(2, v6_client)]
+        );
+        assert_eq!(
+            forward(proxy, &[(packet(1, 2, 0), v4_client)]),
+            [(1, target)]
+        );
+        assert_eq!(
+            forward(proxy, &[(packet(2, 8, 2), target)]),
+            [(2, v4_client)]
+        );
+        let sessions = proxy.sessions.read().unwrap();
+        assert_eq!(
+            sessions.get(&2).unwrap().client.socket,
+            "192.0.2.10:1000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_flow_labels() {
+        let config = Config::from_args(["[2001:db8::2]:51820".to_string()])
+            .unwrap()
+            .unwrap();
+        let socket = MockSocket {
+            bound: Some("[::]:5678".parse().unwrap()),
+            ..MockSocket::default()
+        };
+        let mut proxy = Proxy::with(socket, MockClock::default(), config).unwrap();
+        proxy.flow_labels = (1..=8).collect();
+        let proxy: &'static MockProxy = Box::leak(Box::new(proxy));
+        let target: SocketAddr = "[2001:db8::2]:51820".parse().unwrap();
+        let client: SocketAddr = "[2001:db8::10]:1000".parse().unwrap();
+        let label = |addr: SocketAddr| match addr {
+            SocketAddr::V6(v6) => u32::from_be(v6.flowinfo()),
+            SocketAddr::V4(_) => 0,
+        };
+
+        let sent = forward(proxy, &[(packet(1, 1, 0), client)]);
+        let labeled = sent[0].1;
+        assert!(proxy.flow_labels.contains(&label(labeled)));
+        assert_eq!(proxy.labeled(client, target), labeled);
+        // both ways, and the same after a rekey
+        let back = forward(proxy, &[(packet(2, 9, 1), target)]);
+        assert_eq!(back, [(2, proxy.labeled(client, client))]);
+        assert_eq!(label(back[0].1), label(labeled));
+        assert_eq!(
+            forward(
+                proxy,
+                &[(packet(4, 9, 0), client), (packet(1, 2, 0), client)]
+            ),
+            [(4, labeled), (1, labeled)]
+        );
+    }
+
+    #[test]
+    fn test_expiry_grace() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.12:1000".parse().unwrap();
+        let proxy = proxy(&["--expiry-grace", "5", "192.0.2.2:51820"]);
+        let set_time = |tick| proxy.clock.0.store(tick, Ordering::Relaxed);
+        forward(
+            proxy,
+            &[(packet(1, 1, 0), client), (packet(2, 9, 1), target)],
+        );
+
+        // the client rekeyed right at the edge, the target still had some on the way
+        set_time(clock::ticks(SESSION_VALID_TIME));
+        assert_eq!(forward(proxy, &[(packet(1, 2, 0), client)]), [(1, target)]);
+        assert_eq!(forward(proxy, &[(packet(4, 1, 0), target)]), [(4, client)]);
+        assert!(forward(proxy, &[(packet(4, 9, 0), client)]).is_empty());
+
+        set_time(clock::ticks(SESSION_VALID_TIME + Duration::from_secs(5)));
+        assert_eq!(forward(proxy, &[(packet(1, 3, 0), other)]), [(1, target)]);
+        assert!(forward(proxy, &[(packet(4, 1, 0), target)]).is_empty());
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client = |n: u32| SocketAddr::from(([192, 0, 2, 10], 1000 + n as u16));
+        let cap = (10 * SESSION_MEMORY).to_string();
+        let proxy = proxy(&["--max-memory", &cap, "192.0.2.2:51820"]);
+        let set_time = |secs| {
+            let tick = clock::ticks(Duration::from_secs(secs));
+            proxy.clock.0.store(tick, Ordering::Relaxed)
+        };
+
+        // a new session every so often, each handshake timed out before the next
+        for n in 1..=20 {
+            set_time(n as u64 * 6);
+            assert_eq!(
+                forward(proxy, &[(packet(1, n, 0), client(n))]),
+                [(1, target)]
+            );
+        }
+        let sessions = proxy.sessions.read().unwrap();
+        assert!(sessions.memory() <= 10 * SESSION_MEMORY);
+        assert!(sessions.get(&20).is_some());
+        assert!(sessions.get(&1).is_none());
+        drop(sessions);
+        assert!(proxy.stats.memory_evictions.load(Ordering::Relaxed) > 0);
+
+        // no room for even one
+        let proxy = super::tests::proxy(&["--max-memory", "1", "192.0.2.2:51820"]);
+        assert!(forward(proxy, &[(packet(1, 1, 0), client(1))]).is_empty());
+        assert_eq!(proxy.stats.memory_refused.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_blackhole_after() {
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let failover: SocketAddr = "192.0.2.3:51820".parse().unwrap();
+        let proxy = proxy(&[
+            "--blackhole-after",
+            "2",
+            "--failover",
+            "192.0.2.3:51820",
+            "192.0.2.2:51820",
+        ]);
+        let active = || proxy.targets.read().unwrap().active();
+
+        // initiations the target never answered anyone from don't count
+        for n in 1..=5 {
+            let spoofed = SocketAddr::from(([198, 51, 100, n as u8], 1000));
+            forward(proxy, &[(packet(1, n, 0), spoofed)]);
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(active(), target);
+
+        // those from a client it answered do
+        forward(
+            proxy,
+            &[(packet(1, 10, 0), client), (packet(2, 90, 10), target)],
+        );
+        forward(
+            proxy,
+            &[(packet(1, 11, 0), client), (packet(1, 12, 0), client)],
+        );
+        for _ in 0..100 {
+            if active() == failover {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(active(), failover);
+    }
+
+    #[cfg(feature = "peer-relay")]
+    #[test]
+    fn test_mesh_response() {
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let path = std::env::temp_dir().join(format!("mesh-psk-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "psk").unwrap();
+        let proxy = proxy(&["--psk-file", path, "192.0.2.2:51820"]);
+        std::fs::remove_file(path).unwrap();
+
+        // our client answering a peer of the peer relay's is a client like any other
+        assert_eq!(forward(proxy, &[(packet(2, 7, 3), client)]), [(2, target)]);
+        let sessions = proxy.sessions.read().unwrap();
+        assert!(sessions.get(&7).is_some());
+        assert!(sessions.has_client(&client, 0));
+        assert_eq!(sessions.clients_of(target, client), (1, true));
+    }
+
+    #[test]
+    fn test_cold_store() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.12:1000".parse().unwrap();
+        let path = std::env::temp_dir().join(format!("cold-store-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let proxy = proxy(&["--cold-store", path, "192.0.2.2:51820"]);
+        let set_time = |secs| {
+            let tick = clock::ticks(Duration::from_secs(secs));
+            proxy.clock.0.store(tick, Ordering::Relaxed)
+        };
+        forward(
+            proxy,
+            &[(packet(1, 1, 0), client), (packet(2, 9, 1), target)],
+        );
+
+        // the next handshake moves it out
+        set_time(31);
+        forward(proxy, &[(packet(1, 2, 0), other)]);
+        {
+            let sessions = proxy.sessions.read().unwrap();
+            assert!(sessions.is_cold(&1));
+            assert!(sessions.get(&1).is_none());
+            assert!(sessions.get(&2).is_some());
+        }
+        assert_eq!(forward(proxy, &[(packet(4, 1, 0), target)]), [(4, client)]);
+        assert!(!proxy.sessions.read().unwrap().is_cold(&1));
+
+        // and by what the client addresses
+        set_time(62);
+        forward(proxy, &[(packet(1, 3, 0), other)]);
+        assert!(proxy.sessions.read().unwrap().is_cold(&1));
+        assert_eq!(forward(proxy, &[(packet(4, 9, 0), client)]), [(4, target)]);
+        assert!(proxy.sessions.read().unwrap().get(&1).is_some());
+
+        // cold sessions expire like the others
+        set_time(93);
+        forward(proxy, &[(packet(1, 4, 0), other)]);
+        set_time(181);
+        forward(proxy, &[(packet(1, 5, 0), other)]);
+        let sessions = proxy.sessions.read().unwrap();
+        assert!(!sessions.is_cold(&1));
+        assert!(sessions.targets.get(&9).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_strict_responses() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let proxy = proxy(&["--strict-responses", "192.0.2.2:51820"]);
+        let response = packet(2, 9, 1);
+
+        // no session for it at all
+        assert!(forward(proxy, &[(response.clone(), target)]).is_empty());
+        assert_eq!(proxy.stats.unknown_receiver.load(Ordering::Relaxed), 1);
+        forward(proxy, &[(packet(1, 1, 0), client)]);
+        assert_eq!(forward(proxy, &[(response.clone(), target)]), [(2, client)]);
+        // only once
+        assert!(forward(proxy, &[(response.clone(), target)]).is_empty());
+
+        forward(proxy, &[(packet(1, 1, 0), client)]);
+        proxy
+            .clock
+            .0
+            .store(clock::ticks(crate::handshakes::TIMEOUT), Ordering::Relaxed);
+        assert!(forward(proxy, &[(response, target)]).is_empty());
+        assert_eq!(proxy.stats.unexpected_responses.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_stray_cookies() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let proxy = proxy(&["192.0.2.2:51820"]);
+        let cookie = packet(3, 1, 0);
+
+        forward(proxy, &[(packet(1, 1, 0), client)]);
+        assert_eq!(forward(proxy, &[(cookie.clone(), target)]), [(3, client)]);
+        // they don't go the other way
+        assert!(forward(proxy, &[(cookie.clone(), client)]).is_empty());
+        // nor once the handshake is answered
+        forward(proxy, &[(packet(2, 9, 1), target)]);
+        assert!(forward(proxy, &[(cookie, target)]).is_empty());
+        assert_eq!(proxy.stats.stray_cookies.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_strict_source() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let roamed: SocketAddr = "192.0.2.10:1001".parse().unwrap();
+        let proxy = proxy(&["--strict-source", "192.0.2.2:51820"]);
+
+        forward(
+            proxy,
+            &[(packet(1, 1, 0), client), (packet(2, 9, 1), target)],
+        );
+        assert_eq!(forward(proxy, &[(packet(4, 9, 0), client)]), [(4, target)]);
+        assert!(forward(proxy, &[(packet(4, 9, 0), roamed)]).is_empty());
+        // nor can a handshake from there take the session
+        assert!(forward(proxy, &[(packet(1, 1, 0), roamed)]).is_empty());
+        assert_eq!(forward(proxy, &[(packet(4, 1, 0), target)]), [(4, client)]);
+        assert_eq!(proxy.stats.strict_source.load(Ordering::Relaxed), 1);
+
+        let overwrite = ["--strict-source", "--sender-collision", "overwrite", "x:1"];
+        assert!(Config::from_args(overwrite.iter().map(|a| a.to_string())).is_err());
+    }
+
+    #[test]
+    fn test_sender_collision() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let attacker: SocketAddr = "192.0.2.66:1000".parse().unwrap();
+        let init = packet(1, 1, 0);
+
+        let rejecting = proxy(&["--sender-collision", "reject", "192.0.2.2:51820"]);
+        forward(rejecting, &[(init.clone(), client)]);
+        assert!(forward(rejecting, &[(init.clone(), attacker)]).is_empty());
+        assert_eq!(
+            forward(rejecting, &[(packet(4, 1, 0), target)]),
+            [(4, client)]
+        );
+        // the client itself may
+        assert_eq!(forward(rejecting, &[(init.clone(), client)]), [(1, target)]);
+        assert_eq!(
+            rejecting.stats.collisions_rejected.load(Ordering::Relaxed),
+            1
+        );
+
+        let idling = proxy(&["--sender-collision", "idle", "192.0.2.2:51820"]);
+        forward(idling, &[(init.clone(), client)]);
+        let idle = clock::ticks(COLLISION_IDLE_TIME);
+        idling.clock.0.store(idle - 1, Ordering::Relaxed);
+        forward(idling, &[(packet(4, 1, 0), target)]);
+        idling.clock.0.store(2 * idle - 2, Ordering::Relaxed);
+        assert!(forward(idling, &[(init.clone(), attacker)]).is_empty());
+        idling.clock.0.store(2 * idle - 1, Ordering::Relaxed);
+        assert_eq!(forward(idling, &[(init, attacker)]), [(1, target)]);
+        assert_eq!(
+            idling.stats.collisions_overwritten.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_hijack() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let attacker: SocketAddr = "192.0.2.66:1000".parse().unwrap();
+        let proxy = proxy(&["--freeze-hijacked", "192.0.2.2:51820"]);
+        forward(proxy, &[(packet(1, 1, 0), client)]);
+        assert_eq!(forward(proxy, &[(packet(2, 9, 1), target)]), [(2, client)]);
+        // a replayed initiation takes the session over
+        assert_eq!(
+            forward(proxy, &[(packet(1, 1, 0), attacker)]),
+            [(1, target)]
+        );
+        assert_eq!(
+            forward(proxy, &[(packet(4, 1, 0), target)]),
+            [(4, attacker)]
+        );
+
+        // while the client carries on
+        assert!(forward(proxy, &[(packet(4, 9, 0), client)]).is_empty());
+        assert_eq!(proxy.stats.hijacks.load(Ordering::Relaxed), 1);
+        assert!(forward(proxy, &[(packet(4, 1, 0), target)]).is_empty());
+        assert!(forward(proxy, &[(packet(4, 9, 0), attacker)]).is_empty());
+        assert!(forward(proxy, &[(packet(1, 1, 0), attacker)]).is_empty());
+        #[cfg(feature = "admin")]
+        {
+            let mut out = Vec::new();
+            proxy
+                .command(&["pin", "00000001", "192.0.2.10:1000"], &mut out)
+                .unwrap();
+            assert_eq!(forward(proxy, &[(packet(4, 1, 0), target)]), [(4, client)]);
+            assert_eq!(forward(proxy, &[(packet(4, 9, 0), client)]), [(4, target)]);
+        }
+    }
+
+    #[test]
+    fn test_dead_after() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.11:1000".parse().unwrap();
+        let proxy = proxy(&["--dead-after", "2", "192.0.2.2:51820"]);
+        let data = |receiver| packet(4, receiver, 0);
+        let handshake = |sender, from, receiver| {
+            forward(
+                proxy,
+                &[
+                    (packet(1, sender, 0), from),
+                    (packet(2, receiver, sender), target),
+                ],
+            )
+        };
+        handshake(1, client, 9);
+        handshake(2, other, 8);
+
+        proxy.socket.unroutable.lock().unwrap().insert(client);
+        assert!(forward(proxy, &[(data(1), target)]).is_empty());
+        // hearing from the client starts over
+        assert_eq!(forward(proxy, &[(data(9), client)]), [(4, target)]);
+        forward(proxy, &[(data(1), target)]);
+        assert!(proxy.sessions.read().unwrap().get(&1).is_some());
+        forward(proxy, &[(data(1), target)]);
+        assert!(proxy.sessions.read().unwrap().get(&1).is_none());
+        assert!(!proxy.sessions.read().unwrap().has_client(&client, 0));
+
+        for _ in 0..2 {
+            let unreachable = IcmpError::Unreachable(other);
+            proxy.socket.icmp.lock().unwrap().push(unreachable);
+            assert_eq!(forward(proxy, &[]), []);
+        }
+        assert!(forward(proxy, &[(data(2), target)]).is_empty());
+        assert_eq!(proxy.stats.dead_sessions.load(Ordering::Relaxed), 2);
+        assert_eq!(proxy.stats.unknown_receiver.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_small() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.11:1000".parse().unwrap();
+        let proxy = proxy(&["--small", "1", "192.0.2.2:51820"]);
+        let handshake = |sender, from, receiver| {
+            forward(
+                proxy,
+                &[
+                    (packet(1, sender, 0), from),
+                    (packet(2, receiver, sender), target),
+                ],
+            )
+        };
+        assert_eq!(handshake(1, client, 9), [(1, target), (2, client)]);
+        // full
+        assert!(forward(proxy, &[(packet(1, 2, 0), other)]).is_empty());
+        assert_eq!(handshake(1, client, 9), [(1, target), (2, client)]);
+        // until the session expires, the target's index of it goes with it
+        proxy
+            .clock
+            .0
+            .store(clock::ticks(SESSION_VALID_TIME), Ordering::Relaxed);
+        assert_eq!(handshake(2, other, 8), [(1, target), (2, other)]);
+        assert_eq!(forward(proxy, &[(packet(4, 8, 0), other)]), [(4, target)]);
+
+        let args = ["--small=1", "--metrics=127.0.0.1:9", "192.0.2.2:51820"];
+        assert!(Config::from_args(args.map(String::from)).is_err());
+    }
+
+    #[test]
+    fn test_workers() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let proxy = proxy(&["--dead-after", "5", "192.0.2.2:51820"]);
+        // every run is a worker of its own here
+        forward(proxy, &[(packet(1, 1, 0), client)]);
+        proxy.socket.unroutable.lock().unwrap().insert(client);
+        forward(
+            proxy,
+            &[(packet(2, 9, 1), target), (packet(4, 1, 0), target)],
+        );
+
+        let workers = proxy.workers.lock().unwrap();
+        let counts = |worker: &Worker| {
+            (
+                worker.packets.load(Ordering::Relaxed),
+                worker.send_errors.load(Ordering::Relaxed),
+            )
+        };
+        assert_eq!(counts(&workers[0]), (1, 0));
+        assert_eq!(counts(&workers[1]), (2, 2));
+        drop(workers);
+        #[cfg(feature = "metrics")]
+        {
+            let mut out = String::new();
+            proxy.render_metrics(&mut out);
+            assert!(out.contains("wireguard_udp_proxy_worker_send_errors_total{worker=\"1\"} 2\n"));
+        }
+    }
+
+    #[test]
+    fn test_send_failure() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.11:1000".parse().unwrap();
+        let proxy = proxy(&["192.0.2.2:51820"]);
+        forward(
+            proxy,
+            &[(packet(1, 1, 0), client), (packet(1, 2, 0), other)],
+        );
+        proxy.socket.unroutable.lock().unwrap().insert(client);
+        // without --dead-after too, the worker carries on with the next packet
+        let sent = forward(
+            proxy,
+            &[(packet(2, 9, 1), target), (packet(2, 8, 2), target)],
+        );
+        assert_eq!(sent, [(2, other)]);
+        let workers = proxy.workers.lock().unwrap();
+        assert_eq!(workers[1].send_errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_short_send() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let proxy = proxy(&["192.0.2.2:51820"]);
+        proxy.socket.short.lock().unwrap().insert(target);
+        assert_eq!(forward(proxy, &[(packet(1, 1, 0), client)]), [(1, target)]);
+        assert_eq!(proxy.stats.short_sends.load(Ordering::Relaxed), 1);
+        assert_eq!(proxy.stats.bytes_to_target.load(Ordering::Relaxed), 147);
+    }
+
+    #[test]
+    fn test_pace() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        // a packet's worth of burst, the next takes 148ms
+        let proxy = proxy(&["--pace", "192.0.2.2:51820=8,148", "192.0.2.2:51820"]);
+        let started = Instant::now();
+        let sent = forward(
+            proxy,
+            &[(packet(1, 1, 0), client), (packet(1, 2, 0), client)],
+        );
+        assert_eq!(sent, [(1, target)]);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(proxy.stats.paced.load(Ordering::Relaxed), 1);
+        while proxy.socket.sent.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(140));
+        assert_eq!(proxy.stats.packets_to_target.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_pmtu() {
+        let target: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let proxy = proxy(&["--pmtu", "drop", "192.0.2.2:51820"]);
+        forward(
+            proxy,
+            &[(packet(1, 1, 0), client), (packet(2, 9, 1), target)],
+        );
+        let data = |len| {
+            let mut data = packet(4, 9, 0);
+            data.resize(len, 0);
+            data
+        };
+        assert_eq!(forward(proxy, &[(data(1400), client)]), [(4, target)]);
+
+        let too_big = IcmpError::TooBig(target, 1280);
+        proxy.socket.icmp.lock().unwrap().push(too_big);
+        assert_eq!(forward(proxy, &[]), []);
+        assert!(forward(proxy, &[(data(1400), client)]).is_empty());
+        assert_eq!(forward(proxy, &[(data(1280 - 28), client)]), [(4, target)]);
+        assert_eq!(proxy.stats.oversize.load(Ordering::Relaxed), 1);
+        #[cfg(feature = "admin")]
+        {
+            let mut out = Vec::new();
+            proxy.command(&["paths"], &mut out).unwrap();
+            assert_eq!(out, b"192.0.2.2 1280\n");
+        }
+    }
+
+    #[test]
+    fn test_expire() {
+        let client: SocketAddr = "192.0.2.1:1".parse().unwrap();
+        let target = "192.0.2.2:1".parse().unwrap();
+        let mut sessions = Sessions::default();
+        let session = Session::new(ExpiringSocket::new(client, None, 0), target);
+        let expires = session.client.expires;
+        sessions.insert(1, session, 0);
+        assert!(sessions.answered(2, 1, 0));
+
+        sessions.expire(expires - 1);
+        assert!(sessions.has_client(&client, expires - 1));
+        assert!(sessions.by_target_index(&2).is_some());
+        assert!(!sessions.has_client(&client, expires));
+
+        sessions.expire(expires);
+        assert!(sessions.get(&1).is_none());
+        assert!(sessions.targets.get(&2).is_none());
+        assert!(!sessions.has_client(&client, expires - 1));
+        assert!(sessions.expiries.is_empty());
+
+        // a session handshaking again expires with its new handshake, its target index with it
+        sessions.insert(
+            3,
+            Session::new(ExpiringSocket::new(client, None, 0), target),
+            0,
+        );
+        assert!(sessions.answered(4, 3, 0));
+        let later = Session::new(ExpiringSocket::new(client, None, 1), target);
+        let expires = later.client.expires;
+        sessions.insert(3, later, 1);
+        assert_eq!(sessions.expire(expires - 1), []);
+        assert!(sessions.by_target_index(&4).is_some());
+        assert_eq!(sessions.expire(expires), [(3, Some(client))]);
+        assert!(sessions.targets.get(&4).is_none());
+
+        // one removed ahead of its time takes its target indexes and client with it
+        sessions.insert(
+            5,
+            Session::new(ExpiringSocket::new(client, None, 2), target),
+            2,
+        );
+        assert!(sessions.answered(6, 5, 2));
+        assert_eq!(sessions.clients_of(target, client), (1, true));
+        assert!(sessions.remove(5).is_some());
+        assert_eq!(sessions.clients_of(target, client), (0, false));
+        assert!(sessions.targets.get(&6).is_none());
+        assert!(!sessions.has_client(&client, 2));
+
+        // a full --small table makes room for a new client once a PURGE_INTERVAL at most
+        let mut sessions = Sessions::fixed(1);
+        let session = |n: u16| {
+            let client = SocketAddr::new(client.ip(), n);
+            Session::new(ExpiringSocket::new(client, None, 0), target)
+        };
+        let has =
+            |sessions: &Sessions, n: u16| sessions.has_client(&SocketAddr::new(client.ip(), n), 0);
+        sessions.insert(1, session(1), 0);
+        sessions.insert(1, session(2), 0);
+        assert!(!has(&sessions, 1) && has(&sessions, 2));
+        sessions.insert(1, session(3), 1);
+        assert!(has(&sessions, 2) && !has(&sessions, 3));
+        sessions.insert(1, session(4), clock::ticks(PURGE_INTERVAL));
+        assert!(!has(&sessions, 2) && has(&sessions, 4));
+    }
+}
@@ -0,0 +1,83 @@
+// --quic-camouflage, answering probes that look like QUIC the way a QUIC server would, so a scanner
+// sees a benign service where it would otherwise see the silence of a filtered wireguard port
+//
+// scanners ask for a version nobody speaks to get a version negotiation packet, RFC 9000 17.2.1,
+// that's the one thing we can answer without doing the handshake, anything else stays unanswered
+// like a server that couldn't decrypt it would
+
+use crate::packet;
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+// the only version we claim to speak
+const VERSION_1: u32 = 1;
+
+// servers don't answer anything smaller, so we never send more than we got
+const MIN_INITIAL_LEN: usize = 1200;
+
+// RFC 9000 17.2, longer connection ids are a newer version's business
+const MAX_CID_LEN: usize = 20;
+
+/// a version negotiation packet answering packet, if it's a long header packet asking for a
+/// version we don't claim to speak
+pub fn version_negotiation(packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() < MIN_INITIAL_LEN || packet[0] & 0x80 == 0 {
+        return None;
+    }
+    let version = u32::from_be_bytes(packet::bytes(packet, 1));
+    // 0 is a version negotiation itself, never answer those
+    if version == 0 || version == VERSION_1 {
+        return None;
+    }
+    let dcid_len = packet[5] as usize;
+    let dcid = packet.get(6..6 + dcid_len)?;
+    let scid_len = *packet.get(6 + dcid_len)? as usize;
+    let scid = packet.get(7 + dcid_len..7 + dcid_len + scid_len)?;
+    if dcid_len > MAX_CID_LEN || scid_len > MAX_CID_LEN {
+        return None;
+    }
+
+    // the rest of the first byte is unused and random
+    let random = RandomState::new().build_hasher().finish() as u8;
+    let mut reply = vec![0x80 | random];
+    reply.extend_from_slice(&0u32.to_be_bytes());
+    // their source connection id is our destination, and the other way around
+    reply.push(scid_len as u8);
+    reply.extend_from_slice(scid);
+    reply.push(dcid_len as u8);
+    reply.extend_from_slice(dcid);
+    reply.extend_from_slice(&VERSION_1.to_be_bytes());
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_negotiation() {
+        let mut probe = vec![0u8; MIN_INITIAL_LEN];
+        probe[0] = 0xc0;
+        probe[1..5].copy_from_slice(&0x1a2a3a4au32.to_be_bytes());
+        probe[5] = 2;
+        probe[6..8].copy_from_slice(&[0xd1, 0xd2]);
+        probe[8] = 1;
+        probe[9] = 0x51;
+        let reply = version_negotiation(&probe).unwrap();
+        assert_eq!(reply[0] & 0x80, 0x80);
+        assert_eq!(reply[1..], [0, 0, 0, 0, 1, 0x51, 2, 0xd1, 0xd2, 0, 0, 0, 1]);
+
+        assert!(version_negotiation(&probe[..MIN_INITIAL_LEN - 1]).is_none());
+        probe[1..5].copy_from_slice(&VERSION_1.to_be_bytes());
+        assert!(version_negotiation(&probe).is_none());
+        probe[1..5].copy_from_slice(&2u32.to_be_bytes());
+        probe[5] = 21;
+        assert!(version_negotiation(&probe).is_none());
+        // a short header
+        probe[0] = 0x40;
+        assert!(version_negotiation(&probe).is_none());
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::Relaxed};
+
+// a session must be over its limit for this many consecutive seconds to trip the breaker
+pub const SUSTAINED_SECS: u64 = 5;
+
+// and is then paused for this long
+pub const PAUSE_SECS: u64 = 30;
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Forward,
+    /// over the limit for SUSTAINED_SECS just now, start of a pause
+    Tripped,
+    Paused,
+}
+
+/// per second packet counter that trips when over a limit for a sustained interval, lock free so
+/// it can be shared by every worker thread
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    second: AtomicU64,
+    count: AtomicU32,
+    over: AtomicU64,
+    paused_until: AtomicU64,
+}
+
+impl CircuitBreaker {
+    /// counts one packet at second now (any monotonic second count) against limit
+    pub fn check(&self, now: u64, limit: u32) -> Verdict {
+        if now < self.paused_until.load(Relaxed) {
+            return Verdict::Paused;
+        }
+        let second = self.second.load(Relaxed);
+        if second == now
+            || self
+                .second
+                .compare_exchange(second, now, Relaxed, Relaxed)
+                .is_err()
+        {
+            // same second, or another thread just started the new one
+            self.count.fetch_add(1, Relaxed);
+            return Verdict::Forward;
+        }
+        // we are the first packet of a new second, judge the previous one
+        let previous = self.count.swap(1, Relaxed);
+        let over = if previous > limit && now == second + 1 {
+            self.over.fetch_add(1, Relaxed) + 1
+        } else {
+            self.over.store(0, Relaxed);
+            0
+        };
+        if over >= SUSTAINED_SECS {
+            self.over.store(0, Relaxed);
+            self.paused_until.store(now + PAUSE_SECS, Relaxed);
+            return Verdict::Tripped;
+        }
+        Verdict::Forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::default();
+        for now in 1..=SUSTAINED_SECS {
+            for _ in 0..11 {
+                assert_eq!(breaker.check(now, 10), Verdict::Forward);
+            }
+        }
+        assert_eq!(breaker.check(SUSTAINED_SECS + 1, 10), Verdict::Tripped);
+        assert_eq!(
+            breaker.check(SUSTAINED_SECS + PAUSE_SECS, 10),
+            Verdict::Paused
+        );
+        assert_eq!(
+            breaker.check(SUSTAINED_SECS + PAUSE_SECS + 1, 10),
+            Verdict::Forward
+        );
+
+        // a gap or a quiet second starts over
+        let breaker = CircuitBreaker::default();
+        for now in [1, 2, 4, 5, 6, 7] {
+            for _ in 0..11 {
+                breaker.check(now, 10);
+            }
+        }
+        assert_eq!(breaker.check(8, 10), Verdict::Forward);
+    }
+}
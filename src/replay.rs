@@ -0,0 +1,371 @@
+// replay, packets from a capture through the forwarding loop again, as it would forward them with
+// the options given, to reproduce what a bug report's capture shows without its clients
+//
+// the capture is --pcap's or one tcpdump took on the proxy's host, of ethernet or linux cooked
+// frames; only udp datagrams to bind_addr's port are fed in, in the order captured, so what we
+// sent in the capture is left out and what we send now is printed instead, a line for each packet
+// with where it went, or nowhere if it was dropped
+//
+// one worker on a made up socket and a clock following the capture's timestamps, so the same
+// capture and options forward the same way every time, whatever --speed says; --speed only paces
+// the replay for whoever watches it, max doesn't wait at all
+
+use crate::{
+    args::{self, Args},
+    clock::{self, Clock, Tick},
+    config::{self, Config},
+    datagram::Datagram,
+    packet::WgPacket,
+    pktinfo::LocalAddr,
+    platform::IcmpError,
+    poison::Recover,
+    proxy::Proxy,
+};
+
+use std::{
+    fs,
+    io::{ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const USAGE: &str =
+    "usage: wireguard-udp-proxy replay capture.pcap [--speed n[x] | --speed max] [options] target_addr [bind_addr]
+
+feeds the udp datagrams in capture.pcap that went to bind_addr's port to a proxy with the options
+given, the same ones as when it was captured, and prints where each went this time, at n times the
+speed they were captured at (default: 1x), or as fast as they go with max";
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// a datagram of the capture, at is since the first one
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub at: Duration,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// the udp datagrams in a pcap file, everything else in it is skipped
+pub fn parse(capture: &[u8]) -> std::result::Result<Vec<Record>, String> {
+    let header = capture.get(..24).ok_or("too short for a pcap file")?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap_or_default());
+    let (big, nanos) = match magic {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        0x4d3cb2a1 => (true, true),
+        _ => return Err("not a pcap file, pcapng isn't supported".to_string()),
+    };
+    let u32_at = |buf: &[u8], at: usize| {
+        let bytes = buf[at..at + 4].try_into().unwrap_or_default();
+        match big {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    };
+    let linktype = u32_at(header, 20) & 0xffff;
+    let mut records = Vec::new();
+    let mut first = None;
+    let mut rest = &capture[24..];
+    while rest.len() >= 16 {
+        let (secs, fraction, len) = (u32_at(rest, 0), u32_at(rest, 4), u32_at(rest, 8) as usize);
+        let frame = rest
+            .get(16..16 + len)
+            .ok_or("the last packet is cut short")?;
+        rest = &rest[16 + len..];
+        let since_epoch =
+            Duration::new(secs as u64, if nanos { fraction } else { fraction * 1000 });
+        let first = *first.get_or_insert(since_epoch);
+        let packet = match linktype {
+            LINKTYPE_RAW => Some(frame),
+            LINKTYPE_ETHERNET => frame.get(14..).filter(|_| ip_ethertype(frame, 12)),
+            LINKTYPE_LINUX_SLL => frame.get(16..).filter(|_| ip_ethertype(frame, 14)),
+            LINKTYPE_LINUX_SLL2 => frame.get(20..).filter(|_| ip_ethertype(frame, 0)),
+            _ => return Err(format!("link type {} isn't supported", linktype)),
+        };
+        if let Some((from, to, payload)) = packet.and_then(udp) {
+            records.push(Record {
+                at: since_epoch.saturating_sub(first),
+                from,
+                to,
+                payload: payload.to_vec(),
+            });
+        }
+    }
+    Ok(records)
+}
+
+fn ip_ethertype(frame: &[u8], at: usize) -> bool {
+    matches!(frame.get(at..at + 2), Some([0x08, 0x00] | [0x86, 0xdd]))
+}
+
+/// the addresses and payload of the udp datagram in packet, an ip packet
+fn udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (from, to, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = (packet[0] & 0xf) as usize * 4;
+            let fragmented = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x3fff != 0;
+            if *packet.get(9)? != 17 || fragmented {
+                return None;
+            }
+            let ip = |at: usize| -> Option<IpAddr> {
+                let octets: [u8; 4] = packet.get(at..at + 4)?.try_into().ok()?;
+                Some(Ipv4Addr::from(octets).into())
+            };
+            (ip(12)?, ip(16)?, packet.get(header_len..)?)
+        }
+        6 => {
+            // no extension headers, what we capture has none
+            if *packet.get(6)? != 17 {
+                return None;
+            }
+            let ip = |at: usize| -> Option<IpAddr> {
+                let octets: [u8; 16] = packet.get(at..at + 16)?.try_into().ok()?;
+                Some(Ipv6Addr::from(octets).into())
+            };
+            (ip(8)?, ip(24)?, packet.get(40..)?)
+        }
+        _ => return None,
+    };
+    let port = |at: usize| u16::from_be_bytes([udp[at], udp[at + 1]]);
+    let len = (port(4) as usize).min(udp.len());
+    if udp.len() < 8 || len < 8 {
+        return None;
+    }
+    let from = SocketAddr::new(from, port(0));
+    let to = SocketAddr::new(to, port(2));
+    Some((from, to, &udp[8..len]))
+}
+
+/// what the forwarding loop gets instead of a socket, the capture's datagrams one at a time, and
+/// WouldBlock once they are all gone
+struct Replayed {
+    records: Mutex<std::vec::IntoIter<Record>>,
+    bound: SocketAddr,
+    // None is as fast as we can
+    speed: Option<f64>,
+    started: Instant,
+    clock: &'static ReplayClock,
+    // the packet being forwarded, for the line saying where it went
+    current: Mutex<Option<(Record, Vec<SocketAddr>)>>,
+    // packets fed in, sent on and fed in to go nowhere
+    forwarded: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Replayed {
+    /// prints the line for the packet that was forwarded last
+    fn done(&self) {
+        let Some((record, to)) = self.current.lock().recover().take() else {
+            return;
+        };
+        let to = match to.is_empty() {
+            true => {
+                self.dropped.fetch_add(1, Relaxed);
+                "nowhere".to_string()
+            }
+            false => to
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!(
+            "+{:.3}s {} {} -> {}",
+            record.at.as_secs_f64(),
+            record.from,
+            describe(&record.payload),
+            to
+        );
+    }
+}
+
+// a reference so we can still tell what it saw after the proxy has it
+impl Datagram for &Replayed {
+    fn recv(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr, Option<LocalAddr>, Option<u32>)> {
+        self.done();
+        let record = loop {
+            let record = self
+                .records
+                .lock()
+                .recover()
+                .next()
+                .ok_or(ErrorKind::WouldBlock)?;
+            if record.to.port() == self.bound.port() && record.payload.len() <= buf.len() {
+                break record;
+            }
+        };
+        if let Some(speed) = self.speed {
+            let due = self.started + record.at.div_f64(speed);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        // a tick in, 0 is the clock before it's started
+        self.clock.0.store(1 + clock::ticks(record.at), Relaxed);
+        let len = record.payload.len();
+        buf[..len].copy_from_slice(&record.payload);
+        let (from, to) = (record.from, record.to.ip());
+        *self.current.lock().recover() = Some((record, Vec::new()));
+        self.forwarded.fetch_add(1, Relaxed);
+        let local = LocalAddr { ip: to, ifindex: 0 };
+        Ok((len, from, Some(local), None))
+    }
+
+    fn send(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        _local_addr: Option<LocalAddr>,
+        _dscp: Option<u8>,
+    ) -> Result<usize> {
+        if let Some((_, to)) = self.current.lock().recover().as_mut() {
+            to.push(addr);
+        }
+        self.sent.fetch_add(1, Relaxed);
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.bound)
+    }
+
+    fn icmp_errors(&self) -> Result<Vec<IcmpError>> {
+        Ok(Vec::new())
+    }
+}
+
+/// the capture's time, the tick the packet being forwarded was captured at
+#[derive(Default)]
+struct ReplayClock(AtomicU64);
+
+/// the kind of wireguard message buf is, as a line says it
+fn describe(buf: &[u8]) -> String {
+    match WgPacket::parse(buf) {
+        Some(WgPacket::HandShakeInitiation { sender }) => format!("initiation {:08x}", sender),
+        Some(WgPacket::HandShakeResponse { sender, receiver }) => {
+            format!("response {:08x} to {:08x}", sender, receiver)
+        }
+        Some(WgPacket::Cookie { receiver }) => format!("cookie to {:08x}", receiver),
+        Some(WgPacket::Data { receiver }) if buf.len() == 32 => {
+            format!("keepalive to {:08x}", receiver)
+        }
+        Some(WgPacket::Data { receiver }) => format!("data to {:08x}", receiver),
+        _ => format!("{} bytes", buf.len()),
+    }
+}
+
+/// runs replay with args, the capture first
+pub fn replay<I: IntoIterator<Item = String>>(args: I) -> crate::Result<()> {
+    let mut args = Args::new(args);
+    let speed = match args.get_option("--speed")?.as_deref() {
+        None => Some(1.0),
+        Some("max") => None,
+        Some(speed) => Some(
+            args::parse::<f64>("--speed", speed.strip_suffix('x').unwrap_or(speed))
+                .ok()
+                .filter(|speed| *speed > 0.0)
+                .ok_or_else(|| args::invalid(format!("invalid --speed: {}", speed)))?,
+        ),
+    };
+    let mut options = args.rest();
+    let path = match options.first() {
+        Some(path) if !path.starts_with("--") => options.remove(0),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(());
+        }
+    };
+    let Some(config) = Config::from_args(options)? else {
+        eprintln!("{}", USAGE);
+        return Ok(());
+    };
+    let capture = fs::read(&path)?;
+    let records = parse(&capture).map_err(|e| args::invalid(format!("{}: {}", path, e)))?;
+    let bound = config::resolve(&config.bind_addr)?;
+    let clock: &'static ReplayClock = Box::leak(Box::default());
+    let socket: &'static Replayed = Box::leak(Box::new(Replayed {
+        records: Mutex::new(records.into_iter()),
+        bound,
+        speed,
+        started: Instant::now(),
+        clock,
+        current: Mutex::new(None),
+        forwarded: AtomicU64::new(0),
+        sent: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+    }));
+    let proxy: &'static Proxy<&Replayed, &ReplayClock> =
+        Box::leak(Box::new(Proxy::with(socket, clock, config)?));
+    match proxy.run() {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => socket.done(),
+        result => result?,
+    }
+    let (sent, dropped) = (socket.sent.load(Relaxed), socket.dropped.load(Relaxed));
+    println!(
+        "replayed {} packets to {}, the proxy sent {} and dropped {}",
+        socket.forwarded.load(Relaxed),
+        bound,
+        sent,
+        dropped
+    );
+    Ok(())
+}
+
+impl Clock for &ReplayClock {
+    fn now(&self) -> Tick {
+        self.0.load(Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let client: SocketAddr = "192.0.2.10:1000".parse().unwrap();
+        let ours: SocketAddr = "[2001:db8::1]:5678".parse().unwrap();
+        let mut capture = vec![0xd4, 0xc3, 0xb2, 0xa1, 0, 2, 0, 4];
+        capture.extend_from_slice(&[0; 8]);
+        capture.extend_from_slice(&65535u32.to_le_bytes());
+        capture.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        for (secs, micros, from, to, payload) in [
+            (
+                10u32,
+                0u32,
+                client,
+                "192.0.2.1:5678".parse().unwrap(),
+                &[1u8; 148][..],
+            ),
+            (10, 250_000, ours, client, &[4; 32][..]),
+        ] {
+            let datagram = crate::pcap::datagram(from, to, payload);
+            capture.extend_from_slice(&secs.to_le_bytes());
+            capture.extend_from_slice(&micros.to_le_bytes());
+            capture.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+            capture.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+            capture.extend_from_slice(&datagram);
+        }
+        let records = parse(&capture).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].from, client);
+        assert_eq!(records[0].payload, [1; 148]);
+        assert_eq!(records[1].at, Duration::from_millis(250));
+        assert_eq!(records[1].from, ours);
+        assert_eq!(describe(&records[1].payload), "keepalive to 04040404");
+        assert!(parse(&capture[..capture.len() - 1]).is_err());
+        assert!(parse(b"\x0a\x0d\x0d\x0a").is_err());
+    }
+}
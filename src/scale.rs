@@ -0,0 +1,85 @@
+// num_threads auto, as many workers as the load needs: one per core to start with, then every
+// interval another one if the kernel dropped packets because our receive queue was full or the
+// workers were busy most of the time, or one less if the others could easily take over its share
+//
+// workers all read the one socket, so adding and retiring them never moves clients between
+// sockets, or loses what was queued on one we close
+
+use std::{thread, time::Duration};
+
+/// how often we look at the load
+pub const INTERVAL: Duration = Duration::from_secs(10);
+
+// busier than this share of the time, the workers need help
+const BUSY: f64 = 0.75;
+
+// the workers left after retiring one may be busy this share of the time at most
+const RELAXED: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Bounds {
+    /// --threads-min and --threads-max, by default up to twice the cores
+    pub fn new(min: Option<usize>, max: Option<usize>) -> Bounds {
+        let min = min.unwrap_or(1);
+        let max = max.unwrap_or(2 * cores()).max(min);
+        Bounds { min, max }
+    }
+
+    /// the workers we start with, one per core
+    pub fn initial(&self) -> usize {
+        cores().clamp(self.min, self.max)
+    }
+}
+
+fn cores() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// whether workers that were busy for busy together over elapsed, while the kernel dropped dropped
+/// packets, need another one, Some(true), or one less, Some(false)
+pub fn decide(
+    workers: usize,
+    busy: Duration,
+    elapsed: Duration,
+    dropped: u64,
+    bounds: Bounds,
+) -> Option<bool> {
+    // in workers' worth of time
+    let load = busy.as_secs_f64() / elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+    if workers < bounds.max && (dropped > 0 || load > BUSY * workers as f64) {
+        return Some(true);
+    }
+    if workers > bounds.min && dropped == 0 && load < RELAXED * (workers - 1) as f64 {
+        return Some(false);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let bounds = Bounds { min: 2, max: 4 };
+        let secs = Duration::from_secs;
+        // dropping packets
+        assert_eq!(decide(2, secs(0), INTERVAL, 1, bounds), Some(true));
+        assert_eq!(decide(4, secs(40), INTERVAL, 1, bounds), None);
+        // 2 workers busy 16 of 20 seconds
+        assert_eq!(decide(2, secs(16), INTERVAL, 0, bounds), Some(true));
+        // 3 with 12 of 30, the 2 left would be busy 12 of 20
+        assert_eq!(decide(3, secs(12), INTERVAL, 0, bounds), None);
+        assert_eq!(decide(3, secs(9), INTERVAL, 0, bounds), Some(false));
+        assert_eq!(decide(2, secs(0), INTERVAL, 0, bounds), None);
+
+        let bounds = Bounds::new(Some(3), Some(2));
+        assert_eq!(bounds, Bounds { min: 3, max: 3 });
+        assert_eq!(bounds.initial(), 3);
+    }
+}
@@ -0,0 +1,198 @@
+// --schedule, switching the active target at times given as 5 field cron expressions
+//
+// minute hour day-of-month month day-of-week, each field is * or a comma separated list of values or
+// ranges like 1-5, any of them optionally followed by /step, day-of-week is 0-7 with both 0 and 7
+// being sunday, and like cron if both day fields are restricted either may match
+
+use crate::config::Target;
+
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// a week back is far enough to find the entry that should be in effect for any sane schedule
+const LOOK_BACK_MINUTES: u64 = 7 * 24 * 60;
+
+#[derive(Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // both day fields were restricted, so matching either is enough
+    either_day: bool,
+}
+
+/// broken down local time, like struct tm but with months from 1
+#[derive(Debug, Clone, Copy)]
+struct Tm {
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    weekday: u32,
+}
+
+impl Cron {
+    fn matches(&self, tm: &Tm) -> bool {
+        let bit = |field: u64, value: u32| field & (1 << value) != 0;
+        let day = bit(self.days, tm.day);
+        let weekday = bit(self.weekdays, tm.weekday);
+        bit(self.minutes, tm.minute)
+            && bit(self.hours, tm.hour)
+            && bit(self.months, tm.month)
+            && if self.either_day {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+}
+
+fn field(s: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field: {}", s);
+    let mut bits = 0u64;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse().map_err(|_| invalid())?)),
+            None => (part, None),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (
+                from.parse().map_err(|_| invalid())?,
+                to.parse().map_err(|_| invalid())?,
+            ),
+            None => {
+                let from = range.parse().map_err(|_| invalid())?;
+                // like cron, 5/10 means 5-max/10
+                (from, if step.is_some() { max } else { from })
+            }
+        };
+        let step = step.unwrap_or(1);
+        if from < min || to > max || from > to || step == 0 {
+            return Err(invalid());
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron expression needs 5 fields: {}", s));
+        }
+        let mut weekdays = field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes: field(fields[0], 0, 59)?,
+            hours: field(fields[1], 0, 23)?,
+            days: field(fields[2], 1, 31)?,
+            months: field(fields[3], 1, 12)?,
+            weekdays,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn local_time(unix: u64) -> Tm {
+    let time = unix as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    Tm {
+        minute: tm.tm_min as u32,
+        hour: tm.tm_hour as u32,
+        day: tm.tm_mday as u32,
+        month: tm.tm_mon as u32 + 1,
+        weekday: tm.tm_wday as u32,
+    }
+}
+
+// UTC where we have no localtime_r, days to civil date from
+// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+#[cfg(not(unix))]
+fn local_time(unix: u64) -> Tm {
+    let days = unix / 86400;
+    let secs = unix % 86400;
+    let z = days + 719468;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    Tm {
+        minute: (secs / 60 % 60) as u32,
+        hour: (secs / 3600) as u32,
+        day: (doy - (153 * mp + 2) / 5 + 1) as u32,
+        month: (if mp < 10 { mp + 3 } else { mp - 9 }) as u32,
+        weekday: ((days + 4) % 7) as u32,
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// the target of the entry due in the minute of unix, the last one if several are
+pub fn due(schedule: &[(Cron, Target)], unix: u64) -> Option<&Target> {
+    let tm = local_time(unix);
+    schedule
+        .iter()
+        .rev()
+        .find(|(cron, _)| cron.matches(&tm))
+        .map(|(_, target)| target)
+}
+
+/// the target of the entry that was due most recently as of unix, what should be active when
+/// starting up in the middle of a schedule
+pub fn current(schedule: &[(Cron, Target)], unix: u64) -> Option<&Target> {
+    (0..LOOK_BACK_MINUTES).find_map(|minutes| due(schedule, unix.checked_sub(minutes * 60)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron() {
+        let tm = Tm {
+            minute: 30,
+            hour: 22,
+            day: 14,
+            month: 10,
+            weekday: 3,
+        };
+        let matches = |s: &str| s.parse::<Cron>().unwrap().matches(&tm);
+        assert!(matches("* * * * *"));
+        assert!(matches("30 22 * * *"));
+        assert!(matches("*/15 20-23 * 10 1-5"));
+        assert!(matches("0,30 22 1 * 3"));
+        assert!(!matches("0,30 22 1 * 4"));
+        assert!(!matches("0 22 * * *"));
+        assert!(!matches("30 22 * * 0,6"));
+        assert!(matches("5/5 22 * * *"));
+        assert!(!matches("31/5 * * * *"));
+
+        let sunday = Tm { weekday: 0, ..tm };
+        assert!("* * * * 7".parse::<Cron>().unwrap().matches(&sunday));
+
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("* * 0 * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("5-1 * * * *".parse::<Cron>().is_err());
+    }
+}
@@ -0,0 +1,169 @@
+// --seal-target and --seal-clients, an authenticated wrapper between cooperating proxies sharing a
+// key, so whatever is in the middle can't inject packets or tamper with them, wireguard's own
+// crypto only protects what's inside and the relay envelope not at all
+//
+// 1 byte   SEALED_TYPE, never a valid wireguard message type
+// 24 bytes nonce, a salt random per process and a counter
+// ...      the packet, encrypted with XChaCha20-Poly1305
+// 16 bytes tag
+//
+// a replayed packet opens fine, wireguard drops those itself
+//
+// built without the seal feature there is no Seal to load a key into
+
+#[cfg(feature = "seal")]
+use blake2::{Blake2s256, Digest};
+#[cfg(feature = "seal")]
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    Tag, XChaCha20Poly1305, XNonce,
+};
+use std::io::Result;
+#[cfg(feature = "seal")]
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+#[cfg(feature = "seal")]
+pub const SEALED_TYPE: u8 = 0xfc;
+
+/// keep this much room in front of packets that might get sealed
+pub const HEADER_LEN: usize = 1 + 24;
+
+/// and this much behind them
+pub const TAG_LEN: usize = 16;
+
+// so the same file as a --psk-file doesn't give the same key
+#[cfg(feature = "seal")]
+const CONTEXT: &[u8] = b"wireguard-udp-proxy seal ";
+
+#[cfg(feature = "seal")]
+pub struct Seal {
+    cipher: XChaCha20Poly1305,
+    salt: [u8; 16],
+    counter: AtomicU64,
+}
+
+#[cfg(feature = "seal")]
+impl Seal {
+    /// reads a pre-shared key of any length from path, surrounding whitespace is ignored
+    pub fn from_file(path: &str) -> Result<Seal> {
+        Ok(Seal::from_key(&fs::read_to_string(path)?))
+    }
+
+    /// the seal for a pre-shared key, surrounding whitespace is ignored
+    pub fn from_key(key: &str) -> Seal {
+        let key = Blake2s256::new()
+            .chain_update(CONTEXT)
+            .chain_update(key.trim().as_bytes())
+            .finalize();
+        let random = || RandomState::new().build_hasher().finish().to_le_bytes();
+        let mut salt = [0u8; 16];
+        salt[..8].copy_from_slice(&random());
+        salt[8..].copy_from_slice(&random());
+        Seal {
+            cipher: XChaCha20Poly1305::new(&key),
+            salt,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// seals the packet in buf[start..end] in place and returns where the sealed one is, there must
+    /// be HEADER_LEN bytes in front of it and TAG_LEN behind, None if the cipher refuses, which it
+    /// only does for packets far above anything we receive
+    pub fn seal(&self, buf: &mut [u8], start: usize, end: usize) -> Option<(usize, usize)> {
+        let header = start - HEADER_LEN;
+        buf[header] = SEALED_TYPE;
+        buf[header + 1..header + 17].copy_from_slice(&self.salt);
+        let counter = self.counter.fetch_add(1, Relaxed);
+        buf[header + 17..start].copy_from_slice(&counter.to_le_bytes());
+        let (header_buf, rest) = buf.split_at_mut(start);
+        let nonce = XNonce::from_slice(&header_buf[header + 1..]);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(nonce, &[SEALED_TYPE], &mut rest[..end - start])
+            .ok()?;
+        buf[end..end + TAG_LEN].copy_from_slice(&tag);
+        Some((header, end + TAG_LEN))
+    }
+
+    /// opens the sealed packet in buf[start..end] in place and returns where the packet is, None if
+    /// it wasn't sealed with our key or was tampered with
+    pub fn open(&self, buf: &mut [u8], start: usize, end: usize) -> Option<(usize, usize)> {
+        if end - start < HEADER_LEN + TAG_LEN || buf[start] != SEALED_TYPE {
+            return None;
+        }
+        let (header, rest) = buf[start..end].split_at_mut(HEADER_LEN);
+        let (packet, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+        self.cipher
+            .decrypt_in_place_detached(
+                XNonce::from_slice(&header[1..]),
+                &[SEALED_TYPE],
+                packet,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+        Some((start + HEADER_LEN, end - TAG_LEN))
+    }
+}
+
+#[cfg(not(feature = "seal"))]
+pub enum Seal {}
+
+#[cfg(not(feature = "seal"))]
+impl Seal {
+    pub fn from_file(_path: &str) -> Result<Seal> {
+        Err(crate::args::unavailable("--seal-target and --seal-clients", "seal").into())
+    }
+
+    pub fn seal(&self, _buf: &mut [u8], _start: usize, _end: usize) -> Option<(usize, usize)> {
+        match *self {}
+    }
+
+    pub fn open(&self, _buf: &mut [u8], _start: usize, _end: usize) -> Option<(usize, usize)> {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, feature = "seal"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let path = std::env::temp_dir().join(format!("seal-test-{}", std::process::id()));
+        fs::write(&path, "hunter2\n").unwrap();
+        let ours = Seal::from_file(path.to_str().unwrap()).unwrap();
+        let theirs = Seal::from_file(path.to_str().unwrap()).unwrap();
+        fs::write(&path, "hunter3").unwrap();
+        let wrong = Seal::from_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut buf = [0u8; HEADER_LEN + 4 + TAG_LEN];
+        buf[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&[4, 0, 0, 0]);
+        let (start, end) = ours.seal(&mut buf, HEADER_LEN, HEADER_LEN + 4).unwrap();
+        assert_eq!((start, end), (0, buf.len()));
+        assert_eq!(buf[0], SEALED_TYPE);
+        assert_ne!(buf[HEADER_LEN..HEADER_LEN + 4], [4, 0, 0, 0]);
+
+        let sealed = buf;
+        assert_eq!(wrong.open(&mut buf, start, end), None);
+        buf = sealed;
+        buf[HEADER_LEN] ^= 1;
+        assert_eq!(theirs.open(&mut buf, start, end), None);
+        buf = sealed;
+        assert_eq!(
+            theirs.open(&mut buf, start, end),
+            Some((HEADER_LEN, HEADER_LEN + 4))
+        );
+        assert_eq!(buf[HEADER_LEN..HEADER_LEN + 4], [4, 0, 0, 0]);
+
+        // a fresh nonce every time
+        let mut again = [0u8; HEADER_LEN + 4 + TAG_LEN];
+        ours.seal(&mut again, HEADER_LEN, HEADER_LEN + 4);
+        assert_ne!(again[1..HEADER_LEN], sealed[1..HEADER_LEN]);
+    }
+}
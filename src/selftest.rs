@@ -0,0 +1,365 @@
+// selftest, checking a deployment end to end: resolve both ends, send a handshake initiation
+// straight to the target and then through the proxy, and see whether a response comes back each way
+//
+// the target only answers an initiation from a peer it knows, so with --private-key and
+// --public-key the initiation is a real one, Noise_IKpsk2 as in the wireguard paper 5.4.2, without
+// them it's random bytes in the right shape that can only show the proxy takes them
+//
+// the ephemeral key comes from RandomState like seal's salt, good enough for a handshake that's
+// never used for anything
+
+use crate::args::{self, Args};
+
+use blake2::{
+    digest::{consts::U16, Mac},
+    Blake2s256, Blake2sMac, Digest,
+};
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{ErrorKind, Result},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const USAGE: &str =
+    "usage: wireguard-udp-proxy selftest --via proxy_addr --target target_addr [options]
+
+sends a handshake initiation to target_addr directly and then through the proxy at proxy_addr, and
+reports whether each got a response
+
+options:
+    --private-key path    the private key of a peer the target knows, as wg genkey writes it
+    --public-key key      the target's public key, with --private-key the initiation is a real one
+                          the target answers, without both it's random and can't be answered
+    --timeout ms          how long to wait for each response (default: 2000)";
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+
+const INITIATION_LEN: usize = 148;
+const RESPONSE_LEN: usize = 92;
+const COOKIE_LEN: usize = 64;
+
+// the target takes at most 50 initiations a second from a peer
+const BETWEEN_INITIATIONS: Duration = Duration::from_millis(100);
+
+/// a peer's keys, ours and the target's
+pub struct Keys {
+    private: StaticSecret,
+    target: PublicKey,
+}
+
+impl Keys {
+    /// our private key from the file at path, as wg genkey writes it, and the target's public key
+    pub fn read(path: &str, target: &str) -> Result<Keys> {
+        Ok(Keys {
+            private: StaticSecret::from(args::key_from_base64(fs::read_to_string(path)?.trim())?),
+            target: PublicKey::from(args::key_from_base64(target)?),
+        })
+    }
+}
+
+/// prints each stage as it's done, remembers whether any failed
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn stage(&mut self, name: &str, result: std::result::Result<String, String>) {
+        match result {
+            Ok(detail) => println!("pass  {}: {}", name, detail),
+            Err(detail) => {
+                self.failed = true;
+                println!("FAIL  {}: {}", name, detail);
+            }
+        }
+    }
+}
+
+/// runs selftest with args, returns whether every stage passed
+pub fn selftest<I: IntoIterator<Item = String>>(args: I) -> Result<bool> {
+    let mut args = Args::new(args);
+    let help = args.flag("--help");
+    let via = args.get_option("--via")?;
+    let target = args.get_option("--target")?;
+    let private_key = args.get_option("--private-key")?;
+    let public_key = args.get_option("--public-key")?;
+    let timeout = Duration::from_millis(args.get("--timeout")?.unwrap_or(2000));
+    if let Some(extra) = args.positional()?.first() {
+        return Err(args::invalid(format!("unexpected argument: {}", extra)).into());
+    }
+    let (via, target) = match (via, target) {
+        (Some(via), Some(target)) if !help => (via, target),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(help);
+        }
+    };
+    let keys = match (private_key, public_key) {
+        (Some(path), Some(key)) => Some(Keys::read(&path, &key)?),
+        (None, None) => None,
+        _ => {
+            return Err(
+                args::invalid("--private-key and --public-key go together".to_string()).into(),
+            )
+        }
+    };
+
+    let mut report = Report::default();
+    let resolved = |addr: &str| crate::config::resolve(addr).map_err(|e| e.to_string());
+    let via = resolved(&via);
+    report.stage(
+        "resolve proxy",
+        via.as_ref().map(|a| a.to_string()).map_err(Clone::clone),
+    );
+    let target = resolved(&target);
+    report.stage(
+        "resolve target",
+        target.as_ref().map(|a| a.to_string()).map_err(Clone::clone),
+    );
+    let (via, target) = match (via, target) {
+        (Ok(via), Ok(target)) => (via, target),
+        _ => return Ok(false),
+    };
+    if keys.is_none() {
+        println!("skip  responses: the target only answers peers it knows, see --private-key");
+    }
+
+    // the target might be unreachable from here by design, which is what the proxy is for
+    let (name, result) = ("direct to target", exchange(target, keys.as_ref(), timeout));
+    match result {
+        Ok(detail) => println!("pass  {}: {}", name, detail),
+        Err(detail) => println!("warn  {}: {}", name, detail),
+    }
+    std::thread::sleep(BETWEEN_INITIATIONS);
+    report.stage("through proxy", exchange(via, keys.as_ref(), timeout));
+    Ok(!report.failed)
+}
+
+/// sends an initiation to addr and waits for the answer, or for ICMP saying nobody's there
+fn exchange(
+    addr: SocketAddr,
+    keys: Option<&Keys>,
+    timeout: Duration,
+) -> std::result::Result<String, String> {
+    let socket = connect(addr).map_err(|e| e.to_string())?;
+    exchange_on(&socket, keys, random_u64() as u32, timeout)
+}
+
+/// a socket connected to addr, so ICMP port unreachable comes back as an error
+pub fn connect(addr: SocketAddr) -> Result<UdpSocket> {
+    let bind_addr: SocketAddr = if addr.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+/// exchange on a socket from connect, initiating with sender as our index
+pub fn exchange_on(
+    socket: &UdpSocket,
+    keys: Option<&Keys>,
+    sender: u32,
+    timeout: Duration,
+) -> std::result::Result<String, String> {
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    let initiation = match keys {
+        Some(keys) => initiation(keys, sender),
+        None => {
+            let mut initiation = [0u8; INITIATION_LEN];
+            for chunk in initiation.chunks_mut(8) {
+                chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+            }
+            initiation[..4].copy_from_slice(&[1, 0, 0, 0]);
+            initiation[4..8].copy_from_slice(&sender.to_le_bytes());
+            initiation
+        }
+    };
+    let sent = Instant::now();
+    socket
+        .send(&initiation)
+        .map_err(|e| format!("sending failed: {}", e))?;
+
+    let mut buf = [0u8; 2048];
+    loop {
+        let recv = match socket.recv(&mut buf) {
+            Ok(recv) => recv,
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                return Err("refused, nothing listens there".to_string())
+            }
+            // all a random initiation can tell
+            Err(e)
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && keys.is_none() =>
+            {
+                return Ok(format!("took a {} byte initiation", INITIATION_LEN))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(format!("no response within {}ms", timeout.as_millis()))
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        let ours = recv >= 12 && buf[8..12] == sender.to_le_bytes();
+        let elapsed = sent.elapsed().as_millis();
+        match (buf[0], recv) {
+            (2, RESPONSE_LEN) if ours => {
+                return Ok(format!("handshake response in {}ms", elapsed));
+            }
+            (3, COOKIE_LEN) if buf[4..8] == sender.to_le_bytes() => {
+                return Ok(format!(
+                    "cookie reply in {}ms, the target is under load",
+                    elapsed
+                ));
+            }
+            // something else for this socket, keep waiting
+            _ if sent.elapsed() < timeout => continue,
+            _ => return Err(format!("no response within {}ms", timeout.as_millis())),
+        }
+    }
+}
+
+/// a handshake initiation from keys.private to keys.target with sender as our index
+fn initiation(keys: &Keys, sender: u32) -> [u8; INITIATION_LEN] {
+    let mut msg = [0u8; INITIATION_LEN];
+    msg[0] = 1;
+    msg[4..8].copy_from_slice(&sender.to_le_bytes());
+
+    let mut ephemeral = [0u8; 32];
+    for chunk in ephemeral.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_le_bytes());
+    }
+    let ephemeral = StaticSecret::from(ephemeral);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let our_public = PublicKey::from(&keys.private);
+
+    let chain = hash(&[CONSTRUCTION]);
+    let h = hash(&[&chain, IDENTIFIER]);
+    let h = hash(&[&h, keys.target.as_bytes()]);
+    let chain = kdf(&chain, ephemeral_public.as_bytes()).0;
+    msg[8..40].copy_from_slice(ephemeral_public.as_bytes());
+    let h = hash(&[&h, &msg[8..40]]);
+
+    let (chain, key) = kdf(&chain, ephemeral.diffie_hellman(&keys.target).as_bytes());
+    msg[40..72].copy_from_slice(our_public.as_bytes());
+    seal(&key, &h, &mut msg[40..88]);
+    let h = hash(&[&h, &msg[40..88]]);
+
+    let (_, key) = kdf(&chain, keys.private.diffie_hellman(&keys.target).as_bytes());
+    msg[88..100].copy_from_slice(&tai64n(SystemTime::now()));
+    seal(&key, &h, &mut msg[88..116]);
+
+    let mac1_key = hash(&[LABEL_MAC1, keys.target.as_bytes()]);
+    let mut mac1 = <Blake2sMac<U16> as KeyInit>::new(&mac1_key.into());
+    mac1.update(&msg[..116]);
+    msg[116..132].copy_from_slice(&mac1.finalize().into_bytes());
+    // mac2 stays zeroed, it's only for a cookie we don't have
+    msg
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// HMAC-BLAKE2s, which wireguard uses rather than keyed BLAKE2s for its key derivation
+fn hmac(key: &[u8; 32], parts: &[&[u8]]) -> [u8; 32] {
+    let mut inner = [0x36u8; 64];
+    let mut outer = [0x5cu8; 64];
+    for i in 0..32 {
+        inner[i] ^= key[i];
+        outer[i] ^= key[i];
+    }
+    let mut hashed: Vec<&[u8]> = vec![&inner];
+    hashed.extend_from_slice(parts);
+    let inner = hash(&hashed);
+    hash(&[&outer, &inner])
+}
+
+/// KDF2 from the paper, the new chaining key and a key
+fn kdf(chain: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let t0 = hmac(chain, &[input]);
+    let t1 = hmac(&t0, &[&[1]]);
+    let t2 = hmac(&t0, &[&t1, &[2]]);
+    (t1, t2)
+}
+
+/// encrypts all but the last 16 bytes of buf in place with a zero nonce and h as associated
+/// data, and puts the tag in those
+fn seal(key: &[u8; 32], h: &[u8; 32], buf: &mut [u8]) {
+    let (plain, tag) = buf.split_at_mut(buf.len() - 16);
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let sealed = cipher
+        .encrypt_in_place_detached(&Nonce::default(), h, plain)
+        .expect("fits any buffer");
+    tag.copy_from_slice(&sealed);
+}
+
+/// TAI64N, which the target wants to be newer than the last initiation it took from us
+fn tai64n(now: SystemTime) -> [u8; 12] {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut tai = [0u8; 12];
+    tai[..8].copy_from_slice(&(0x400000000000000a + since.as_secs()).to_be_bytes());
+    tai[8..].copy_from_slice(&since.subsec_nanos().to_be_bytes());
+    tai
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_initiation() {
+        // the key pair from the wireguard docs' example config
+        let private =
+            args::key_from_base64("yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=").unwrap();
+        let public = args::key_from_base64("HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=").unwrap();
+        assert_eq!(
+            PublicKey::from(&StaticSecret::from(private)).as_bytes(),
+            &public
+        );
+        assert!(args::key_from_base64("HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8yk=").is_err());
+
+        // what the target does with it, up to the static key it finds inside
+        let target = StaticSecret::from([7u8; 32]);
+        let keys = Keys {
+            private: StaticSecret::from(private),
+            target: PublicKey::from(&target),
+        };
+        let msg = initiation(&keys, 0x1234);
+        assert_eq!(msg[..8], [1, 0, 0, 0, 0x34, 0x12, 0, 0]);
+        let chain = hash(&[CONSTRUCTION]);
+        let h = hash(&[&hash(&[&chain, IDENTIFIER]), keys.target.as_bytes()]);
+        let ephemeral = PublicKey::from(<[u8; 32]>::try_from(&msg[8..40]).unwrap());
+        let chain = kdf(&chain, ephemeral.as_bytes()).0;
+        let h = hash(&[&h, &msg[8..40]]);
+        let (_, key) = kdf(&chain, target.diffie_hellman(&ephemeral).as_bytes());
+        let mut sealed = msg[40..72].to_vec();
+        ChaCha20Poly1305::new((&key).into())
+            .decrypt_in_place_detached(&Nonce::default(), &h, &mut sealed, msg[72..88].into())
+            .unwrap();
+        assert_eq!(sealed, public);
+        assert_eq!(msg[132..], [0; 16]);
+        assert!(tai64n(SystemTime::now()) > tai64n(UNIX_EPOCH));
+    }
+}
@@ -0,0 +1,212 @@
+// --config-key, so whoever can write our configuration files, or what delivers them, can't point
+// clients at a target of their own: with it every --uci and --tenants file needs a signature by
+// the key next to it, at path.minisig, or we don't start
+//
+// the key is a minisign public key, as its .pub file has it on the second line or that file, or an
+// ed25519 one as 64 hex digits; the signature is what minisign -S writes, either kind it makes, or
+// for a hex key 128 hex digits, like `openssl pkeyutl -sign -rawin -inkey key.pem -in path | xxd -p
+// -c 64` prints
+
+use crate::{args, error::Result};
+
+use blake2::{Blake2b512, Digest};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use std::fs;
+
+// minisign's algorithm, a signature over the file as is or over its blake2b-512
+const PURE: &[u8; 2] = b"Ed";
+const PREHASHED: &[u8; 2] = b"ED";
+
+/// who configuration files must be signed by
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    /// minisign's key id, signatures by another key of the same person are refused
+    id: Option<[u8; 8]>,
+    key: [u8; 32],
+}
+
+impl Key {
+    /// --config-key's value, a key or the minisign .pub file it's in
+    pub fn parse(value: &str) -> Result<Key> {
+        let invalid = || args::invalid(format!("invalid --config-key: {}", value));
+        if let Some(key) = unhex::<32>(value) {
+            return Ok(Key { id: None, key });
+        }
+        let line = match fs::read_to_string(value) {
+            Ok(file) => file
+                .lines()
+                .rfind(|l| !l.trim().is_empty())
+                .unwrap_or("")
+                .to_string(),
+            Err(_) => value.to_string(),
+        };
+        let bytes = base64(line.trim()).ok_or_else(invalid)?;
+        match bytes.len() == 42 && bytes[..2] == PURE[..] {
+            true => Ok(Key {
+                id: bytes[2..10].try_into().ok(),
+                key: bytes[10..].try_into().map_err(|_| invalid())?,
+            }),
+            false => Err(invalid()),
+        }
+    }
+
+    /// whether signature, what path.minisig has, is ours over data
+    pub fn verify(&self, data: &[u8], signature: &str) -> std::result::Result<(), String> {
+        let key = UnparsedPublicKey::new(&ED25519, &self.key[..]);
+        let check = |message: &[u8], signature: &[u8]| {
+            key.verify(message, signature)
+                .map_err(|_| "signature doesn't verify".to_string())
+        };
+        let lines: Vec<&str> = signature.lines().map(str::trim).collect();
+        if self.id.is_none() {
+            let signature = unhex::<64>(&lines.concat()).ok_or("not 128 hex digits")?;
+            return check(data, &signature);
+        }
+        let (signature, comment, global) = match lines[..] {
+            [untrusted, signature, trusted, global, ..]
+                if untrusted.starts_with("untrusted comment:") =>
+            {
+                let comment = trusted
+                    .strip_prefix("trusted comment: ")
+                    .ok_or("no trusted comment")?;
+                (signature, comment, global)
+            }
+            _ => return Err("not a minisign signature".to_string()),
+        };
+        let signature = base64(signature)
+            .filter(|s| s.len() == 74)
+            .ok_or("invalid signature")?;
+        if signature[2..10] != self.id.unwrap_or_default()[..] {
+            return Err("signed by another key".to_string());
+        }
+        match &signature[..2] {
+            a if a == PURE => check(data, &signature[10..])?,
+            a if a == PREHASHED => check(&Blake2b512::digest(data), &signature[10..])?,
+            _ => return Err("unknown signature algorithm".to_string()),
+        }
+        // the trusted comment is signed too, along with the signature
+        let global = base64(global).ok_or("invalid global signature")?;
+        check(&[&signature[10..], comment.as_bytes()].concat(), &global)
+    }
+
+    /// what's in path, once its signature at path.minisig verifies
+    pub fn read(&self, path: &str) -> Result<String> {
+        let invalid = |why: String| args::invalid(format!("{}: {}, see --config-key", path, why));
+        let data = fs::read(path)?;
+        let signature = fs::read_to_string(format!("{}.minisig", path))
+            .map_err(|e| invalid(format!("can't read its signature {}.minisig: {}", path, e)))?;
+        self.verify(&data, &signature).map_err(invalid)?;
+        String::from_utf8(data).map_err(|_| invalid("not UTF-8".to_string()))
+    }
+}
+
+fn unhex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let digit = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | digit as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn encode(bytes: &[u8]) -> String {
+        const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk.iter().fold(0u32, |n, b| n << 8 | *b as u32) << (8 * (3 - chunk.len()));
+            for i in 0..=chunk.len() {
+                out.push(DIGITS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        out + &"=".repeat((3 - bytes.len() % 3) % 3)
+    }
+
+    #[test]
+    fn test_verify() {
+        let pair = Ed25519KeyPair::from_seed_unchecked(&[9; 32]).unwrap();
+        let public = pair.public_key().as_ref();
+        let id = [1, 2, 3, 4, 5, 6, 7, 8];
+        let data = b"option target 'vpn.example.com:51820'\n";
+
+        let hex: String = public.iter().map(|b| format!("{:02x}", b)).collect();
+        let key = Key::parse(&hex).unwrap();
+        let signature: String = pair
+            .sign(data)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(key.verify(data, &signature), Ok(()));
+        assert!(key
+            .verify(b"option target 'evil.example.com:51820'\n", &signature)
+            .is_err());
+
+        let key = Key::parse(&encode(&[&PURE[..], &id, public].concat())).unwrap();
+        assert_eq!(
+            key,
+            Key {
+                id: Some(id),
+                key: public.try_into().unwrap()
+            }
+        );
+        let minisig = |algorithm: &[u8; 2], id: &[u8], comment: &str| {
+            let signature = match algorithm {
+                PURE => pair.sign(data),
+                _ => pair.sign(&Blake2b512::digest(data)),
+            };
+            let signature = signature.as_ref();
+            let global = pair.sign(&[signature, comment.as_bytes()].concat());
+            format!(
+                "untrusted comment: signature from minisign secret key\n{}\ntrusted comment: {}\n{}\n",
+                encode(&[&algorithm[..], id, signature].concat()),
+                comment,
+                encode(global.as_ref())
+            )
+        };
+        assert_eq!(key.verify(data, &minisig(PURE, &id, "timestamp:1")), Ok(()));
+        assert_eq!(
+            key.verify(data, &minisig(PREHASHED, &id, "file:uci")),
+            Ok(())
+        );
+        assert_eq!(
+            key.verify(data, &minisig(PURE, &[0; 8], "timestamp:1")),
+            Err("signed by another key".to_string())
+        );
+        // the trusted comment can't be changed
+        let changed = minisig(PREHASHED, &id, "file:uci").replace("file:uci", "file:other");
+        assert!(key.verify(data, &changed).is_err());
+        assert!(key.verify(data, &signature).is_err());
+        assert!(Key::parse("RWQ").is_err());
+    }
+}
@@ -0,0 +1,447 @@
+// soak, thousands of made up clients through a proxy at once for as long as it takes to see how
+// its tables, expiry and locks hold up under churn, not a benchmark of how fast one client goes
+//
+// we are the target too: run the proxy with our --target as its target_addr, what we answer there
+// goes back through it. our packets have the right types, lengths and random indexes but nothing
+// real in them, so a proxy with --strict-responses or anything that looks closer drops them
+//
+// every client handshakes once while we ramp up and again every --rehandshake after its last one
+// was answered, or REKEY_TIMEOUT after one that wasn't, and in between data goes out at --rate in
+// all, the clients taking turns, each carrying its client and when it left for the target to send
+// back, so the round trip is measured without the target keeping anything
+//
+// clients share --sockets sockets, so a client is a sender index more than an address
+
+use crate::{
+    args::{self, Args},
+    config,
+    packet::WgPacket,
+    poison::Recover,
+};
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BinaryHeap, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{ErrorKind, Result},
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const USAGE: &str =
+    "usage: wireguard-udp-proxy soak --via proxy_addr --target target_addr [options]
+
+runs --clients made up wireguard clients through the proxy at proxy_addr, whose target_addr must
+be our --target, where we answer their handshakes and send their data back, and reports every
+--report-every how many have a session, how many handshakes and packets made it and their round
+trip, failing if more than --max-loss of it didn't
+
+options:
+    --clients n           how many clients (default: 1000)
+    --rate n[pps]         data packets a second, from all of them together (default: 1000)
+    --duration secs       how long to run for (default: 60)
+    --ramp secs           spread the first handshakes over this long (default: 10)
+    --rehandshake secs    handshake again this long after the last one (default: 120, wireguard's)
+    --sockets n           how many sockets the clients share (default: 64)
+    --report-every secs   how often to print what happened since the last report (default: 10)
+    --max-loss percent    of handshakes and packets, more isn't a pass (default: 1)";
+
+const INITIATION_LEN: usize = 148;
+const RESPONSE_LEN: usize = 92;
+// the header, what we put in it and a tag's worth
+const DATA_LEN: usize = 16 + 16 + 16;
+
+// how long wireguard waits for a response before it initiates again
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long a receive waits before it looks whether we are done
+const POLL: Duration = Duration::from_millis(100);
+
+struct Client {
+    socket: usize,
+    // our current sender index and the target's for the session it got, if it got one
+    sender: u32,
+    receiver: Option<u32>,
+    // when it handshakes next, entries in the schedule for another time are stale
+    due: Instant,
+    counter: u64,
+}
+
+/// what happened since the last report, or in all
+#[derive(Default, Clone)]
+struct Counts {
+    handshakes: u64,
+    answered: u64,
+    sent: u64,
+    back: u64,
+    // round trips in microseconds
+    rtts: Vec<u32>,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.handshakes += other.handshakes;
+        self.answered += other.answered;
+        self.sent += other.sent;
+        self.back += other.back;
+        self.rtts.extend_from_slice(&other.rtts);
+    }
+
+    /// percent of what was sent that never came back, as if what's still in flight did
+    fn loss(&self) -> f64 {
+        let sent = self.handshakes + self.sent;
+        match sent {
+            0 => 0.0,
+            _ => 100.0 * sent.saturating_sub(self.answered + self.back) as f64 / sent as f64,
+        }
+    }
+
+    fn describe(&mut self) -> String {
+        self.rtts.sort_unstable();
+        let at = |p: usize| {
+            let rtt = self.rtts.get(self.rtts.len() * p / 100).copied();
+            rtt.map_or("-".to_string(), |us| format!("{:.1}ms", us as f64 / 1000.0))
+        };
+        format!(
+            "{} of {} handshakes answered, {} of {} packets back, rtt p50 {} p99 {}",
+            self.answered,
+            self.handshakes,
+            self.back,
+            self.sent,
+            at(50),
+            at(99)
+        )
+    }
+}
+
+struct State {
+    clients: Vec<Client>,
+    // sender index of a handshake waiting for its response -> its client
+    initiated: HashMap<u32, usize>,
+    schedule: BinaryHeap<Reverse<(Instant, usize)>>,
+    counts: Counts,
+}
+
+/// runs soak with args, returns whether no more than --max-loss was lost
+pub fn soak<I: IntoIterator<Item = String>>(args: I) -> Result<bool> {
+    let mut args = Args::new(args);
+    let help = args.flag("--help");
+    let via = args.get_option("--via")?;
+    let target = args.get_option("--target")?;
+    let clients: usize = args.get("--clients")?.unwrap_or(1000);
+    let rate: u64 = match args.get_option("--rate")? {
+        Some(rate) => args::parse("--rate", rate.strip_suffix("pps").unwrap_or(&rate))?,
+        None => 1000,
+    };
+    let secs = |secs: Option<u64>, default| Duration::from_secs(secs.unwrap_or(default));
+    let duration = secs(args.get("--duration")?, 60);
+    let ramp = secs(args.get("--ramp")?, 10);
+    let rehandshake = secs(args.get("--rehandshake")?, 120);
+    let sockets: usize = args.get("--sockets")?.unwrap_or(64);
+    let report_every = secs(args.get("--report-every")?, 10);
+    let max_loss: f64 = args.get("--max-loss")?.unwrap_or(1.0);
+    if let Some(extra) = args.positional()?.first() {
+        return Err(args::invalid(format!("unexpected argument: {}", extra)).into());
+    }
+    let (via, target) = match (via, target) {
+        (Some(via), Some(target)) if !help => (via, target),
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(help);
+        }
+    };
+    if clients == 0 || sockets == 0 || rehandshake.is_zero() || report_every.is_zero() {
+        let e = "--clients, --sockets, --rehandshake and --report-every must be at least 1";
+        return Err(args::invalid(e.to_string()).into());
+    }
+    let via = config::resolve(&via)?;
+    let unspecified: SocketAddr = match via {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let target = UdpSocket::bind(config::resolve(&target)?)?;
+    target.set_read_timeout(Some(POLL))?;
+    let sockets = (0..sockets.min(clients))
+        .map(|_| {
+            let socket = UdpSocket::bind(unspecified)?;
+            socket.connect(via)?;
+            socket.set_read_timeout(Some(POLL))?;
+            Ok(socket)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let started = Instant::now();
+    let state = Mutex::new(State {
+        clients: (0..clients)
+            .map(|i| Client {
+                socket: i % sockets.len(),
+                sender: 0,
+                receiver: None,
+                due: started + ramp * i as u32 / clients as u32,
+                counter: 0,
+            })
+            .collect(),
+        initiated: HashMap::new(),
+        schedule: BinaryHeap::new(),
+        counts: Counts::default(),
+    });
+    {
+        let state = &mut *state.lock().recover();
+        for (i, client) in state.clients.iter().enumerate() {
+            state.schedule.push(Reverse((client.due, i)));
+        }
+    }
+    let done = AtomicBool::new(false);
+    let mut total = Counts::default();
+    println!(
+        "soak: {} clients on {} sockets through {} for {}s",
+        clients,
+        sockets.len(),
+        via,
+        duration.as_secs()
+    );
+    thread::scope(|scope| -> Result<()> {
+        scope.spawn(|| answer(&target, &done));
+        for socket in &sockets {
+            scope.spawn(|| receive(socket, &state, &done, started, rehandshake));
+        }
+        let result = send(
+            &sockets,
+            &state,
+            started,
+            duration,
+            rate,
+            report_every,
+            &mut total,
+        );
+        done.store(true, Ordering::Relaxed);
+        result
+    })?;
+    let state = state.into_inner().recover();
+    let up = state
+        .clients
+        .iter()
+        .filter(|c| c.receiver.is_some())
+        .count();
+    total.add(&state.counts);
+    let loss = total.loss();
+    println!(
+        "in all: {} of {} clients had a session, {}",
+        up,
+        clients,
+        total.describe()
+    );
+    match loss <= max_loss {
+        true => println!("pass  soak: lost {:.2}%", loss),
+        false => println!(
+            "FAIL  soak: lost {:.2}%, more than --max-loss {}%",
+            loss, max_loss
+        ),
+    }
+    Ok(loss <= max_loss)
+}
+
+/// the clients' side: handshakes when they are due and data at rate, until duration is up
+fn send(
+    sockets: &[UdpSocket],
+    state: &Mutex<State>,
+    started: Instant,
+    duration: Duration,
+    rate: u64,
+    report_every: Duration,
+    total: &mut Counts,
+) -> Result<()> {
+    let between = (rate > 0).then(|| Duration::from_secs(1) / rate.min(1_000_000_000) as u32);
+    let mut next_data = started;
+    let mut next_report = started + report_every;
+    let mut turn = 0;
+    loop {
+        let now = Instant::now();
+        if now >= started + duration {
+            return Ok(());
+        }
+        if now >= next_report {
+            let state = &mut *state.lock().recover();
+            let up = state
+                .clients
+                .iter()
+                .filter(|c| c.receiver.is_some())
+                .count();
+            let mut counts = std::mem::take(&mut state.counts);
+            println!(
+                "{}s: {} clients have a session, {}",
+                (now - started).as_secs(),
+                up,
+                counts.describe()
+            );
+            total.add(&counts);
+            next_report += report_every;
+        }
+        let mut wake = next_report.min(started + duration);
+        {
+            let state = &mut *state.lock().recover();
+            while let Some(Reverse((due, i))) = state.schedule.peek().copied() {
+                if due > now {
+                    wake = wake.min(due);
+                    break;
+                }
+                state.schedule.pop();
+                if state.clients[i].due != due {
+                    continue;
+                }
+                let client = &mut state.clients[i];
+                state.initiated.remove(&client.sender);
+                client.sender = random_u64() as u32;
+                client.due = now + REKEY_TIMEOUT;
+                let mut initiation = [0; INITIATION_LEN];
+                initiation[0] = 1;
+                initiation[4..8].copy_from_slice(&client.sender.to_le_bytes());
+                ignore_refused(sockets[client.socket].send(&initiation))?;
+                state.initiated.insert(client.sender, i);
+                state.schedule.push(Reverse((client.due, i)));
+                state.counts.handshakes += 1;
+            }
+        }
+        if let Some(between) = between {
+            if now >= next_data {
+                let state = &mut *state.lock().recover();
+                let len = state.clients.len();
+                // the next client with a session, if any has one
+                if let Some(i) = (0..len)
+                    .map(|n| (turn + n) % len)
+                    .find(|i| state.clients[*i].receiver.is_some())
+                {
+                    let client = &mut state.clients[i];
+                    let mut data = [0; DATA_LEN];
+                    data[0] = 4;
+                    data[4..8].copy_from_slice(&client.receiver.unwrap_or(0).to_le_bytes());
+                    data[8..16].copy_from_slice(&client.counter.to_le_bytes());
+                    data[16..20].copy_from_slice(&client.sender.to_le_bytes());
+                    data[20..24].copy_from_slice(&(i as u32).to_le_bytes());
+                    let sent = (now - started).as_micros() as u64;
+                    data[24..32].copy_from_slice(&sent.to_le_bytes());
+                    client.counter += 1;
+                    ignore_refused(sockets[client.socket].send(&data))?;
+                    state.counts.sent += 1;
+                    turn = i + 1;
+                }
+                next_data += between;
+                // don't make up for time we fell behind by all at once
+                next_data = next_data.max(now - between * 10);
+            }
+            wake = wake.min(next_data);
+        }
+        if let Some(wait) = wake.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// what comes back to the clients on socket
+fn receive(
+    socket: &UdpSocket,
+    state: &Mutex<State>,
+    done: &AtomicBool,
+    started: Instant,
+    rehandshake: Duration,
+) {
+    let mut buf = [0; 1500];
+    while !done.load(Ordering::Relaxed) {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(_) => continue,
+        };
+        let state = &mut *state.lock().recover();
+        match WgPacket::parse(&buf[..len]) {
+            Some(WgPacket::HandShakeResponse { sender, receiver }) if len == RESPONSE_LEN => {
+                let Some(i) = state.initiated.remove(&receiver) else {
+                    continue;
+                };
+                let client = &mut state.clients[i];
+                client.receiver = Some(sender);
+                client.due = Instant::now() + rehandshake;
+                state.schedule.push(Reverse((client.due, i)));
+                state.counts.answered += 1;
+            }
+            Some(WgPacket::Data { .. }) if len == DATA_LEN => {
+                let at =
+                    |n: usize| u32::from_le_bytes(buf[n..n + 4].try_into().unwrap_or_default());
+                let sent = u64::from_le_bytes(buf[24..32].try_into().unwrap_or_default());
+                if state.clients.get(at(20) as usize).is_none() {
+                    continue;
+                }
+                let rtt = (started.elapsed().as_micros() as u64).saturating_sub(sent);
+                state.counts.back += 1;
+                state.counts.rtts.push(rtt.min(u32::MAX as u64) as u32);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// the target's side: a response to every initiation, every data packet back to who sent it
+fn answer(socket: &UdpSocket, done: &AtomicBool) {
+    let mut buf = [0; 1500];
+    while !done.load(Ordering::Relaxed) {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        match WgPacket::parse(&buf[..len]) {
+            Some(WgPacket::HandShakeInitiation { sender }) if len == INITIATION_LEN => {
+                let mut response = [0; RESPONSE_LEN];
+                response[0] = 2;
+                response[4..8].copy_from_slice(&(random_u64() as u32).to_le_bytes());
+                response[8..12].copy_from_slice(&sender.to_le_bytes());
+                let _ = socket.send_to(&response, from);
+            }
+            Some(WgPacket::Data { .. }) if len == DATA_LEN => {
+                // to the client's sender index, which says where the proxy sends it
+                let mut data = buf;
+                data[4..8].copy_from_slice(&buf[16..20]);
+                let _ = socket.send_to(&data[..DATA_LEN], from);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// a proxy that isn't there yet, or restarting, shouldn't end the soak
+fn ignore_refused(result: Result<usize>) -> Result<()> {
+    match result {
+        Err(e) if e.kind() != ErrorKind::ConnectionRefused => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let mut counts = Counts {
+            handshakes: 10,
+            answered: 9,
+            sent: 90,
+            back: 89,
+            rtts: (1..=100).rev().map(|ms| ms * 1000).collect(),
+        };
+        assert_eq!(counts.loss(), 2.0);
+        assert_eq!(
+            counts.describe(),
+            "9 of 10 handshakes answered, 89 of 90 packets back, rtt p50 51.0ms p99 100.0ms"
+        );
+        counts.add(&Counts::default());
+        assert_eq!(Counts::default().loss(), 0.0);
+    }
+}
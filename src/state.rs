@@ -0,0 +1,69 @@
+// --state-file, what should outlive a restart, for now the cumulative counters so long term
+// dashboards don't start over every time we do
+//
+// a line per counter, "name value", written to a temporary file next to it and renamed over it so a
+// crash halfway through leaves the previous one
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind, Result},
+    time::Duration,
+};
+
+/// how often the state file is rewritten, what was counted since is lost with the process
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the counters saved to path, none if it doesn't exist yet
+pub fn load(path: &str) -> Result<HashMap<String, u64>> {
+    let contents = match fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        contents => contents?,
+    };
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(' ')
+                .and_then(|(name, value)| Some((name.to_string(), value.parse().ok()?)))
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid line in {}: {}", path, line),
+                    )
+                })
+        })
+        .collect()
+}
+
+pub fn save(path: &str, counters: &[(&str, u64)]) -> Result<()> {
+    let mut contents = String::from("# wireguard-udp-proxy counters\n");
+    for (name, value) in counters {
+        contents.push_str(&format!("{} {}\n", name, value));
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let path = std::env::temp_dir().join(format!("wgup-state-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(load(path).unwrap().is_empty());
+
+        save(path, &[("packets", 5), ("bytes", 1000)]).unwrap();
+        let counters = load(path).unwrap();
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters["packets"], 5);
+        assert_eq!(counters["bytes"], 1000);
+
+        fs::write(path, "packets five\n").unwrap();
+        assert_eq!(load(path).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+}
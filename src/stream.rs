@@ -0,0 +1,707 @@
+// --tcp-target and --tcp-clients, for networks that let nothing but TCP through: packets between
+// two of us go over a TCP connection per client, each with its length in front, in TLS with --tls
+//
+// a stalled connection only holds up its own client, packets for it wait in a queue of QUEUE_LEN
+// and beyond that are dropped, as they would be on a congested path
+//
+// or with --tcp-mux every client shares one connection, see mux.rs, the far end takes either
+//
+// control.rs's heartbeats go along on either kind, so a connection to a far end that's gone ends
+// in DEAD_AFTER rather than whenever TCP gives up
+//
+// whatever is queued by the time a write starts goes in that write, up to COALESCE, so a handful
+// of keepalives takes one segment and one TLS record rather than one each
+
+use crate::{
+    config::Target,
+    control::{self, Message, Peer, DEAD_AFTER, HEARTBEAT},
+    mux::{Frame, Mux, PREFACE},
+    poison::Recover,
+    WgPacket,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::{Tls, TlsStream};
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Result, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// after a connection failed, before another is tried
+const RETRY: Duration = Duration::from_secs(1);
+
+// a connection nothing went through for this long is closed
+const IDLE_TIME: Duration = Duration::from_secs(60);
+
+// packets waiting to be written to a connection, beyond that they are dropped
+const QUEUE_LEN: usize = 64;
+
+// bytes of packets written together, a TLS record's worth
+pub const COALESCE: usize = 16 * 1024;
+
+/// a connection between two of us, plain or in TLS, which the TLS state or the lock keeps
+/// threads from writing into the middle of each other's packets
+pub enum Conn {
+    Tcp(TcpStream, Mutex<()>),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Conn {
+    fn tcp(socket: TcpStream) -> Conn {
+        Conn::Tcp(socket, Mutex::new(()))
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Conn::Tcp(socket, _) => socket,
+            #[cfg(feature = "tls")]
+            Conn::Tls(tls) => tls.socket(),
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Conn::Tcp(socket, _) => Read::read(&mut &*socket, buf),
+            #[cfg(feature = "tls")]
+            Conn::Tls(tls) => tls.read(buf),
+        }
+    }
+
+    /// packet with its length in front
+    pub fn send(&self, packet: &[u8]) -> Result<()> {
+        self.send_all(&[packet])
+    }
+
+    /// packets in one write
+    pub fn send_all<P: AsRef<[u8]>>(&self, packets: &[P]) -> Result<()> {
+        let len = packets.iter().map(|p| 2 + p.as_ref().len()).sum();
+        let mut frame = Vec::with_capacity(len);
+        for packet in packets {
+            let packet = packet.as_ref();
+            frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            frame.extend_from_slice(packet);
+        }
+        match self {
+            Conn::Tcp(socket, writing) => {
+                let _writing = writing.lock().recover();
+                Write::write_all(&mut &*socket, &frame)
+            }
+            #[cfg(feature = "tls")]
+            Conn::Tls(tls) => tls.write_all(&frame),
+        }
+    }
+
+    /// hands each packet that comes to packet, until the connection ends
+    pub fn recv(&self, mut packet: impl FnMut(&[u8])) -> Result<()> {
+        let mut frames = Frames::default();
+        let mut buf = [0u8; 65536];
+        loop {
+            let read = self.read(&mut buf)?;
+            if read == 0 {
+                return Ok(());
+            }
+            frames.extend(&buf[..read]);
+            while let Some(frame) = frames.next() {
+                packet(&frame);
+            }
+        }
+    }
+
+    pub fn close(&self) {
+        let _ = self.socket().shutdown(Shutdown::Both);
+    }
+}
+
+/// packets with their lengths in front, taken back apart from however the bytes came
+#[derive(Default)]
+struct Frames {
+    buf: Vec<u8>,
+}
+
+impl Frames {
+    fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*self.buf.first()?, *self.buf.get(1)?]) as usize;
+        if self.buf.len() < 2 + len {
+            return None;
+        }
+        let frame = self.buf[2..2 + len].to_vec();
+        self.buf.drain(..2 + len);
+        Some(frame)
+    }
+}
+
+/// which connection a client's packets go to, and the queue for it
+type Session = (u64, SyncSender<Vec<u8>>);
+
+/// with --tcp-mux, the connection every client shares and which stream each is
+#[derive(Default)]
+struct Shared {
+    mux: Option<Arc<Mux>>,
+    connecting: bool,
+    streams: HashMap<SocketAddr, u32>,
+    clients: HashMap<u32, SocketAddr>,
+    next_stream: u32,
+}
+
+/// the end wireguard clients talk to, a connection to target for each, or one for all with mux
+pub struct Near {
+    local: UdpSocket,
+    target: Target,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    mux: bool,
+    verbose: bool,
+    sessions: Mutex<HashMap<SocketAddr, Session>>,
+    shared: Mutex<Shared>,
+}
+
+impl Near {
+    pub fn new(
+        local: UdpSocket,
+        target: Target,
+        #[cfg(feature = "tls")] tls: Option<Tls>,
+        mux: bool,
+        verbose: bool,
+    ) -> Near {
+        Near {
+            local,
+            target,
+            #[cfg(feature = "tls")]
+            tls,
+            mux,
+            verbose,
+            sessions: Mutex::new(HashMap::new()),
+            shared: Mutex::new(Shared::default()),
+        }
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        if self.mux {
+            return self.run_mux();
+        }
+        let mut buf = [0u8; 65536];
+        let mut next_id = 0;
+        loop {
+            let (recv, client) = self.local.recv_from(&mut buf)?;
+            let packet = &buf[..recv];
+            if WgPacket::parse(packet).is_none() {
+                continue;
+            }
+            let mut sessions = self.sessions.lock().recover();
+            let (_, queue) = sessions.entry(client).or_insert_with(|| {
+                next_id += 1;
+                let id = next_id;
+                let (queue, packets) = mpsc::sync_channel(QUEUE_LEN);
+                thread::spawn(move || {
+                    if let Err(e) = self.connect(client, &packets) {
+                        if self.verbose {
+                            eprintln!("connection to {} for {}: {}", self.target.addr, client, e);
+                        }
+                        // packets meanwhile fill the queue and are dropped, rather than each
+                        // trying again
+                        thread::sleep(RETRY);
+                    }
+                    let mut sessions = self.sessions.lock().recover();
+                    if sessions
+                        .get(&client)
+                        .is_some_and(|(current, _)| *current == id)
+                    {
+                        sessions.remove(&client);
+                    }
+                });
+                (id, queue)
+            });
+            match queue.try_send(packet.to_vec()) {
+                // a full queue drops, a closed one takes the next packet to start over
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => {
+                    sessions.remove(&client);
+                }
+            }
+        }
+    }
+
+    fn open(&self) -> Result<Conn> {
+        let socket = TcpStream::connect_timeout(&self.target.addr, CONNECT_TIMEOUT)?;
+        socket.set_nodelay(true)?;
+        #[cfg(feature = "tls")]
+        let conn = match &self.tls {
+            Some(tls) => Conn::Tls(Box::new(TlsStream::connect(
+                tls,
+                socket,
+                &self.target.host,
+            )?)),
+            None => Conn::tcp(socket),
+        };
+        #[cfg(not(feature = "tls"))]
+        let conn = Conn::tcp(socket);
+        Ok(conn)
+    }
+
+    /// a connection for client, writing its packets to it and passing what comes back
+    fn connect(&'static self, client: SocketAddr, packets: &Receiver<Vec<u8>>) -> Result<()> {
+        let conn = Arc::new(self.open()?);
+        if self.verbose {
+            eprintln!("connected to {} for {}", self.target.addr, client);
+        }
+        conn.send(&control::hello())?;
+        let reader = Arc::clone(&conn);
+        thread::spawn(move || {
+            let mut peer = Peer::new(&self.target.host, self.verbose);
+            // the connection ending is for the writer to find out
+            let received = reader.recv(|packet| match Message::parse(packet) {
+                Some(message) => self.heard(&reader, &mut peer, &message),
+                None if control::is_control(packet) => {}
+                None => {
+                    let _ = self.local.send_to(packet, client);
+                }
+            });
+            self.ended(received);
+            reader.close();
+        });
+        let mut last = Instant::now();
+        let mut ping = Instant::now() + HEARTBEAT;
+        let written = loop {
+            let packet = match packets.recv_timeout(ping.saturating_duration_since(Instant::now()))
+            {
+                Ok(packet) => Some(packet),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            };
+            if let Some(packet) = packet {
+                last = Instant::now();
+                let mut len = packet.len();
+                let mut batch = vec![packet];
+                while len < COALESCE {
+                    let Ok(packet) = packets.try_recv() else {
+                        break;
+                    };
+                    len += packet.len();
+                    batch.push(packet);
+                }
+                if let Err(e) = conn.send_all(&batch) {
+                    break Err(e);
+                }
+            } else if last.elapsed() >= IDLE_TIME {
+                break Ok(());
+            }
+            if ping <= Instant::now() {
+                ping = Instant::now() + HEARTBEAT;
+                if let Err(e) = conn.send(&control::ping()) {
+                    break Err(e);
+                }
+            }
+        };
+        conn.close();
+        written
+    }
+
+    /// a control message from the far end, which once it says hello must keep talking
+    fn heard(&self, conn: &Conn, peer: &mut Peer, message: &Message) {
+        if let Message::Hello { .. } = message {
+            let _ = conn.socket().set_read_timeout(Some(DEAD_AFTER));
+        }
+        peer.heard(message, control::now());
+    }
+
+    /// reading the connection ended like received, saying so if that's the far end being gone
+    fn ended(&self, received: Result<()>) {
+        if let Err(e) = received {
+            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+                eprintln!(
+                    "peer proxy {} unreachable, nothing from it for {}s",
+                    self.target.host,
+                    DEAD_AFTER.as_secs()
+                );
+            }
+        }
+    }
+
+    /// every client as a stream of the one connection, packets dropped while there is none
+    fn run_mux(&'static self) -> Result<()> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (recv, client) = self.local.recv_from(&mut buf)?;
+            let packet = &buf[..recv];
+            if WgPacket::parse(packet).is_none() {
+                continue;
+            }
+            let mut shared = self.shared.lock().recover();
+            if shared.mux.as_ref().is_some_and(|mux| mux.is_closed()) {
+                *shared = Shared::default();
+            }
+            let Some(mux) = shared.mux.clone() else {
+                if !shared.connecting {
+                    shared.connecting = true;
+                    thread::spawn(move || self.connect_mux());
+                }
+                continue;
+            };
+            let stream = match shared.streams.get(&client) {
+                Some(stream) => *stream,
+                None => {
+                    shared.next_stream += 1;
+                    let stream = shared.next_stream;
+                    shared.streams.insert(client, stream);
+                    shared.clients.insert(stream, client);
+                    stream
+                }
+            };
+            mux.send(stream, packet);
+        }
+    }
+
+    /// the shared connection, passing what comes back on each stream to its client until it ends
+    fn connect_mux(&'static self) {
+        let conn = match self.open().and_then(|conn| {
+            conn.send(PREFACE)?;
+            conn.send(&control::hello())?;
+            Ok(Arc::new(conn))
+        }) {
+            Ok(conn) => conn,
+            Err(e) => {
+                if self.verbose {
+                    eprintln!("connection to {}: {}", self.target.addr, e);
+                }
+                thread::sleep(RETRY);
+                self.shared.lock().recover().connecting = false;
+                return;
+            }
+        };
+        if self.verbose {
+            eprintln!("connected to {}", self.target.addr);
+        }
+        let mux = Mux::new(Arc::clone(&conn));
+        {
+            let mut shared = self.shared.lock().recover();
+            shared.mux = Some(Arc::clone(&mux));
+            shared.connecting = false;
+        }
+        let pinger = Arc::clone(&mux);
+        let pings = Arc::clone(&conn);
+        thread::spawn(move || {
+            while !pinger.is_closed() && pings.send(&control::ping()).is_ok() {
+                thread::sleep(HEARTBEAT);
+            }
+        });
+        let mut peer = Peer::new(&self.target.host, self.verbose);
+        let received = conn.recv(|frame| match Frame::parse(frame) {
+            _ if control::is_control(frame) => {
+                if let Some(message) = Message::parse(frame) {
+                    self.heard(&conn, &mut peer, &message);
+                }
+            }
+            Some(Frame::Data(stream, packet)) => {
+                let shared = self.shared.lock().recover();
+                if let Some(client) = shared.clients.get(&stream) {
+                    let _ = self.local.send_to(packet, client);
+                    mux.received(stream, packet.len());
+                }
+            }
+            Some(Frame::Credit(stream, credit)) => mux.credit(stream, credit),
+            Some(Frame::Close(stream)) => {
+                let mut shared = self.shared.lock().recover();
+                if let Some(client) = shared.clients.remove(&stream) {
+                    shared.streams.remove(&client);
+                }
+                mux.close(stream, false);
+            }
+            None => {}
+        });
+        self.ended(received);
+        if self.verbose {
+            eprintln!("connection to {} ended", self.target.addr);
+        }
+        // the next packet finds it closed and starts over
+        mux.shutdown();
+    }
+}
+
+/// with --tcp-mux, a stream's socket to the target
+struct FarStream {
+    socket: UdpSocket,
+    closed: AtomicBool,
+}
+
+/// takes connections on bind_addr, passing the packets in them to target and its packets back
+pub struct Far {
+    listener: TcpListener,
+    target: SocketAddr,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    verbose: bool,
+}
+
+impl Far {
+    pub fn new(
+        bind: SocketAddr,
+        target: SocketAddr,
+        #[cfg(feature = "tls")] tls: Option<Tls>,
+        verbose: bool,
+    ) -> Result<Far> {
+        Ok(Far {
+            listener: TcpListener::bind(bind)?,
+            target,
+            #[cfg(feature = "tls")]
+            tls,
+            verbose,
+        })
+    }
+
+    pub fn run(&'static self) -> Result<()> {
+        loop {
+            let (socket, peer) = self.listener.accept()?;
+            thread::spawn(move || {
+                if let Err(e) = self.serve(socket, peer) {
+                    if self.verbose {
+                        eprintln!("connection from {}: {}", peer, e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// passes what comes on socket to the target, and what it sends back
+    fn serve(&'static self, socket: TcpStream, peer: SocketAddr) -> Result<()> {
+        socket.set_nodelay(true)?;
+        #[cfg(feature = "tls")]
+        let conn = match &self.tls {
+            Some(tls) => {
+                // a handshake that never comes shouldn't keep a thread forever
+                socket.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+                let conn = TlsStream::accept(tls, socket)?;
+                conn.socket().set_read_timeout(None)?;
+                Conn::Tls(Box::new(conn))
+            }
+            None => Conn::tcp(socket),
+        };
+        #[cfg(not(feature = "tls"))]
+        let conn = Conn::tcp(socket);
+        if self.verbose {
+            eprintln!("connection from {}", peer);
+        }
+        let conn = Arc::new(conn);
+        let ended = Arc::new(AtomicBool::new(false));
+        let refused = Arc::new(AtomicBool::new(false));
+        let mut first = true;
+        let mut mux = None;
+        let mut udp_socket = None;
+        let mut failed = None;
+        let mut streams = HashMap::new();
+        let received = conn.recv(|frame| {
+            if control::is_control(frame) {
+                let Some(message) = Message::parse(frame) else {
+                    return;
+                };
+                if let Message::Hello { .. } = message {
+                    // it pings from now on
+                    let _ = conn.socket().set_read_timeout(Some(DEAD_AFTER));
+                }
+                if let Some(answer) = control::answer(&message, refused.load(Ordering::Relaxed)) {
+                    if let Err(e) = conn.send(&answer) {
+                        failed = Some(e);
+                        conn.close();
+                    }
+                }
+                return;
+            }
+            if std::mem::take(&mut first) && frame == PREFACE {
+                mux = Some(Mux::new(Arc::clone(&conn)));
+                return;
+            }
+            let done = match &mux {
+                Some(mux) => self.demux(mux, &refused, &mut streams, frame),
+                None => self.plain(&conn, &ended, &refused, &mut udp_socket, frame),
+            };
+            if let Err(e) = done {
+                failed = Some(e);
+                conn.close();
+            }
+        });
+        ended.store(true, Ordering::Relaxed);
+        for stream in streams.values() {
+            stream.closed.store(true, Ordering::Relaxed);
+        }
+        match mux {
+            Some(mux) => mux.shutdown(),
+            None => conn.close(),
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => received,
+        }
+    }
+
+    /// a socket connected to the target
+    fn to_target(&self) -> Result<UdpSocket> {
+        let udp_socket = UdpSocket::bind(if self.target.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        })?;
+        udp_socket.connect(self.target)?;
+        // often enough to notice the connection ended
+        udp_socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(udp_socket)
+    }
+
+    /// a connection with a client of its own, its socket and the thread writing back from the
+    /// first packet on
+    fn plain(
+        &self,
+        conn: &Arc<Conn>,
+        ended: &Arc<AtomicBool>,
+        refused: &Arc<AtomicBool>,
+        udp_socket: &mut Option<Arc<UdpSocket>>,
+        packet: &[u8],
+    ) -> Result<()> {
+        if udp_socket.is_none() {
+            let socket = Arc::new(self.to_target()?);
+            let writer = Arc::clone(conn);
+            let from_target = Arc::clone(&socket);
+            let writer_ended = Arc::clone(ended);
+            let refused = Arc::clone(refused);
+            thread::spawn(move || {
+                let mut buf = [0u8; 65536];
+                while !writer_ended.load(Ordering::Relaxed) {
+                    let Some(recv) = recv_target(&from_target, &mut buf, &refused) else {
+                        continue;
+                    };
+                    if writer.send(&buf[..recv]).is_err() {
+                        break;
+                    }
+                }
+                writer.close();
+            });
+            *udp_socket = Some(socket);
+        }
+        if let Some(udp_socket) = udp_socket {
+            // the target being down is its business
+            let _ = udp_socket.send(packet);
+        }
+        Ok(())
+    }
+
+    /// a frame of a shared connection, a stream's socket from its first packet on, until the
+    /// target has sent nothing for IDLE_TIME
+    fn demux(
+        &self,
+        mux: &Arc<Mux>,
+        refused: &Arc<AtomicBool>,
+        streams: &mut HashMap<u32, Arc<FarStream>>,
+        frame: &[u8],
+    ) -> Result<()> {
+        let (id, packet) = match Frame::parse(frame) {
+            Some(Frame::Data(id, packet)) => (id, packet),
+            Some(Frame::Credit(id, credit)) => {
+                mux.credit(id, credit);
+                return Ok(());
+            }
+            Some(Frame::Close(id)) => {
+                if let Some(stream) = streams.remove(&id) {
+                    stream.closed.store(true, Ordering::Relaxed);
+                }
+                mux.close(id, false);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        let stream = match streams.get(&id) {
+            Some(stream) if !stream.closed.load(Ordering::Relaxed) => Arc::clone(stream),
+            // new, or one the target went quiet on, which this packet starts over
+            _ => {
+                let stream = self.open_stream(mux, refused, id)?;
+                streams.insert(id, Arc::clone(&stream));
+                stream
+            }
+        };
+        let _ = stream.socket.send(packet);
+        mux.received(id, packet.len());
+        Ok(())
+    }
+
+    /// a stream's socket, and the thread writing what the target sends back to it
+    fn open_stream(
+        &self,
+        mux: &Arc<Mux>,
+        refused: &Arc<AtomicBool>,
+        id: u32,
+    ) -> Result<Arc<FarStream>> {
+        let stream = Arc::new(FarStream {
+            socket: self.to_target()?,
+            closed: AtomicBool::new(false),
+        });
+        let writer = Arc::clone(mux);
+        let from_target = Arc::clone(&stream);
+        let refused = Arc::clone(refused);
+        thread::spawn(move || {
+            let mut buf = [0u8; 65536];
+            let mut last = Instant::now();
+            while !from_target.closed.load(Ordering::Relaxed) && !writer.is_closed() {
+                if last.elapsed() > IDLE_TIME {
+                    from_target.closed.store(true, Ordering::Relaxed);
+                    writer.close(id, true);
+                    break;
+                }
+                let Some(recv) = recv_target(&from_target.socket, &mut buf, &refused) else {
+                    continue;
+                };
+                last = Instant::now();
+                // past its queue it's dropped, like any congested path
+                writer.send(id, &buf[..recv]);
+            }
+        });
+        Ok(stream)
+    }
+}
+
+/// what the target sent, None if that timed out or it refused an earlier packet, which refused
+/// keeps until it answers again, for the far end's pongs
+fn recv_target(socket: &UdpSocket, buf: &mut [u8], refused: &AtomicBool) -> Option<usize> {
+    match socket.recv(buf) {
+        Ok(recv) => {
+            refused.store(false, Ordering::Relaxed);
+            Some(recv)
+        }
+        Err(e) => {
+            if e.kind() == ErrorKind::ConnectionRefused {
+                refused.store(true, Ordering::Relaxed);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut frames = Frames::default();
+        frames.extend(&[0, 3, 1, 2]);
+        assert_eq!(frames.next(), None);
+        frames.extend(&[3, 0, 0, 0, 1]);
+        assert_eq!(frames.next(), Some(vec![1, 2, 3]));
+        assert_eq!(frames.next(), Some(Vec::new()));
+        assert_eq!(frames.next(), None);
+        frames.extend(&[9]);
+        assert_eq!(frames.next(), Some(vec![9]));
+    }
+}
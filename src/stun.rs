@@ -0,0 +1,136 @@
+// just enough of STUN (RFC 5389) to answer binding requests, so clients can learn their public
+// address/port as seen by us without a separate STUN server
+//
+// stun messages start with 0b00, wireguard message types are 1-4 followed by 3 zero bytes, so there
+// is no way to confuse the two
+
+use crate::{datagram::Datagram, packet, pktinfo::LocalAddr};
+
+use std::{
+    io::Result,
+    net::{IpAddr, SocketAddr},
+};
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const HEADER_LEN: usize = 20;
+
+/// big enough for a binding response carrying an IPv6 address
+pub const MAX_RESPONSE_LEN: usize = HEADER_LEN + 4 + 20;
+
+/// if request is a binding request, writes the response telling src_addr its address into response
+/// and returns its length
+pub fn binding_response(
+    request: &[u8],
+    src_addr: SocketAddr,
+    response: &mut [u8],
+) -> Option<usize> {
+    if request.len() < HEADER_LEN
+        || u16::from_be_bytes([request[0], request[1]]) != BINDING_REQUEST
+        || u32::from_be_bytes(packet::bytes(request, 4)) != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let length = u16::from_be_bytes([request[2], request[3]]) as usize;
+    if !length.is_multiple_of(4) || HEADER_LEN + length != request.len() {
+        return None;
+    }
+    let transaction_id = &request[8..HEADER_LEN];
+
+    // report v4-mapped addresses from a dual stack socket as the v4 address they really are
+    let ip = match src_addr.ip() {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    };
+    let (family, addr_len) = match ip {
+        IpAddr::V4(_) => (1, 4),
+        IpAddr::V6(_) => (2, 16),
+    };
+    let attr_len = 4 + addr_len;
+    let len = HEADER_LEN + 4 + attr_len;
+    let response = &mut response[..len];
+
+    response[0..2].copy_from_slice(&BINDING_RESPONSE.to_be_bytes());
+    response[2..4].copy_from_slice(&((4 + attr_len) as u16).to_be_bytes());
+    response[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    response[8..HEADER_LEN].copy_from_slice(transaction_id);
+
+    let attr = &mut response[HEADER_LEN..];
+    attr[0..2].copy_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    attr[2..4].copy_from_slice(&(attr_len as u16).to_be_bytes());
+    attr[4] = 0;
+    attr[5] = family;
+    attr[6..8].copy_from_slice(&(src_addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    // the address is xored with the magic cookie followed by the transaction id
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..].copy_from_slice(transaction_id);
+    let mut octets = [0u8; 16];
+    match ip {
+        IpAddr::V4(ip) => octets[..4].copy_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => octets = ip.octets(),
+    }
+    for (out, (octet, key)) in attr[8..].iter_mut().zip(octets.iter().zip(key)) {
+        *out = octet ^ key;
+    }
+
+    Some(len)
+}
+
+/// answers request if it is a binding request, returns whether it was
+pub fn respond(
+    socket: &impl Datagram,
+    request: &[u8],
+    src_addr: SocketAddr,
+    local_addr: Option<LocalAddr>,
+) -> Result<bool> {
+    let mut response = [0u8; MAX_RESPONSE_LEN];
+    match binding_response(request, src_addr, &mut response) {
+        None => Ok(false),
+        Some(len) => {
+            socket.send(&response[..len], src_addr, local_addr, None)?;
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binding_response() {
+        let mut request = [0u8; HEADER_LEN];
+        request[1] = 1;
+        request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        request[8..].copy_from_slice(b"transaction!");
+
+        let mut response = [0u8; MAX_RESPONSE_LEN];
+        let src_addr = "[::ffff:192.0.2.1]:32853".parse().unwrap();
+        let len = binding_response(&request, src_addr, &mut response).unwrap();
+        assert_eq!(len, 32);
+        assert_eq!(response[..4], [0x01, 0x01, 0, 12]);
+        assert_eq!(response[8..HEADER_LEN], *b"transaction!");
+        // example values from RFC 5769 section 2.2
+        assert_eq!(
+            response[HEADER_LEN..len],
+            [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]
+        );
+
+        let len = binding_response(&request, "[2001:db8::1]:1".parse().unwrap(), &mut response);
+        assert_eq!(len, Some(MAX_RESPONSE_LEN));
+
+        assert_eq!(
+            binding_response(&request[..19], src_addr, &mut response),
+            None
+        );
+        request[1] = 4;
+        assert_eq!(binding_response(&request, src_addr, &mut response), None);
+    }
+}
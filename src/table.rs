@@ -0,0 +1,168 @@
+// the tables sessions are kept in, a HashMap that grows as needed, or for --small an array that
+// is allocated once and never grows, found by a linear scan, which is quick for the few dozen
+// peers a router has and costs no memory beyond the entries themselves
+//
+// a full array refuses new keys, whoever inserts decides what to drop to make room
+
+use crate::memory;
+
+use std::{
+    collections::{hash_map, HashMap},
+    hash::Hash,
+    slice,
+};
+
+#[derive(Debug)]
+pub enum Table<K, V> {
+    Hashed(HashMap<K, V>),
+    Fixed {
+        entries: Vec<(K, V)>,
+        capacity: usize,
+    },
+}
+
+impl<K, V> Default for Table<K, V> {
+    fn default() -> Self {
+        Table::Hashed(HashMap::new())
+    }
+}
+
+impl<K: Eq + Hash, V> Table<K, V> {
+    /// an array of capacity entries, allocated now
+    pub fn fixed(capacity: usize) -> Self {
+        Table::Fixed {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Table::Hashed(map) => map.len(),
+            Table::Fixed { entries, .. } => entries.len(),
+        }
+    }
+
+    /// approximately what the entries take, as memory::table counts them
+    pub fn memory(&self) -> usize {
+        memory::table::<K, V>(self.len())
+    }
+
+    /// whether inserting a new key would fail
+    pub fn is_full(&self) -> bool {
+        match self {
+            Table::Hashed(_) => false,
+            Table::Fixed { entries, capacity } => entries.len() == *capacity,
+        }
+    }
+
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        match self {
+            Table::Hashed(map) => map.get_key_value(key),
+            Table::Fixed { entries, .. } => {
+                entries.iter().find(|(k, _)| k == key).map(|(k, v)| (k, v))
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        match self {
+            Table::Hashed(map) => map.get_mut(key),
+            Table::Fixed { entries, .. } => {
+                entries.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// inserts or replaces key's value, false if key is new and the table full
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        match self {
+            Table::Hashed(map) => {
+                map.insert(key, value);
+            }
+            Table::Fixed { entries, capacity } => {
+                match entries.iter().position(|(k, _)| *k == key) {
+                    Some(i) => entries[i].1 = value,
+                    None if entries.len() == *capacity => return false,
+                    None => entries.push((key, value)),
+                }
+            }
+        }
+        true
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        match self {
+            Table::Hashed(map) => map.remove(key),
+            Table::Fixed { entries, .. } => {
+                let i = entries.iter().position(|(k, _)| k == key)?;
+                Some(entries.swap_remove(i).1)
+            }
+        }
+    }
+
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut keep: F) {
+        match self {
+            Table::Hashed(map) => map.retain(keep),
+            Table::Fixed { entries, .. } => entries.retain_mut(|(k, v)| keep(k, v)),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        match self {
+            Table::Hashed(map) => Iter::Hashed(map.iter()),
+            Table::Fixed { entries, .. } => Iter::Fixed(entries.iter()),
+        }
+    }
+
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+pub enum Iter<'a, K, V> {
+    Hashed(hash_map::Iter<'a, K, V>),
+    Fixed(slice::Iter<'a, (K, V)>),
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Hashed(iter) => iter.next(),
+            Iter::Fixed(iter) => iter.next().map(|(k, v)| (k, v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed() {
+        let mut table = Table::fixed(2);
+        assert!(table.insert(1, "a"));
+        assert!(table.insert(2, "b"));
+        assert!(table.is_full());
+        assert!(!table.insert(3, "c"));
+        // replacing needs no room
+        assert!(table.insert(1, "A"));
+        assert_eq!(table.get(&1), Some(&"A"));
+        assert_eq!(table.remove(&1), Some("A"));
+        assert!(table.insert(3, "c"));
+        table.retain(|k, _| *k == 3);
+        assert_eq!(table.iter().collect::<Vec<_>>(), [(&3, &"c")]);
+    }
+}
@@ -0,0 +1,372 @@
+// the targets we forward to, normally just the one from the command line, --schedule and --failover
+// add more and switch which one new sessions go to
+//
+// --max-sessions caps how many clients may have a session with a target, new ones go to the
+// next target with room
+//
+// --blackhole-after counts handshake initiations we sent each target since its last response, once
+// there are too many in a row it's probably gone, or its name points somewhere else by now; only
+// those from clients it answered before count, it doesn't answer initiations that don't check out
+
+use crate::{
+    clock::{self, Tick},
+    config::Target,
+    pacer::{Pace, Pacer},
+};
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering::Relaxed},
+        Arc,
+    },
+    time::Duration,
+};
+
+struct Backend {
+    target: Target,
+    // whether --blackhole-after may fail over to it, and --max-sessions overflow to it
+    failover: bool,
+    max_sessions: Option<usize>,
+    // when we last switched away from it, its sessions are cut over a transition after that
+    retired: Option<Tick>,
+    // the transition switch-target gave it instead of --schedule-transition's
+    drain: Option<Duration>,
+    unanswered: AtomicU32,
+    // someone is already looking into it being blackholed
+    checking: AtomicBool,
+    // shared so senders can hold packets back on it without holding on to us
+    pacer: Option<Arc<Pacer>>,
+}
+
+pub struct Targets {
+    // the first one is the target from the command line, active until something switches
+    backends: Vec<Backend>,
+    active: usize,
+}
+
+impl Targets {
+    pub fn new(target: Target) -> Targets {
+        Targets {
+            backends: vec![Backend::new(target, true)],
+            active: 0,
+        }
+    }
+
+    /// adds another target unless we have one with that name already
+    pub fn add(&mut self, target: Target, failover: bool) {
+        match self
+            .backends
+            .iter_mut()
+            .find(|b| b.target.host == target.host)
+        {
+            Some(backend) => backend.failover |= failover,
+            None => self.backends.push(Backend::new(target, failover)),
+        }
+    }
+
+    /// where new sessions go
+    pub fn active(&self) -> SocketAddr {
+        self.backends[self.active].target.addr
+    }
+
+    /// the address of our target called name, as given on the command line or its address
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn find(&self, name: &str) -> Option<SocketAddr> {
+        self.named(name).map(|target| target.addr)
+    }
+
+    /// our target called name, as given on the command line or its address
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn named(&self, name: &str) -> Option<&Target> {
+        self.backends
+            .iter()
+            .find(|b| b.target.host == name || b.target.addr.to_string() == name)
+            .map(|b| &b.target)
+    }
+
+    /// whether addr is any of our targets, packets from it are never from a client
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.backends.iter().any(|b| b.target.addr == *addr)
+    }
+
+    /// caps the target named host at max_sessions clients, false if there is no such target
+    pub fn limit(&mut self, host: &str, max_sessions: usize) -> bool {
+        match self.backends.iter_mut().find(|b| b.target.host == host) {
+            Some(backend) => backend.max_sessions = Some(max_sessions),
+            None => return false,
+        }
+        true
+    }
+
+    /// paces packets to the target named host, false if there is no such target
+    pub fn pace(&mut self, host: &str, pace: Pace) -> bool {
+        match self.backends.iter_mut().find(|b| b.target.host == host) {
+            Some(backend) => backend.pacer = Some(Arc::new(Pacer::new(pace))),
+            None => return false,
+        }
+        true
+    }
+
+    /// the --pace of target addr, if it has one
+    pub fn pacer(&self, addr: &SocketAddr) -> Option<Arc<Pacer>> {
+        self.backends
+            .iter()
+            .find(|b| b.target.addr == *addr)
+            .and_then(|b| b.pacer.clone())
+    }
+
+    /// whether any target has --max-sessions
+    pub fn limited(&self) -> bool {
+        self.backends.iter().any(|b| b.max_sessions.is_some())
+    }
+
+    /// how many clients the targets new ones may go to take together, None if any has no
+    /// --max-sessions
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn capacity(&self) -> Option<usize> {
+        self.candidates()
+            .map(|(_, max_sessions)| max_sessions)
+            .sum()
+    }
+
+    /// where a new client may go in order of preference, the active target then the failover
+    /// targets after it, with their --max-sessions
+    pub fn candidates(&self) -> impl Iterator<Item = (SocketAddr, Option<usize>)> + '_ {
+        let len = self.backends.len();
+        (0..len)
+            .map(move |i| (i, &self.backends[(self.active + i) % len]))
+            .filter(|(i, b)| *i == 0 || (b.failover && b.retired.is_none()))
+            .map(|(_, b)| (b.target.addr, b.max_sessions))
+    }
+
+    /// whether there is more than the one target, sessions only need to remember theirs if so
+    pub fn several(&self) -> bool {
+        self.backends.len() > 1
+    }
+
+    /// whether sessions with target may still use it, unless we switched away from it more than
+    /// transition ago
+    pub fn current(&self, target: SocketAddr, transition: Duration) -> bool {
+        self.backends
+            .iter()
+            .find(|b| b.target.addr == target)
+            .is_some_and(|b| {
+                let transition = b.drain.unwrap_or(transition);
+                b.retired
+                    .is_none_or(|at| clock::now() - at < clock::ticks(transition))
+            })
+    }
+
+    /// makes the target named host the active one, returns the previous one if it changed
+    pub fn switch(&mut self, host: &str) -> Option<&Target> {
+        let index = self.backends.iter().position(|b| b.target.host == host)?;
+        if index == self.active {
+            return None;
+        }
+        let previous = self.active;
+        self.active = index;
+        self.backends[previous].retired = Some(clock::now());
+        self.backends[previous].drain = None;
+        self.backends[index].retired = None;
+        Some(&self.backends[previous].target)
+    }
+
+    /// what --control-plane says: target becomes the active one, added if it's new, failover
+    /// the only targets new clients may fail over and overflow to and max_sessions the only
+    /// caps, None keeps what we have, returns the previous active target if it changed
+    #[cfg_attr(not(feature = "control-plane"), allow(dead_code))]
+    pub fn apply(
+        &mut self,
+        target: Option<Target>,
+        failover: Option<Vec<Target>>,
+        max_sessions: Option<&[(String, usize)]>,
+    ) -> Result<Option<Target>, String> {
+        // checked before anything changes
+        let known = |host: &str| {
+            self.backends.iter().any(|b| b.target.host == host)
+                || target
+                    .iter()
+                    .chain(failover.iter().flatten())
+                    .any(|t| t.host == host)
+        };
+        if let Some((host, _)) = max_sessions
+            .into_iter()
+            .flatten()
+            .find(|(host, _)| !known(host))
+        {
+            return Err(format!("max-sessions for unknown target: {}", host));
+        }
+        if let Some(failover) = failover {
+            for backend in &mut self.backends {
+                backend.failover = failover.iter().any(|t| t.host == backend.target.host);
+            }
+            for target in failover {
+                self.add(target, true);
+            }
+        }
+        let previous = match target {
+            Some(target) => {
+                let host = target.host.clone();
+                self.add(target, false);
+                self.switch(&host).cloned()
+            }
+            None => None,
+        };
+        if let Some(max_sessions) = max_sessions {
+            for backend in &mut self.backends {
+                backend.max_sessions = None;
+            }
+            for (host, n) in max_sessions {
+                self.limit(host, *n);
+            }
+        }
+        Ok(previous)
+    }
+
+    /// counts a handshake initiation sent to addr, returns its target if that makes limit in a row
+    /// without a response and it isn't being checked already
+    pub fn initiated(&self, addr: SocketAddr, limit: u32) -> Option<&Target> {
+        let backend = self.backends.iter().find(|b| b.target.addr == addr)?;
+        if backend.unanswered.fetch_add(1, Relaxed) + 1 < limit
+            || backend.checking.swap(true, Relaxed)
+        {
+            return None;
+        }
+        Some(&backend.target)
+    }
+
+    /// addr responded to a handshake, so it isn't blackholed
+    pub fn answered(&self, addr: SocketAddr) {
+        if let Some(backend) = self.backends.iter().find(|b| b.target.addr == addr) {
+            backend.unanswered.store(0, Relaxed);
+        }
+    }
+
+    /// done checking the target named host, which is now at addr, counting starts over
+    pub fn checked(&mut self, host: &str, addr: SocketAddr) {
+        if let Some(backend) = self.backends.iter_mut().find(|b| b.target.host == host) {
+            backend.target.addr = addr;
+            backend.unanswered.store(0, Relaxed);
+            backend.checking.store(false, Relaxed);
+        }
+    }
+
+    /// has sessions with the target named host stay for drain after it's switched away from,
+    /// rather than --schedule-transition
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn drain(&mut self, host: &str, drain: Duration) {
+        if let Some(backend) = self.backends.iter_mut().find(|b| b.target.host == host) {
+            backend.drain = Some(drain);
+        }
+    }
+
+    /// every target we have
+    pub fn all(&self) -> impl Iterator<Item = &Target> {
+        self.backends.iter().map(|b| &b.target)
+    }
+
+    /// the target named host if it isn't being checked already, which it is from now on, for
+    /// --probe finding its path down
+    pub fn suspect(&self, host: &str) -> Option<&Target> {
+        let backend = self.backends.iter().find(|b| b.target.host == host)?;
+        if backend.checking.swap(true, Relaxed) {
+            return None;
+        }
+        Some(&backend.target)
+    }
+
+    /// the next failover target after the one named host, if there is another
+    pub fn next_failover(&self, host: &str) -> Option<&Target> {
+        let index = self.backends.iter().position(|b| b.target.host == host)?;
+        let len = self.backends.len();
+        (1..len)
+            .map(|i| &self.backends[(index + i) % len])
+            .find(|b| b.failover)
+            .map(|b| &b.target)
+    }
+}
+
+impl Backend {
+    fn new(target: Target, failover: bool) -> Backend {
+        Backend {
+            target,
+            failover,
+            max_sessions: None,
+            retired: None,
+            drain: None,
+            unanswered: AtomicU32::new(0),
+            checking: AtomicBool::new(false),
+            pacer: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str) -> Target {
+        Target {
+            host: host.to_string(),
+            addr: host.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_targets() {
+        let a = target("192.0.2.1:1");
+        let b = target("192.0.2.2:1");
+        let c = target("192.0.2.3:1");
+        let mut targets = Targets::new(a.clone());
+        targets.add(b.clone(), false);
+        targets.add(c.clone(), true);
+        assert_eq!(targets.active(), a.addr);
+        assert!(targets.switch(&a.host).is_none());
+        assert!(targets.switch("192.0.2.4:1").is_none());
+        assert_eq!(targets.switch(&b.host).unwrap().addr, a.addr);
+        assert_eq!(targets.active(), b.addr);
+        assert!(targets.current(b.addr, Duration::ZERO));
+        assert!(!targets.current(a.addr, Duration::ZERO));
+        assert!(targets.current(a.addr, Duration::from_secs(60)));
+        // switch-target's drain instead
+        targets.drain(&a.host, Duration::from_secs(60));
+        assert!(targets.current(a.addr, Duration::ZERO));
+        assert_eq!(targets.named("192.0.2.1:1").map(|t| t.addr), Some(a.addr));
+
+        // b isn't a failover target
+        assert_eq!(targets.next_failover(&a.host).unwrap().addr, c.addr);
+        assert_eq!(targets.next_failover(&c.host).unwrap().addr, a.addr);
+
+        assert!(targets.initiated(b.addr, 3).is_none());
+        targets.answered(b.addr);
+        assert!(targets.initiated(b.addr, 3).is_none());
+        assert!(targets.initiated(b.addr, 3).is_none());
+        assert_eq!(targets.initiated(b.addr, 3).unwrap().host, b.host);
+        // only reported once until checked
+        assert!(targets.initiated(b.addr, 3).is_none());
+        let moved = "192.0.2.5:1".parse().unwrap();
+        targets.checked(&b.host, moved);
+        assert_eq!(targets.active(), moved);
+        assert!(!targets.contains(&b.addr));
+
+        assert!(!targets.limited());
+        assert!(targets.limit(&c.host, 10));
+        assert!(!targets.limit("192.0.2.4:1", 10));
+        assert!(targets.limited());
+        // a was switched away from
+        let candidates: Vec<_> = targets.candidates().collect();
+        assert_eq!(candidates, [(moved, None), (c.addr, Some(10))]);
+
+        // --control-plane, c loses its failover and cap, d is new
+        let d = target("192.0.2.4:1");
+        let caps = [(d.host.clone(), 5)];
+        let bad = [("192.0.2.9:1".to_string(), 5)];
+        assert!(targets.apply(None, None, Some(&bad)).is_err());
+        let previous = targets.apply(Some(a.clone()), Some(vec![d.clone()]), Some(&caps));
+        assert_eq!(previous.unwrap().unwrap().addr, moved);
+        let candidates: Vec<_> = targets.candidates().collect();
+        assert_eq!(candidates, [(a.addr, None), (d.addr, Some(5))]);
+        assert!(targets.apply(None, None, None).unwrap().is_none());
+    }
+}
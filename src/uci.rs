@@ -0,0 +1,241 @@
+// --uci, options from OpenWrt style configuration instead of the command line, either as an
+// /etc/config file has them:
+//
+//     config proxy 'main'
+//         option target 'vpn.example.com:51820'
+//         list failover 'backup.example.com:51820'
+//         option verbose '1'
+//
+// or as key=value lines, the way uci show prints a package or a flat file has them:
+//
+//     wireguard-udp-proxy.main.target='vpn.example.com:51820'
+//     failover=backup.example.com:51820
+//
+// each is the long option of the same name with - for _, but target, bind and threads, which are
+// target_addr, bind_addr and num_threads, a flag is on for 1, yes, on or true, and enabled is the
+// init script's
+
+use crate::{
+    args::{self, Args},
+    config,
+};
+
+use std::{
+    collections::HashSet,
+    io::{self, Result},
+};
+
+/// options with every --uci path replaced by the options in path, or on stdin for -
+pub fn expand(options: Vec<String>) -> Result<Vec<String>> {
+    let mut args = Args::new(options);
+    let paths = args.get_all("--uci")?;
+    let key = args.get_option("--config-key")?;
+    let mut options = args.rest();
+    options.extend(key.iter().map(|key| format!("--config-key={}", key)));
+    for path in paths {
+        let input = match path.as_str() {
+            "-" if key.is_some() => {
+                return Err(args::invalid("--config-key can't verify --uci -".to_string()).into())
+            }
+            "-" => io::read_to_string(io::stdin())?,
+            path => config::read(path, key.as_deref())?,
+        };
+        let uci = parse(&input).map_err(|e| args::invalid(format!("--uci {}: {}", path, e)))?;
+        // or a file could pick who signs the next one
+        if uci.iter().any(|option| option.starts_with("--config-key")) {
+            let e = format!("--uci {}: --config-key only goes on the command line", path);
+            return Err(args::invalid(e).into());
+        }
+        options.extend(uci);
+    }
+    Ok(options)
+}
+
+/// the command line input stands for
+fn parse(input: &str) -> std::result::Result<Vec<String>, String> {
+    let mut entries = Vec::new();
+    let mut sections = HashSet::new();
+    for line in input.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once(char::is_whitespace) {
+            Some(("config", section)) => {
+                sections.insert(section.trim().to_string());
+                continue;
+            }
+            Some(("option" | "list", entry)) => {
+                let entry = entry.trim();
+                entry.split_once(char::is_whitespace).unwrap_or((entry, ""))
+            }
+            _ => line
+                .split_once('=')
+                .ok_or_else(|| format!("can't make sense of {}", line))?,
+        };
+        // package.section.option from uci show, package.section=type declares the section
+        let key = match key.trim().split('.').collect::<Vec<_>>()[..] {
+            [key] => key,
+            [_, section] => {
+                sections.insert(section.to_string());
+                continue;
+            }
+            [_, section, key] => {
+                sections.insert(section.to_string());
+                key
+            }
+            _ => return Err(format!("can't make sense of {}", line)),
+        };
+        for value in values(value.trim())? {
+            entries.push((unquote(key), value));
+        }
+    }
+    if sections.len() > 1 {
+        return Err("only one section please".to_string());
+    }
+    options(entries)
+}
+
+/// the options that say what entries do
+fn options(entries: Vec<(String, String)>) -> std::result::Result<Vec<String>, String> {
+    let mut options = Vec::new();
+    let (mut target, mut bind, mut threads) = (None, None, None);
+    for (key, value) in entries {
+        match key.as_str() {
+            "enabled" => {}
+            "target" => target = Some(value),
+            "bind" => bind = Some(value),
+            "threads" => threads = Some(value),
+            _ => {
+                let option = format!("--{}", key.replace('_', "-"));
+                if !is_flag(&option) {
+                    options.push(format!("{}={}", option, value));
+                    continue;
+                }
+                match value.as_str() {
+                    "1" | "yes" | "on" | "true" => options.push(option),
+                    "0" | "no" | "off" | "false" => {}
+                    _ => return Err(format!("{} is on or off, not {}", key, value)),
+                }
+            }
+        }
+    }
+    options.extend(target);
+    if bind.is_some() || threads.is_some() {
+        options.push(bind.unwrap_or_else(|| config::DEFAULT_BIND.to_string()));
+    }
+    options.extend(threads);
+    Ok(options)
+}
+
+/// whether option takes no value, going by how USAGE lists it
+fn is_flag(option: &str) -> bool {
+    config::USAGE.lines().any(|line| {
+        line.trim_start()
+            .strip_prefix(option)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("  "))
+    })
+}
+
+/// what value says, a list of 'quoted' words, as uci show prints lists, or all of it
+fn values(value: &str) -> std::result::Result<Vec<String>, String> {
+    if !value.starts_with(['\'', '"']) {
+        return Ok(vec![value.to_string()]);
+    }
+    let mut values = Vec::new();
+    let mut word = String::new();
+    let mut chars = value.chars();
+    let mut started = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                started = true;
+                // uci show writes a ' as '\'', so quoted parts next to each other are one word
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(other) => word.push(other),
+                        None => return Err(format!("unterminated quote in {}", value)),
+                    }
+                }
+            }
+            '\\' => word.extend(chars.next()),
+            c if c.is_whitespace() => {
+                if started {
+                    values.push(std::mem::take(&mut word));
+                    started = false;
+                }
+            }
+            c => {
+                started = true;
+                word.push(c)
+            }
+        }
+    }
+    if started {
+        values.push(word);
+    }
+    Ok(values)
+}
+
+fn unquote(s: &str) -> String {
+    s.trim_matches(['\'', '"']).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let file = "
+# /etc/config/wireguard-udp-proxy
+config proxy 'main'
+\toption enabled '1'
+\toption target 'vpn.example.com:51820'
+\toption threads '2'
+\tlist failover 'a:51820'
+\tlist failover 'b:51820'
+\toption schedule '0 22 * * * a:51820'
+\toption verbose '1'
+\toption stun '0'
+";
+        assert_eq!(
+            parse(file).unwrap(),
+            [
+                "--failover=a:51820",
+                "--failover=b:51820",
+                "--schedule=0 22 * * * a:51820",
+                "--verbose",
+                "vpn.example.com:51820",
+                config::DEFAULT_BIND,
+                "2",
+            ]
+        );
+
+        let show = "wireguard-udp-proxy.main=proxy
+wireguard-udp-proxy.main.target='vpn.example.com:51820'
+wireguard-udp-proxy.main.failover='a:51820' 'b:51820'
+wireguard-udp-proxy.main.log_file='/tmp/it'\\''s.log'
+";
+        assert_eq!(
+            parse(show).unwrap(),
+            [
+                "--failover=a:51820",
+                "--failover=b:51820",
+                "--log-file=/tmp/it's.log",
+                "vpn.example.com:51820",
+            ]
+        );
+
+        let flat = "bind=127.0.0.1:5000\npeer_relay=yes\npsk_file=/etc/psk\n";
+        assert_eq!(
+            parse(flat).unwrap(),
+            ["--peer-relay", "--psk-file=/etc/psk", "127.0.0.1:5000"]
+        );
+
+        assert!(parse("verbose=maybe").is_err());
+        assert!(parse("p.a.target=x\np.b.target=y").is_err());
+        assert!(parse("target 'x'").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+// --webhook-url, POSTs each event as JSON over plain http, a connection per event is plenty for how
+// few there are, put something local in front of it for https
+
+use crate::{args, events::Deliver};
+
+use std::{
+    io::{BufRead, BufReader, Error, ErrorKind, Result, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+// for connecting, and for each read and write after
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub struct Webhook {
+    /// host:port, what we connect to and the Host header
+    host: String,
+    path: String,
+}
+
+impl Webhook {
+    pub fn parse(url: &str) -> Result<Webhook> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            args::invalid(format!(
+                "--webhook-url must be an http:// url, https isn't supported: {}",
+                url
+            ))
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(args::invalid(format!("invalid --webhook-url: {}", url)).into());
+        }
+        // the port is optional in urls but not for connecting, a literal ipv6 address is in []
+        let host = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'))
+        {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Webhook {
+            host,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Deliver for Webhook {
+    fn describe(&self) -> String {
+        format!("http://{}{}", self.host, self.path)
+    }
+
+    fn deliver(&mut self, _event: &str, json: &str) -> Result<()> {
+        // looked up every time, it may move
+        let addr = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            json.len(),
+            json
+        )?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        // HTTP/1.1 200 OK
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(Error::other(format!("it answered {}", status.trim_end()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io::Read, net::TcpListener, thread};
+
+    #[test]
+    fn test_webhook() {
+        let parse = |url| Webhook::parse(url).map(|w| (w.host, w.path)).ok();
+        assert_eq!(
+            parse("http://example.com"),
+            Some(("example.com:80".to_string(), "/".to_string()))
+        );
+        assert_eq!(
+            parse("http://[::1]/hook?a=b"),
+            Some(("[::1]:80".to_string(), "/hook?a=b".to_string()))
+        );
+        assert_eq!(
+            parse("http://127.0.0.1:8080/hook"),
+            Some(("127.0.0.1:8080".to_string(), "/hook".to_string()))
+        );
+        assert_eq!(parse("https://example.com/"), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            // up to the end of the body, the client keeps its end open for our answer
+            let mut buf = [0u8; 1024];
+            while !request.ends_with('}') {
+                let read = stream.read(&mut buf).unwrap();
+                request.push_str(std::str::from_utf8(&buf[..read]).unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            request
+        });
+        let mut webhook = Webhook::parse(&url).unwrap();
+        webhook.deliver("failover", "{}").unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\n{}"));
+    }
+}